@@ -0,0 +1,279 @@
+//! Benchmark del servidor: abre varias conexiones que publican y otras suscriptas al mismo topic,
+//! publica a un ritmo fijo y muestra el throughput, la latencia de punta a punta y los mensajes
+//! perdidos o duplicados:
+//!
+//! ```text
+//! bench -p 1883 --pub 10 --sub 5 -n 200 --rate 50 --size 64 -q 1
+//! ```
+mod options;
+mod stats;
+mod worker;
+
+use crate::options::Options;
+use crate::stats::{percentile, Stats};
+use crate::worker::Published;
+use std::env;
+use std::error::Error;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Payload con el que se crea el topic, que los suscriptores ignoran.
+const SETUP_PAYLOAD: &str = "bench";
+/// Espera para que el servidor procese el publish que crea el topic.
+const SETUP_MILLIS: u64 = 200;
+const POLL_MILLIS: u64 = 50;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match options::parse(&args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, options::USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    create_topic(&options)?;
+    let options = Arc::new(options);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let (ready, subscribed) = mpsc::channel();
+    let subscribers: Vec<JoinHandle<Result<Stats, String>>> = (0..options.subscribers)
+        .map(|id| {
+            let (options, ready, done) = (options.clone(), ready.clone(), done.clone());
+            thread::spawn(move || worker::subscribe(&options, id, ready, done))
+        })
+        .collect();
+    drop(ready);
+    for _ in 0..options.subscribers {
+        let _res = subscribed.recv();
+    }
+
+    let start = Arc::new(Barrier::new(options.publishers as usize + 1));
+    let publishers: Vec<JoinHandle<Result<Published, String>>> = (0..options.publishers)
+        .map(|id| {
+            let (options, start) = (options.clone(), start.clone());
+            thread::spawn(move || worker::publish(&options, id, &start))
+        })
+        .collect();
+    start.wait();
+    let began = Instant::now();
+    let published: Vec<Result<Published, String>> = publishers.into_iter().map(join).collect();
+    let publish_elapsed = began.elapsed();
+
+    // Los suscriptores terminan solos cuando reciben todo; si no, se cortan despues de la espera.
+    let deadline = Instant::now() + options.wait;
+    while Instant::now() < deadline && subscribers.iter().any(|s| !s.is_finished()) {
+        thread::sleep(Duration::from_millis(POLL_MILLIS));
+    }
+    done.store(true, Ordering::SeqCst);
+    let received: Vec<Result<Stats, String>> = subscribers.into_iter().map(join).collect();
+
+    println!(
+        "{}",
+        report(&options, &published, &received, began, publish_elapsed)
+    );
+    Ok(())
+}
+
+/// Publica en el topic para que exista antes de que se suscriban, porque el servidor no acepta
+/// suscripciones a topics que no tienen mensajes.
+fn create_topic(options: &Options) -> Result<(), Box<dyn Error>> {
+    let (connection, _events) = worker::connect(options, &worker::client_id("setup", 0))?;
+    let result = connection.publish(&options.topic, SETUP_PAYLOAD, 0, false);
+    thread::sleep(Duration::from_millis(SETUP_MILLIS));
+    let _res = connection.disconnect();
+    connection.close();
+    result.map_err(|e| format!("No se pudo crear el topic {}: {:?}", options.topic, e))?;
+    Ok(())
+}
+
+fn join<T>(handle: JoinHandle<Result<T, String>>) -> Result<T, String> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err("El thread termino con un panic".to_string()))
+}
+
+/// Resumen del benchmark. `began` es cuando empezaron a publicar y `publish_elapsed` cuanto
+/// tardaron en terminar todos los publicadores.
+fn report(
+    options: &Options,
+    published: &[Result<Published, String>],
+    received: &[Result<Stats, String>],
+    began: Instant,
+    publish_elapsed: Duration,
+) -> String {
+    let mut lines = vec![format!(
+        "{} publicadores y {} suscriptores en {} (topic {}, QoS {}), {} mensajes de {} bytes por \
+         publicador a {}",
+        options.publishers,
+        options.subscribers,
+        options.address(),
+        options.topic,
+        options.qos,
+        options.messages,
+        options.size,
+        match options.rate {
+            0 => "maxima velocidad".to_string(),
+            rate => format!("{} msg/s", rate),
+        }
+    )];
+    let (mut sent, mut errors, mut pubacks) = (0u64, 0u64, 0u64);
+    for result in published {
+        match result {
+            Ok(p) => {
+                sent += p.sent as u64;
+                errors += p.errors as u64;
+                pubacks += p.pubacks as u64;
+            }
+            Err(e) => lines.push(format!("Publicador: {}", e)),
+        }
+    }
+    lines.push(format!(
+        "Enviados: {} en {:.2} s ({:.1} msg/s), errores: {}",
+        sent,
+        publish_elapsed.as_secs_f64(),
+        throughput(sent, publish_elapsed),
+        errors
+    ));
+    if options.qos == 1 {
+        lines.push(format!("PUBACK: {} de {}", pubacks, sent));
+    }
+
+    // Cada suscriptor deberia recibir todo lo que se envio.
+    let (mut total, mut lost, mut duplicated, mut ignored) = (0u64, 0u64, 0u64, 0u64);
+    let mut latencies: Vec<Duration> = vec![];
+    let mut last_received: Option<Instant> = None;
+    let mut subscribed = 0u64;
+    for result in received {
+        match result {
+            Ok(stats) => {
+                subscribed += 1;
+                total += stats.total();
+                lost += sent.saturating_sub(stats.unique());
+                duplicated += stats.duplicated();
+                ignored += stats.get_ignored() as u64;
+                latencies.extend_from_slice(stats.get_latencies());
+                last_received = last_received.max(stats.get_last_received());
+            }
+            Err(e) => lines.push(format!("Suscriptor: {}", e)),
+        }
+    }
+    let delivery_elapsed = match last_received {
+        Some(last) => last.saturating_duration_since(began),
+        None => Duration::ZERO,
+    };
+    lines.push(format!(
+        "Recibidos: {} de {} esperados ({:.1} msg/s), perdidos: {}, duplicados: {}, ajenos: {}",
+        total,
+        sent * subscribed,
+        throughput(total, delivery_elapsed),
+        lost,
+        duplicated,
+        ignored
+    ));
+    latencies.sort();
+    lines.push(format!(
+        "Latencia (ms): min {}, p50 {}, p90 {}, p99 {}, max {}",
+        millis(latencies.first().copied()),
+        millis(percentile(&latencies, 50.0)),
+        millis(percentile(&latencies, 90.0)),
+        millis(percentile(&latencies, 99.0)),
+        millis(latencies.last().copied()),
+    ));
+    lines.join("\n")
+}
+
+/// Mensajes por segundo.
+fn throughput(messages: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => messages as f64 / secs,
+        _ => 0.0,
+    }
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    match duration {
+        Some(d) => format!("{:.2}", d.as_secs_f64() * 1000.0),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::parse;
+    use crate::stats::{payload, percentile, Stats};
+    use std::time::{Duration, SystemTime};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_bench_options() {
+        let options = parse(&args(
+            "-p 18830 --pub 4 --sub 2 -t carga -n 50 --rate 0 --size 128 -q 1 --wait 5",
+        ))
+        .unwrap();
+        assert_eq!(options.address(), "127.0.0.1:18830");
+        assert_eq!((options.publishers, options.subscribers), (4, 2));
+        assert_eq!(options.topic, "carga");
+        assert_eq!((options.messages, options.rate, options.size), (50, 0, 128));
+        assert_eq!(options.qos, 1);
+        assert_eq!(options.wait, Duration::from_secs(5));
+        assert_eq!(options.expected(), 200);
+        assert!(parse(&args("-q 2")).is_err());
+        assert!(parse(&args("--pub 0")).is_err());
+        assert!(parse(&args("--rate")).is_err());
+        assert_eq!(parse(&args("--size 244")).unwrap().max_size(), 244);
+        assert!(parse(&args("--size 245")).is_err());
+        assert!(parse(&args("-V 3 --size 248")).is_ok());
+        assert!(parse(&args("-q 1 --size 243")).is_err());
+    }
+
+    #[test]
+    fn stats_count_lost_and_duplicated_messages() {
+        let sent = SystemTime::now();
+        let first = payload(0, 0, sent, 32);
+        assert_eq!(first.len(), 32);
+        let mut stats = Stats::new();
+        stats.record(&first, sent + Duration::from_millis(3));
+        stats.record(&first, sent + Duration::from_millis(5));
+        stats.record(&payload(1, 7, sent, 0), sent + Duration::from_millis(1));
+        stats.record("bench", sent);
+        assert_eq!(
+            (stats.total(), stats.unique(), stats.duplicated()),
+            (3, 2, 1)
+        );
+        assert_eq!(stats.get_ignored(), 1);
+        let mut latencies = stats.get_latencies().to_vec();
+        latencies.sort();
+        assert_eq!(latencies[0], Duration::from_millis(1));
+        assert_eq!(latencies[2], Duration::from_millis(5));
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(
+            percentile(&latencies, 50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            percentile(&latencies, 99.0),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(percentile(&latencies, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}
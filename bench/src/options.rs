@@ -0,0 +1,151 @@
+use mqtt_client::{PROTOCOL_VERSION_3, PROTOCOL_VERSION_5};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// El remaining length de un PUBLISH se codifica en un solo byte.
+const MAX_REMAINING_LENGTH: usize = 255;
+/// Digitos de los microsegundos del encabezado del payload, alcanzan hasta el año 2286.
+const MICROS_DIGITS: usize = 16;
+
+/// Opciones de linea de comandos del benchmark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub protocol_version: u8,
+    pub publishers: u32,
+    pub subscribers: u32,
+    pub topic: String,
+    /// Mensajes que envia cada publicador.
+    pub messages: u32,
+    /// Mensajes por segundo de cada publicador. Con 0 publica lo mas rapido que puede.
+    pub rate: u32,
+    /// Tamaño minimo del payload en bytes.
+    pub size: usize,
+    pub qos: u8,
+    /// Cuanto esperar los mensajes que faltan despues de que terminan los publicadores.
+    pub wait: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            protocol_version: PROTOCOL_VERSION_5,
+            publishers: 1,
+            subscribers: 1,
+            topic: "bench".to_string(),
+            messages: 100,
+            rate: 10,
+            size: 32,
+            qos: 0,
+            wait: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Options {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Mensajes que deberia recibir cada suscriptor.
+    pub fn expected(&self) -> u64 {
+        self.publishers as u64 * self.messages as u64
+    }
+
+    /// Payload mas grande que entra en un PUBLISH con este topic, QoS y version.
+    pub fn max_size(&self) -> usize {
+        let packet_id = if self.qos == 1 { 2 } else { 0 };
+        // En MQTT 5 van el largo de las properties y el topic alias (identificador y dos bytes).
+        let properties = if self.protocol_version == PROTOCOL_VERSION_5 {
+            4
+        } else {
+            0
+        };
+        MAX_REMAINING_LENGTH.saturating_sub(2 + self.topic.len() + packet_id + properties)
+    }
+
+    /// Largo maximo del encabezado que se pone al principio de cada payload: publicador,
+    /// secuencia, microsegundos y tres separadores.
+    fn header_size(&self) -> usize {
+        self.publishers.to_string().len() + self.messages.to_string().len() + MICROS_DIGITS + 3
+    }
+}
+
+pub const USAGE: &str = "Uso: bench [opciones]
+
+Conexion:
+  -h <host>            servidor (127.0.0.1)
+  -p <port>            puerto (1883)
+  -V <3|5>             version de MQTT (5)
+
+Carga:
+  --pub <cantidad>     conexiones que publican (1)
+  --sub <cantidad>     conexiones suscriptas (1)
+  -t <topic>           topic en el que se publica y al que se suscriben (bench)
+  -n <cantidad>        mensajes que envia cada publicador (100)
+  --rate <msg/s>       mensajes por segundo de cada publicador, 0 sin limite (10)
+  --size <bytes>       tamaño minimo del payload (32), a lo sumo 255 bytes menos el
+                       topic + 2, el packet id con QoS 1 (2) y las properties en MQTT 5 (4)
+  -q <0|1>             QoS de los publish y de las suscripciones (0)
+  --wait <segundos>    espera de los mensajes que faltan al terminar de publicar (2)";
+
+/// Lee las opciones de los argumentos, sin el nombre del programa.
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("Falta el valor de {}", arg))
+        };
+        match arg.as_str() {
+            "-h" => options.host = value()?,
+            "-p" => options.port = number(arg, &value()?)?,
+            "-V" => {
+                options.protocol_version = match value()?.as_str() {
+                    "3" | "311" => PROTOCOL_VERSION_3,
+                    "5" => PROTOCOL_VERSION_5,
+                    v => return Err(format!("Version de MQTT invalida: {}", v)),
+                }
+            }
+            "--pub" => options.publishers = number(arg, &value()?)?,
+            "--sub" => options.subscribers = number(arg, &value()?)?,
+            "-t" => options.topic = value()?,
+            "-n" => options.messages = number(arg, &value()?)?,
+            "--rate" => options.rate = number(arg, &value()?)?,
+            "--size" => options.size = number(arg, &value()?)?,
+            "-q" => {
+                options.qos = match number(arg, &value()?)? {
+                    q @ 0..=1 => q,
+                    q => return Err(format!("QoS invalido: {}", q)),
+                }
+            }
+            "--wait" => options.wait = Duration::from_secs(number(arg, &value()?)?),
+            _ => return Err(format!("Opcion desconocida: {}", arg)),
+        }
+    }
+    if options.publishers == 0 {
+        return Err("Hace falta al menos un publicador".to_string());
+    }
+    if options.size > options.max_size() {
+        return Err(format!(
+            "--size {} no entra en un PUBLISH, el maximo con este topic, QoS y version es {}",
+            options.size,
+            options.max_size()
+        ));
+    }
+    if options.header_size() > options.max_size() {
+        return Err(format!("El topic {:?} es demasiado largo", options.topic));
+    }
+    Ok(options)
+}
+
+fn number<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Valor invalido para {}: {}", arg, value))
+}
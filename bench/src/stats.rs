@@ -0,0 +1,114 @@
+//! Payloads del benchmark y estadisticas de lo que recibe cada suscriptor.
+//!
+//! Cada payload empieza con `<publicador>:<secuencia>:<microsegundos>:`, donde los microsegundos
+//! son los del momento en que se publico desde 1970-01-01 UTC, y se completa con `.` hasta el
+//! tamaño pedido. Asi el suscriptor puede medir la latencia y detectar perdidos y duplicados sin
+//! estado compartido con los publicadores.
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SEPARATOR: char = ':';
+const PADDING: char = '.';
+
+/// Payload del mensaje `seq` del publicador `publisher`. Si el encabezado ya ocupa mas de `size`
+/// bytes no se completa.
+pub fn payload(publisher: u32, seq: u32, now: SystemTime, size: usize) -> String {
+    let mut payload = format!(
+        "{}{}{}{}{}{}",
+        publisher,
+        SEPARATOR,
+        seq,
+        SEPARATOR,
+        micros(now),
+        SEPARATOR
+    );
+    while payload.len() < size {
+        payload.push(PADDING);
+    }
+    payload
+}
+
+fn micros(time: SystemTime) -> u128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_micros(),
+        Err(_) => 0,
+    }
+}
+
+/// Publicador, secuencia y momento de publicacion de un payload del benchmark.
+fn parse(payload: &str) -> Option<(u32, u32, u128)> {
+    let mut fields = payload.splitn(4, SEPARATOR);
+    let publisher = fields.next()?.parse().ok()?;
+    let seq = fields.next()?.parse().ok()?;
+    let sent = fields.next()?.parse().ok()?;
+    fields.next()?;
+    Some((publisher, seq, sent))
+}
+
+/// Mensajes recibidos por un suscriptor.
+#[derive(Default)]
+pub struct Stats {
+    received: HashMap<(u32, u32), u32>,
+    latencies: Vec<Duration>,
+    last_received: Option<Instant>,
+    ignored: u32,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    /// Registra un payload recibido en `now`. Los que no son del benchmark se cuentan aparte.
+    pub fn record(&mut self, payload: &str, now: SystemTime) {
+        let (publisher, seq, sent) = match parse(payload) {
+            Some(fields) => fields,
+            None => {
+                self.ignored += 1;
+                return;
+            }
+        };
+        *self.received.entry((publisher, seq)).or_insert(0) += 1;
+        // Con los relojes del mismo equipo no deberia pasar, pero si el payload viene del futuro
+        // la latencia queda en 0.
+        let latency = micros(now).saturating_sub(sent);
+        self.latencies
+            .push(Duration::from_micros(latency.min(u64::MAX as u128) as u64));
+        self.last_received = Some(Instant::now());
+    }
+
+    /// Mensajes recibidos, contando los duplicados.
+    pub fn total(&self) -> u64 {
+        self.received.values().map(|count| *count as u64).sum()
+    }
+
+    /// Mensajes distintos recibidos.
+    pub fn unique(&self) -> u64 {
+        self.received.len() as u64
+    }
+
+    pub fn duplicated(&self) -> u64 {
+        self.total() - self.unique()
+    }
+
+    pub fn get_ignored(&self) -> u32 {
+        self.ignored
+    }
+
+    pub fn get_latencies(&self) -> &[Duration] {
+        &self.latencies
+    }
+
+    pub fn get_last_received(&self) -> Option<Instant> {
+        self.last_received
+    }
+}
+
+/// Percentil `p` (entre 0 y 100) de `sorted` por el metodo del rango mas cercano.
+pub fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
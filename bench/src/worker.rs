@@ -0,0 +1,185 @@
+//! Conexiones simuladas del benchmark. Cada publicador y cada suscriptor corre en su thread con su
+//! propia conexion al servidor.
+use crate::options::Options;
+use crate::stats::{self, Stats};
+use mqtt_client::{Connection, Event};
+use serializer::{new_connect_flag, new_payload_connect, new_properties, SubackReturnCode};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Segundos que se espera el CONNACK o el SUBACK.
+const RESPONSE_TIMEOUT_SECS: u64 = 10;
+/// Cada cuanto se fija el suscriptor si tiene que terminar.
+const POLL_MILLIS: u64 = 100;
+const KEEP_ALIVE_SECS: u16 = 60;
+
+/// Lo que envio un publicador.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Published {
+    pub sent: u32,
+    pub errors: u32,
+    pub pubacks: u32,
+}
+
+/// Se conecta con `client_id`, sin credenciales ni will, y espera a que el servidor lo acepte.
+pub fn connect(
+    options: &Options,
+    client_id: &str,
+) -> Result<(Connection, Receiver<Event>), String> {
+    let (tx, rx) = mpsc::channel();
+    let connection = Connection::open(&options.address(), tx)
+        .map_err(|e| format!("No se pudo conectar a {}: {}", options.address(), e))?;
+    let connect_flag = new_connect_flag(
+        Some(true),
+        Some(false),
+        Some(false),
+        Some(false),
+        Some(false),
+        Some(false),
+        Some(false),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    let connect_payload = new_payload_connect(
+        client_id.to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        KEEP_ALIVE_SECS,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    connection
+        .connect(
+            connect_flag,
+            connect_payload,
+            options.protocol_version,
+            new_properties(),
+        )
+        .map_err(|e| format!("{:?}", e))?;
+    match rx.recv_timeout(Duration::from_secs(RESPONSE_TIMEOUT_SECS)) {
+        Ok(Event::Connack { accepted: true, .. }) => Ok((connection, rx)),
+        Ok(Event::Connack { reason, .. }) => {
+            connection.close();
+            Err(format!("{}: conexion rechazada: {}", client_id, reason))
+        }
+        _ => {
+            connection.close();
+            Err(format!(
+                "{}: el servidor no respondio el CONNECT",
+                client_id
+            ))
+        }
+    }
+}
+
+/// Client id unico por proceso, para poder correr varios benchmarks contra el mismo servidor.
+pub fn client_id(role: &str, id: u32) -> String {
+    format!("bench_{}_{}_{}", role, process::id(), id)
+}
+
+/// Se conecta, espera en `start` a que esten listos los demas y publica `options.messages`
+/// mensajes a `options.rate` por segundo. Con QoS 1 espera los PUBACK hasta `options.wait`.
+pub fn publish(options: &Options, id: u32, start: &Barrier) -> Result<Published, String> {
+    let connected = connect(options, &client_id("pub", id));
+    // Aunque no se haya podido conectar hay que pasar la barrera para no trabar a los demas.
+    start.wait();
+    let (connection, events) = connected?;
+    let period = match options.rate {
+        0 => None,
+        rate => Some(Duration::from_secs(1) / rate),
+    };
+    let mut published = Published::default();
+    let began = Instant::now();
+    for seq in 0..options.messages {
+        // Se calcula desde el comienzo para que las demoras de un publish no se acumulen.
+        if let Some(period) = period {
+            let due = began + period * seq;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        let payload = stats::payload(id, seq, SystemTime::now(), options.size);
+        match connection.publish(&options.topic, &payload, options.qos, false) {
+            Ok(_) => published.sent += 1,
+            Err(_) => published.errors += 1,
+        }
+        while let Ok(event) = events.try_recv() {
+            if let Event::Puback = event {
+                published.pubacks += 1;
+            }
+        }
+    }
+    if options.qos == 1 {
+        let deadline = Instant::now() + options.wait;
+        while published.pubacks < published.sent {
+            match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Event::Puback) => published.pubacks += 1,
+                Ok(Event::Disconnected) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+    let _res = connection.disconnect();
+    connection.close();
+    Ok(published)
+}
+
+/// Se conecta, se suscribe al topic y avisa por `ready` (tambien si falla). Registra los mensajes
+/// hasta recibir todos los que se esperan o hasta que se marque `done`.
+pub fn subscribe(
+    options: &Options,
+    id: u32,
+    ready: Sender<()>,
+    done: Arc<AtomicBool>,
+) -> Result<Stats, String> {
+    let subscribed =
+        connect(options, &client_id("sub", id)).and_then(
+            |(connection, events)| match await_suback(options, &connection, &events) {
+                Ok(()) => Ok((connection, events)),
+                Err(e) => {
+                    connection.close();
+                    Err(e)
+                }
+            },
+        );
+    let _res = ready.send(());
+    let (connection, events) = subscribed?;
+    let mut stats = Stats::new();
+    let expected = options.expected();
+    while stats.unique() < expected && !done.load(Ordering::SeqCst) {
+        match events.recv_timeout(Duration::from_millis(POLL_MILLIS)) {
+            Ok(Event::Publish(message)) => stats.record(&message.get_payload(), SystemTime::now()),
+            Ok(Event::Disconnected) => break,
+            _ => {}
+        }
+    }
+    let _res = connection.disconnect();
+    connection.close();
+    Ok(stats)
+}
+
+fn await_suback(
+    options: &Options,
+    connection: &Connection,
+    events: &Receiver<Event>,
+) -> Result<(), String> {
+    connection
+        .subscribe(vec![options.topic.clone()], options.qos)
+        .map_err(|e| e.to_string())?;
+    match events.recv_timeout(Duration::from_secs(RESPONSE_TIMEOUT_SECS)) {
+        Ok(Event::Suback { return_codes })
+            if !return_codes
+                .iter()
+                .any(|code| matches!(code, SubackReturnCode::Failure)) =>
+        {
+            Ok(())
+        }
+        Ok(Event::Suback { .. }) => Err("El servidor rechazo la suscripcion".to_string()),
+        _ => Err("El servidor no respondio el SUBSCRIBE".to_string()),
+    }
+}
@@ -0,0 +1,107 @@
+//! Herramienta de linea de comandos para publicar y suscribirse sin la interfaz grafica:
+//!
+//! ```text
+//! mqtt pub -h 127.0.0.1 -p 1883 -t sensores/temp -q 1 -m 21.5
+//! mqtt sub -t sensores/temp -F json -C 10
+//! mqtt pub -t sensores/temp -m '{"n":{{counter}}}' --save-template temp
+//! mqtt pub -T temp --interval 500 --times 20
+//! ```
+mod options;
+mod publish;
+mod session;
+mod subscribe;
+
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => usage(),
+    };
+    let options = match options::parse(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n", e);
+            usage()
+        }
+    };
+    let result = match command {
+        "pub" => publish::run(&options),
+        "sub" => subscribe::run(&options),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", options::USAGE);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::{parse, Format, Source, Will};
+    use std::time::Duration;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_publish_options() {
+        let options = parse(&args(
+            "-h broker -p 1884 -i sensor -u admin -P secret -V 3 -k 0 -t temp -q 1 -r -m 21.5 \
+             --will-topic estado --will-payload offline --will-qos 1 --will-retain",
+        ))
+        .unwrap();
+        assert_eq!(options.address(), "broker:1884");
+        assert_eq!(options.client_id, "sensor");
+        assert_eq!(options.protocol_version, mqtt_client::PROTOCOL_VERSION_3);
+        assert_eq!(options.keep_alive, 0);
+        assert_eq!(options.topics, vec!["temp".to_string()]);
+        assert_eq!((options.qos, options.retain), (1, true));
+        assert_eq!(options.source, Some(Source::Message("21.5".to_string())));
+        assert_eq!(
+            options.will,
+            Some(Will {
+                topic: "estado".to_string(),
+                payload: "offline".to_string(),
+                qos: 1,
+                retain: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_subscribe_options() {
+        let options = parse(&args("-t a -t b -F json -v -C 3")).unwrap();
+        assert_eq!(options.topics.len(), 2);
+        assert_eq!(options.format, Format::Json);
+        assert_eq!(options.count, Some(3));
+
+        assert!(parse(&args("-t a -q 2")).is_err());
+        assert!(parse(&args("-t a --will-topic estado")).is_err());
+        assert!(parse(&args("-m sin-topic")).is_err());
+        assert!(parse(&args("-t a -p")).is_err());
+    }
+
+    #[test]
+    fn parse_template_options() {
+        let options = parse(&args("-T temp --interval 250 --times 4 --templates t.json")).unwrap();
+        assert_eq!(options.source, Some(Source::Template("temp".to_string())));
+        assert_eq!(options.interval, Some(Duration::from_millis(250)));
+        assert_eq!(options.times, Some(4));
+        assert_eq!(options.templates, "t.json");
+        assert!(options.is_scheduled());
+
+        let options = parse(&args("-t temp -m 21 --save-template temp")).unwrap();
+        assert_eq!(options.save_template, Some("temp".to_string()));
+        assert!(!options.is_scheduled());
+        assert!(parse(&args("--times 4")).is_err());
+    }
+}
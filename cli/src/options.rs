@@ -0,0 +1,228 @@
+use mqtt_client::{PROTOCOL_VERSION_3, PROTOCOL_VERSION_5, TEMPLATES_PATH};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// De donde se toma el mensaje a publicar.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Message(String),
+    File(String),
+    /// Todo stdin como un solo mensaje.
+    Stdin,
+    /// Cada linea de stdin como un mensaje.
+    Lines,
+    /// Template guardado, que tiene el topic, el QoS y el retain.
+    Template(String),
+}
+
+/// Como se imprimen los mensajes recibidos.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `<topic> <payload>`, o solo el payload si no se pidio el topic con `-v`.
+    Plain,
+    /// Un objeto JSON por linea con topic, payload, qos y retain.
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Opciones de linea de comandos de `mqtt pub` y `mqtt sub`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub protocol_version: u8,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub will: Option<Will>,
+    pub topics: Vec<String>,
+    pub qos: u8,
+    pub retain: bool,
+    pub source: Option<Source>,
+    /// Espera entre publicaciones al repetir el mensaje.
+    pub interval: Option<Duration>,
+    /// Cuantas veces publicar el mensaje.
+    pub times: Option<u32>,
+    pub templates: String,
+    /// Guardar el mensaje como template con este nombre en vez de publicarlo.
+    pub save_template: Option<String>,
+    pub format: Format,
+    pub verbose: bool,
+    pub count: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: format!("mqtt_{}", std::process::id()),
+            username: "".to_string(),
+            password: "".to_string(),
+            protocol_version: PROTOCOL_VERSION_5,
+            clean_session: true,
+            keep_alive: 60,
+            will: None,
+            topics: vec![],
+            qos: 0,
+            retain: false,
+            source: None,
+            interval: None,
+            times: None,
+            templates: TEMPLATES_PATH.to_string(),
+            save_template: None,
+            format: Format::Plain,
+            verbose: false,
+            count: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Si hay que publicar mas de una vez o con un template. Si no se indica el intervalo se
+    /// publica cada segundo.
+    pub fn is_scheduled(&self) -> bool {
+        self.interval.is_some()
+            || self.times.is_some()
+            || matches!(self.source, Some(Source::Template(_)))
+    }
+}
+
+pub const USAGE: &str = "Uso: mqtt <pub|sub> [opciones]
+
+Conexion:
+  -h <host>            servidor (127.0.0.1)
+  -p <port>            puerto (1883)
+  -i <client id>       client id (mqtt_<pid>)
+  -u <username>        usuario
+  -P <password>        password
+  -V <3|5>             version de MQTT (5)
+  -k <segundos>        keep alive (60, 0 lo desactiva)
+  -c                   no limpiar la sesion al conectarse
+  --will-topic <t>     topic del last will
+  --will-payload <m>   mensaje del last will
+  --will-qos <0|1>     QoS del last will (0)
+  --will-retain        publicar el last will como retain
+
+Publish y subscribe:
+  -t <topic>           topic (en sub se puede repetir)
+  -q <0|1>             QoS (0)
+
+Publish:
+  -r                   publicar como retain
+  -m <mensaje>         mensaje
+  -f <archivo>         mensaje leido de un archivo
+  -s                   mensaje leido de stdin
+  -l                   cada linea de stdin es un mensaje
+  -T <nombre>          publicar el template guardado con ese nombre (sin -t)
+  --interval <ms>      repetir el mensaje con esa espera entre publicaciones (1000)
+  --times <cantidad>   publicar el mensaje esa cantidad de veces (1 con -T, sin fin con --interval)
+  --save-template <n>  guardar -t, -q, -r y -m como template en vez de publicar
+  --templates <arch>   archivo de templates (templates.json)
+  En los mensajes que se repiten y en los templates se reemplazan {{timestamp}},
+  {{counter}} y {{random}}.
+
+Subscribe:
+  -F <plain|json>      formato de los mensajes recibidos (plain)
+  -v                   imprimir el topic en formato plain
+  -C <cantidad>        salir despues de recibir esa cantidad de mensajes";
+
+/// Lee las opciones de los argumentos, sin el nombre del programa ni el subcomando.
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut will_topic = None;
+    let mut will_payload = None;
+    let mut will_qos = 0;
+    let mut will_retain = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("Falta el valor de {}", arg))
+        };
+        match arg.as_str() {
+            "-h" => options.host = value()?,
+            "-p" => options.port = number(arg, &value()?)?,
+            "-i" => options.client_id = value()?,
+            "-u" => options.username = value()?,
+            "-P" => options.password = value()?,
+            "-V" => {
+                options.protocol_version = match value()?.as_str() {
+                    "3" | "311" => PROTOCOL_VERSION_3,
+                    "5" => PROTOCOL_VERSION_5,
+                    v => return Err(format!("Version de MQTT invalida: {}", v)),
+                }
+            }
+            "-k" => options.keep_alive = number(arg, &value()?)?,
+            "-c" => options.clean_session = false,
+            "--will-topic" => will_topic = Some(value()?),
+            "--will-payload" => will_payload = Some(value()?),
+            "--will-qos" => will_qos = qos(arg, &value()?)?,
+            "--will-retain" => will_retain = true,
+            "-t" => options.topics.push(value()?),
+            "-q" => options.qos = qos(arg, &value()?)?,
+            "-r" => options.retain = true,
+            "-m" => options.source = Some(Source::Message(value()?)),
+            "-f" => options.source = Some(Source::File(value()?)),
+            "-s" => options.source = Some(Source::Stdin),
+            "-l" => options.source = Some(Source::Lines),
+            "-T" => options.source = Some(Source::Template(value()?)),
+            "--interval" => options.interval = Some(Duration::from_millis(number(arg, &value()?)?)),
+            "--times" => options.times = Some(number(arg, &value()?)?),
+            "--templates" => options.templates = value()?,
+            "--save-template" => options.save_template = Some(value()?),
+            "-F" => {
+                options.format = match value()?.as_str() {
+                    "plain" => Format::Plain,
+                    "json" => Format::Json,
+                    f => return Err(format!("Formato invalido: {}", f)),
+                }
+            }
+            "-v" => options.verbose = true,
+            "-C" => options.count = Some(number(arg, &value()?)?),
+            _ => return Err(format!("Opcion desconocida: {}", arg)),
+        }
+    }
+    options.will = match (will_topic, will_payload) {
+        (Some(topic), Some(payload)) => Some(Will {
+            topic,
+            payload,
+            qos: will_qos,
+            retain: will_retain,
+        }),
+        (None, None) => None,
+        _ => return Err("El last will necesita --will-topic y --will-payload".to_string()),
+    };
+    if options.topics.is_empty() && !matches!(options.source, Some(Source::Template(_))) {
+        return Err("Falta el topic (-t)".to_string());
+    }
+    Ok(options)
+}
+
+fn number<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Valor invalido para {}: {}", arg, value))
+}
+
+/// No implementamos QoS 2.
+fn qos(arg: &str, value: &str) -> Result<u8, String> {
+    match number(arg, value)? {
+        q @ 0..=1 => Ok(q),
+        _ => Err(format!("QoS invalido para {}: {}", arg, value)),
+    }
+}
@@ -0,0 +1,175 @@
+use crate::options::{Options, Source};
+use crate::session;
+use mqtt_client::{Connection, Event, Schedule, Template, Templates};
+use serializer::mqtt_response::MqttError;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Segundos que se espera el PUBACK de un publish QoS 1.
+const PUBACK_TIMEOUT_SECS: u64 = 10;
+/// Espera entre publicaciones si se repite el mensaje sin `--interval`.
+const DEFAULT_INTERVAL_MILLIS: u64 = 1000;
+
+/// Publica el mensaje en el topic y se desconecta. Con QoS 1 espera el PUBACK de cada mensaje.
+pub fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    let source = match &options.source {
+        Some(source) => source.clone(),
+        None => return Err("Falta el mensaje (-m, -f, -s, -l o -T)".into()),
+    };
+    if let Some(name) = &options.save_template {
+        return save_template(options, name, &source);
+    }
+    if options.is_scheduled() {
+        return schedule(options, source);
+    }
+    let topic = single_topic(options)?;
+    let (connection, events) = session::connect(options)?;
+    match source {
+        Source::Lines => {
+            for line in io::stdin().lock().lines() {
+                publish(&connection, &events, options, &topic, &line?)?;
+            }
+        }
+        source => {
+            let message = read_message(&source)?;
+            publish(&connection, &events, options, &topic, &message)?
+        }
+    }
+    let _res = connection.disconnect();
+    connection.close();
+    Ok(())
+}
+
+fn single_topic(options: &Options) -> Result<String, Box<dyn Error>> {
+    match options.topics.as_slice() {
+        [topic] => Ok(topic.clone()),
+        _ => Err("mqtt pub publica en un solo topic".into()),
+    }
+}
+
+/// Lee el mensaje de `-m`, `-f` o `-s`.
+fn read_message(source: &Source) -> Result<String, Box<dyn Error>> {
+    match source {
+        Source::Message(message) => Ok(message.clone()),
+        Source::File(path) => Ok(fs::read_to_string(path)?),
+        Source::Stdin => {
+            let mut message = String::new();
+            io::stdin().read_to_string(&mut message)?;
+            Ok(message)
+        }
+        Source::Lines => Err("No se puede usar cada linea de stdin (-l) como un mensaje".into()),
+        Source::Template(_) => Err("El mensaje ya es un template (-T)".into()),
+    }
+}
+
+/// Guarda el topic, el QoS, el retain y el mensaje como template, sin conectarse.
+fn save_template(options: &Options, name: &str, source: &Source) -> Result<(), Box<dyn Error>> {
+    let template = Template::new(name, &single_topic(options)?, &read_message(source)?)
+        .set_qos(options.qos)
+        .set_retain(options.retain);
+    let mut templates = Templates::load(&options.templates)?;
+    templates.save_template(template)?;
+    println!("Template {} guardado en {}", name, options.templates);
+    Ok(())
+}
+
+/// Publica el template, o el mensaje como template, las veces que se pidio y espera los PUBACK.
+fn schedule(options: &Options, source: Source) -> Result<(), Box<dyn Error>> {
+    let template = match source {
+        Source::Template(name) => match Templates::load(&options.templates)?.get(&name) {
+            Some(template) => template.clone(),
+            None => return Err(format!("No existe el template {}", name).into()),
+        },
+        source => Template::new("", &single_topic(options)?, &read_message(&source)?)
+            .set_qos(options.qos)
+            .set_retain(options.retain),
+    };
+    // Con --interval se publica hasta que se corte el programa.
+    let times = match (options.times, options.interval) {
+        (Some(times), _) => Some(times),
+        (None, Some(_)) => None,
+        (None, None) => Some(1),
+    };
+    let interval = options
+        .interval
+        .unwrap_or_else(|| Duration::from_millis(DEFAULT_INTERVAL_MILLIS));
+    let (connection, events) = session::connect(options)?;
+    // Despues de cada publicacion se leen los PUBACK que llegaron, para que no se acumulen los
+    // eventos si se publica hasta que se corte el programa.
+    let events = Arc::new(Mutex::new(events));
+    let sent = Arc::new(AtomicU32::new(0));
+    let acked = Arc::new(AtomicU32::new(0));
+    let (sent_publish, acked_publish, events_publish) =
+        (sent.clone(), acked.clone(), events.clone());
+    let qos = template.get_qos();
+    let scheduled = connection.schedule(
+        template,
+        Schedule::new(interval).set_times(times),
+        move |counter, result| {
+            match result {
+                Ok(_) => {
+                    sent_publish.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(e) => eprintln!("Error al enviar la publicacion {}: {:?}", counter, e),
+            }
+            let events = lock(&events_publish);
+            while let Ok(event) = events.try_recv() {
+                if let Event::Puback = event {
+                    acked_publish.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        },
+    );
+    scheduled.join();
+    if qos == 1 {
+        let pending = sent
+            .load(Ordering::SeqCst)
+            .saturating_sub(acked.load(Ordering::SeqCst));
+        await_pubacks(&lock(&events), pending)?;
+    }
+    let _res = connection.disconnect();
+    connection.close();
+    Ok(())
+}
+
+fn publish(
+    connection: &Connection,
+    events: &Receiver<Event>,
+    options: &Options,
+    topic: &str,
+    message: &str,
+) -> Result<(), Box<dyn Error>> {
+    connection
+        .publish(topic, message, options.qos, options.retain)
+        .map_err(|error| MqttError { error })?;
+    if options.qos == 0 {
+        return Ok(());
+    }
+    await_pubacks(events, 1)
+}
+
+/// Espera `pending` PUBACK, que pueden haber llegado mientras se publicaba.
+fn await_pubacks(events: &Receiver<Event>, mut pending: u32) -> Result<(), Box<dyn Error>> {
+    while pending > 0 {
+        match events.recv_timeout(Duration::from_secs(PUBACK_TIMEOUT_SECS)) {
+            Ok(Event::Puback) => pending -= 1,
+            Ok(Event::Disconnected) | Err(_) => {
+                return Err(format!("No llegaron {} PUBACK del servidor", pending).into())
+            }
+            Ok(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
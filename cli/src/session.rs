@@ -0,0 +1,62 @@
+use crate::options::Options;
+use mqtt_client::{Connection, Event};
+use serializer::mqtt_response::MqttError;
+use serializer::{new_connect_flag, new_payload_connect, new_properties};
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+/// Segundos que se espera el CONNACK.
+const CONNACK_TIMEOUT_SECS: u64 = 10;
+
+/// Se conecta al servidor con las opciones de conexion y espera a que acepte el CONNECT. Los
+/// PINGREQ del keep alive los envia la conexion.
+pub fn connect(options: &Options) -> Result<(Connection, Receiver<Event>), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let connection = Connection::open(&options.address(), tx)?;
+    let (will_topic, will_payload, will_qos, will_retain) = match &options.will {
+        Some(will) => (
+            will.topic.clone(),
+            will.payload.clone(),
+            will.qos,
+            will.retain,
+        ),
+        None => ("".to_string(), "".to_string(), 0, false),
+    };
+    // Como no implementamos QoS2 el primer bit va a ser siempre 0.
+    let connect_flag = new_connect_flag(
+        Some(options.clean_session),
+        Some(options.will.is_some()),
+        Some(false),
+        Some(will_qos == 1),
+        Some(will_retain),
+        Some(!options.password.is_empty()),
+        Some(!options.username.is_empty()),
+    )
+    .map_err(|error| MqttError { error })?;
+    let connect_payload = new_payload_connect(
+        options.client_id.clone(),
+        will_topic,
+        will_payload,
+        options.username.clone(),
+        options.password.clone(),
+        options.keep_alive,
+    )
+    .map_err(|error| MqttError { error })?;
+    connection
+        .connect(
+            connect_flag,
+            connect_payload,
+            options.protocol_version,
+            new_properties(),
+        )
+        .map_err(|error| MqttError { error })?;
+    match rx.recv_timeout(Duration::from_secs(CONNACK_TIMEOUT_SECS)) {
+        Ok(Event::Connack { accepted: true, .. }) => {}
+        Ok(Event::Connack { reason, .. }) => {
+            return Err(format!("Conexion rechazada: {}", reason).into())
+        }
+        _ => return Err("El servidor no respondio el CONNECT".into()),
+    }
+    Ok((connection, rx))
+}
@@ -0,0 +1,65 @@
+use crate::options::{Format, Options};
+use crate::session;
+use mqtt_client::{Event, Message};
+use serde_json::json;
+use serializer::SubackReturnCode;
+use std::error::Error;
+use std::io::{self, Write};
+
+/// Se suscribe a los topics e imprime los mensajes que llegan, hasta recibir `-C` mensajes o que se
+/// corte la conexion.
+pub fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    if options.topics.is_empty() {
+        return Err("Falta el topic (-t)".into());
+    }
+    if options.count == Some(0) {
+        return Ok(());
+    }
+    let (connection, events) = session::connect(options)?;
+    connection.subscribe(options.topics.clone(), options.qos)?;
+    let mut received = 0;
+    let stdout = io::stdout();
+    for event in events.iter() {
+        match event {
+            Event::Suback { return_codes }
+                if return_codes
+                    .iter()
+                    .any(|code| matches!(code, SubackReturnCode::Failure)) =>
+            {
+                connection.close();
+                return Err("El servidor rechazo la suscripcion".into());
+            }
+            Event::Publish(message) => {
+                let mut out = stdout.lock();
+                writeln!(out, "{}", format_message(&message, options))?;
+                out.flush()?;
+                received += 1;
+                if Some(received) == options.count {
+                    break;
+                }
+            }
+            Event::Disconnected => return Err("Se corto la conexion con el servidor".into()),
+            _ => {}
+        }
+    }
+    let _res = connection.disconnect();
+    connection.close();
+    Ok(())
+}
+
+/// Linea que se imprime por cada mensaje recibido.
+pub fn format_message(message: &Message, options: &Options) -> String {
+    match options.format {
+        Format::Plain if options.verbose => {
+            format!("{} {}", message.get_topic(), message.get_payload())
+        }
+        Format::Plain => message.get_payload(),
+        Format::Json => json!({
+            "topic": message.get_topic(),
+            "payload": message.get_payload(),
+            "qos": message.get_qos(),
+            "retain": message.get_retain(),
+        })
+        .to_string(),
+    }
+}
@@ -0,0 +1,218 @@
+//! Historial de los mensajes recibidos, que se muestra en la tabla de la pantalla de suscripcion.
+//!
+//! No depende de GTK: guarda los mensajes, los filtra por topic y los exporta a CSV o a JSON
+//! lines. La tabla se vuelve a armar con `History::matching` cada vez que cambia el filtro.
+use mqtt_client::Message;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Cantidad de mensajes que se guardan. Al superarla se descartan los mas viejos.
+const MAX_ENTRIES: usize = 10_000;
+/// Caracteres del payload que se muestran en la tabla.
+const PREVIEW_LEN: usize = 60;
+
+/// Mensaje recibido con la hora (UTC) en que llego.
+pub struct Entry {
+    id: u32,
+    timestamp: String,
+    topic: String,
+    qos: u8,
+    retain: bool,
+    payload: String,
+}
+
+impl Entry {
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn get_timestamp(&self) -> &str {
+        &self.timestamp
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn get_qos(&self) -> u8 {
+        self.qos
+    }
+
+    pub fn get_retain(&self) -> bool {
+        self.retain
+    }
+
+    /// Primera parte del payload en una sola linea, para la tabla.
+    pub fn preview(&self) -> String {
+        preview(&self.payload)
+    }
+
+    /// Payload para ver el detalle del mensaje: si es json se muestra indentado.
+    pub fn pretty_payload(&self) -> String {
+        match serde_json::from_str::<Value>(&self.payload) {
+            Ok(value) if value.is_object() || value.is_array() => {
+                serde_json::to_string_pretty(&value).unwrap_or_else(|_| self.payload.clone())
+            }
+            _ => self.payload.clone(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp,
+            "topic": self.topic,
+            "qos": self.qos,
+            "retain": self.retain,
+            "payload": self.payload,
+        })
+    }
+}
+
+/// Que mensajes se muestran segun el topic.
+pub enum Filter {
+    All,
+    /// El topic contiene el texto.
+    Contains(String),
+    Regex(Regex),
+}
+
+/// Arma el filtro con el texto que ingreso el usuario. Sin texto se muestran todos los mensajes.
+pub fn new_filter(text: &str, regex: bool) -> Result<Filter, regex::Error> {
+    if text.is_empty() {
+        return Ok(Filter::All);
+    }
+    if regex {
+        return Ok(Filter::Regex(Regex::new(text)?));
+    }
+    Ok(Filter::Contains(text.to_string()))
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Contains(text) => entry.topic.contains(text.as_str()),
+            Filter::Regex(regex) => regex.is_match(&entry.topic),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    entries: Vec<Entry>,
+    next_id: u32,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    /// Agrega el mensaje que llego en `received_at` y devuelve la entrada que se guardo.
+    pub fn push(&mut self, received_at: SystemTime, message: &Message) -> &Entry {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.entries.push(Entry {
+            id: self.next_id,
+            timestamp: format_timestamp(received_at),
+            topic: message.get_topic(),
+            qos: message.get_qos(),
+            retain: message.get_retain(),
+            payload: message.get_payload(),
+        });
+        self.next_id = self.next_id.wrapping_add(1);
+        &self.entries[self.entries.len() - 1]
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn matching(&self, filter: &Filter) -> Vec<&Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| filter.matches(entry))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Exporta los mensajes a CSV, con una linea de encabezado.
+pub fn to_csv(entries: &[&Entry]) -> String {
+    let mut csv = "timestamp,topic,qos,retain,payload\n".to_string();
+    for entry in entries {
+        csv += &format!(
+            "{},{},{},{},{}\n",
+            csv_field(&entry.timestamp),
+            csv_field(&entry.topic),
+            entry.qos,
+            entry.retain,
+            csv_field(&entry.payload)
+        );
+    }
+    csv
+}
+
+/// Exporta los mensajes con un objeto json por linea.
+pub fn to_json_lines(entries: &[&Entry]) -> String {
+    let mut lines = String::new();
+    for entry in entries {
+        lines += &entry.to_json().to_string();
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Fecha y hora UTC con el formato `AAAA-MM-DD HH:MM:SS`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    };
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Convierte dias desde 1970-01-01 en (anio, mes, dia) del calendario gregoriano.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Primera parte del payload en una sola linea, para mostrarlo en una tabla.
+pub fn preview(payload: &str) -> String {
+    let line = payload.replace('\n', " ");
+    if line.chars().count() <= PREVIEW_LEN {
+        return line;
+    }
+    line.chars().take(PREVIEW_LEN).collect::<String>() + "..."
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
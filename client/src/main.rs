@@ -1,24 +1,29 @@
 mod client;
-mod packets;
+mod history;
+mod profiles;
+mod topic_tree;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 extern crate gtk;
 extern crate serializer;
-use crate::client::Client;
 use gtk::prelude::*;
 use gtk::{glib, ButtonsType, NONE_ADJUSTMENT};
-use serializer::{new_connect_flag, new_payload_connect};
+use mqtt_client::{
+    Connection, Event, Message, Reconnect, Schedule, Scheduled, Template, Templates, TEMPLATES_PATH,
+};
+use serializer::{new_connect_flag, new_payload_connect, new_properties};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs;
+use std::io;
 use std::net::{Shutdown, TcpStream};
-use std::os::unix::process::CommandExt;
-use std::process::Command;
 use std::rc::Rc;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+/// Segundos que se espera la respuesta a un pedido.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
 
 fn main() {
     // Creo un subscriber no bloqueante para todos los tipos de eventos que escriba en un archivo .log con rotacion diaria.
@@ -29,19 +34,20 @@ fn main() {
 
     let application =
         gtk::Application::new(Some("com.github.gtk-rs.examples.basic"), Default::default());
-    let config;
-    match client::decode_config() {
-        Ok(c) => {
-            config = c;
+    // Al iniciar se abre una pestaña con el primer perfil.
+    let profiles;
+    match profiles::Profiles::load(profiles::PROFILES_PATH) {
+        Ok(p) => {
+            profiles = p;
         }
-        Err(_) => {
+        Err(e) => {
+            error!("Error al leer los perfiles: {:?}", e);
             application.connect_activate(error_ui);
             application.run();
             return;
         }
     }
-    let address = config[0][1].to_string() + ":" + config[1][1].to_string().as_str();
-    match TcpStream::connect(address) {
+    match TcpStream::connect(profiles.get_default().address()) {
         Ok(t) => {
             let _res = t.shutdown(Shutdown::Both);
         }
@@ -61,7 +67,7 @@ fn error_ui(application: &gtk::Application) {
         Rc::new(RefCell::new(HashMap::new()));
     let window = gtk::ApplicationWindow::new(application);
 
-    // Traigo el config para mostrarlo en la UI.
+    // Traigo el perfil por defecto para mostrarlo en la UI.
 
     window.set_title("Error al iniciar");
     window.set_border_width(10);
@@ -83,10 +89,10 @@ fn error_ui(application: &gtk::Application) {
     //port_entry.set_editable(false);
     // port_entry.set_placeholder_text(Some("7878"));
 
-    match client::decode_config() {
-        Ok(c) => {
-            ip_entry.set_text(c[0][1].to_string().as_str());
-            port_entry.set_text(c[1][1].to_string().as_str());
+    match profiles::Profiles::load(profiles::PROFILES_PATH) {
+        Ok(profiles) => {
+            ip_entry.set_text(profiles.get_default().get_host());
+            port_entry.set_text(&profiles.get_default().get_port().to_string());
         }
         Err(_) => {
             ip_entry.set_text("127.0.0.1");
@@ -97,7 +103,7 @@ fn error_ui(application: &gtk::Application) {
     port_layout.add(&port_label);
     port_layout.add(&port_entry);
 
-    let save_changes_buttons = gtk::Button::with_label("Save and Restart");
+    let save_changes_buttons = gtk::Button::with_label("Save and Connect");
     let save_button_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
     save_button_layout.add(&save_changes_buttons);
 
@@ -109,16 +115,46 @@ fn error_ui(application: &gtk::Application) {
         "Client config updated!",
     );
 
-    save_button_dialog.connect_button_press_event(|save_button_dialog, _| {
-        save_button_dialog.hide();
-        gtk::Inhibit(true)
-    });
+    let application = application.clone();
     save_changes_buttons.connect_clicked(glib::clone!(@weak save_button_dialog, @weak ip_entry
-                                    ,@weak port_entry => move |_| {
-            let ip = if ip_entry.text().is_empty() { "".to_string() } else { ip_entry.text().to_string() };
-            let port = if port_entry.text().is_empty() { "".to_string() } else { port_entry.text().to_string() };
-            client::update_changes(ip, port);
-            Command::new("./target/debug/client").exec();
+                                    ,@weak port_entry, @weak window, @weak application => move |_| {
+            let ip = ip_entry.text().to_string();
+            let port = match u16::from_str(port_entry.text().as_str()) {
+                Ok(port) if !ip.is_empty() => port,
+                _ => {
+                    save_button_dialog.set_text(Some("Ingrese un Server Ip y un Port validos."));
+                    save_button_dialog.set_message_type(gtk::MessageType::Warning);
+                    save_button_dialog.show_all();
+                    return;
+                }
+            };
+            // Se cambia la direccion del perfil por defecto, que es el que se abre al iniciar.
+            let address = match profiles::Profiles::load(profiles::PROFILES_PATH) {
+                Ok(mut profiles) => {
+                    let profile = profiles.get_default().clone().set_address(ip, port);
+                    let address = profile.address();
+                    if let Err(e) = profiles.save_profile(profile) {
+                        error!("Error al guardar el perfil: {:?}", e);
+                    }
+                    address
+                }
+                Err(e) => {
+                    error!("Error al leer los perfiles: {:?}", e);
+                    save_button_dialog.set_text(Some("No se pudieron leer los perfiles."));
+                    save_button_dialog.set_message_type(gtk::MessageType::Error);
+                    save_button_dialog.show_all();
+                    return;
+                }
+            };
+            if TcpStream::connect(&address).is_err() {
+                save_button_dialog.set_text(Some("No se pudo conectar con el servidor. Intente con otra direccion o mas tarde."));
+                save_button_dialog.set_message_type(gtk::MessageType::Warning);
+                save_button_dialog.show_all();
+                return;
+            }
+            // El servidor responde: se abre la ventana principal sin reiniciar el cliente.
+            build_ui(&application);
+            window.close();
     }));
     let ok_button = gtk::Button::with_label("Ok");
     ok_button.connect_clicked(glib::clone!(@weak window => move |_| {
@@ -135,18 +171,19 @@ fn error_ui(application: &gtk::Application) {
     window.show_all();
 }
 
+/// Lo que comparten las pestañas de la ventana. Cada pestaña tiene su propia conexion, abierta
+/// con uno de los perfiles guardados.
+#[derive(Clone)]
+struct Workspace {
+    window: gtk::ApplicationWindow,
+    notebook: gtk::Notebook,
+    profiles: Rc<RefCell<profiles::Profiles>>,
+    profile_combo: gtk::ComboBoxText,
+}
+
 fn build_ui(application: &gtk::Application) {
-    // Creo el cliente aqui para poder usar sus thread.
-    let client = client::Client::new();
-    // Write necesito que sea de esa forma porque voy a tener que clonarlo cada vez que
-    // quiera que un boton envie un mensaje.
-    let socket = client.socket;
-    let read = Arc::new(Mutex::new(
-        socket.try_clone().expect("couldn't clone the socket"),
-    ));
-    let write = Arc::new(Mutex::new(
-        socket.try_clone().expect("couldn't clone the socket"),
-    ));
+    let profiles =
+        profiles::Profiles::load(profiles::PROFILES_PATH).expect("Error al leer los perfiles.");
 
     let _windows: Rc<RefCell<HashMap<usize, glib::WeakRef<gtk::Window>>>> =
         Rc::new(RefCell::new(HashMap::new()));
@@ -157,6 +194,129 @@ fn build_ui(application: &gtk::Application) {
     window.set_position(gtk::WindowPosition::Center);
     window.set_default_size(400, 200);
 
+    // PERFILES
+    let profile_label = gtk::Label::new(Some("Profile"));
+    let profile_combo = gtk::ComboBoxText::new();
+    let open_tab_button = gtk::Button::with_label("Open Tab");
+    let delete_profile_button = gtk::Button::with_label("Delete Profile");
+    let profiles_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    profiles_layout.add(&profile_label);
+    profiles_layout.add(&profile_combo);
+    profiles_layout.add(&open_tab_button);
+    profiles_layout.add(&delete_profile_button);
+
+    let notebook = gtk::Notebook::new();
+    notebook.set_scrollable(true);
+
+    let layout = gtk::Box::new(gtk::Orientation::Vertical, 5);
+    layout.add(&profiles_layout);
+    layout.add(&notebook);
+    window.add(&layout);
+
+    let workspace = Workspace {
+        window: window.clone(),
+        notebook,
+        profiles: Rc::new(RefCell::new(profiles)),
+        profile_combo,
+    };
+    refresh_profiles(&workspace.profile_combo, &workspace.profiles.borrow());
+
+    let workspace_open = workspace.clone();
+    open_tab_button.connect_clicked(move |_| {
+        let profile = match workspace_open.profile_combo.active_text() {
+            Some(name) => workspace_open.profiles.borrow().get(name.as_str()).cloned(),
+            None => None,
+        };
+        if let Some(profile) = profile {
+            open_tab(&workspace_open, &profile, None);
+        }
+    });
+    let workspace_delete = workspace.clone();
+    delete_profile_button.connect_clicked(move |_| {
+        if let Some(name) = workspace_delete.profile_combo.active_text() {
+            if let Err(e) = workspace_delete.profiles.borrow_mut().remove(name.as_str()) {
+                error!("Error al borrar el perfil: {:?}", e);
+            }
+            refresh_profiles(
+                &workspace_delete.profile_combo,
+                &workspace_delete.profiles.borrow(),
+            );
+        }
+    });
+
+    // Las paginas se agregan con la ventana ya visible para que cada una decida que mostrar.
+    window.show_all();
+    let profile = workspace.profiles.borrow().get_default().clone();
+    open_tab(&workspace, &profile, None);
+}
+
+/// Vuelve a llenar la lista de perfiles y elige el primero.
+fn refresh_profiles(combo: &gtk::ComboBoxText, profiles: &profiles::Profiles) {
+    combo.remove_all();
+    for profile in profiles.get_profiles() {
+        combo.append_text(profile.get_name());
+    }
+    combo.set_active(Some(0));
+}
+
+/// Abre una conexion con el perfil en una pestaña nueva, en `position` o al final.
+fn open_tab(workspace: &Workspace, profile: &profiles::Profile, position: Option<u32>) {
+    match build_connection_page(workspace, profile) {
+        Ok((page, tab_label)) => {
+            let page_num = workspace
+                .notebook
+                .insert_page(&page, Some(&tab_label), position);
+            workspace.notebook.set_current_page(Some(page_num));
+        }
+        Err(e) => {
+            error!("Error al conectarse con {:?}: {:?}", profile.address(), e);
+            let dialog = gtk::MessageDialog::new(
+                Some(&workspace.window),
+                gtk::DialogFlags::DESTROY_WITH_PARENT,
+                gtk::MessageType::Error,
+                ButtonsType::Ok,
+                &format!("No se pudo conectar con {}.", profile.address()),
+            );
+            dialog.run();
+            dialog.close();
+        }
+    }
+}
+
+/// Arma la pagina de una pestaña, con la conexion al servidor del perfil y las pantallas de
+/// connect, publish y subscribe. Devuelve la pagina y el titulo de la pestaña.
+fn build_connection_page(
+    workspace: &Workspace,
+    profile: &profiles::Profile,
+) -> io::Result<(gtk::Box, gtk::Box)> {
+    // La conexion lee del servidor en su propio thread y cada evento se envia por el channel al
+    // buffer de la pestaña. Los mensajes recibidos van por otro channel a la tabla del historial,
+    // con la hora en que llegaron.
+    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    let (tx_history, rx_history) =
+        glib::MainContext::channel::<(SystemTime, Message)>(glib::PRIORITY_DEFAULT);
+    let address = profile.address();
+    let tx_events = tx.clone();
+    let connection = Connection::open_with_callback(&address, move |event| match event {
+        Event::Publish(message) => {
+            tx_history
+                .send((SystemTime::now(), message))
+                .expect("Couldn't send data to channel");
+        }
+        event => {
+            if let Some(msg) = client::describe_event(event) {
+                tx_events.send(msg).expect("Couldn't send data to channel");
+            }
+        }
+    })?;
+    // Si se cae el servidor se reconecta solo y retoma la sesion.
+    connection.set_reconnect(Some(Reconnect::new()));
+    // Publicacion periodica de la pestaña, que se detiene al desconectarse o cerrar la pestaña.
+    let scheduled: Rc<RefCell<Option<Scheduled>>> = Rc::new(RefCell::new(None));
+
+    let window = workspace.window.clone();
+    let layout_general = gtk::Box::new(gtk::Orientation::Vertical, 5);
+
     // CONNECT
     let con_header = gtk::HeaderBar::new();
     con_header.set_title(Some("Connection"));
@@ -176,33 +336,10 @@ fn build_ui(application: &gtk::Application) {
     //port_entry.set_editable(false);
     // port_entry.set_placeholder_text(Some("7878"));
 
-    match client::decode_config() {
-        Ok(c) => {
-            ip_entry.set_text(c[0][1].to_string().as_str());
-            port_entry.set_text(c[1][1].to_string().as_str());
-        }
-        Err(_) => {
-            ip_entry.set_text("127.0.0.1");
-            port_entry.set_text("7878");
-        }
-    }
-
     let port_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
     port_layout.add(&port_label);
     port_layout.add(&port_entry);
 
-    let save_changes_buttons = gtk::Button::with_label("Save Port and IP");
-    let save_button_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
-    save_button_layout.add(&save_changes_buttons);
-
-    let save_button_dialog = gtk::MessageDialog::new(
-        None::<&gtk::Window>,
-        gtk::DialogFlags::DESTROY_WITH_PARENT,
-        gtk::MessageType::Info,
-        ButtonsType::Ok,
-        "Client config updated!",
-    );
-
     let connected_status = gtk::Label::new(Some("Status"));
     let connected_entry = gtk::Entry::new();
 
@@ -217,19 +354,6 @@ fn build_ui(application: &gtk::Application) {
         save_button_dialog.hide();
         gtk::Inhibit(true)
     });
-    save_changes_buttons.connect_clicked(glib::clone!(@weak save_button_dialog, @weak ip_entry
-                                    ,@weak port_entry, @weak connected_entry => move |_| {
-        if connected_entry.text() == *"Connected"{
-            save_button_dialog.set_text(Some("No puedes cambiar la configuracion estando conectado!"));
-            save_button_dialog.set_message_type(gtk::MessageType::Warning);
-            save_button_dialog.show_all();
-            return;
-        }
-        let ip = if ip_entry.text().is_empty() { "".to_string() } else { ip_entry.text().to_string() };
-        let port = if port_entry.text().is_empty() { "".to_string() } else { port_entry.text().to_string() };
-        client::update_changes(ip, port);
-        Command::new("./target/debug/client").exec();
-    }));
     let client_id_label = gtk::Label::new(Some("Client ID"));
     let client_id_entry = gtk::Entry::new();
     client_id_entry.set_placeholder_text(Some("123"));
@@ -237,6 +361,12 @@ fn build_ui(application: &gtk::Application) {
     client_id_layout.add(&client_id_label);
     client_id_layout.add(&client_id_entry);
 
+    let mqtt5_label = gtk::Label::new(Some("MQTT 5"));
+    let mqtt5_cb = gtk::CheckButton::new();
+    let mqtt5_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    mqtt5_layout.add(&mqtt5_label);
+    mqtt5_layout.add(&mqtt5_cb);
+
     let clean_session_label = gtk::Label::new(Some("Clean Session"));
     let clean_session_cb = gtk::CheckButton::new();
     let clean_s_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
@@ -261,6 +391,12 @@ fn build_ui(application: &gtk::Application) {
     willqos_layout.add(&willqos_switch);
     willqos_layout.add(&willqos1_label);
 
+    let will_delay_label = gtk::Label::new(Some("Will Delay (s)"));
+    let will_delay_spin = gtk::SpinButton::with_range(0.00, 3600.00, 1.0);
+    let will_delay_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    will_delay_layout.add(&will_delay_label);
+    will_delay_layout.add(&will_delay_spin);
+
     let will_retain_label = gtk::Label::new(Some("Will Retain"));
     let will_retain_cb = gtk::CheckButton::new();
     let will_ret_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
@@ -285,11 +421,18 @@ fn build_ui(application: &gtk::Application) {
     keep_alive_layout.add(&keep_alive_label);
     keep_alive_layout.add(&keep_alive_spin);
 
+    let persist_label = gtk::Label::new(Some("Persist Outbound"));
+    let persist_cb = gtk::CheckButton::new();
+    persist_cb.set_active(true);
+    let persist_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    persist_layout.add(&persist_label);
+    persist_layout.add(&persist_cb);
+
     let connect_label = gtk::Label::new(Some("Connect Packet"));
     let connect_username_entry = gtk::Entry::new();
     connect_username_entry.set_placeholder_text(Some("Username"));
     let connect_password_entry = gtk::Entry::new();
-    connect_password_entry.set_placeholder_text(Some("Password"));
+    connect_password_entry.set_placeholder_text(Some("Password (no se guarda en el perfil)"));
     let connect_lwm_entry = gtk::Entry::new();
     connect_lwm_entry.set_placeholder_text(Some("Last Will Message"));
     let connect_lwt_entry = gtk::Entry::new();
@@ -305,6 +448,28 @@ fn build_ui(application: &gtk::Application) {
     con_pack_layout.add(&connect_label);
     con_pack_layout.add(&pack_layout);
 
+    // Valores del perfil
+    let form = ConnectForm {
+        ip_entry: ip_entry.clone(),
+        port_entry: port_entry.clone(),
+        client_id_entry: client_id_entry.clone(),
+        mqtt5_cb: mqtt5_cb.clone(),
+        clean_session_cb: clean_session_cb.clone(),
+        will_flag_cb: will_flag_cb.clone(),
+        willqos_switch: willqos_switch.clone(),
+        will_delay_spin: will_delay_spin.clone(),
+        will_retain_cb: will_retain_cb.clone(),
+        username_flag_cb: username_flag_cb.clone(),
+        password_flag_cb: password_flag_cb.clone(),
+        keep_alive_spin: keep_alive_spin.clone(),
+        connect_username_entry: connect_username_entry.clone(),
+        connect_password_entry: connect_password_entry.clone(),
+        connect_lwt_entry: connect_lwt_entry.clone(),
+        connect_lwm_entry: connect_lwm_entry.clone(),
+    };
+    form.fill(profile);
+    let profile_layout = build_profile_pane(workspace, profile, form, address.clone());
+
     let button_connect = gtk::Button::with_label("Connect");
     let connect_dialog = gtk::MessageDialog::new(
         None::<&gtk::Window>,
@@ -319,20 +484,17 @@ fn build_ui(application: &gtk::Application) {
         gtk::Inhibit(true)
     });
 
-    //Debemos clonar el mutex por cada vez que querramos hacer algun envio de mensaje.
-    let write_connect = write.clone();
-    let _read_connect = read.clone();
-
+    //Debemos clonar la conexion por cada vez que querramos hacer algun envio de mensaje.
+    let connection_connect = connection.clone();
 
-
-    button_connect.connect_clicked(glib::clone!(@weak connect_dialog, @weak clean_session_cb,
+    button_connect.connect_clicked(glib::clone!(@weak connect_dialog, @weak clean_session_cb, @weak mqtt5_cb,
                                                    @weak will_flag_cb, @weak will_retain_cb,
                                                    @weak username_flag_cb, @weak password_flag_cb,
                                                    @weak willqos_switch, @weak ip_entry,
                                                    @weak client_id_entry, @weak connect_lwt_entry,
                                                    @weak connect_lwm_entry, @weak connect_username_entry,
                                                    @weak connect_password_entry, @weak connected_entry,
-                                                   @weak keep_alive_spin => move |_| {
+                                                   @weak keep_alive_spin, @weak will_delay_spin, @weak persist_cb => move |_| {
         if client_id_entry.text().is_empty() {
             connect_dialog.set_text(Some("El campo Client ID es obligatorio."));
             connect_dialog.set_message_type(gtk::MessageType::Warning);
@@ -422,8 +584,9 @@ fn build_ui(application: &gtk::Application) {
                 }
             };
 
+        let protocol_version = if mqtt5_cb.is_active() { mqtt_client::PROTOCOL_VERSION_5 } else { mqtt_client::PROTOCOL_VERSION_3 };
         let connect_payload = new_payload_connect(
-            client_id,
+            client_id.clone(),
             connect_lwt,
             connect_lwm,
             connect_username,
@@ -437,35 +600,21 @@ fn build_ui(application: &gtk::Application) {
             connect_dialog.set_message_type(gtk::MessageType::Warning);
             connect_dialog.show_all()
         } else {
-            let mut connect;
-            let write_ping = write_connect.clone();
-            match write_connect.lock() {
-                Ok(r) => {connect = r}
-                Err(e) => {panic!("{:?}",e)}
+            // Con delay el will solo se publica si el cliente no se reconecta antes.
+            let will_delay = will_delay_spin.value_as_int() as u32;
+            let will_properties = new_properties().set_will_delay_interval(if will_delay > 0 { Some(will_delay) } else { None });
+            // Los publish QoS 1 sin confirmar y las suscripciones se guardan por client id y se
+            // reenvian al conectarse aunque se haya cerrado el cliente.
+            let store = if persist_cb.is_active() { Some(format!("pendientes_{}.json", client_id)) } else { None };
+            if let Err(e) = connection_connect.set_store(store.as_deref()) {
+                error!("Error al leer los mensajes pendientes: {:?}", e);
             }
-            match packets::send_connect(&mut connect, connect_flag, connect_payload) {
+            match connection_connect.connect(connect_flag, connect_payload, protocol_version, will_properties) {
                     Ok(_) => {
-                        match u64::from_str(keep_alive_spin.text().as_str()) {
-                            Ok(keep_alive_secs) => {
-                                if keep_alive_secs > 0 {
-                                    glib::timeout_add(Duration::from_secs(keep_alive_secs), move || {
-                                        let mut ping;
-                                        match write_ping.lock() {
-                                            Ok(r) => {ping = r}
-                                            Err(e) => {panic!("{:?}",e)}
-                                        };
-                                        if packets::send_pingreq(&mut ping).is_ok() {}
-                                        glib::Continue(true)
-                                    });
-                                    info!("Se enviara un PINGREQ cada {:?} segundos.", keep_alive_secs);
-                                }
-
-                            },
-                            Err(_) => {
-                                error!("Error al convertir keep alive secs.");
-                            }
-                        };
-
+                        // La conexion envia los PINGREQ segun el keep alive del CONNECT.
+                        if keep_alive > 0 {
+                            info!("Se enviara un PINGREQ si no se envia nada en {:?} segundos.", keep_alive);
+                        }
                     }, // Se va a mostrar la respuesta cuando se reciba.
                     Err(_) => {
                         connected_entry.set_text("Disconnected");
@@ -487,12 +636,12 @@ fn build_ui(application: &gtk::Application) {
     );
     disconnect_dialog.connect_button_press_event(|disconnect_dialog, _| {
         disconnect_dialog.hide();
-        Command::new("./target/debug/client").exec();
         gtk::Inhibit(true)
     });
-    let write_disconnect = write.clone();
-    let _read_disconnect = read.clone();
-    button_disconnect.connect_clicked(glib::clone!(@weak disconnect_dialog, @weak clean_session_cb,
+    let connection_disconnect = connection.clone();
+    let workspace_disconnect = workspace.clone();
+    let profile_disconnect = profile.clone();
+    button_disconnect.connect_clicked(glib::clone!(@weak disconnect_dialog, @weak layout_general, @strong scheduled, @weak clean_session_cb,
                                                       @weak will_flag_cb, @weak will_retain_cb, @weak port_entry,
                                                       @weak username_flag_cb, @weak password_flag_cb,
                                                       @weak willqos_switch, @weak ip_entry,
@@ -503,7 +652,7 @@ fn build_ui(application: &gtk::Application) {
             disconnect_dialog.set_text(Some("No estas conectado!"));
             disconnect_dialog.set_message_type(gtk::MessageType::Warning);
         } else {
-            let resp = packets::send_disconnect(&mut write_disconnect.lock().unwrap());
+            let resp = connection_disconnect.disconnect();
             match resp {
                     Ok(_) => {
                         disconnect_dialog.set_text(Some("Disconnected successfully!"));
@@ -523,6 +672,16 @@ fn build_ui(application: &gtk::Application) {
                         // port_entry.set_text("");
                         connected_entry.set_text("Disconnected");
                         info!("Desconectado correctamente.");
+                        // El servidor cierra la conexion despues del DISCONNECT, asi que la
+                        // pestaña se reemplaza por otra con una conexion nueva al mismo perfil.
+                        if let Some(scheduled) = scheduled.borrow_mut().take() {
+                            scheduled.stop();
+                        }
+                        connection_disconnect.close();
+                        let notebook = &workspace_disconnect.notebook;
+                        let position = notebook.page_num(&layout_general);
+                        notebook.remove(&layout_general);
+                        open_tab(&workspace_disconnect, &profile_disconnect, position);
                     }
                     Err(_) => {
                         info!("No se ha podido desconectar.");
@@ -536,17 +695,20 @@ fn build_ui(application: &gtk::Application) {
 
     let layout_connect = gtk::Box::new(gtk::Orientation::Vertical, 5);
     layout_connect.add(&con_header);
+    layout_connect.add(&profile_layout);
     layout_connect.add(&ip_layout);
     layout_connect.add(&port_layout);
-    layout_connect.add(&save_button_layout);
     layout_connect.add(&client_id_layout);
+    layout_connect.add(&mqtt5_layout);
     layout_connect.add(&clean_s_layout);
     layout_connect.add(&will_flag_layout);
     layout_connect.add(&willqos_layout);
+    layout_connect.add(&will_delay_layout);
     layout_connect.add(&will_ret_layout);
     layout_connect.add(&username_flag_layout);
     layout_connect.add(&password_flag_layout);
     layout_connect.add(&keep_alive_layout);
+    layout_connect.add(&persist_layout);
     layout_connect.add(&con_pack_layout);
     layout_connect.add(&button_connect);
     layout_connect.add(&button_disconnect);
@@ -590,6 +752,7 @@ fn build_ui(application: &gtk::Application) {
     retain_layout.add(&retain_cb);
 
     let publish_button = gtk::Button::with_label("Publish");
+    let request_button = gtk::Button::with_label("Request");
     let publish_dialog = gtk::MessageDialog::new(
         None::<&gtk::Window>,
         gtk::DialogFlags::DESTROY_WITH_PARENT,
@@ -603,8 +766,7 @@ fn build_ui(application: &gtk::Application) {
         gtk::Inhibit(true)
     });
     // publish_dialog.set_secondary_text(Some("Never received puback"));
-    let write_publish = write.clone();
-    let _read_publish = read.clone();
+    let connection_publish = connection.clone();
     publish_button.connect_clicked(glib::clone!(@weak publish_dialog, @weak qos_switch,
                                                    @weak retain_cb, @weak publish_topic_entry,
                                                    @weak publish_message_view, @weak connected_entry  => move |_| {
@@ -614,26 +776,16 @@ fn build_ui(application: &gtk::Application) {
             publish_dialog.show_all();
             return;
         }
-        // Como no implementamos QoS2 el primer bit va a ser siempre 0.
-        let qos_b1 = false;
-        let mut qos_b2 = false;
-
-        if qos_switch.is_active()
-        {
-            qos_b2 = true;
-        }
-
-        let flags = serializer::new_publish_packet_flags(Some(retain_cb.is_active()), Some(qos_b2),
-                                                         Some(qos_b1), Some(false)).ok().unwrap();
+        // Como no implementamos QoS2 el QoS es 0 o 1.
+        let qos = if qos_switch.is_active() { 1 } else { 0 };
 
         let topic_message = if publish_topic_entry.text().is_empty() { "".to_string() } else { publish_topic_entry.text().to_string() };
-        let topic = serializer::new_topic_filter(topic_message).ok().unwrap();
 
          let buffer = publish_message_view.buffer().unwrap();
          let (start, end) = buffer.bounds();
          let message = if buffer.text(&start, &end, true).unwrap().is_empty() { "".to_string() } else { buffer.text(&start, &end, true).unwrap().to_string() };
          if message != *"" && ! publish_topic_entry.text().is_empty() {
-            let result = packets::send_publish(&mut write_publish.lock().unwrap(), flags, topic, message);
+            let result = connection_publish.publish(&topic_message, &message, qos, retain_cb.is_active());
 
             match result {
                 Ok(_) => {
@@ -667,13 +819,173 @@ fn build_ui(application: &gtk::Application) {
 
     }));
 
+    // TEMPLATES
+    // Los templates se guardan en el mismo archivo que usa `mqtt pub -T`. Al cargar uno se
+    // completan los campos de la pantalla, y al guardar se toman de ahi.
+    let template_label = gtk::Label::new(Some("Template"));
+    let template_combo = gtk::ComboBoxText::new();
+    let load_template_button = gtk::Button::with_label("Load");
+    let delete_template_button = gtk::Button::with_label("Delete");
+    let template_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    template_layout.add(&template_label);
+    template_layout.add(&template_combo);
+    template_layout.add(&load_template_button);
+    template_layout.add(&delete_template_button);
+    refresh_templates(&template_combo);
+
+    let template_name_entry = gtk::Entry::new();
+    template_name_entry.set_placeholder_text(Some("Template Name"));
+    let save_template_button = gtk::Button::with_label("Save Template");
+    let template_save_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    template_save_layout.add(&template_name_entry);
+    template_save_layout.add(&save_template_button);
+
+    load_template_button.connect_clicked(glib::clone!(@weak template_combo, @weak template_name_entry, @weak publish_topic_entry,
+                                                         @weak publish_message_view, @weak qos_switch, @weak retain_cb => move |_| {
+        let name = match template_combo.active_text() {
+            Some(name) => name,
+            None => return,
+        };
+        match Templates::load(TEMPLATES_PATH) {
+            Ok(templates) => {
+                if let Some(template) = templates.get(name.as_str()) {
+                    template_name_entry.set_text(template.get_name());
+                    publish_topic_entry.set_text(template.get_topic());
+                    publish_message_view.buffer().unwrap().set_text(template.get_payload());
+                    qos_switch.set_active(template.get_qos() == 1);
+                    retain_cb.set_active(template.get_retain());
+                }
+            }
+            Err(e) => error!("Error al leer los templates: {:?}", e),
+        }
+    }));
+    save_template_button.connect_clicked(glib::clone!(@weak publish_dialog, @weak template_combo, @weak template_name_entry,
+                                                         @weak publish_topic_entry, @weak publish_message_view,
+                                                         @weak qos_switch, @weak retain_cb => move |_| {
+        let buffer = publish_message_view.buffer().unwrap();
+        let (start, end) = buffer.bounds();
+        let payload = buffer.text(&start, &end, true).unwrap().to_string();
+        if template_name_entry.text().is_empty() || publish_topic_entry.text().is_empty() {
+            publish_dialog.set_text(Some("Los campos Template Name y Topic son obligatorios."));
+            publish_dialog.set_message_type(gtk::MessageType::Warning);
+            publish_dialog.show_all();
+            return;
+        }
+        let template = Template::new(&template_name_entry.text(), &publish_topic_entry.text(), &payload)
+            .set_qos(if qos_switch.is_active() { 1 } else { 0 })
+            .set_retain(retain_cb.is_active());
+        match Templates::load(TEMPLATES_PATH).and_then(|mut templates| templates.save_template(template)) {
+            Ok(_) => {
+                refresh_templates(&template_combo);
+                publish_dialog.set_text(Some("Template guardado."));
+                publish_dialog.set_message_type(gtk::MessageType::Info);
+            }
+            Err(e) => {
+                error!("Error al guardar el template: {:?}", e);
+                publish_dialog.set_text(Some("Error al guardar el template."));
+                publish_dialog.set_message_type(gtk::MessageType::Error);
+            }
+        }
+        publish_dialog.show_all();
+    }));
+    delete_template_button.connect_clicked(glib::clone!(@weak template_combo => move |_| {
+        if let Some(name) = template_combo.active_text() {
+            if let Err(e) = Templates::load(TEMPLATES_PATH).and_then(|mut templates| templates.remove(name.as_str())) {
+                error!("Error al borrar el template: {:?}", e);
+            }
+            refresh_templates(&template_combo);
+        }
+    }));
+
+    // PUBLICACION PERIODICA
+    // Publica lo que hay en la pantalla cada intervalo, reemplazando los placeholders del payload
+    // ({{timestamp}}, {{counter}} y {{random}}). Con 0 veces publica hasta que se detenga.
+    let interval_label = gtk::Label::new(Some("Interval (ms)"));
+    let interval_spin = gtk::SpinButton::with_range(100.00, 3_600_000.00, 100.0);
+    interval_spin.set_value(1000.00);
+    let times_label = gtk::Label::new(Some("Times (0 = until stopped)"));
+    let times_spin = gtk::SpinButton::with_range(0.00, 1_000_000.00, 1.0);
+    let schedule_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    schedule_layout.add(&interval_label);
+    schedule_layout.add(&interval_spin);
+    schedule_layout.add(&times_label);
+    schedule_layout.add(&times_spin);
+
+    let start_schedule_button = gtk::Button::with_label("Start");
+    let stop_schedule_button = gtk::Button::with_label("Stop");
+    let schedule_status = gtk::Label::new(None);
+    let schedule_buttons_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    schedule_buttons_layout.add(&start_schedule_button);
+    schedule_buttons_layout.add(&stop_schedule_button);
+    schedule_buttons_layout.add(&schedule_status);
+
+    // El avance llega desde el thread que publica por el channel de eventos.
+    let connection_schedule = connection.clone();
+    let tx_schedule = tx.clone();
+    start_schedule_button.connect_clicked(glib::clone!(@weak publish_dialog, @weak template_name_entry, @weak publish_topic_entry,
+                                                          @weak publish_message_view, @weak qos_switch, @weak retain_cb,
+                                                          @weak interval_spin, @weak times_spin, @weak schedule_status,
+                                                          @weak connected_entry, @strong scheduled => move |_| {
+        if connected_entry.text() == *"Disconnected" {
+            publish_dialog.set_text(Some("No estas conectado!"));
+            publish_dialog.set_message_type(gtk::MessageType::Warning);
+            publish_dialog.show_all();
+            return;
+        }
+        let buffer = publish_message_view.buffer().unwrap();
+        let (start, end) = buffer.bounds();
+        let payload = buffer.text(&start, &end, true).unwrap().to_string();
+        if publish_topic_entry.text().is_empty() || payload.is_empty() {
+            publish_dialog.set_text(Some("Los campos Message y Topic son obligatorios."));
+            publish_dialog.set_message_type(gtk::MessageType::Error);
+            publish_dialog.show_all();
+            return;
+        }
+        let template = Template::new(&template_name_entry.text(), &publish_topic_entry.text(), &payload)
+            .set_qos(if qos_switch.is_active() { 1 } else { 0 })
+            .set_retain(retain_cb.is_active());
+        let times = times_spin.value_as_int() as u32;
+        let schedule = Schedule::new(Duration::from_millis(interval_spin.value_as_int() as u64))
+            .set_times(if times > 0 { Some(times) } else { None });
+        let tx = tx_schedule.clone();
+        let new_scheduled = connection_schedule.schedule(template, schedule, move |counter, result| {
+            let msg = match result {
+                Ok(_) if schedule.get_times() == Some(counter) => format!("SCHEDULE|Publicados {}, terminado.", counter),
+                Ok(_) => format!("SCHEDULE|Publicados {}.", counter),
+                Err(e) => {
+                    error!("Error en la publicacion periodica: {:?}", e);
+                    format!("SCHEDULE|Error al enviar la publicacion {}.", counter)
+                }
+            };
+            tx.send(msg).expect("Couldn't send data to channel");
+        });
+        if let Some(previous) = scheduled.borrow_mut().replace(new_scheduled) {
+            previous.stop();
+        }
+        schedule_status.set_text("Publicando...");
+        info!("Publicacion periodica en {:?} cada {:?}", publish_topic_entry.text(), schedule.get_interval());
+    }));
+    stop_schedule_button.connect_clicked(
+        glib::clone!(@weak schedule_status, @strong scheduled => move |_| {
+            if let Some(scheduled) = scheduled.borrow_mut().take() {
+                scheduled.stop();
+                schedule_status.set_text("Detenido.");
+            }
+        }),
+    );
+
     let layout_publish = gtk::Box::new(gtk::Orientation::Vertical, 5);
     layout_publish.add(&pub_header);
+    layout_publish.add(&template_layout);
+    layout_publish.add(&template_save_layout);
     layout_publish.add(&publish_msg_layout);
     layout_publish.add(&publish_topic_layout);
     layout_publish.add(&qos_layout);
     layout_publish.add(&retain_layout);
     layout_publish.add(&publish_button);
+    layout_publish.add(&request_button);
+    layout_publish.add(&schedule_layout);
+    layout_publish.add(&schedule_buttons_layout);
 
     // SUBSCRIBE
     let sub_header = gtk::HeaderBar::new();
@@ -694,8 +1006,7 @@ fn build_ui(application: &gtk::Application) {
     ));
     let sub_topic_view = gtk::TextView::new();
     sub_topic_view.set_size_request(350, 100);
-    let write_sub = write.clone();
-    let _read_sub = read.clone();
+    let connection_sub = connection.clone();
     let sub_button = gtk::Button::with_label("Subscribe");
     let subscribe_dialog = gtk::MessageDialog::new(
         None::<&gtk::Window>,
@@ -722,7 +1033,7 @@ fn build_ui(application: &gtk::Application) {
             if topic != *"" {
                 let topic_str = topic.split(',').map(|s| s.to_string()).collect();
                 if sub_qos_switch.is_active() {
-                    match packets::send_subscribe(&mut write_sub.lock().unwrap(), topic_str, 1) {
+                    match connection_sub.subscribe(topic_str, 1) {
                         Ok(_) => {
                             subscribe_dialog.set_text(Some("Suscripcion exitosa."));
                             subscribe_dialog.set_message_type(gtk::MessageType::Info);
@@ -734,7 +1045,7 @@ fn build_ui(application: &gtk::Application) {
                         }
                     }
                 } else {
-                    match packets::send_subscribe(&mut write_sub.lock().unwrap(), topic_str, 0) {
+                    match connection_sub.subscribe(topic_str, 0) {
                         Ok(_) => {
                             subscribe_dialog.set_text(Some("Suscripcion exitosa."));
                             subscribe_dialog.set_message_type(gtk::MessageType::Info);
@@ -761,8 +1072,7 @@ fn build_ui(application: &gtk::Application) {
     sub_sub_layout.add(&sub_topic_label);
     sub_sub_layout.add(&sub_topic_view);
     sub_sub_layout.add(&sub_button);
-    let write_unsub = write.clone();
-    let _read_unsub = read.clone();
+    let connection_unsub = connection.clone();
     let unsub_topic_label = gtk::Label::new(Some(
         "Unsubscribe from topics \n user commas (,) to separate them",
     ));
@@ -795,7 +1105,7 @@ fn build_ui(application: &gtk::Application) {
             let topic = if buffer.text(&start, &end, true).unwrap().is_empty() { "".to_string() } else { buffer.text(&start, &end, true).unwrap().to_string() };
             if topic != *"" {
                 let topic_str = topic.split(',').map(|s| s.to_string()).collect();
-                match packets::send_unsubscribe(&mut write_unsub.lock().unwrap(), topic_str) {
+                match connection_unsub.unsubscribe(topic_str) {
                     Ok(_) => {
                         unsubscribe_dialog.set_text(Some("Cancelación de suscripción exitosa."));
                         unsubscribe_dialog.set_message_type(gtk::MessageType::Info);
@@ -820,41 +1130,83 @@ fn build_ui(application: &gtk::Application) {
     unsub_sub_layout.add(&unsub_topic_view);
     unsub_sub_layout.add(&unsub_button);
 
-    let received_msg_label = gtk::Label::new(Some("Received Messages"));
-    let msg_view = gtk::TextView::new();
-    let scroll = gtk::ScrolledWindow::new(NONE_ADJUSTMENT, NONE_ADJUSTMENT);
-    scroll.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
-    scroll.set_expand(true);
-    scroll.add(&msg_view);
-
-    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    // Los mensajes recibidos se agregan al arbol de topics y al historial.
+    let topics_pane = build_topics_pane(
+        &connection,
+        subscribe_dialog.clone(),
+        connected_entry.clone(),
+        publish_topic_entry.clone(),
+        layout_publish.clone(),
+    );
+    let layout_topics = topics_pane.layout.clone();
+    let history_pane = build_history_pane(window.clone());
+    let layout_history = history_pane.layout.clone();
+    rx_history.attach(None, move |(received_at, message)| {
+        topics_pane.record(&message);
+        history_pane.record(received_at, &message);
+        glib::Continue(true)
+    });
 
-    // El cliente sera el que va a estar escuchando al servidor.
-    // Cuando recibe un mensaje, lo enviara por el channel al buffer de la aplicacion.
-    let read_subs = read;
-    let write_subss = write;
+    // El pedido se envia desde otro thread porque espera la respuesta, que llega por el channel.
+    let connection_request = connection.clone();
+    let tx_request = tx;
+    request_button.connect_clicked(glib::clone!(@weak publish_dialog, @weak publish_topic_entry,
+                                                   @weak publish_message_view, @weak connected_entry => move |_| {
+        if connected_entry.text() == *"Disconnected" {
+            publish_dialog.set_text(Some("No estas conectado!"));
+            publish_dialog.set_message_type(gtk::MessageType::Warning);
+            publish_dialog.show_all();
+            return;
+        }
+        let topic = publish_topic_entry.text().to_string();
+        let buffer = publish_message_view.buffer().unwrap();
+        let (start, end) = buffer.bounds();
+        let message = buffer.text(&start, &end, true).unwrap().to_string();
+        if topic.is_empty() {
+            publish_dialog.set_text(Some("El campo Topic es obligatorio."));
+            publish_dialog.set_message_type(gtk::MessageType::Error);
+            publish_dialog.show_all();
+            return;
+        }
+        let connection = connection_request.clone();
+        let tx = tx_request.clone();
+        thread::spawn(move || {
+            let msg = match connection.request(&topic, &message, Duration::from_secs(REQUEST_TIMEOUT_SECS)) {
+                Ok(response) => "REQUEST|Respuesta: ".to_string() + &response,
+                Err(e) => {
+                    error!("Error en el pedido: {:?}", e);
+                    "REQUEST|No se recibio respuesta al pedido.".to_string()
+                }
+            };
+            tx.send(msg).expect("Couldn't send data to channel");
+        });
+    }));
 
-    thread::spawn(move || loop {
-        Client::await_packets(&mut read_subs.lock().unwrap(), &tx, &write_subss);
-    });
+    // TITULO DE LA PESTAÑA
+    // Al cerrar la pestaña se cierra su conexion. El estado queda en Disconnected para que no se
+    // avise que se corto la conexion.
+    let tab_label = gtk::Label::new(Some(profile.get_name()));
+    let close_tab_button = gtk::Button::with_label("x");
+    close_tab_button.set_relief(gtk::ReliefStyle::None);
+    let tab_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    tab_layout.add(&tab_label);
+    tab_layout.add(&close_tab_button);
+    tab_layout.show_all();
+    let notebook = workspace.notebook.clone();
+    let connection_close = connection.clone();
+    close_tab_button.connect_clicked(glib::clone!(@weak notebook, @weak layout_general, @weak connected_entry, @strong scheduled => move |_| {
+        if let Some(scheduled) = scheduled.borrow_mut().take() {
+            scheduled.stop();
+        }
+        connected_entry.set_text("Disconnected");
+        connection_close.close();
+        notebook.remove(&layout_general);
+    }));
 
-    let msg_buffer = msg_view
-        .buffer()
-        .expect("Couldn't get buffer from text_view");
     rx.attach(None, move |msg| {
         let split: Vec<&str> = msg.split('|').collect();
 
         match split[0] {
-            "PUBLISH" => {
-                let (start, end) = msg_buffer.bounds();
-                let mut actual_text = msg_buffer.text(&start, &end, true).unwrap().to_string();
-                let new_line = "\n";
-                actual_text.push_str(new_line);
-                actual_text.push_str(split[1]);
-                msg_buffer.set_text(&actual_text);
-                //Ahora hago que el scroll este al final siempre.
-                msg_view.scroll_to_mark(&msg_buffer.get_insert().unwrap(), 0.0, true, 0.5, 0.5);
-            }
             "CONNACK" => {
                 if split[1].eq("Conexion aceptada!") {
                     connected_entry.set_text("Connected");
@@ -867,6 +1219,27 @@ fn build_ui(application: &gtk::Application) {
                 connect_dialog.set_text(Some(split[1]));
                 connect_dialog.show_all()
             }
+            "RECONNECTING" => {
+                connected_entry.set_text("Reconnecting");
+                info!("{}", split[1]);
+            }
+            "DISCONNECTED" => {
+                // Si se desconecto el usuario ya lo sabe.
+                if connected_entry.text() != *"Disconnected" {
+                    connected_entry.set_text("Disconnected");
+                    connect_dialog.set_text(Some(split[1]));
+                    connect_dialog.set_message_type(gtk::MessageType::Warning);
+                    connect_dialog.show_all()
+                }
+            }
+            "REQUEST" => {
+                publish_dialog.set_text(Some(&split[1..].join("|")));
+                publish_dialog.set_message_type(gtk::MessageType::Info);
+                publish_dialog.show_all();
+            }
+            "SCHEDULE" => {
+                schedule_status.set_text(split[1]);
+            }
             "PUBACK" => {
                 publish_dialog.set_text(Some("Published Succesfully!"));
                 publish_dialog.set_message_type(gtk::MessageType::Info);
@@ -899,69 +1272,797 @@ fn build_ui(application: &gtk::Application) {
     layout_subscribe.add(&sub_header);
     layout_subscribe.add(&sub_sub_layout);
     layout_subscribe.add(&unsub_sub_layout);
-    layout_subscribe.add(&received_msg_label);
-    layout_subscribe.add(&scroll);
+    layout_subscribe.add(&layout_history);
 
     // MENU ITEMS
     let menu_bar = gtk::MenuBar::new();
     let connect_menu_item = gtk::MenuItem::with_label("Connection");
     let publish_menu_item = gtk::MenuItem::with_label("Publish");
     let subscribe_menu_item = gtk::MenuItem::with_label("Subscription");
+    let topics_menu_item = gtk::MenuItem::with_label("Topics");
     let quit = gtk::MenuItem::with_label("Quit");
 
     menu_bar.append(&connect_menu_item);
     menu_bar.append(&publish_menu_item);
     menu_bar.append(&subscribe_menu_item);
+    menu_bar.append(&topics_menu_item);
     menu_bar.append(&quit);
 
     // PANTALLA GENERAL
-    let layout_general = gtk::Box::new(gtk::Orientation::Vertical, 5);
     layout_general.add(&menu_bar);
 
     layout_general.add(&layout_connect);
     layout_general.add(&layout_publish);
     layout_general.add(&layout_subscribe);
+    layout_general.add(&layout_topics);
 
-    window.add(&layout_general);
-    let socket = socket.try_clone().unwrap();
     // ACCIONES DE NAVEGACION
     // gblib::clone! will automatically create the new reference and pass it with the same name into the closure.
     quit.connect_activate(glib::clone!(@weak window => move |_| {
-        let _res = socket.shutdown(Shutdown::Both);
+        connection.close();
         window.close();
     }));
 
-    connect_menu_item.connect_activate(glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish => move |_| {
+    connect_menu_item.connect_activate(glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish, @weak layout_topics => move |_| {
         layout_connect.show();
         layout_publish.hide();
         layout_subscribe.hide();
+        layout_topics.hide();
     }));
 
-    publish_menu_item.connect_activate(glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish => move |_| {
+    // Los templates se pueden haber guardado desde otra pestaña o desde la linea de comandos.
+    publish_menu_item.connect_activate(glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish, @weak layout_topics,
+                                                       @weak template_combo => move |_| {
+        refresh_templates(&template_combo);
         layout_connect.hide();
         layout_publish.show();
         layout_subscribe.hide();
+        layout_topics.hide();
     }));
 
     subscribe_menu_item.connect_activate(
-        glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish =>move |_| {
+        glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish, @weak layout_topics =>move |_| {
             layout_connect.hide();
             layout_publish.hide();
             layout_subscribe.show();
+            layout_topics.hide();
         }),
     );
 
-    window.show_all();
+    topics_menu_item.connect_activate(
+        glib::clone!(@weak layout_connect, @weak layout_subscribe, @weak layout_publish, @weak layout_topics =>move |_| {
+            layout_connect.hide();
+            layout_publish.hide();
+            layout_subscribe.hide();
+            layout_topics.show();
+        }),
+    );
+
+    layout_general.show_all();
 
     // Cuando entramos por primera vez queremos ver solo connect
     layout_publish.hide();
     layout_subscribe.hide();
+    layout_topics.hide();
+    Ok((layout_general, tab_layout))
+}
+
+/// Campos de la pantalla de connect que se guardan en los perfiles.
+#[derive(Clone)]
+struct ConnectForm {
+    ip_entry: gtk::Entry,
+    port_entry: gtk::Entry,
+    client_id_entry: gtk::Entry,
+    mqtt5_cb: gtk::CheckButton,
+    clean_session_cb: gtk::CheckButton,
+    will_flag_cb: gtk::CheckButton,
+    willqos_switch: gtk::Switch,
+    will_delay_spin: gtk::SpinButton,
+    will_retain_cb: gtk::CheckButton,
+    username_flag_cb: gtk::CheckButton,
+    password_flag_cb: gtk::CheckButton,
+    keep_alive_spin: gtk::SpinButton,
+    connect_username_entry: gtk::Entry,
+    connect_password_entry: gtk::Entry,
+    connect_lwt_entry: gtk::Entry,
+    connect_lwm_entry: gtk::Entry,
+}
+
+impl ConnectForm {
+    /// Completa los campos con los valores del perfil.
+    fn fill(&self, profile: &profiles::Profile) {
+        self.ip_entry.set_text(profile.get_host());
+        self.port_entry.set_text(&profile.get_port().to_string());
+        self.client_id_entry.set_text(profile.get_client_id());
+        self.mqtt5_cb.set_active(profile.get_mqtt5());
+        self.clean_session_cb
+            .set_active(profile.get_clean_session());
+        if let Some(will) = profile.get_will() {
+            self.will_flag_cb.set_active(true);
+            self.willqos_switch.set_active(will.get_qos() == 1);
+            self.will_delay_spin.set_value(will.get_delay() as f64);
+            self.will_retain_cb.set_active(will.get_retain());
+            self.connect_lwt_entry.set_text(will.get_topic());
+            self.connect_lwm_entry.set_text(will.get_message());
+        }
+        self.username_flag_cb
+            .set_active(profile.get_username().is_some());
+        self.connect_username_entry
+            .set_text(profile.get_username().unwrap_or(""));
+        // La password no se guarda en el perfil: se ingresa antes de conectar.
+        self.password_flag_cb
+            .set_active(profile.get_password_flag());
+        self.connect_password_entry.set_text("");
+        self.keep_alive_spin
+            .set_value(profile.get_keep_alive() as f64);
+    }
+
+    /// Perfil `name` con los valores de los campos, o el mensaje a mostrar si alguno es invalido.
+    fn to_profile(&self, name: String) -> Result<profiles::Profile, &'static str> {
+        let ip = self.ip_entry.text().to_string();
+        if name.is_empty() || ip.is_empty() {
+            return Err("Los campos Profile Name y Server Ip son obligatorios.");
+        }
+        let port = match u16::from_str(self.port_entry.text().as_str()) {
+            Ok(port) => port,
+            Err(_) => return Err("Invalid Port!"),
+        };
+        let will = if self.will_flag_cb.is_active() {
+            let will = profiles::Will::new(
+                self.connect_lwt_entry.text().to_string(),
+                self.connect_lwm_entry.text().to_string(),
+            );
+            Some(
+                will.set_qos(if self.willqos_switch.is_active() {
+                    1
+                } else {
+                    0
+                })
+                .set_retain(self.will_retain_cb.is_active())
+                .set_delay(self.will_delay_spin.value_as_int() as u32),
+            )
+        } else {
+            None
+        };
+        let username = if self.username_flag_cb.is_active() {
+            Some(self.connect_username_entry.text().to_string())
+        } else {
+            None
+        };
+        Ok(profiles::Profile::new(name, ip, port)
+            .set_credentials(username, self.password_flag_cb.is_active())
+            .set_client_id(self.client_id_entry.text().to_string())
+            .set_will(will)
+            .set_keep_alive(self.keep_alive_spin.value_as_int() as u16)
+            .set_clean_session(self.clean_session_cb.is_active())
+            .set_mqtt5(self.mqtt5_cb.is_active()))
+    }
+}
+
+/// Arma el nombre del perfil y el boton que guarda los campos de la pantalla como perfil. La
+/// conexion de la pestaña sigue con `address`, la direccion con la que se abrio.
+fn build_profile_pane(
+    workspace: &Workspace,
+    profile: &profiles::Profile,
+    form: ConnectForm,
+    address: String,
+) -> gtk::Box {
+    let profile_name_label = gtk::Label::new(Some("Profile Name"));
+    let profile_name_entry = gtk::Entry::new();
+    profile_name_entry.set_text(profile.get_name());
+    let save_changes_buttons = gtk::Button::with_label("Save Profile");
+
+    let save_button_dialog = gtk::MessageDialog::new(
+        None::<&gtk::Window>,
+        gtk::DialogFlags::DESTROY_WITH_PARENT,
+        gtk::MessageType::Info,
+        ButtonsType::Ok,
+        "Client config updated!",
+    );
+    save_button_dialog.connect_button_press_event(|save_button_dialog, _| {
+        save_button_dialog.hide();
+        gtk::Inhibit(true)
+    });
+
+    let workspace_save = workspace.clone();
+    save_changes_buttons.connect_clicked(
+        glib::clone!(@weak save_button_dialog, @weak profile_name_entry => move |_| {
+            let new_profile = match form.to_profile(profile_name_entry.text().to_string()) {
+                Ok(new_profile) => new_profile,
+                Err(msg) => {
+                    save_button_dialog.set_text(Some(msg));
+                    save_button_dialog.set_message_type(gtk::MessageType::Warning);
+                    save_button_dialog.show_all();
+                    return;
+                }
+            };
+            let address_changed = new_profile.address() != address;
+            let result = workspace_save.profiles.borrow_mut().save_profile(new_profile);
+            match result {
+                Ok(_) => {
+                    refresh_profiles(&workspace_save.profile_combo, &workspace_save.profiles.borrow());
+                    if address_changed {
+                        save_button_dialog.set_text(Some("Perfil guardado sin la password. La nueva direccion se usa al abrirlo en otra pestaña."));
+                    } else {
+                        save_button_dialog.set_text(Some("Perfil guardado sin la password."));
+                    }
+                    save_button_dialog.set_message_type(gtk::MessageType::Info);
+                }
+                Err(e) => {
+                    error!("Error al guardar el perfil: {:?}", e);
+                    save_button_dialog.set_text(Some("Error al guardar el perfil."));
+                    save_button_dialog.set_message_type(gtk::MessageType::Error);
+                }
+            }
+            save_button_dialog.show_all();
+        }),
+    );
+
+    let layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    layout.add(&profile_name_label);
+    layout.add(&profile_name_entry);
+    layout.add(&save_changes_buttons);
+    layout
+}
+
+/// Historial de mensajes recibidos de una pestaña.
+struct HistoryPane {
+    layout: gtk::Box,
+    history: Rc<RefCell<history::History>>,
+    filter: Rc<RefCell<history::Filter>>,
+    store: gtk::ListStore,
+    view: gtk::TreeView,
+}
+
+impl HistoryPane {
+    /// Agrega el mensaje al historial y, si pasa el filtro, a la tabla.
+    fn record(&self, received_at: SystemTime, message: &Message) {
+        let mut history = self.history.borrow_mut();
+        let entry = history.push(received_at, message);
+        if self.filter.borrow().matches(entry) {
+            let iter = append_history_row(&self.store, entry);
+            //Ahora hago que el scroll este al final siempre.
+            if let Some(path) = self.store.path(&iter) {
+                self.view.scroll_to_cell(
+                    Some(&path),
+                    None::<&gtk::TreeViewColumn>,
+                    false,
+                    0.0,
+                    0.0,
+                );
+            }
+        }
+    }
+}
+
+/// Arma el historial. La tabla muestra los mensajes recibidos que pasan el filtro y al elegir uno
+/// se ve el payload completo abajo. La columna 5 guarda el id de la entrada del historial y no se
+/// muestra.
+fn build_history_pane(window: gtk::ApplicationWindow) -> HistoryPane {
+    let received_msg_label = gtk::Label::new(Some("Received Messages"));
+    let history = Rc::new(RefCell::new(history::History::new()));
+    let history_filter = Rc::new(RefCell::new(history::Filter::All));
+    let history_store = gtk::ListStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        u32::static_type(),
+        String::static_type(),
+        String::static_type(),
+        u32::static_type(),
+    ]);
+    let history_view = gtk::TreeView::with_model(&history_store);
+    for (column_id, title) in ["Time (UTC)", "Topic", "QoS", "Retain", "Payload"]
+        .iter()
+        .enumerate()
+    {
+        let renderer = gtk::CellRendererText::new();
+        let column = gtk::TreeViewColumn::new();
+        column.set_title(title);
+        column.set_resizable(true);
+        column.pack_start(&renderer, true);
+        column.add_attribute(&renderer, "text", column_id as i32);
+        history_view.append_column(&column);
+    }
+    let scroll = gtk::ScrolledWindow::new(NONE_ADJUSTMENT, NONE_ADJUSTMENT);
+    scroll.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+    scroll.set_expand(true);
+    scroll.set_size_request(500, 200);
+    scroll.add(&history_view);
+
+    let history_filter_entry = gtk::Entry::new();
+    history_filter_entry.set_placeholder_text(Some("Filter by topic"));
+    let history_regex_label = gtk::Label::new(Some("Regex"));
+    let history_regex_cb = gtk::CheckButton::new();
+    let export_csv_button = gtk::Button::with_label("Export CSV");
+    let export_json_button = gtk::Button::with_label("Export JSON");
+    let clear_history_button = gtk::Button::with_label("Clear");
+    let history_filter_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    history_filter_layout.add(&history_filter_entry);
+    history_filter_layout.add(&history_regex_label);
+    history_filter_layout.add(&history_regex_cb);
+    history_filter_layout.add(&export_csv_button);
+    history_filter_layout.add(&export_json_button);
+    history_filter_layout.add(&clear_history_button);
+
+    let payload_view = gtk::TextView::new();
+    payload_view.set_editable(false);
+    let payload_scroll = gtk::ScrolledWindow::new(NONE_ADJUSTMENT, NONE_ADJUSTMENT);
+    payload_scroll.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+    payload_scroll.set_size_request(500, 100);
+    payload_scroll.add(&payload_view);
+
+    history_filter_entry.connect_changed(glib::clone!(@weak history_store, @weak history_regex_cb,
+                                                         @strong history, @strong history_filter => move |entry| {
+        refilter_history(&history_store, &history.borrow(), &mut history_filter.borrow_mut(), &entry.text(), history_regex_cb.is_active());
+    }));
+    history_regex_cb.connect_toggled(glib::clone!(@weak history_store, @weak history_filter_entry,
+                                                     @strong history, @strong history_filter => move |regex_cb| {
+        refilter_history(&history_store, &history.borrow(), &mut history_filter.borrow_mut(), &history_filter_entry.text(), regex_cb.is_active());
+    }));
+    history_view.selection().connect_changed(
+        glib::clone!(@weak payload_view, @strong history => move |selection| {
+            if let Some((model, iter)) = selection.selected() {
+                if let Ok(id) = model.value(&iter, 5).get::<u32>() {
+                    if let Some(entry) = history.borrow().get(id) {
+                        payload_view.buffer().unwrap().set_text(&entry.pretty_payload());
+                    }
+                }
+            }
+        }),
+    );
+    export_csv_button.connect_clicked(
+        glib::clone!(@weak window, @strong history, @strong history_filter => move |_| {
+            let history = history.borrow();
+            let entries = history.matching(&history_filter.borrow());
+            export_history(&window, "mensajes.csv", &history::to_csv(&entries));
+        }),
+    );
+    export_json_button.connect_clicked(
+        glib::clone!(@weak window, @strong history, @strong history_filter => move |_| {
+            let history = history.borrow();
+            let entries = history.matching(&history_filter.borrow());
+            export_history(&window, "mensajes.jsonl", &history::to_json_lines(&entries));
+        }),
+    );
+    clear_history_button.connect_clicked(
+        glib::clone!(@weak history_store, @weak payload_view, @strong history => move |_| {
+            history.borrow_mut().clear();
+            history_store.clear();
+            payload_view.buffer().unwrap().set_text("");
+        }),
+    );
+
+    let layout = gtk::Box::new(gtk::Orientation::Vertical, 5);
+    layout.add(&received_msg_label);
+    layout.add(&history_filter_layout);
+    layout.add(&scroll);
+    layout.add(&payload_scroll);
+    HistoryPane {
+        layout,
+        history,
+        filter: history_filter,
+        store: history_store,
+        view: history_view,
+    }
+}
+
+/// Arbol de topics de una pestaña.
+struct TopicsPane {
+    layout: gtk::Box,
+    tree: Rc<RefCell<topic_tree::TopicTree>>,
+    rows: Rc<RefCell<HashMap<String, gtk::TreeIter>>>,
+    store: gtk::TreeStore,
+}
+
+impl TopicsPane {
+    /// Cuenta el mensaje en el arbol y actualiza las filas de los niveles de su topic.
+    fn record(&self, message: &Message) {
+        let changed = self.tree.borrow_mut().record(message);
+        update_topic_rows(
+            &self.store,
+            &mut self.rows.borrow_mut(),
+            &self.tree.borrow(),
+            &changed,
+        );
+    }
+}
+
+/// Arma el arbol de los topics de los mensajes recibidos. Se llena suscribiendose a un filtro
+/// raiz, y con el topic elegido se puede suscribir o ir a publicar. La columna 4 guarda el topic
+/// completo del nodo y no se muestra.
+fn build_topics_pane(
+    connection: &Connection,
+    subscribe_dialog: gtk::MessageDialog,
+    connected_entry: gtk::Entry,
+    publish_topic_entry: gtk::Entry,
+    layout_publish: gtk::Box,
+) -> TopicsPane {
+    let topics_header = gtk::HeaderBar::new();
+    topics_header.set_title(Some("Topics"));
+    let layout_topics = gtk::Box::new(gtk::Orientation::Vertical, 5);
+
+    let topic_root_label = gtk::Label::new(Some("Root Filter"));
+    let topic_root_entry = gtk::Entry::new();
+    topic_root_entry.set_text("*");
+    let browse_button = gtk::Button::with_label("Browse");
+    let topic_root_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    topic_root_layout.add(&topic_root_label);
+    topic_root_layout.add(&topic_root_entry);
+    topic_root_layout.add(&browse_button);
+
+    let topic_tree = Rc::new(RefCell::new(topic_tree::TopicTree::new()));
+    let topic_rows: Rc<RefCell<HashMap<String, gtk::TreeIter>>> =
+        Rc::new(RefCell::new(HashMap::new()));
+    let topic_store = gtk::TreeStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        u32::static_type(),
+        String::static_type(),
+        String::static_type(),
+    ]);
+    let topic_view = gtk::TreeView::with_model(&topic_store);
+    for (column_id, title) in ["Topic", "Last Value", "Messages", "Retained"]
+        .iter()
+        .enumerate()
+    {
+        let renderer = gtk::CellRendererText::new();
+        let column = gtk::TreeViewColumn::new();
+        column.set_title(title);
+        column.set_resizable(true);
+        column.pack_start(&renderer, true);
+        column.add_attribute(&renderer, "text", column_id as i32);
+        topic_view.append_column(&column);
+    }
+    let topic_scroll = gtk::ScrolledWindow::new(NONE_ADJUSTMENT, NONE_ADJUSTMENT);
+    topic_scroll.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+    topic_scroll.set_expand(true);
+    topic_scroll.set_size_request(500, 300);
+    topic_scroll.add(&topic_view);
+
+    let topic_subscribe_button = gtk::Button::with_label("Subscribe");
+    let topic_publish_button = gtk::Button::with_label("Publish");
+    let clear_topics_button = gtk::Button::with_label("Clear");
+    let topic_buttons_layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    topic_buttons_layout.add(&topic_subscribe_button);
+    topic_buttons_layout.add(&topic_publish_button);
+    topic_buttons_layout.add(&clear_topics_button);
+
+    let connection_browse = connection.clone();
+    browse_button.connect_clicked(glib::clone!(@weak subscribe_dialog, @weak topic_root_entry, @weak connected_entry => move |_| {
+        if connected_entry.text() == *"Disconnected" {
+            subscribe_dialog.set_text(Some("No estas conectado!"));
+            subscribe_dialog.set_message_type(gtk::MessageType::Warning);
+            subscribe_dialog.show_all();
+            return;
+        }
+        let filter = topic_root_entry.text().to_string();
+        if filter.is_empty() {
+            subscribe_dialog.set_text(Some("Completar el filtro raiz."));
+            subscribe_dialog.set_message_type(gtk::MessageType::Warning);
+            subscribe_dialog.show_all();
+            return;
+        }
+        // El arbol se llena con los mensajes que lleguen por esta suscripcion.
+        if let Err(e) = connection_browse.subscribe(vec![filter], 0) {
+            error!("Error al suscribirse al filtro raiz: {:?}", e);
+            subscribe_dialog.set_text(Some("Error al suscribirse."));
+            subscribe_dialog.set_message_type(gtk::MessageType::Error);
+            subscribe_dialog.show_all();
+        }
+    }));
+    let connection_topic_sub = connection.clone();
+    topic_subscribe_button.connect_clicked(
+        glib::clone!(@weak subscribe_dialog, @weak topic_view, @weak connected_entry,
+                                                              @strong topic_tree => move |_| {
+            if connected_entry.text() == *"Disconnected" {
+                subscribe_dialog.set_text(Some("No estas conectado!"));
+                subscribe_dialog.set_message_type(gtk::MessageType::Warning);
+                subscribe_dialog.show_all();
+                return;
+            }
+            let topic = match selected_topic(&topic_view) {
+                Some(topic) => topic,
+                None => {
+                    subscribe_dialog.set_text(Some("Elegir un topic del arbol."));
+                    subscribe_dialog.set_message_type(gtk::MessageType::Warning);
+                    subscribe_dialog.show_all();
+                    return;
+                }
+            };
+            let filters = match topic_tree.borrow().get(&topic) {
+                Some(node) => node.subscription_filters(),
+                None => return,
+            };
+            if let Err(e) = connection_topic_sub.subscribe(filters, 0) {
+                error!("Error al suscribirse al topic: {:?}", e);
+                subscribe_dialog.set_text(Some("Error al suscribirse."));
+                subscribe_dialog.set_message_type(gtk::MessageType::Error);
+                subscribe_dialog.show_all();
+            }
+        }),
+    );
+    topic_publish_button.connect_clicked(glib::clone!(@weak topic_view, @weak publish_topic_entry,
+                                                        @weak layout_publish, @weak layout_topics => move |_| {
+        if let Some(topic) = selected_topic(&topic_view) {
+            publish_topic_entry.set_text(&topic);
+            layout_topics.hide();
+            layout_publish.show();
+        }
+    }));
+    clear_topics_button.connect_clicked(
+        glib::clone!(@weak topic_store, @strong topic_tree, @strong topic_rows => move |_| {
+            topic_tree.borrow_mut().clear();
+            topic_rows.borrow_mut().clear();
+            topic_store.clear();
+        }),
+    );
+
+    layout_topics.add(&topics_header);
+    layout_topics.add(&topic_root_layout);
+    layout_topics.add(&topic_scroll);
+    layout_topics.add(&topic_buttons_layout);
+    TopicsPane {
+        layout: layout_topics,
+        tree: topic_tree,
+        rows: topic_rows,
+        store: topic_store,
+    }
+}
+
+/// Arma el filtro con lo que ingreso el usuario y vuelve a llenar la tabla. Si la regex es
+/// invalida se deja el filtro anterior.
+fn refilter_history(
+    store: &gtk::ListStore,
+    history: &history::History,
+    filter: &mut history::Filter,
+    text: &str,
+    regex: bool,
+) {
+    match history::new_filter(text, regex) {
+        Ok(new_filter) => *filter = new_filter,
+        Err(e) => {
+            warn!("Filtro invalido: {:?}", e);
+            return;
+        }
+    }
+    store.clear();
+    for entry in history.matching(filter) {
+        append_history_row(store, entry);
+    }
+}
+
+/// Vuelve a llenar la lista de templates con los que estan guardados.
+fn refresh_templates(combo: &gtk::ComboBoxText) {
+    combo.remove_all();
+    match Templates::load(TEMPLATES_PATH) {
+        Ok(templates) => {
+            for template in templates.get_templates() {
+                combo.append_text(template.get_name());
+            }
+        }
+        Err(e) => error!("Error al leer los templates: {:?}", e),
+    }
+    combo.set_active(Some(0));
+}
+
+/// Actualiza las filas de los topics que cambiaron, del primer nivel al ultimo, agregando las que
+/// falten debajo de la fila del nivel anterior.
+fn update_topic_rows(
+    store: &gtk::TreeStore,
+    rows: &mut HashMap<String, gtk::TreeIter>,
+    tree: &topic_tree::TopicTree,
+    changed: &[String],
+) {
+    let mut parent: Option<gtk::TreeIter> = None;
+    for topic in changed {
+        let node = match tree.get(topic) {
+            Some(node) => node,
+            None => continue,
+        };
+        let iter = rows
+            .entry(topic.clone())
+            .or_insert_with(|| store.append(parent.as_ref()))
+            .clone();
+        // Los niveles intermedios sin mensajes propios no tienen valor ni retain.
+        let (value, retained) = match node.get_last_value() {
+            Some(value) => (
+                history::preview(value),
+                if node.get_retained() { "Yes" } else { "No" },
+            ),
+            None => (String::new(), ""),
+        };
+        store.set(
+            &iter,
+            &[
+                (0, &node.get_name().to_string()),
+                (1, &value),
+                (2, &node.get_count()),
+                (3, &retained.to_string()),
+                (4, &node.get_topic().to_string()),
+            ],
+        );
+        parent = Some(iter);
+    }
+}
+
+/// Topic completo del nodo elegido en el arbol.
+fn selected_topic(view: &gtk::TreeView) -> Option<String> {
+    let (model, iter) = view.selection().selected()?;
+    model.value(&iter, 4).get::<String>().ok()
+}
+
+fn append_history_row(store: &gtk::ListStore, entry: &history::Entry) -> gtk::TreeIter {
+    let retain = if entry.get_retain() { "Yes" } else { "No" };
+    store.insert_with_values(
+        None,
+        &[
+            (0, &entry.get_timestamp().to_string()),
+            (1, &entry.get_topic().to_string()),
+            (2, &(entry.get_qos() as u32)),
+            (3, &retain.to_string()),
+            (4, &entry.preview()),
+            (5, &entry.get_id()),
+        ],
+    )
+}
+
+/// Pide donde guardar el archivo y escribe en el los mensajes exportados.
+fn export_history(window: &gtk::ApplicationWindow, file_name: &str, content: &str) {
+    let dialog = gtk::FileChooserDialog::with_buttons(
+        Some("Export messages"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Save", gtk::ResponseType::Accept),
+        ],
+    );
+    dialog.set_current_name(file_name);
+    dialog.set_do_overwrite_confirmation(true);
+    if dialog.run() == gtk::ResponseType::Accept {
+        if let Some(path) = dialog.filename() {
+            match fs::write(&path, content) {
+                Ok(_) => info!("Mensajes exportados a {:?}", path),
+                Err(e) => error!("Error al exportar los mensajes: {:?}", e),
+            }
+        }
+    }
+    dialog.close();
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::history;
+    use crate::profiles::{Profile, Profiles, Will};
+    use crate::topic_tree;
+    use mqtt_client::Message;
+    use serializer::new_properties;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
     #[test]
     fn test_sample_client() {
         assert_eq!(1, 1)
     }
+
+    #[test]
+    fn history_filters_and_exports_messages() {
+        let mut history = history::History::new();
+        let received_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let json = "{\"temp\":21.5}".to_string();
+        history.push(
+            received_at,
+            &Message::new("sensores/temp".to_string(), json, 1, true, new_properties()),
+        );
+        history.push(
+            received_at,
+            &Message::new(
+                "alertas".to_string(),
+                "a,b".to_string(),
+                0,
+                false,
+                new_properties(),
+            ),
+        );
+
+        let filter = history::new_filter("sensores", false).unwrap();
+        let entries = history.matching(&filter);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].get_timestamp(), "2023-11-14 22:13:20");
+        assert_eq!(entries[0].pretty_payload(), "{\n  \"temp\": 21.5\n}");
+
+        let filter = history::new_filter("^a.*s$", true).unwrap();
+        let entries = history.matching(&filter);
+        assert_eq!(
+            history::to_csv(&entries),
+            "timestamp,topic,qos,retain,payload\n2023-11-14 22:13:20,alertas,0,false,\"a,b\"\n"
+        );
+        assert!(history::new_filter("(", true).is_err());
+
+        let entries = history.matching(&history::Filter::All);
+        assert_eq!(history::to_json_lines(&entries).lines().count(), 2);
+    }
+
+    #[test]
+    fn profiles_are_saved_and_replaced_by_name() {
+        let path = std::env::temp_dir().join(format!("perfiles_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "{\"profiles\":[{\"name\":\"local\",\"host\":\"127.0.0.1\",\"port\":1883}]}",
+        )
+        .unwrap();
+
+        let mut profiles = Profiles::load(path).unwrap();
+        assert_eq!(profiles.get_default().address(), "127.0.0.1:1883");
+        let will = Will::new("estado".to_string(), "offline".to_string())
+            .set_qos(1)
+            .set_delay(5);
+        let sensores = Profile::new("sensores".to_string(), "10.0.0.2".to_string(), 7878)
+            .set_credentials(Some("sensor".to_string()), true)
+            .set_client_id("sensor-1".to_string())
+            .set_will(Some(will))
+            .set_keep_alive(30);
+        profiles.save_profile(sensores.clone()).unwrap();
+        profiles
+            .save_profile(Profile::new(
+                "local".to_string(),
+                "localhost".to_string(),
+                1884,
+            ))
+            .unwrap();
+
+        let profiles = Profiles::load(path).unwrap();
+        assert_eq!(profiles.get_profiles().len(), 2);
+        assert_eq!(profiles.get_default().address(), "localhost:1884");
+        let loaded = profiles.get("sensores").unwrap();
+        assert_eq!(loaded, &sensores);
+        assert!(loaded.get_password_flag());
+        assert_eq!(loaded.get_will().unwrap().get_qos(), 1);
+
+        // Las passwords de una version anterior se borran del archivo al cargarlo.
+        fs::write(
+            path,
+            "{\"profiles\":[{\"name\":\"local\",\"host\":\"127.0.0.1\",\"port\":1883,\"username\":\"admin\",\"password\":\"secreto\"}]}",
+        )
+        .unwrap();
+        let profiles = Profiles::load(path).unwrap();
+        let data = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(profiles.get_default().get_password_flag());
+        assert!(!data.contains("secreto"));
+    }
+
+    #[test]
+    fn topic_tree_counts_messages_by_level() {
+        let mut tree = topic_tree::TopicTree::new();
+        let message = |topic: &str, payload: &str, retain: bool| {
+            Message::new(
+                topic.to_string(),
+                payload.to_string(),
+                0,
+                retain,
+                new_properties(),
+            )
+        };
+        assert_eq!(
+            tree.record(&message("casa/cocina/temp", "21", false)),
+            vec!["casa", "casa/cocina", "casa/cocina/temp"]
+        );
+        tree.record(&message("casa/cocina/temp", "22", true));
+        tree.record(&message("casa/living", "on", false));
+
+        let casa = tree.get("casa").unwrap();
+        assert_eq!(casa.get_count(), 3);
+        assert_eq!(casa.get_last_value(), None);
+        assert_eq!(casa.subscription_filters(), vec!["casa/*"]);
+        let temp = tree.get("casa/cocina/temp").unwrap();
+        assert_eq!(temp.get_name(), "temp");
+        assert_eq!(
+            (temp.get_count(), temp.get_last_value(), temp.get_retained()),
+            (2, Some("22"), true)
+        );
+        assert_eq!(temp.subscription_filters(), vec!["casa/cocina/temp"]);
+        assert!(tree.get("casa/garage").is_none());
+    }
 }
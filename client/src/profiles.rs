@@ -0,0 +1,373 @@
+//! Perfiles de conexion del cliente.
+//!
+//! Cada perfil tiene un nombre y todo lo necesario para conectarse a un servidor: direccion,
+//! usuario, client id, will, keep alive y clean session. Se guardan en `profiles.json` con la
+//! forma `{"profiles":[{"name":..,"host":..,"port":..,...}]}`. Si el archivo no existe se arma un
+//! perfil `default` con lo que hay en `config.txt`, para no perder la configuracion anterior.
+//!
+//! La password no se guarda: el perfil solo indica si la conexion la usa, y se ingresa al
+//! conectarse. Si el archivo tiene passwords de una version anterior se reescribe sin ellas.
+use crate::client;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+pub const PROFILES_PATH: &str = "profiles.json";
+const DEFAULT_NAME: &str = "default";
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 7878;
+
+/// Mensaje que publica el servidor si el cliente se desconecta sin enviar DISCONNECT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Will {
+    topic: String,
+    message: String,
+    qos: u8,
+    retain: bool,
+    delay: u32,
+}
+
+impl Will {
+    pub fn new(topic: String, message: String) -> Self {
+        Will {
+            topic,
+            message,
+            qos: 0,
+            retain: false,
+            delay: 0,
+        }
+    }
+
+    pub fn set_qos(mut self, qos: u8) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn set_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Segundos que espera el servidor antes de publicarlo.
+    pub fn set_delay(mut self, delay: u32) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_qos(&self) -> u8 {
+        self.qos
+    }
+
+    pub fn get_retain(&self) -> bool {
+        self.retain
+    }
+
+    pub fn get_delay(&self) -> u32 {
+        self.delay
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "topic": self.topic,
+            "message": self.message,
+            "qos": self.qos,
+            "retain": self.retain,
+            "delay": self.delay,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let will = Will::new(
+            value["topic"].as_str()?.to_string(),
+            value["message"].as_str()?.to_string(),
+        );
+        Some(
+            will.set_qos(value["qos"].as_u64().unwrap_or(0).min(1) as u8)
+                .set_retain(value["retain"].as_bool().unwrap_or(false))
+                .set_delay(value["delay"].as_u64().unwrap_or(0) as u32),
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    name: String,
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password_flag: bool,
+    client_id: String,
+    will: Option<Will>,
+    keep_alive: u16,
+    clean_session: bool,
+    mqtt5: bool,
+}
+
+impl Profile {
+    /// Perfil sin credenciales ni will, con MQTT 5 y sin keep alive.
+    pub fn new(name: String, host: String, port: u16) -> Self {
+        Profile {
+            name,
+            host,
+            port,
+            username: None,
+            password_flag: false,
+            client_id: String::new(),
+            will: None,
+            keep_alive: 0,
+            clean_session: false,
+            mqtt5: true,
+        }
+    }
+
+    pub fn set_address(mut self, host: String, port: u16) -> Self {
+        self.host = host;
+        self.port = port;
+        self
+    }
+
+    /// Sin usuario no se puede enviar password, por eso se reciben juntos. `password_flag` indica
+    /// si la conexion usa password.
+    pub fn set_credentials(mut self, username: Option<String>, password_flag: bool) -> Self {
+        self.password_flag = username.is_some() && password_flag;
+        self.username = username;
+        self
+    }
+
+    pub fn set_client_id(mut self, client_id: String) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn set_will(mut self, will: Option<Will>) -> Self {
+        self.will = will;
+        self
+    }
+
+    pub fn set_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn set_clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn set_mqtt5(mut self, mqtt5: bool) -> Self {
+        self.mqtt5 = mqtt5;
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn get_username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn get_password_flag(&self) -> bool {
+        self.password_flag
+    }
+
+    pub fn get_client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn get_will(&self) -> Option<&Will> {
+        self.will.as_ref()
+    }
+
+    pub fn get_keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    pub fn get_clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn get_mqtt5(&self) -> bool {
+        self.mqtt5
+    }
+
+    /// Direccion `host:port` para abrir la conexion.
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "host": self.host,
+            "port": self.port,
+            "username": self.username,
+            "password_flag": self.password_flag,
+            "client_id": self.client_id,
+            "will": self.will.as_ref().map(Will::to_json),
+            "keep_alive": self.keep_alive,
+            "clean_session": self.clean_session,
+            "mqtt5": self.mqtt5,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let port = value["port"].as_u64()?;
+        if port > u16::MAX as u64 {
+            return None;
+        }
+        let profile = Profile::new(
+            value["name"].as_str()?.to_string(),
+            value["host"].as_str()?.to_string(),
+            port as u16,
+        );
+        Some(
+            profile
+                .set_credentials(
+                    value["username"].as_str().map(String::from),
+                    value["password_flag"].as_bool().unwrap_or(false)
+                        || value["password"].is_string(),
+                )
+                .set_client_id(value["client_id"].as_str().unwrap_or("").to_string())
+                .set_will(Will::from_json(&value["will"]))
+                .set_keep_alive(
+                    value["keep_alive"]
+                        .as_u64()
+                        .unwrap_or(0)
+                        .min(u16::MAX as u64) as u16,
+                )
+                .set_clean_session(value["clean_session"].as_bool().unwrap_or(false))
+                .set_mqtt5(value["mqtt5"].as_bool().unwrap_or(true)),
+        )
+    }
+}
+
+/// Perfiles guardados, en el orden en que se muestran.
+pub struct Profiles {
+    path: PathBuf,
+    profiles: Vec<Profile>,
+}
+
+impl Profiles {
+    /// Lee los perfiles de `path`. Si el archivo no existe se crea con el perfil de `config.txt`.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let profiles = Profiles {
+                    path: PathBuf::from(path),
+                    profiles: vec![profile_from_config()],
+                };
+                profiles.save()?;
+                info!("Perfiles creados a partir de config.txt en {:?}", path);
+                return Ok(profiles);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Profiles::parse(path, &data)
+    }
+
+    fn parse(path: &str, data: &str) -> Result<Self, Box<dyn Error>> {
+        let value: Value = serde_json::from_str(data)?;
+        let mut profiles: Vec<Profile> = vec![];
+        if let Some(entries) = value["profiles"].as_array() {
+            for entry in entries {
+                match Profile::from_json(entry) {
+                    Some(profile) if !profiles.iter().any(|p| p.name == profile.name) => {
+                        profiles.push(profile)
+                    }
+                    _ => warn!("Perfil invalido o repetido: {:?}", entry),
+                }
+            }
+        }
+        if profiles.is_empty() {
+            profiles.push(profile_from_config());
+        }
+        let profiles = Profiles {
+            path: PathBuf::from(path),
+            profiles,
+        };
+        let plain_passwords = value["profiles"]
+            .as_array()
+            .is_some_and(|entries| entries.iter().any(|entry| entry["password"].is_string()));
+        if plain_passwords {
+            warn!("Se borran las passwords guardadas en {:?}", path);
+            profiles.save()?;
+        }
+        Ok(profiles)
+    }
+
+    pub fn get_profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Perfil que se abre al iniciar el cliente.
+    pub fn get_default(&self) -> &Profile {
+        &self.profiles[0]
+    }
+
+    /// Agrega el perfil, o reemplaza al que tiene el mismo nombre, y guarda el archivo.
+    pub fn save_profile(&mut self, profile: Profile) -> Result<(), Box<dyn Error>> {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(current) => *current = profile,
+            None => self.profiles.push(profile),
+        }
+        self.save()
+    }
+
+    /// Borra el perfil y guarda el archivo. Siempre queda al menos uno.
+    pub fn remove(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        if self.profiles.len() > 1 {
+            self.profiles.retain(|profile| profile.name != name);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let profiles: Vec<Value> = self.profiles.iter().map(Profile::to_json).collect();
+        let data = serde_json::to_string_pretty(&json!({ "profiles": profiles }))?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Perfil `default` con el servidor, puerto y usuario de `config.txt`, o local si no existe.
+fn profile_from_config() -> Profile {
+    match client::decode_config() {
+        Ok(config) => {
+            let port = config[1][1].parse().unwrap_or(DEFAULT_PORT);
+            Profile::new(DEFAULT_NAME.to_string(), config[0][1].clone(), port)
+                .set_client_id(config[2].get(1).cloned().unwrap_or_default())
+        }
+        Err(_) => Profile::new(
+            DEFAULT_NAME.to_string(),
+            DEFAULT_HOST.to_string(),
+            DEFAULT_PORT,
+        ),
+    }
+}
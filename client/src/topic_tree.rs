@@ -0,0 +1,114 @@
+//! Arbol de los topics que se vieron en los mensajes recibidos, armado por niveles separados con
+//! `/`, que se muestra en la pantalla de topics.
+//!
+//! No depende de GTK: cada nodo guarda el ultimo valor publicado en su topic, cuantos mensajes se
+//! recibieron en el o en sus hijos, y si el ultimo mensaje era retenido.
+use mqtt_client::Message;
+use std::collections::BTreeMap;
+
+/// Separador de niveles de los topics.
+const LEVEL_SEPARATOR: char = '/';
+/// Wildcard del servidor, que acepta cualquier texto.
+const WILDCARD: &str = "*";
+
+#[derive(Default)]
+pub struct Node {
+    name: String,
+    topic: String,
+    last_value: Option<String>,
+    count: u32,
+    retained: bool,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn new(name: &str, topic: String) -> Self {
+        Node {
+            name: name.to_string(),
+            topic,
+            ..Node::default()
+        }
+    }
+
+    /// Ultimo nivel del topic.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Ultimo payload publicado justo en este topic. Los niveles intermedios pueden no tenerlo.
+    pub fn get_last_value(&self) -> Option<&str> {
+        self.last_value.as_deref()
+    }
+
+    /// Mensajes recibidos en este topic o en los que estan debajo.
+    pub fn get_count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn get_retained(&self) -> bool {
+        self.retained
+    }
+
+    /// Filtros para suscribirse al nodo: el topic si tiene mensajes propios y todo lo que esta
+    /// debajo si tiene hijos.
+    pub fn subscription_filters(&self) -> Vec<String> {
+        let mut filters = vec![];
+        if self.last_value.is_some() {
+            filters.push(self.topic.clone());
+        }
+        if !self.children.is_empty() {
+            filters.push(format!("{}{}{}", self.topic, LEVEL_SEPARATOR, WILDCARD));
+        }
+        filters
+    }
+}
+
+#[derive(Default)]
+pub struct TopicTree {
+    root: Node,
+}
+
+impl TopicTree {
+    pub fn new() -> Self {
+        TopicTree::default()
+    }
+
+    /// Agrega el mensaje al nodo de su topic, creando los niveles que falten. Devuelve los topics
+    /// de los nodos que cambiaron, desde el primer nivel hasta el del mensaje.
+    pub fn record(&mut self, message: &Message) -> Vec<String> {
+        let topic = message.get_topic();
+        let mut changed = vec![];
+        let mut node = &mut self.root;
+        for level in topic.split(LEVEL_SEPARATOR) {
+            let path = match changed.last() {
+                Some(parent) => format!("{}{}{}", parent, LEVEL_SEPARATOR, level),
+                None => level.to_string(),
+            };
+            node = node
+                .children
+                .entry(level.to_string())
+                .or_insert_with(|| Node::new(level, path.clone()));
+            node.count = node.count.saturating_add(1);
+            changed.push(path);
+        }
+        node.last_value = Some(message.get_payload());
+        node.retained = message.get_retain();
+        changed
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&Node> {
+        let mut node = &self.root;
+        for level in topic.split(LEVEL_SEPARATOR) {
+            node = node.children.get(level)?;
+        }
+        Some(node)
+    }
+
+    pub fn clear(&mut self) {
+        self.root = Node::default();
+    }
+}
@@ -0,0 +1,582 @@
+use crate::event::{connack_reason, Event, Message};
+use crate::keep_alive::{Check, KeepAlive, TICK};
+use crate::packets::{self, TOPIC_ALIAS_MAXIMUM};
+use crate::reconnect::{LastConnect, Reconnect, Resume};
+use crate::request::Requests;
+use crate::session::Session;
+use crate::store::Store;
+use crate::template::{Schedule, Scheduled, Template};
+use serializer::mqtt_response::Mqtt5ReturnCodes::MqttRcProtocolError;
+use serializer::mqtt_response::MqttError;
+use serializer::{
+    new_disconnect_with_reason, new_mqtt_header, new_properties, new_puback, new_publish,
+    new_publish_packet_flags, new_topic_aliases, new_topic_filter, ConnectAcknowledgeFlags,
+    ConnectFlag, Mqtt5ReturnCodes, MqttHeader, PacketType, PayloadConnect, Properties, Publish,
+};
+use std::cmp;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Conexion con el servidor. Se puede clonar para enviar paquetes desde varios threads; los
+/// paquetes que llegan los lee un thread propio y los entrega como `Event`. Con
+/// `set_reconnect` la conexion se reabre sola si se corta. Si el CONNECT tiene keep alive, otro
+/// thread envia PINGREQ cuando el cliente no envia nada en ese tiempo.
+#[derive(Clone)]
+pub struct Connection {
+    address: String,
+    write: Arc<Mutex<TcpStream>>,
+    session: Arc<Session>,
+    requests: Arc<Requests>,
+    resume: Arc<Resume>,
+    keep_alive: Arc<KeepAlive>,
+}
+
+impl Connection {
+    /// Abre la conexion TCP y envia los eventos por el channel.
+    pub fn open(address: &str, events: Sender<Event>) -> io::Result<Connection> {
+        Connection::open_with_callback(address, move |event| {
+            if events.send(event).is_err() {
+                warn!("Nadie recibe los eventos de la conexion");
+            }
+        })
+    }
+
+    /// Abre la conexion TCP y llama a `on_event` desde el thread que lee del servidor por cada
+    /// paquete que llega.
+    pub fn open_with_callback<F>(address: &str, mut on_event: F) -> io::Result<Connection>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        let socket = TcpStream::connect(address)?;
+        info!("Conectado al servidor {:?}.", address);
+        let mut read = socket.try_clone()?;
+        let connection = Connection {
+            address: address.to_string(),
+            write: Arc::new(Mutex::new(socket)),
+            session: Arc::new(Session::new()),
+            requests: Arc::new(Requests::new()),
+            resume: Arc::new(Resume::new()),
+            keep_alive: Arc::new(KeepAlive::new()),
+        };
+        let reader = connection.clone();
+        thread::spawn(move || loop {
+            match read_event(&mut read, &reader.write, &reader.session, &reader.requests) {
+                Ok(Some(event)) => {
+                    reader.handle(&event);
+                    on_event(event)
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    info!("Se corto la conexion con el servidor: {:?}", e.to_string());
+                    reader.keep_alive.suspend();
+                    match reader.reconnect(&mut on_event) {
+                        Some(socket) => read = socket,
+                        None => {
+                            reader.keep_alive.stop();
+                            on_event(Event::Disconnected);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        let scheduler = connection.clone();
+        thread::spawn(move || scheduler.schedule_pings());
+        Ok(connection)
+    }
+
+    /// Activa o desactiva la reconexion automatica. Solo se reconecta si ya se envio un CONNECT y
+    /// no se llamo a `disconnect` ni a `close`.
+    pub fn set_reconnect(&self, policy: Option<Reconnect>) {
+        self.resume.set_policy(policy);
+    }
+
+    /// Guarda en el archivo `path` los publish QoS 1 sin confirmar y las suscripciones, para
+    /// reenviarlos aunque se reinicie el cliente. Lo que ya estaba guardado se envia al aceptarse
+    /// el proximo CONNECT, asi que hay que llamarlo antes de `connect`. Con `None` se deja de
+    /// guardar.
+    pub fn set_store(&self, path: Option<&str>) -> Result<(), Box<dyn Error>> {
+        self.resume.set_store(path.map(Store::new))
+    }
+
+    /// Envia el CONNECT con la version de MQTT indicada. Con MQTT 5 se envian tambien las
+    /// properties del will; con MQTT 3.1.1 se ignoran.
+    pub fn connect(
+        &self,
+        connect_flag: ConnectFlag,
+        connect_payload: PayloadConnect,
+        protocol_version: u8,
+        will_properties: Properties,
+    ) -> Result<usize, Mqtt5ReturnCodes> {
+        let last_connect = LastConnect {
+            flag: connect_flag,
+            payload: connect_payload,
+            protocol_version,
+            will_properties,
+        };
+        self.resume.connected(last_connect.clone());
+        self.send_connect(last_connect)
+    }
+
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: &str,
+        qos: u8,
+        retain: bool,
+    ) -> Result<usize, Mqtt5ReturnCodes> {
+        self.publish_with_properties(topic, payload, qos, retain, new_properties())
+    }
+
+    /// Igual que `publish`, con properties de MQTT 5. Con MQTT 3.1.1 las properties se ignoran.
+    /// Los publish QoS 1 se guardan hasta recibir el PUBACK para reenviarlos si hay que
+    /// reconectarse, aunque no se hayan podido enviar.
+    pub fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: &str,
+        qos: u8,
+        retain: bool,
+        properties: Properties,
+    ) -> Result<usize, Mqtt5ReturnCodes> {
+        // No implementamos QoS 2.
+        let flags = match qos {
+            0 => new_publish_packet_flags(Some(retain), None, None, None)?,
+            1 => new_publish_packet_flags(Some(retain), Some(true), None, None)?,
+            _ => return Err(Mqtt5ReturnCodes::MqttRcQosNotSupported),
+        };
+        let topic = new_topic_filter(topic.to_string())?;
+        let publish = new_publish(flags, topic, payload.to_string())?;
+        if qos == 1 {
+            self.resume.published(publish.clone(), properties.clone());
+        }
+        let sent = packets::send_publish_packet(
+            &mut self.lock_write(),
+            &publish,
+            &self.session,
+            properties,
+        );
+        self.sent(sent)
+    }
+
+    pub fn subscribe(&self, topics: Vec<String>, qos: u8) -> Result<usize, Box<dyn Error>> {
+        self.resume.subscribed(&topics, qos);
+        let sent = packets::send_subscribe(&mut self.lock_write(), topics, qos, &self.session);
+        self.sent(sent)
+    }
+
+    pub fn unsubscribe(&self, topics: Vec<String>) -> Result<usize, Box<dyn Error>> {
+        self.resume.unsubscribed(&topics);
+        let sent = packets::send_unsubscribe(&mut self.lock_write(), topics);
+        self.sent(sent)
+    }
+
+    /// Envia un PINGREQ. Si el PINGRESP no llega dentro del keep alive se corta la conexion.
+    pub fn ping(&self) -> Result<usize, Mqtt5ReturnCodes> {
+        // Se marca antes de enviarlo porque el PINGRESP puede llegar antes de que termine el envio.
+        self.keep_alive.ping_sent();
+        packets::send_pingreq(&mut self.lock_write())
+    }
+
+    /// Envia el DISCONNECT. Cuando el servidor cierre la conexion no se reconecta.
+    pub fn disconnect(&self) -> Result<usize, Mqtt5ReturnCodes> {
+        self.resume.set_online(false);
+        packets::send_disconnect(&mut self.lock_write())
+    }
+
+    /// Publica un pedido en `topic` y espera la respuesta hasta `timeout`. Bloquea al thread que lo
+    /// llama.
+    pub fn request(
+        &self,
+        topic: &str,
+        payload: &str,
+        timeout: Duration,
+    ) -> Result<String, Box<dyn Error>> {
+        self.requests
+            .request(&self.write, &self.session, topic, payload, timeout)
+    }
+
+    /// Publica el template segun `schedule` desde otro thread. `on_publish` recibe el numero de
+    /// cada publicacion, empezando en 1, y el resultado de enviarla.
+    pub fn schedule<F>(&self, template: Template, schedule: Schedule, on_publish: F) -> Scheduled
+    where
+        F: FnMut(u32, Result<usize, Mqtt5ReturnCodes>) + Send + 'static,
+    {
+        let connection = self.clone();
+        let publish = move |template: &Template, payload: &str| {
+            connection.publish(
+                template.get_topic(),
+                payload,
+                template.get_qos(),
+                template.get_retain(),
+            )
+        };
+        Scheduled::start(template, schedule, publish, on_publish)
+    }
+
+    /// Cierra el socket sin reconectarse. El thread que lee del servidor termina y entrega
+    /// `Event::Disconnected`.
+    pub fn close(&self) {
+        self.resume.set_online(false);
+        let _res = self.lock_write().shutdown(Shutdown::Both);
+    }
+
+    pub fn is_v5(&self) -> bool {
+        self.session.is_v5()
+    }
+
+    fn send_connect(&self, last_connect: LastConnect) -> Result<usize, Mqtt5ReturnCodes> {
+        self.session.connect(last_connect.protocol_version);
+        self.keep_alive
+            .connected(last_connect.payload.get_keep_alive());
+        self.requests
+            .connected(last_connect.payload.get_client_identifier());
+        packets::send_connect(
+            &mut self.lock_write(),
+            last_connect.flag,
+            last_connect.payload,
+            last_connect.protocol_version,
+            last_connect.will_properties,
+        )
+    }
+
+    /// Actualiza el estado de la sesion con lo que llego del servidor.
+    fn handle(&self, event: &Event) {
+        match event {
+            Event::Puback => self.resume.acknowledged(),
+            Event::Pingresp => self.keep_alive.pingresp(),
+            Event::Connack {
+                accepted: true,
+                session_present,
+                ..
+            } => self.resume_session(*session_present),
+            // Si el servidor rechaza la conexion no tiene sentido reintentarla.
+            Event::Connack {
+                accepted: false, ..
+            } => self.resume.set_online(false),
+            _ => {}
+        }
+    }
+
+    /// Despues de reconectarse vuelve a suscribirse si el servidor no tenia la sesion, y reenvia
+    /// los publish QoS 1 que no se confirmaron.
+    fn resume_session(&self, session_present: bool) {
+        if self.resume.take_resuming() && !session_present {
+            let subscriptions = self.resume.get_subscriptions();
+            for qos in 0..=1 {
+                let topics: Vec<String> = subscriptions
+                    .iter()
+                    .filter(|(_, q)| *q == qos)
+                    .map(|(topic, _)| topic.clone())
+                    .collect();
+                if topics.is_empty() {
+                    continue;
+                }
+                info!("Suscribiendo de nuevo a {:?}", topics);
+                if let Err(e) =
+                    packets::send_subscribe(&mut self.lock_write(), topics, qos, &self.session)
+                {
+                    error!("Error al volver a suscribirse: {:?}", e.to_string());
+                }
+            }
+        }
+        for (mut publish, properties) in self.resume.get_inflight() {
+            let publish = publish.set_dup_flag(true);
+            info!(
+                "Reenviando publish sin confirmar en {:?}",
+                publish.get_topic().get_topic()
+            );
+            if let Err(e) = packets::send_publish_packet(
+                &mut self.lock_write(),
+                &publish,
+                &self.session,
+                properties,
+            ) {
+                error!("Error al reenviar publish: {:?}", e);
+            }
+        }
+    }
+
+    /// Reabre la conexion esperando entre intentos segun la politica de reconexion y reenvia el
+    /// ultimo CONNECT. Devuelve el socket del que hay que seguir leyendo, o None si no hay que
+    /// reconectarse o se agotaron los intentos.
+    fn reconnect<F: FnMut(Event)>(&self, on_event: &mut F) -> Option<TcpStream> {
+        let policy = self.resume.get_policy()?;
+        let last_connect = self.resume.get_last_connect()?;
+        let mut attempt = 0;
+        while self.resume.is_online() {
+            attempt += 1;
+            if policy.get_max_attempts().is_some_and(|max| attempt > max) {
+                warn!(
+                    "No se pudo reconectar al servidor en {:?} intentos",
+                    attempt - 1
+                );
+                return None;
+            }
+            let delay = policy.delay(attempt);
+            info!("Reconectando en {:?} (intento {:?})", delay, attempt);
+            on_event(Event::Reconnecting { attempt });
+            thread::sleep(delay);
+            if !self.resume.is_online() {
+                break;
+            }
+            let socket = match TcpStream::connect(&self.address) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Error al reconectar: {:?}", e.to_string());
+                    continue;
+                }
+            };
+            let read = match socket.try_clone() {
+                Ok(read) => read,
+                Err(e) => {
+                    error!("Error al clonar el socket: {:?}", e.to_string());
+                    continue;
+                }
+            };
+            *self.lock_write() = socket;
+            self.resume.set_resuming();
+            if self.send_connect(last_connect.clone()).is_ok() {
+                info!("Reconectado al servidor {:?}.", self.address);
+                return Some(read);
+            }
+        }
+        None
+    }
+
+    fn sent<T, E>(&self, sent: Result<T, E>) -> Result<T, E> {
+        if sent.is_ok() {
+            self.keep_alive.sent();
+        }
+        sent
+    }
+
+    /// Envia un PINGREQ cuando pasa el keep alive sin enviar nada, y si no llega el PINGRESP corta
+    /// el socket para que el thread que lee del servidor lo trate como una conexion perdida.
+    fn schedule_pings(&self) {
+        while !self.keep_alive.is_stopped() {
+            thread::sleep(TICK);
+            if !self.resume.is_online() {
+                continue;
+            }
+            match self.keep_alive.check() {
+                Check::Ping => {
+                    if let Err(e) = self.ping() {
+                        warn!("No se pudo enviar el PINGREQ: {:?}", e);
+                    }
+                }
+                Check::Lost => {
+                    warn!("No llego el PINGRESP, se considera perdida la conexion.");
+                    self.keep_alive.suspend();
+                    let _res = self.lock_write().shutdown(Shutdown::Both);
+                }
+                Check::Idle => {}
+            }
+        }
+    }
+
+    fn lock_write(&self) -> MutexGuard<'_, TcpStream> {
+        match self.write.lock() {
+            Ok(write) => write,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Lee un paquete del servidor y lo convierte en el evento para el usuario. Los PUBLISH que son
+/// respuestas a pedidos se le entregan al pedido y no generan evento. Devuelve error si se corto la
+/// conexion.
+fn read_event(
+    stream: &mut TcpStream,
+    write: &Mutex<TcpStream>,
+    session: &Session,
+    requests: &Requests,
+) -> Result<Option<Event>, Box<dyn Error>> {
+    let header = read_all(stream)?;
+    let event = match header.get_control_packet_type() {
+        PacketType::CONNACK => {
+            let connack = if session.is_v5() {
+                serializer::new_connack_v5_by_hex(header)
+            } else {
+                serializer::new_connack_by_hex(header).map(|c| (c, new_properties()))
+            };
+            match connack {
+                Ok((connack, properties)) => {
+                    // El servidor indica cuantos topic aliases se le pueden enviar.
+                    let maximum = properties.get_topic_alias_maximum().unwrap_or(0);
+                    *session.lock_aliases() = new_topic_aliases(
+                        if session.is_v5() {
+                            TOPIC_ALIAS_MAXIMUM
+                        } else {
+                            0
+                        },
+                        maximum,
+                    );
+                    info!(
+                        "Respuesta recibida: Paquete {:?} \n\
+                               Connect Aknowledge Flags: {:?} \n\
+                               Return codes: {:?}",
+                        connack.get_packet_type(),
+                        connack.get_connect_acknowledge_flags(),
+                        connack.get_connect_return_codes().is_accepted()
+                    );
+                    let return_codes = connack.get_connect_return_codes();
+                    Event::Connack {
+                        accepted: return_codes.is_accepted(),
+                        reason: connack_reason(return_codes.get_reason()),
+                        session_present: matches!(
+                            connack.get_connect_acknowledge_flags(),
+                            ConnectAcknowledgeFlags::Sp1
+                        ),
+                    }
+                }
+                Err(e) => {
+                    error!("Error en crear CONNACK: {:?}", e);
+                    Event::InvalidPacket(PacketType::CONNACK)
+                }
+            }
+        }
+        PacketType::PUBACK => {
+            // Si me llega un PUBACK es porque se publico exitosamente. No me importa lo que dice adentro.
+            info!("Respuesta recibida: Paquete PUBACK.");
+            Event::Puback
+        }
+        PacketType::SUBACK => {
+            let suback = if session.is_v5() {
+                serializer::new_suback_v5_by_hex(header).map(|(suback, _properties)| suback)
+            } else {
+                serializer::new_suback_by_hex(header)
+            };
+            match suback {
+                Ok(suback) => Event::Suback {
+                    return_codes: suback.get_return_codes(),
+                },
+                Err(e) => {
+                    error!("Error en recibir SUBACK: {:?}", e);
+                    Event::InvalidPacket(PacketType::SUBACK)
+                }
+            }
+        }
+        PacketType::UNSUBACK => {
+            info!("Me llego un unsuback");
+            Event::Unsuback
+        }
+        PacketType::PUBLISH => {
+            let publish = if session.is_v5() {
+                match serializer::new_publish_v5_by_hex(header) {
+                    Ok((publish, properties)) => {
+                        resolve_alias(publish, &properties, session, write)
+                            .map(|publish| (publish, properties))
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
+                serializer::new_publish_by_hex(header).map(|p| (p, new_properties()))
+            };
+            match publish {
+                Ok((publish, properties)) => {
+                    if publish.get_flags().get_qos() == 1 {
+                        let puback = new_puback();
+                        if let Ok(mut write) = write.lock() {
+                            if write.write(&puback.get_data()).is_ok() {}
+                        }
+                    }
+                    // Las respuestas a los pedidos se le entregan al que las espera.
+                    if requests.resolve_reply(&publish, &properties, session) {
+                        return Ok(None);
+                    }
+                    Event::Publish(Message::new(
+                        publish.get_topic().get_topic(),
+                        publish.get_payload(),
+                        publish.get_flags().get_qos(),
+                        publish.get_flags().get_retain(),
+                        properties,
+                    ))
+                }
+                Err(e) => {
+                    error!("Error en recibir PUBLISH: {:?}", e);
+                    Event::InvalidPacket(PacketType::PUBLISH)
+                }
+            }
+        }
+        PacketType::PINGRESP => {
+            info!("Me llego un PINGRESP.");
+            Event::Pingresp
+        }
+        _ => {
+            //No me deberian llegar otro tipo de mensajes.
+            warn!("Llego un tipo de mensaje inesperado.");
+            return Ok(None);
+        }
+    };
+    Ok(Some(event))
+}
+
+/// Completa el topic de un publish que llego con topic alias. Si el alias es invalido se
+/// desconecta del servidor con el reason code correspondiente.
+fn resolve_alias(
+    publish: Publish,
+    properties: &Properties,
+    session: &Session,
+    write: &Mutex<TcpStream>,
+) -> Result<Publish, Box<dyn Error>> {
+    let resolved = session
+        .lock_aliases()
+        .resolve_inbound(publish, properties.get_topic_alias());
+    resolved.map_err(|e| {
+        if let Ok(mut write) = write.lock() {
+            if write
+                .write(&new_disconnect_with_reason(e.clone()).get_data())
+                .is_ok()
+            {}
+        }
+        Box::new(MqttError { error: e }) as Box<dyn Error>
+    })
+}
+
+fn read_all(stream: &mut TcpStream) -> Result<MqttHeader, Box<dyn Error>> {
+    let mut size_buf = [0_u8; 2];
+    let mut result: Vec<u8> = Vec::new();
+    let msg_size = match stream.read_exact(&mut size_buf) {
+        Ok(_) => match new_mqtt_header(size_buf.to_vec()) {
+            Ok(header) => {
+                result.append(&mut size_buf.to_vec());
+                header.get_remaining_length() as u32
+            }
+            Err(e) => {
+                return Err(Box::new(MqttError { error: e }));
+            }
+        },
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    // Leer del socket la cantidad de bytes que indica el header
+    let mut bytes_read: u32 = 0;
+    while bytes_read < msg_size {
+        let max_limit = cmp::min(msg_size - bytes_read, 1024);
+        let mut buf = vec![0; max_limit as usize].into_boxed_slice();
+        match stream.read(&mut buf) {
+            Ok(0) => {
+                return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            Ok(size) => {
+                let mut received = Vec::from(&buf[0..size]);
+                result.append(&mut received);
+                bytes_read += size as u32
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    match new_mqtt_header(result) {
+        Ok(h) => Ok(h),
+        Err(_) => Err(Box::new(MqttError {
+            error: MqttRcProtocolError,
+        })),
+    }
+}
@@ -0,0 +1,98 @@
+use serializer::{PacketType, Properties, SubackReturnCode};
+
+/// Lo que le llega al cliente desde el servidor. Se entrega por el callback o el channel que se le
+/// pasa a la conexion.
+#[derive(Clone)]
+pub enum Event {
+    /// Respuesta al CONNECT. Si no se acepto, `reason` indica por que. `session_present` indica
+    /// si el servidor tenia guardada la sesion del cliente.
+    Connack {
+        accepted: bool,
+        reason: String,
+        session_present: bool,
+    },
+    /// Se confirmo un publish QoS 1.
+    Puback,
+    /// Respuesta al SUBSCRIBE, con un return code por topic.
+    Suback {
+        return_codes: Vec<SubackReturnCode>,
+    },
+    Unsuback,
+    /// Mensaje publicado en un topic al que esta suscripto el cliente.
+    Publish(Message),
+    Pingresp,
+    /// Llego un paquete que no se pudo decodificar.
+    InvalidPacket(PacketType),
+    /// Se corto la conexion y se va a intentar reconectar por vez numero `attempt`. Si se
+    /// reconecta llega un nuevo `Connack`.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// Se corto la conexion con el servidor y no se reconecta. Despues de este evento no llegan
+    /// mas.
+    Disconnected,
+}
+
+/// Mensaje recibido en un PUBLISH.
+#[derive(Clone)]
+pub struct Message {
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: bool,
+    properties: Properties,
+}
+
+impl Message {
+    /// Los mensajes los arma la conexion al recibir un PUBLISH; se puede usar para armarlos en
+    /// pruebas.
+    pub fn new(
+        topic: String,
+        payload: String,
+        qos: u8,
+        retain: bool,
+        properties: Properties,
+    ) -> Self {
+        Message {
+            topic,
+            payload,
+            qos,
+            retain,
+            properties,
+        }
+    }
+
+    pub fn get_topic(&self) -> String {
+        self.topic.clone()
+    }
+
+    pub fn get_payload(&self) -> String {
+        self.payload.clone()
+    }
+
+    pub fn get_qos(&self) -> u8 {
+        self.qos
+    }
+
+    pub fn get_retain(&self) -> bool {
+        self.retain
+    }
+
+    /// Properties de MQTT 5 del mensaje. Con MQTT 3.1.1 estan vacias.
+    pub fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+}
+
+/// Razon por la que el servidor rechazo la conexion.
+pub(crate) fn connack_reason(code: u8) -> String {
+    match code {
+        0x00 => "ConnectionAccepted".to_string(),
+        0x01 => "InvalidProtocol".to_string(),
+        0x02 => "IdentifierRejected".to_string(),
+        0x03 => "ServerUnavailable".to_string(),
+        0x04 => "BadUserNameOrPassword".to_string(),
+        0x05 => "NotAuthorized".to_string(),
+        _ => "CloseConnection".to_string(),
+    }
+}
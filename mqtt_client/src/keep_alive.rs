@@ -0,0 +1,105 @@
+//! Keep alive del cliente.
+//!
+//! El servidor corta la conexion si no recibe ningun paquete en 1.5 veces el keep alive del
+//! CONNECT, asi que si pasa el keep alive sin que el cliente envie nada se envia un PINGREQ. Si el
+//! PINGRESP no llega dentro de otro keep alive se considera que se perdio la conexion.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Cada cuanto se revisa si hay que enviar un PINGREQ.
+pub(crate) const TICK: Duration = Duration::from_millis(250);
+
+/// Que hacer en cada revision del keep alive.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Check {
+    Idle,
+    Ping,
+    Lost,
+}
+
+pub(crate) struct KeepAlive {
+    interval: Mutex<Option<Duration>>,
+    last_sent: Mutex<Instant>,
+    ping_sent: Mutex<Option<Instant>>,
+    stopped: AtomicBool,
+}
+
+impl KeepAlive {
+    pub(crate) fn new() -> Self {
+        KeepAlive {
+            interval: Mutex::new(None),
+            last_sent: Mutex::new(Instant::now()),
+            ping_sent: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Se llama al enviar el CONNECT con el keep alive en segundos. Con 0 no se envian PINGREQ.
+    pub(crate) fn connected(&self, keep_alive: u16) {
+        *lock(&self.interval) = match keep_alive {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        };
+        *lock(&self.ping_sent) = None;
+        self.sent();
+    }
+
+    /// Deja de enviar PINGREQ hasta el proximo CONNECT, porque se corto la conexion.
+    pub(crate) fn suspend(&self) {
+        *lock(&self.interval) = None;
+        *lock(&self.ping_sent) = None;
+    }
+
+    /// La conexion se cerro definitivamente y no hay que revisar mas.
+    pub(crate) fn stop(&self) {
+        self.suspend();
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Se envio un paquete al servidor.
+    pub(crate) fn sent(&self) {
+        *lock(&self.last_sent) = Instant::now();
+    }
+
+    pub(crate) fn ping_sent(&self) {
+        *lock(&self.ping_sent) = Some(Instant::now());
+        self.sent();
+    }
+
+    pub(crate) fn pingresp(&self) {
+        *lock(&self.ping_sent) = None;
+    }
+
+    pub(crate) fn check(&self) -> Check {
+        self.check_at(Instant::now())
+    }
+
+    pub(crate) fn check_at(&self, now: Instant) -> Check {
+        let interval = match *lock(&self.interval) {
+            Some(interval) => interval,
+            None => return Check::Idle,
+        };
+        if let Some(ping_sent) = *lock(&self.ping_sent) {
+            if now.saturating_duration_since(ping_sent) >= interval {
+                return Check::Lost;
+            }
+            return Check::Idle;
+        }
+        if now.saturating_duration_since(*lock(&self.last_sent)) >= interval {
+            return Check::Ping;
+        }
+        Check::Idle
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
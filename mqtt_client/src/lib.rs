@@ -0,0 +1,299 @@
+//! Cliente MQTT sin interfaz grafica.
+//!
+//! `Connection` abre la conexion con el servidor y expone `connect`, `publish`, `subscribe`,
+//! `unsubscribe` y `disconnect`. Lo que llega del servidor se entrega como `Event`, por un channel
+//! (`Connection::open`) o por un callback (`Connection::open_with_callback`):
+//!
+//! ```no_run
+//! use mqtt_client::{Connection, Event, PROTOCOL_VERSION_5};
+//! use serializer::{new_connect_flag, new_payload_connect, new_properties};
+//! use std::sync::mpsc;
+//!
+//! let (tx, rx) = mpsc::channel();
+//! let connection = Connection::open("127.0.0.1:1883", tx).unwrap();
+//! let flag = new_connect_flag(Some(true), None, None, None, None, None, None).unwrap();
+//! let payload = new_payload_connect("sensor".into(), "".into(), "".into(), "".into(), "".into(), 0)
+//!     .unwrap();
+//! connection
+//!     .connect(flag, payload, PROTOCOL_VERSION_5, new_properties())
+//!     .unwrap();
+//! connection.publish("sensores/temp", "21.5", 1, false).unwrap();
+//! while let Ok(event) = rx.recv() {
+//!     if let Event::Publish(message) = event {
+//!         println!("{}: {}", message.get_topic(), message.get_payload());
+//!     }
+//! }
+//! ```
+mod connection;
+mod event;
+mod keep_alive;
+pub mod packets;
+mod reconnect;
+mod request;
+mod session;
+mod store;
+mod template;
+
+pub use crate::connection::Connection;
+pub use crate::event::{Event, Message};
+pub use crate::packets::{PROTOCOL_VERSION_3, PROTOCOL_VERSION_5};
+pub use crate::reconnect::Reconnect;
+pub use crate::session::Session;
+pub use crate::template::{Schedule, Scheduled, Template, Templates, TEMPLATES_PATH};
+
+#[cfg(test)]
+mod tests {
+    use crate::keep_alive::{Check, KeepAlive};
+    use crate::request::Requests;
+    use crate::template::Scheduled;
+    use crate::{
+        Reconnect, Schedule, Session, Template, Templates, PROTOCOL_VERSION_3, PROTOCOL_VERSION_5,
+    };
+    use serializer::{
+        new_properties, new_publish, new_publish_packet_flags, new_topic_filter, Properties,
+        Publish,
+    };
+    use std::io::{ErrorKind, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    #[test]
+    fn session_encodes_publish_by_version() {
+        let flags = new_publish_packet_flags(None, None, None, None).unwrap();
+        let topic = new_topic_filter("sensores/temp".to_string()).unwrap();
+        let publish = new_publish(flags, topic, "21.5".to_string()).unwrap();
+        let session = Session::new();
+
+        session.connect(PROTOCOL_VERSION_3);
+        assert!(!session.is_v5());
+        let v3 = session.encode_publish(&publish, new_properties()).unwrap();
+        assert_eq!(v3, publish.get_data());
+
+        session.connect(PROTOCOL_VERSION_5);
+        let v5 = session.encode_publish(&publish, new_properties()).unwrap();
+        assert_eq!(v5.len(), v3.len() + 1);
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_max_delay() {
+        let policy = Reconnect::new()
+            .set_initial_delay(Duration::from_millis(100))
+            .set_max_delay(Duration::from_millis(1000));
+        for (attempt, base) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(base / 2));
+            assert!(delay <= Duration::from_millis(base));
+        }
+    }
+
+    #[test]
+    fn store_keeps_pending_publishes_and_subscriptions() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt_client_store_{}.json", std::process::id()));
+        let store = crate::store::Store::new(path.to_str().unwrap());
+        assert!(store.load().unwrap().0.is_empty());
+
+        let flags = new_publish_packet_flags(Some(true), Some(true), None, None).unwrap();
+        let topic = new_topic_filter("sensores/temp".to_string()).unwrap();
+        let publish = new_publish(flags, topic, "21.5".to_string()).unwrap();
+        let properties = new_properties()
+            .set_message_expiry_interval(Some(60))
+            .set_response_topic(Some("respuestas/sensor".to_string()))
+            .set_correlation_data(Some(b"7".to_vec()));
+        let subscriptions = vec![("alertas".to_string(), 1)];
+        store
+            .save(&[(publish.clone(), properties.clone())], &subscriptions)
+            .unwrap();
+
+        let (publishes, loaded) = store.load().unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].0.get_data(), publish.get_data());
+        assert_eq!(publishes[0].1, properties);
+        assert_eq!(loaded, subscriptions);
+        let _res = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn pings_when_idle_and_detects_missing_pingresp() {
+        let keep_alive = KeepAlive::new();
+        assert_eq!(keep_alive.check(), Check::Idle);
+
+        keep_alive.connected(2);
+        let start = Instant::now();
+        assert_eq!(keep_alive.check_at(start), Check::Idle);
+        assert_eq!(
+            keep_alive.check_at(start + Duration::from_secs(3)),
+            Check::Ping
+        );
+
+        keep_alive.ping_sent();
+        let sent = Instant::now();
+        assert_eq!(
+            keep_alive.check_at(sent + Duration::from_secs(1)),
+            Check::Idle
+        );
+        assert_eq!(
+            keep_alive.check_at(sent + Duration::from_secs(3)),
+            Check::Lost
+        );
+
+        keep_alive.pingresp();
+        assert_eq!(
+            keep_alive.check_at(sent + Duration::from_secs(1)),
+            Check::Idle
+        );
+    }
+
+    #[test]
+    fn template_renders_placeholders_and_is_saved_by_name() {
+        let template = Template::new(
+            "temp",
+            "sensores/temp",
+            "{\"t\":{{timestamp}},\"n\":{{counter}},\"r\":{{random}}}",
+        )
+        .set_qos(1);
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let payload = template.render_at(7, now);
+        assert!(payload.starts_with("{\"t\":1700000000123,\"n\":7,\"r\":"));
+        let random = &payload["{\"t\":1700000000123,\"n\":7,\"r\":".len()..payload.len() - 1];
+        assert!(random.parse::<u32>().is_ok());
+
+        let path = std::env::temp_dir().join(format!("templates_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut templates = Templates::load(path).unwrap();
+        assert!(templates.get_templates().is_empty());
+        templates.save_template(template.clone()).unwrap();
+        templates
+            .save_template(template.clone().set_retain(true))
+            .unwrap();
+        let templates = Templates::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(templates.get_templates().len(), 1);
+        assert_eq!(templates.get("temp"), Some(&template.set_retain(true)));
+    }
+
+    #[test]
+    fn schedule_publishes_n_times_or_until_stopped() {
+        let template = Template::new("contador", "contador", "{{counter}}");
+        let (tx, rx) = mpsc::channel();
+        let schedule = Schedule::new(Duration::from_millis(5)).set_times(Some(3));
+        let publish = move |_: &Template, payload: &str| {
+            tx.send(payload.to_string()).unwrap();
+            Ok(payload.len())
+        };
+        let scheduled = Scheduled::start(template.clone(), schedule, publish, |_, _| {});
+        assert_eq!(scheduled.join(), 3);
+        assert_eq!(rx.iter().collect::<Vec<String>>(), vec!["1", "2", "3"]);
+
+        let schedule = Schedule::new(Duration::from_secs(60));
+        let scheduled = Scheduled::start(template, schedule, |_, _| Ok(0), |_, _| {});
+        scheduled.stop();
+        assert_eq!(scheduled.join(), 1);
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = vec![0_u8; 2];
+        stream.read_exact(&mut data).unwrap();
+        let mut rest = vec![0_u8; data[1] as usize];
+        stream.read_exact(&mut rest).unwrap();
+        data.append(&mut rest);
+        data
+    }
+
+    fn connected_requests(
+        client_id: &str,
+    ) -> (
+        Arc<Requests>,
+        Arc<Mutex<TcpStream>>,
+        Arc<Session>,
+        TcpStream,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let write = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let session = Session::new();
+        session.connect(PROTOCOL_VERSION_5);
+        let requests = Requests::new();
+        requests.connected(client_id);
+        (
+            Arc::new(requests),
+            Arc::new(Mutex::new(write)),
+            Arc::new(session),
+            server,
+        )
+    }
+
+    fn reply(topic: &str, payload: &str, correlation: &[u8]) -> (Publish, Properties) {
+        let flags = new_publish_packet_flags(None, None, None, None).unwrap();
+        let topic = new_topic_filter(topic.to_string()).unwrap();
+        let publish = new_publish(flags, topic, payload.to_string()).unwrap();
+        let properties = new_properties().set_correlation_data(Some(correlation.to_vec()));
+        (publish, properties)
+    }
+
+    #[test]
+    fn request_gets_the_reply_with_its_correlation_data() {
+        let (requests, write, session, mut server) = connected_requests("cliente");
+        let pending = {
+            let (requests, session) = (requests.clone(), session.clone());
+            thread::spawn(move || {
+                requests
+                    .request(&write, &session, "pedidos", "ping", Duration::from_secs(5))
+                    .map_err(|e| e.to_string())
+            })
+        };
+        // El SUBSCRIBE al topic de respuestas y despues el pedido.
+        let _subscribe = read_packet(&mut server);
+        let _request = read_packet(&mut server);
+
+        let (other, properties) = reply("otro/topic", "pong", b"1");
+        assert!(!requests.resolve_reply(&other, &properties, &session));
+        let (unknown, properties) = reply("respuestas/cliente", "otro", b"99");
+        assert!(requests.resolve_reply(&unknown, &properties, &session));
+        let (answer, properties) = reply("respuestas/cliente", "pong", b"1");
+        assert!(requests.resolve_reply(&answer, &properties, &session));
+        assert_eq!(pending.join().unwrap(), Ok("pong".to_string()));
+    }
+
+    #[test]
+    fn request_times_out_and_late_replies_are_dropped() {
+        let (requests, write, session, _server) = connected_requests("cliente");
+        let error = requests
+            .request(
+                &write,
+                &session,
+                "pedidos",
+                "ping",
+                Duration::from_millis(50),
+            )
+            .unwrap_err();
+        let error = error.downcast::<std::io::Error>().unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        let (late, properties) = reply("respuestas/cliente", "pong", b"1");
+        assert!(requests.resolve_reply(&late, &properties, &session));
+    }
+
+    #[test]
+    fn request_without_client_id_fails_without_sending() {
+        let (requests, write, session, mut server) = connected_requests("");
+        let error = requests
+            .request(&write, &session, "pedidos", "ping", Duration::from_secs(5))
+            .unwrap_err();
+        let error = error.downcast::<std::io::Error>().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        server.set_nonblocking(true).unwrap();
+        let read = server.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(read.kind(), ErrorKind::WouldBlock);
+    }
+}
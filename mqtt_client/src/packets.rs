@@ -1,26 +1,56 @@
-use crate::client;
+use crate::session::Session;
 use serializer::mqtt_response::MqttError;
 use serializer::{
-    new_connect, new_topic_filter, new_topic_filter_with_qos, ConnectFlag, Mqtt5ReturnCodes,
-    PayloadConnect, PublishFlag, TopicFilter,
+    new_connect, new_connect_v5, new_properties, new_topic_filter, new_topic_filter_with_qos,
+    ConnectFlag, Mqtt5ReturnCodes, PayloadConnect, Properties, Publish, PublishFlag, TopicFilter,
 };
 use std::error::Error;
 use std::io::Write;
 use std::net::TcpStream;
 use tracing::{error, info};
 
+/// Cantidad de topic aliases que el cliente acepta del servidor.
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 10;
+
+// Protocol level del CONNECT de cada version de MQTT.
+pub const PROTOCOL_VERSION_3: u8 = 4;
+pub const PROTOCOL_VERSION_5: u8 = 5;
+
+/// Envia el publish usando un topic alias si el servidor los acepta: la primera vez que se publica
+/// en un topic se le asigna un alias y las siguientes se envia solo el alias.
 pub fn send_publish(
     stream: &mut TcpStream,
     flags: PublishFlag,
     topic: TopicFilter,
     payload: String,
+    session: &Session,
+) -> Result<usize, Mqtt5ReturnCodes> {
+    send_publish_with_properties(stream, flags, topic, payload, session, new_properties())
+}
+
+/// Igual que `send_publish`, con properties de MQTT 5. Con MQTT 3.1.1 las properties se ignoran.
+pub fn send_publish_with_properties(
+    stream: &mut TcpStream,
+    flags: PublishFlag,
+    topic: TopicFilter,
+    payload: String,
+    session: &Session,
+    properties: Properties,
 ) -> Result<usize, Mqtt5ReturnCodes> {
     let publish = serializer::new_publish(flags, topic, payload)?;
-    return match stream.write(&publish.get_data()) {
+    send_publish_packet(stream, &publish, session, properties)
+}
+
+/// Envia un publish ya armado, por ejemplo al reenviar uno que no se confirmo.
+pub fn send_publish_packet(
+    stream: &mut TcpStream,
+    publish: &Publish,
+    session: &Session,
+    properties: Properties,
+) -> Result<usize, Mqtt5ReturnCodes> {
+    let data = session.encode_publish(publish, properties)?;
+    match stream.write(&data) {
         Ok(size) => {
-            publish.get_topic().get_topic();
-            publish.get_payload();
-            publish.get_flags().get_qos();
             info!(
                 "Enviado Paquete PUBLISH:\n\
             Topic: {:?} \n\
@@ -38,13 +68,14 @@ pub fn send_publish(
             error!("Error al enviar PUBLISH");
             Err(Mqtt5ReturnCodes::MqttRcServerUnavailable)
         }
-    };
+    }
 }
 
 pub fn send_subscribe(
     stream: &mut TcpStream,
     topics: Vec<String>,
     qos: u8,
+    session: &Session,
 ) -> Result<usize, Box<dyn Error>> {
     let mut topic_vec = vec![];
     for t in topics {
@@ -59,7 +90,10 @@ pub fn send_subscribe(
         }
     }
     let subscribe = serializer::new_subscribe(topic_vec)?;
-    return match stream.write(&subscribe.get_data()) {
+    let data = session
+        .encode_subscribe(&subscribe)
+        .map_err(|e| MqttError { error: e })?;
+    match stream.write(&data) {
         Ok(s) => {
             let mut topics: Vec<String> = vec![];
             for i in subscribe.get_topics() {
@@ -77,7 +111,7 @@ pub fn send_subscribe(
             error!("Error al enviar Subscribe");
             Err(e.into())
         }
-    };
+    }
 }
 
 pub fn send_unsubscribe(
@@ -97,7 +131,7 @@ pub fn send_unsubscribe(
         }
     }
     let unsubscribe = serializer::new_unsubscribe(topic_vec)?;
-    return match stream.write(&unsubscribe.get_data()) {
+    match stream.write(&unsubscribe.get_data()) {
         Ok(s) => {
             let mut topics: Vec<String> = vec![];
             for i in unsubscribe.get_topic_filters() {
@@ -116,19 +150,28 @@ pub fn send_unsubscribe(
             error!("Error al enviar Unsubscribe");
             Err(e.into())
         }
-    };
+    }
 }
 
+/// Envia el CONNECT. Con MQTT 5 se envian tambien las properties del will (delay, vencimiento,
+/// content type); con MQTT 3.1.1 se ignoran.
 pub fn send_connect(
     stream: &mut TcpStream,
     connect_flag: ConnectFlag,
     connect_payload: PayloadConnect,
+    protocol_version: u8,
+    will_properties: Properties,
 ) -> Result<usize, Mqtt5ReturnCodes> {
-    let connect_packet = new_connect(connect_flag, connect_payload);
+    let connect_packet = if protocol_version == PROTOCOL_VERSION_5 {
+        let properties = new_properties().set_topic_alias_maximum(Some(TOPIC_ALIAS_MAXIMUM));
+        new_connect_v5(connect_flag, connect_payload, properties, will_properties)
+    } else {
+        new_connect(connect_flag, connect_payload)
+    };
     match connect_packet {
         Ok(connect_packet) => {
             let data = connect_packet.get_data();
-            return match stream.write(&data) {
+            match stream.write(&data) {
                 Ok(s) => {
                     info!(
                         "Enviando Paquete CONNECT:\n\
@@ -157,16 +200,13 @@ pub fn send_connect(
                         connect_packet.get_connect_flags().get_username_flag(),
                         connect_packet.get_connect_flags().get_password_flag()
                     );
-                    client::update_changes_user(
-                        connect_packet.get_payload().get_client_identifier().clone(),
-                    );
                     Ok(s)
                 }
                 Err(_) => {
                     error!("Error al enviar CONNECT");
                     Err(Mqtt5ReturnCodes::MqttRcServerUnavailable)
                 }
-            };
+            }
         }
         Err(_) => {
             error!("Error al crear CONNECT");
@@ -178,7 +218,7 @@ pub fn send_connect(
 pub fn send_disconnect(stream: &mut TcpStream) -> Result<usize, Mqtt5ReturnCodes> {
     let disc = serializer::new_disconnect();
 
-    return match stream.write(&disc.get_data()) {
+    match stream.write(&disc.get_data()) {
         Ok(s) => {
             info!("Desconectado");
             Ok(s)
@@ -187,13 +227,13 @@ pub fn send_disconnect(stream: &mut TcpStream) -> Result<usize, Mqtt5ReturnCodes
             error!("error al desconectar");
             Err(Mqtt5ReturnCodes::MqttRcServerUnavailable)
         }
-    };
+    }
 }
 
 pub fn send_pingreq(stream: &mut TcpStream) -> Result<usize, Mqtt5ReturnCodes> {
     let pingreq = serializer::new_pingreq();
 
-    return match stream.write(&pingreq.get_data()) {
+    match stream.write(&pingreq.get_data()) {
         Ok(s) => {
             info!("Paquete PINGREQ enviado.");
             Ok(s)
@@ -202,5 +242,5 @@ pub fn send_pingreq(stream: &mut TcpStream) -> Result<usize, Mqtt5ReturnCodes> {
             error!("Error al enviar PINGREQ.");
             Err(Mqtt5ReturnCodes::MqttRcServerUnavailable)
         }
-    };
+    }
 }
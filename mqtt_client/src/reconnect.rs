@@ -0,0 +1,241 @@
+//! Reconexion automatica cuando se corta la conexion con el servidor.
+//!
+//! Se reintenta con backoff exponencial con jitter: la espera se duplica en cada intento hasta
+//! `max_delay`, y se elige al azar entre la mitad y el total para que varios clientes no se
+//! reconecten todos a la vez cuando el servidor vuelve. Al reconectarse se reenvia el ultimo
+//! CONNECT; si el servidor no tenia la sesion (`ConnectAcknowledgeFlags::Sp0`) se vuelve a
+//! suscribir a los topics, y en cualquier caso se reenvian los publish QoS 1 sin PUBACK. Con un
+//! `Store` lo pendiente tambien se guarda en un archivo para retomarlo si se reinicia el cliente.
+use crate::store::Store;
+use serializer::{ConnectFlag, PayloadConnect, Properties, Publish};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tracing::error;
+
+const INITIAL_DELAY_MILLIS: u64 = 1000;
+const MAX_DELAY_MILLIS: u64 = 30_000;
+
+/// Cuanto esperar entre intentos de reconexion y cuantas veces intentar.
+#[derive(Clone, Copy, Debug)]
+pub struct Reconnect {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Reconnect {
+    /// Empieza esperando un segundo, nunca espera mas de 30 y reintenta indefinidamente.
+    pub fn new() -> Self {
+        Reconnect {
+            initial_delay: Duration::from_millis(INITIAL_DELAY_MILLIS),
+            max_delay: Duration::from_millis(MAX_DELAY_MILLIS),
+            max_attempts: None,
+        }
+    }
+
+    pub fn set_initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn set_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Con `None` se reintenta hasta que se cierre la conexion.
+    pub fn set_max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn get_max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    /// Espera antes del intento `attempt` (empezando en 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + random() % (half + 1))
+    }
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect::new()
+    }
+}
+
+/// Numero al azar para el jitter. No hace falta que sea bueno, solo que varie entre clientes.
+pub(crate) fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u32(now.subsec_nanos());
+    }
+    hasher.finish()
+}
+
+/// Ultimo CONNECT que se envio, para repetirlo al reconectarse.
+#[derive(Clone)]
+pub(crate) struct LastConnect {
+    pub flag: ConnectFlag,
+    pub payload: PayloadConnect,
+    pub protocol_version: u8,
+    pub will_properties: Properties,
+}
+
+/// Lo que hace falta para retomar la sesion despues de reconectarse.
+pub(crate) struct Resume {
+    policy: Mutex<Option<Reconnect>>,
+    // El usuario quiere estar conectado: se envio un CONNECT y todavia no se desconecto ni cerro.
+    online: AtomicBool,
+    // Se reconecto y falta retomar la sesion cuando llegue el CONNACK.
+    resuming: AtomicBool,
+    last_connect: Mutex<Option<LastConnect>>,
+    subscriptions: Mutex<Vec<(String, u8)>>,
+    inflight: Mutex<VecDeque<(Publish, Properties)>>,
+    store: Mutex<Option<Store>>,
+}
+
+impl Resume {
+    pub(crate) fn new() -> Self {
+        Resume {
+            policy: Mutex::new(None),
+            online: AtomicBool::new(false),
+            resuming: AtomicBool::new(false),
+            last_connect: Mutex::new(None),
+            subscriptions: Mutex::new(vec![]),
+            inflight: Mutex::new(VecDeque::new()),
+            store: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn set_policy(&self, policy: Option<Reconnect>) {
+        *lock(&self.policy) = policy;
+    }
+
+    pub(crate) fn get_policy(&self) -> Option<Reconnect> {
+        *lock(&self.policy)
+    }
+
+    pub(crate) fn connected(&self, last_connect: LastConnect) {
+        *lock(&self.last_connect) = Some(last_connect);
+        self.online.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn get_last_connect(&self) -> Option<LastConnect> {
+        lock(&self.last_connect).clone()
+    }
+
+    pub(crate) fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_resuming(&self) {
+        self.resuming.store(true, Ordering::SeqCst);
+    }
+
+    /// Devuelve si habia que retomar la sesion, y deja de hacerlo.
+    pub(crate) fn take_resuming(&self) -> bool {
+        self.resuming.swap(false, Ordering::SeqCst)
+    }
+
+    pub(crate) fn subscribed(&self, topics: &[String], qos: u8) {
+        let mut subscriptions = lock(&self.subscriptions);
+        for topic in topics {
+            subscriptions.retain(|(t, _)| t != topic);
+            subscriptions.push((topic.clone(), qos));
+        }
+        drop(subscriptions);
+        self.persist();
+    }
+
+    pub(crate) fn unsubscribed(&self, topics: &[String]) {
+        lock(&self.subscriptions).retain(|(t, _)| !topics.contains(t));
+        self.persist();
+    }
+
+    pub(crate) fn get_subscriptions(&self) -> Vec<(String, u8)> {
+        lock(&self.subscriptions).clone()
+    }
+
+    pub(crate) fn published(&self, publish: Publish, properties: Properties) {
+        lock(&self.inflight).push_back((publish, properties));
+        self.persist();
+    }
+
+    /// El servidor confirma los publish QoS 1 en el orden en que los recibe, y como el paquete no
+    /// lleva packet identifier cada PUBACK confirma el mas viejo.
+    pub(crate) fn acknowledged(&self) {
+        if lock(&self.inflight).pop_front().is_some() {
+            self.persist();
+        }
+    }
+
+    pub(crate) fn get_inflight(&self) -> Vec<(Publish, Properties)> {
+        lock(&self.inflight).iter().cloned().collect()
+    }
+
+    /// Empieza a guardar en `store` lo pendiente. Lo que ya estaba guardado se suma a lo pendiente
+    /// de esta conexion, como mas viejo, y se envia al aceptarse el proximo CONNECT.
+    pub(crate) fn set_store(&self, store: Option<Store>) -> Result<(), Box<dyn Error>> {
+        if let Some(store) = &store {
+            let (publishes, subscriptions) = store.load()?;
+            let mut inflight = lock(&self.inflight);
+            for pending in publishes.into_iter().rev() {
+                inflight.push_front(pending);
+            }
+            drop(inflight);
+            let mut current = lock(&self.subscriptions);
+            for (topic, qos) in subscriptions {
+                if !current.iter().any(|(t, _)| *t == topic) {
+                    current.push((topic, qos));
+                }
+            }
+            // Si el servidor no tiene la sesion hay que volver a suscribirse.
+            if !current.is_empty() {
+                self.set_resuming();
+            }
+        }
+        *lock(&self.store) = store;
+        self.persist();
+        Ok(())
+    }
+
+    fn persist(&self) {
+        let store = lock(&self.store);
+        if let Some(store) = store.as_ref() {
+            let subscriptions = self.get_subscriptions();
+            let publishes: Vec<(Publish, Properties)> =
+                lock(&self.inflight).iter().cloned().collect();
+            if let Err(e) = store.save(&publishes, &subscriptions) {
+                error!(
+                    "Error al guardar los mensajes pendientes: {:?}",
+                    e.to_string()
+                );
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
@@ -0,0 +1,201 @@
+//! Pedidos con respuesta (request/response) sobre MQTT.
+//!
+//! La primera vez que se hace un pedido el cliente se suscribe a su topic de respuestas,
+//! `respuestas/<client id>`; el servidor acepta esa suscripcion aunque el topic no exista. Por eso
+//! solo se pueden hacer pedidos con un client id. Con MQTT 5 el pedido lleva ese topic como
+//! Response Topic y un numero de pedido como Correlation Data, y el que responde tiene que
+//! publicar en el Response Topic con la misma Correlation Data. MQTT 3.1.1 no tiene properties, asi que se usa una convencion en el
+//! payload: el pedido se envia como `<response topic>|<correlation>|<payload>` y la respuesta
+//! tiene que ser `<correlation>|<payload>`.
+use crate::packets;
+use crate::session::Session;
+use serializer::mqtt_response::MqttError;
+use serializer::{new_properties, new_publish_packet_flags, new_topic_filter, Properties, Publish};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const RESPONSE_TOPIC_PREFIX: &str = "respuestas/";
+
+/// Pedidos que esperan respuesta. Las respuestas las entrega `read_event` (en `connection.rs`) al
+/// recibir un publish en el topic de respuestas.
+pub(crate) struct Requests {
+    response_topic: Mutex<String>,
+    anonymous: AtomicBool,
+    subscribed: AtomicBool,
+    next_correlation: AtomicU64,
+    pending: Mutex<HashMap<Vec<u8>, Sender<String>>>,
+}
+
+impl Requests {
+    pub(crate) fn new() -> Self {
+        Requests {
+            response_topic: Mutex::new(RESPONSE_TOPIC_PREFIX.to_string()),
+            anonymous: AtomicBool::new(true),
+            subscribed: AtomicBool::new(false),
+            next_correlation: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Se llama al enviar el CONNECT. Los pedidos de la conexion anterior ya no se van a responder.
+    pub fn connected(&self, client_id: &str) {
+        self.anonymous.store(client_id.is_empty(), Ordering::SeqCst);
+        *lock(&self.response_topic) = RESPONSE_TOPIC_PREFIX.to_string() + client_id;
+        self.subscribed.store(false, Ordering::SeqCst);
+        lock(&self.pending).clear();
+    }
+
+    /// Publica el pedido en `topic` y espera la respuesta hasta `timeout`. Bloquea al thread que lo
+    /// llama, asi que no hay que llamarlo desde el de la interfaz. Falla si la conexion no tiene
+    /// client id, porque el servidor no le permitiria suscribirse al topic de respuestas.
+    pub fn request(
+        &self,
+        write: &Mutex<TcpStream>,
+        session: &Session,
+        topic: &str,
+        payload: &str,
+        timeout: Duration,
+    ) -> Result<String, Box<dyn Error>> {
+        if self.anonymous.load(Ordering::SeqCst) {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Hace falta un client id para hacer pedidos",
+            )));
+        }
+        let response_topic = lock(&self.response_topic).clone();
+        if !self.subscribed.swap(true, Ordering::SeqCst) {
+            if let Err(e) = subscribe(write, session, &response_topic) {
+                self.subscribed.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        let correlation = self
+            .next_correlation
+            .fetch_add(1, Ordering::SeqCst)
+            .to_string();
+        let (tx, rx) = mpsc::channel();
+        lock(&self.pending).insert(correlation.clone().into_bytes(), tx);
+
+        let (payload, properties) = if session.is_v5() {
+            let properties = new_properties()
+                .set_response_topic(Some(response_topic))
+                .set_correlation_data(Some(correlation.clone().into_bytes()));
+            (payload.to_string(), properties)
+        } else {
+            let payload = format!("{}|{}|{}", response_topic, correlation, payload);
+            (payload, new_properties())
+        };
+        if let Err(e) = publish(write, session, topic, payload, properties) {
+            lock(&self.pending).remove(correlation.as_bytes());
+            return Err(e);
+        }
+        info!("Pedido {:?} enviado a {:?}", correlation, topic);
+
+        match rx.recv_timeout(timeout) {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                lock(&self.pending).remove(correlation.as_bytes());
+                warn!("No llego la respuesta al pedido {:?}", correlation);
+                Err(Box::new(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "No llego la respuesta al pedido",
+                )))
+            }
+        }
+    }
+
+    /// Si el publish llego al topic de respuestas se lo entrega al pedido que lo espera y devuelve
+    /// true. Las respuestas a pedidos que ya vencieron se descartan.
+    pub fn resolve_reply(
+        &self,
+        publish: &Publish,
+        properties: &Properties,
+        session: &Session,
+    ) -> bool {
+        if publish.get_topic().get_topic() != *lock(&self.response_topic) {
+            return false;
+        }
+        let payload = publish.get_payload();
+        let reply = if session.is_v5() {
+            properties
+                .get_correlation_data()
+                .map(|correlation| (correlation, payload))
+        } else {
+            payload.split_once('|').map(|(correlation, payload)| {
+                (correlation.as_bytes().to_vec(), payload.to_string())
+            })
+        };
+        let (correlation, payload) = match reply {
+            Some(reply) => reply,
+            None => {
+                warn!("Llego una respuesta sin correlation data");
+                return true;
+            }
+        };
+        match lock(&self.pending).remove(&correlation) {
+            Some(tx) => {
+                if tx.send(payload).is_err() {
+                    warn!("El pedido ya no espera la respuesta");
+                }
+            }
+            None => warn!("Llego la respuesta a un pedido desconocido o vencido"),
+        }
+        true
+    }
+}
+
+/// El servidor solo permite suscribirse a topics que ya existen, salvo al topic de respuestas del
+/// propio client id.
+fn subscribe(
+    write: &Mutex<TcpStream>,
+    session: &Session,
+    response_topic: &str,
+) -> Result<(), Box<dyn Error>> {
+    packets::send_subscribe(
+        &mut lock(write),
+        vec![response_topic.to_string()],
+        0,
+        session,
+    )?;
+    Ok(())
+}
+
+fn publish(
+    write: &Mutex<TcpStream>,
+    session: &Session,
+    topic: &str,
+    payload: String,
+    properties: Properties,
+) -> Result<(), Box<dyn Error>> {
+    let flags = new_publish_packet_flags(None, None, None, None);
+    let topic = new_topic_filter(topic.to_string());
+    let sent = match (flags, topic) {
+        (Ok(flags), Ok(topic)) => packets::send_publish_with_properties(
+            &mut lock(write),
+            flags,
+            topic,
+            payload,
+            session,
+            properties,
+        ),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    match sent {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Box::new(MqttError { error: e })),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
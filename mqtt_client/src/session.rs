@@ -0,0 +1,68 @@
+use crate::packets::PROTOCOL_VERSION_5;
+use serializer::{
+    new_properties, new_topic_aliases, Mqtt5ReturnCodes, Properties, Publish, Subscribe,
+    TopicAliases,
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Version de MQTT con la que se conecto el cliente y los topic aliases de la conexion, que se
+/// negocian con el CONNACK. Se comparte entre el thread que lee del servidor y los que envian
+/// paquetes.
+pub struct Session {
+    protocol_version: AtomicU8,
+    aliases: Mutex<TopicAliases>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            protocol_version: AtomicU8::new(PROTOCOL_VERSION_5),
+            aliases: Mutex::new(new_topic_aliases(0, 0)),
+        }
+    }
+
+    /// Se llama al enviar el CONNECT. Los aliases de la conexion anterior dejan de valer.
+    pub fn connect(&self, protocol_version: u8) {
+        self.protocol_version
+            .store(protocol_version, Ordering::SeqCst);
+        *self.lock_aliases() = new_topic_aliases(0, 0);
+    }
+
+    pub fn is_v5(&self) -> bool {
+        self.protocol_version.load(Ordering::SeqCst) == PROTOCOL_VERSION_5
+    }
+
+    /// Codifica el publish a enviar segun la version de la conexion.
+    pub fn encode_publish(
+        &self,
+        publish: &Publish,
+        properties: Properties,
+    ) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        if !self.is_v5() {
+            return Ok(publish.get_data());
+        }
+        self.lock_aliases().encode_outbound(publish, properties)
+    }
+
+    /// Codifica el subscribe a enviar segun la version de la conexion.
+    pub fn encode_subscribe(&self, subscribe: &Subscribe) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        if !self.is_v5() {
+            return Ok(subscribe.get_data());
+        }
+        subscribe.get_data_v5(&new_properties())
+    }
+
+    pub(crate) fn lock_aliases(&self) -> MutexGuard<'_, TopicAliases> {
+        match self.aliases.lock() {
+            Ok(aliases) => aliases,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
@@ -0,0 +1,142 @@
+//! Persistencia en archivo de lo que el cliente envio y el servidor todavia no confirmo.
+//!
+//! Se guardan los publish QoS 1 sin PUBACK, con sus properties de MQTT 5, y los topics a los que
+//! esta suscripto el cliente, para que si el cliente se cierra antes de recibir la confirmacion se
+//! reenvien en la proxima conexion. El archivo es un json con la forma
+//! `{"publish":[{"topic":..,"payload":..,"retain":..,"properties":{..}}],
+//! "subscriptions":[{"topic":..,"qos":..}]}`.
+use serde_json::{json, Map, Value};
+use serializer::mqtt_response::MqttError;
+use serializer::{
+    new_properties, new_publish, new_publish_packet_flags, new_topic_filter, Mqtt5ReturnCodes,
+    Properties, Publish,
+};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Publish sin confirmar con sus properties y suscripciones (topic y QoS) guardados.
+pub(crate) type Pending = (Vec<(Publish, Properties)>, Vec<(String, u8)>);
+
+pub(crate) struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    pub(crate) fn new(path: &str) -> Self {
+        Store {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Lee los publish y las suscripciones guardados. Si el archivo no existe no hay nada pendiente.
+    pub(crate) fn load(&self) -> Result<Pending, Box<dyn Error>> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((vec![], vec![])),
+            Err(e) => return Err(e.into()),
+        };
+        let value: Value = serde_json::from_str(&data)?;
+        let mut publishes = vec![];
+        for entry in entries(&value, "publish") {
+            match (entry["topic"].as_str(), entry["payload"].as_str()) {
+                (Some(topic), Some(payload)) => {
+                    let retain = entry["retain"].as_bool().unwrap_or(false);
+                    let publish = stored_publish(topic, payload, retain)
+                        .map_err(|error| MqttError { error })?;
+                    publishes.push((publish, properties_from_json(&entry["properties"])));
+                }
+                _ => warn!("Publish guardado invalido: {:?}", entry),
+            }
+        }
+        let mut subscriptions = vec![];
+        for entry in entries(&value, "subscriptions") {
+            match (entry["topic"].as_str(), entry["qos"].as_u64()) {
+                (Some(topic), Some(qos)) if qos <= 1 => {
+                    subscriptions.push((topic.to_string(), qos as u8))
+                }
+                _ => warn!("Suscripcion guardada invalida: {:?}", entry),
+            }
+        }
+        Ok((publishes, subscriptions))
+    }
+
+    /// Reemplaza el contenido del archivo. Se escribe en un archivo temporal y se renombra para no
+    /// dejarlo a medio escribir si el cliente se cierra en el medio.
+    pub(crate) fn save(
+        &self,
+        publishes: &[(Publish, Properties)],
+        subscriptions: &[(String, u8)],
+    ) -> Result<(), Box<dyn Error>> {
+        let publishes: Vec<Value> = publishes
+            .iter()
+            .map(|(publish, properties)| {
+                json!({
+                    "topic": publish.get_topic().get_topic(),
+                    "payload": publish.get_payload(),
+                    "retain": publish.get_flags().get_retain(),
+                    "properties": properties_to_json(properties),
+                })
+            })
+            .collect();
+        let subscriptions: Vec<Value> = subscriptions
+            .iter()
+            .map(|(topic, qos)| json!({ "topic": topic, "qos": qos }))
+            .collect();
+        let data = json!({ "publish": publishes, "subscriptions": subscriptions });
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data.to_string())?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn stored_publish(topic: &str, payload: &str, retain: bool) -> Result<Publish, Mqtt5ReturnCodes> {
+    let flags = new_publish_packet_flags(Some(retain), Some(true), None, None)?;
+    let topic = new_topic_filter(topic.to_string())?;
+    new_publish(flags, topic, payload.to_string())
+}
+
+/// Properties de un publish, sin el topic alias, que depende de la conexion.
+fn properties_to_json(properties: &Properties) -> Value {
+    let mut stored = Map::new();
+    if let Some(interval) = properties.get_message_expiry_interval() {
+        stored.insert("message_expiry_interval".to_string(), json!(interval));
+    }
+    if let Some(content_type) = properties.get_content_type() {
+        stored.insert("content_type".to_string(), json!(content_type));
+    }
+    if let Some(topic) = properties.get_response_topic() {
+        stored.insert("response_topic".to_string(), json!(topic));
+    }
+    if let Some(correlation) = properties.get_correlation_data() {
+        stored.insert("correlation_data".to_string(), json!(correlation));
+    }
+    Value::Object(stored)
+}
+
+/// Properties guardadas con `properties_to_json`. Los publish guardados sin properties no tienen
+/// ninguna.
+fn properties_from_json(value: &Value) -> Properties {
+    let string = |key: &str| value[key].as_str().map(|s| s.to_string());
+    let correlation = value["correlation_data"].as_array().map(|bytes| {
+        bytes
+            .iter()
+            .filter_map(|b| b.as_u64().map(|b| b as u8))
+            .collect()
+    });
+    new_properties()
+        .set_message_expiry_interval(value["message_expiry_interval"].as_u64().map(|i| i as u32))
+        .set_content_type(string("content_type"))
+        .set_response_topic(string("response_topic"))
+        .set_correlation_data(correlation)
+}
+
+fn entries<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    match value[key].as_array() {
+        Some(entries) => entries,
+        None => &[],
+    }
+}
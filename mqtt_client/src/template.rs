@@ -0,0 +1,282 @@
+//! Templates de publish y publicacion periodica.
+//!
+//! Un template guarda el topic, el QoS, el retain y el payload de un publish que se repite. El
+//! payload puede tener placeholders que se reemplazan en cada publicacion:
+//!
+//! - `{{timestamp}}`: milisegundos desde 1970-01-01 UTC.
+//! - `{{counter}}`: numero de publicacion, empezando en 1.
+//! - `{{random}}`: numero al azar entre 0 y 4294967295, distinto en cada aparicion.
+//!
+//! Los templates se guardan en un json con la forma
+//! `{"templates":[{"name":..,"topic":..,"qos":..,"retain":..,"payload":..}]}`, que comparten la
+//! interfaz grafica y la linea de comandos.
+use crate::reconnect::random;
+use serde_json::{json, Value};
+use serializer::Mqtt5ReturnCodes;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Archivo donde se guardan los templates si no se elige otro.
+pub const TEMPLATES_PATH: &str = "templates.json";
+
+const TIMESTAMP: &str = "{{timestamp}}";
+const COUNTER: &str = "{{counter}}";
+const RANDOM: &str = "{{random}}";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    name: String,
+    topic: String,
+    qos: u8,
+    retain: bool,
+    payload: String,
+}
+
+impl Template {
+    /// Template QoS 0 sin retain.
+    pub fn new(name: &str, topic: &str, payload: &str) -> Self {
+        Template {
+            name: name.to_string(),
+            topic: topic.to_string(),
+            qos: 0,
+            retain: false,
+            payload: payload.to_string(),
+        }
+    }
+
+    pub fn set_qos(mut self, qos: u8) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn set_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn get_qos(&self) -> u8 {
+        self.qos
+    }
+
+    pub fn get_retain(&self) -> bool {
+        self.retain
+    }
+
+    pub fn get_payload(&self) -> &str {
+        &self.payload
+    }
+
+    /// Payload de la publicacion numero `counter`, con los placeholders reemplazados.
+    pub fn render(&self, counter: u32) -> String {
+        self.render_at(counter, SystemTime::now())
+    }
+
+    pub(crate) fn render_at(&self, counter: u32, now: SystemTime) -> String {
+        let timestamp = match now.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis(),
+            Err(_) => 0,
+        };
+        let payload = self
+            .payload
+            .replace(TIMESTAMP, &timestamp.to_string())
+            .replace(COUNTER, &counter.to_string());
+        let mut parts = payload.split(RANDOM);
+        let mut rendered = parts.next().unwrap_or("").to_string();
+        for part in parts {
+            rendered += &(random() as u32).to_string();
+            rendered += part;
+        }
+        rendered
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "topic": self.topic,
+            "qos": self.qos,
+            "retain": self.retain,
+            "payload": self.payload,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let template = Template::new(
+            value["name"].as_str()?,
+            value["topic"].as_str()?,
+            value["payload"].as_str()?,
+        );
+        match value["qos"].as_u64().unwrap_or(0) {
+            qos @ 0..=1 => Some(
+                template
+                    .set_qos(qos as u8)
+                    .set_retain(value["retain"].as_bool().unwrap_or(false)),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Templates guardados en un archivo.
+pub struct Templates {
+    path: PathBuf,
+    templates: Vec<Template>,
+}
+
+impl Templates {
+    /// Lee los templates de `path`. Si el archivo no existe no hay templates.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut templates: Vec<Template> = vec![];
+        match fs::read_to_string(path) {
+            Ok(data) => {
+                let value: Value = serde_json::from_str(&data)?;
+                if let Some(entries) = value["templates"].as_array() {
+                    for entry in entries {
+                        match Template::from_json(entry) {
+                            Some(template)
+                                if !templates.iter().any(|t| t.name == template.name) =>
+                            {
+                                templates.push(template)
+                            }
+                            _ => warn!("Template invalido o repetido: {:?}", entry),
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Templates {
+            path: PathBuf::from(path),
+            templates,
+        })
+    }
+
+    pub fn get_templates(&self) -> &[Template] {
+        &self.templates
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.iter().find(|template| template.name == name)
+    }
+
+    /// Agrega el template, o reemplaza al que tiene el mismo nombre, y guarda el archivo.
+    pub fn save_template(&mut self, template: Template) -> Result<(), Box<dyn Error>> {
+        match self.templates.iter_mut().find(|t| t.name == template.name) {
+            Some(current) => *current = template,
+            None => self.templates.push(template),
+        }
+        self.save()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.templates.retain(|template| template.name != name);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let templates: Vec<Value> = self.templates.iter().map(Template::to_json).collect();
+        let data = serde_json::to_string_pretty(&json!({ "templates": templates }))?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Cada cuanto y cuantas veces publicar un template.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    interval: Duration,
+    times: Option<u32>,
+}
+
+impl Schedule {
+    /// Publica cada `interval` hasta que se detenga.
+    pub fn new(interval: Duration) -> Self {
+        Schedule {
+            interval,
+            times: None,
+        }
+    }
+
+    /// Con `Some(n)` se publica `n` veces y termina.
+    pub fn set_times(mut self, times: Option<u32>) -> Self {
+        self.times = times;
+        self
+    }
+
+    pub fn get_interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn get_times(&self) -> Option<u32> {
+        self.times
+    }
+}
+
+/// Publicacion periodica en curso. Se detiene con `stop` o al descartarla.
+pub struct Scheduled {
+    stop: Sender<()>,
+    handle: JoinHandle<u32>,
+}
+
+impl Scheduled {
+    /// Publica desde otro thread con `publish` segun `schedule`. `on_publish` recibe el numero de
+    /// cada publicacion y el resultado de enviarla; si falla se sigue con la proxima.
+    pub(crate) fn start<P, F>(
+        template: Template,
+        schedule: Schedule,
+        mut publish: P,
+        mut on_publish: F,
+    ) -> Self
+    where
+        P: FnMut(&Template, &str) -> Result<usize, Mqtt5ReturnCodes> + Send + 'static,
+        F: FnMut(u32, Result<usize, Mqtt5ReturnCodes>) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut published = 0;
+            while schedule.times != Some(published) {
+                published += 1;
+                let payload = template.render(published);
+                on_publish(published, publish(&template, &payload));
+                if schedule.times == Some(published) {
+                    break;
+                }
+                match stopped.recv_timeout(schedule.interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+            published
+        });
+        Scheduled { stop, handle }
+    }
+
+    /// Deja de publicar. La publicacion que se este enviando termina igual.
+    pub fn stop(&self) {
+        let _res = self.stop.send(());
+    }
+
+    /// Espera a que termine y devuelve cuantas veces se publico.
+    pub fn join(self) -> u32 {
+        let Scheduled { stop, handle } = self;
+        let published = handle.join().unwrap_or(0);
+        drop(stop);
+        published
+    }
+}
@@ -2,5 +2,7 @@ pub(crate) mod connect_flag;
 pub(crate) mod connect_return_codes;
 pub(crate) mod mqtt_constants;
 pub(crate) mod payload_connect;
+pub(crate) mod properties;
 pub(crate) mod publish_flag;
+pub(crate) mod topic_alias;
 pub(crate) mod topic_filter;
@@ -30,15 +30,16 @@ pub(crate) const PROTOCOL_NAME_M: u8 = 0x04d; //B 5
 pub(crate) const PROTOCOL_NAME_Q: u8 = 0x051; // B 6
 pub(crate) const PROTOCOL_NAME_T: u8 = 0x054; // B 7,8
 pub(crate) const PROTOCOL_VERSION: u8 = 0x04; // B 9
-                                              // B 10 ConnectFlag
-                                              // pub(crate) const KEEP_ALIVE_MSB: u8 = 0x00; // B 11
-                                              // pub(crate) const KEEP_ALIVE_LSB: u8 = 0x0A; // B 12
-                                              // PAYLOAD
-                                              // B 13 - 36 Client Identifier (Follows 1.5.3 UTF-8 ENCODING)
-                                              // B 14 Will Topic Flag dependent (FD) (Follows 1.5.3 UTF-8 ENCODING) http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#RFC3629
-                                              // B 15 Will Message FD  (Follows 1.5.3 NOT UTF-8 ENCODING)
-                                              // B 16 User Name FD  (Follows 1.5.3 UTF-8 ENCODING)
-                                              // B 17 Password FD (Follows 1.5.3 NOT UTF-8 ENCODING)
+pub(crate) const PROTOCOL_VERSION_5: u8 = 0x05; // B 9 (MQTT 5, con properties despues del keep alive)
+                                                // B 10 ConnectFlag
+                                                // pub(crate) const KEEP_ALIVE_MSB: u8 = 0x00; // B 11
+                                                // pub(crate) const KEEP_ALIVE_LSB: u8 = 0x0A; // B 12
+                                                // PAYLOAD
+                                                // B 13 - 36 Client Identifier (Follows 1.5.3 UTF-8 ENCODING)
+                                                // B 14 Will Topic Flag dependent (FD) (Follows 1.5.3 UTF-8 ENCODING) http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#RFC3629
+                                                // B 15 Will Message FD  (Follows 1.5.3 NOT UTF-8 ENCODING)
+                                                // B 16 User Name FD  (Follows 1.5.3 UTF-8 ENCODING)
+                                                // B 17 Password FD (Follows 1.5.3 NOT UTF-8 ENCODING)
 
 //CONNACK
 pub(crate) const PACKET_FLAGS_CONNACK: u8 = 0x00; // B 1/2
@@ -121,6 +122,7 @@ pub enum SubackReturnCode {
     MaxQoS0 = 0x00,
     MaxQoS1 = 0x01,
     MaxQoS2 = 0x02,
+    QuotaExceeded = 0x97, // Solo MQTT 5
 }
 
 // UNSUBSCRIBE  HEADER
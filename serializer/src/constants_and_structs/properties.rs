@@ -0,0 +1,254 @@
+use crate::mqtt_response::Mqtt5ReturnCodes;
+use tracing::error;
+
+// IDENTIFICADORES DE PROPERTIES (MQTT 5)
+
+pub(crate) const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02; // Four Byte Integer, PUBLISH y Will
+pub(crate) const CONTENT_TYPE: u8 = 0x03; // UTF-8 String, PUBLISH y Will
+pub(crate) const RESPONSE_TOPIC: u8 = 0x08; // UTF-8 String, PUBLISH y Will
+pub(crate) const CORRELATION_DATA: u8 = 0x09; // Binary Data, PUBLISH y Will
+pub(crate) const WILL_DELAY_INTERVAL: u8 = 0x18; // Four Byte Integer, Will
+pub(crate) const TOPIC_ALIAS_MAXIMUM: u8 = 0x22; // Two Byte Integer, CONNECT y CONNACK
+pub(crate) const TOPIC_ALIAS: u8 = 0x23; // Two Byte Integer, PUBLISH
+
+/// Properties de MQTT 5 de un paquete. Se decodifican las que usa el broker; el resto se
+/// saltean segun su tipo y no se vuelven a codificar.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties {
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<Vec<u8>>,
+    will_delay_interval: Option<u32>,
+    topic_alias_maximum: Option<u16>,
+    topic_alias: Option<u16>,
+}
+
+impl Properties {
+    pub(crate) fn new() -> Self {
+        Properties::default()
+    }
+
+    /// Decodifica las properties, sin el largo que las precede.
+    pub(crate) fn new_by_hex(data: &[u8]) -> Result<Self, Mqtt5ReturnCodes> {
+        let mut properties = Properties::new();
+        let mut i = 0;
+        while i < data.len() {
+            let identifier = data[i];
+            i += 1;
+            let size = value_size(identifier, &data[i..])?;
+            if i + size > data.len() {
+                error!(
+                    "[Serializer:Properties] Property {:?} incompleta",
+                    identifier
+                );
+                return Err(Mqtt5ReturnCodes::MqttRcMalformedPacket);
+            }
+            let value = &data[i..i + size];
+            match identifier {
+                MESSAGE_EXPIRY_INTERVAL => {
+                    properties.message_expiry_interval =
+                        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                CONTENT_TYPE => properties.content_type = Some(utf8_string(identifier, value)?),
+                RESPONSE_TOPIC => properties.response_topic = Some(utf8_string(identifier, value)?),
+                CORRELATION_DATA => properties.correlation_data = Some(value[2..].to_vec()),
+                WILL_DELAY_INTERVAL => {
+                    properties.will_delay_interval =
+                        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                TOPIC_ALIAS_MAXIMUM => {
+                    properties.topic_alias_maximum = Some(u16::from_be_bytes([value[0], value[1]]))
+                }
+                TOPIC_ALIAS => {
+                    properties.topic_alias = Some(u16::from_be_bytes([value[0], value[1]]))
+                }
+                _ => {}
+            }
+            i += size;
+        }
+        Ok(properties)
+    }
+
+    /// Codifica las properties precedidas por su largo, como van en el paquete.
+    pub fn get_data(&self) -> Vec<u8> {
+        let mut properties = vec![];
+        if let Some(interval) = self.message_expiry_interval {
+            properties.push(MESSAGE_EXPIRY_INTERVAL);
+            properties.extend_from_slice(&interval.to_be_bytes());
+        }
+        if let Some(content_type) = &self.content_type {
+            properties.push(CONTENT_TYPE);
+            push_length_prefixed(&mut properties, content_type.as_bytes());
+        }
+        if let Some(topic) = &self.response_topic {
+            properties.push(RESPONSE_TOPIC);
+            push_length_prefixed(&mut properties, topic.as_bytes());
+        }
+        if let Some(correlation) = &self.correlation_data {
+            properties.push(CORRELATION_DATA);
+            push_length_prefixed(&mut properties, correlation);
+        }
+        if let Some(delay) = self.will_delay_interval {
+            properties.push(WILL_DELAY_INTERVAL);
+            properties.extend_from_slice(&delay.to_be_bytes());
+        }
+        if let Some(maximum) = self.topic_alias_maximum {
+            properties.push(TOPIC_ALIAS_MAXIMUM);
+            properties.extend_from_slice(&maximum.to_be_bytes());
+        }
+        if let Some(alias) = self.topic_alias {
+            properties.push(TOPIC_ALIAS);
+            properties.extend_from_slice(&alias.to_be_bytes());
+        }
+        let mut data = encode_variable_length(properties.len());
+        data.append(&mut properties);
+        data
+    }
+
+    pub fn get_message_expiry_interval(&self) -> Option<u32> {
+        self.message_expiry_interval
+    }
+
+    pub fn set_message_expiry_interval(mut self, interval: Option<u32>) -> Self {
+        self.message_expiry_interval = interval;
+        self
+    }
+
+    /// Tipo de contenido del payload, por ejemplo un MIME type.
+    pub fn get_content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+
+    pub fn set_content_type(mut self, content_type: Option<String>) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Topic en el que el que publico espera la respuesta.
+    pub fn get_response_topic(&self) -> Option<String> {
+        self.response_topic.clone()
+    }
+
+    pub fn set_response_topic(mut self, topic: Option<String>) -> Self {
+        self.response_topic = topic;
+        self
+    }
+
+    /// Dato con el que el que publico relaciona la respuesta con su pedido.
+    pub fn get_correlation_data(&self) -> Option<Vec<u8>> {
+        self.correlation_data.clone()
+    }
+
+    pub fn set_correlation_data(mut self, correlation: Option<Vec<u8>>) -> Self {
+        self.correlation_data = correlation;
+        self
+    }
+
+    /// Segundos que se espera despues de que se corta la conexion antes de publicar el will.
+    pub fn get_will_delay_interval(&self) -> Option<u32> {
+        self.will_delay_interval
+    }
+
+    pub fn set_will_delay_interval(mut self, delay: Option<u32>) -> Self {
+        self.will_delay_interval = delay;
+        self
+    }
+
+    pub fn get_topic_alias_maximum(&self) -> Option<u16> {
+        self.topic_alias_maximum
+    }
+
+    pub fn set_topic_alias_maximum(mut self, maximum: Option<u16>) -> Self {
+        self.topic_alias_maximum = maximum;
+        self
+    }
+
+    pub fn get_topic_alias(&self) -> Option<u16> {
+        self.topic_alias
+    }
+
+    pub fn set_topic_alias(mut self, alias: Option<u16>) -> Self {
+        self.topic_alias = alias;
+        self
+    }
+}
+
+/// Largo del valor de la property segun su tipo. `data` empieza en el valor.
+fn value_size(identifier: u8, data: &[u8]) -> Result<usize, Mqtt5ReturnCodes> {
+    match identifier {
+        // Byte
+        0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => Ok(1),
+        // Two Byte Integer
+        0x13 | 0x21 | 0x22 | 0x23 => Ok(2),
+        // Four Byte Integer
+        0x02 | 0x11 | 0x18 | 0x27 => Ok(4),
+        // Variable Byte Integer
+        0x0B => match decode_variable_length(data) {
+            Some((_, bytes)) => Ok(bytes),
+            None => Err(Mqtt5ReturnCodes::MqttRcMalformedPacket),
+        },
+        // UTF-8 String y Binary Data: largo de dos bytes y el contenido
+        0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1A | 0x1C | 0x1F => {
+            length_prefixed_size(data, 0)
+        }
+        // UTF-8 String Pair
+        0x26 => {
+            let key = length_prefixed_size(data, 0)?;
+            Ok(key + length_prefixed_size(data, key)?)
+        }
+        _ => {
+            error!(
+                "[Serializer:Properties] Property desconocida {:?}",
+                identifier
+            );
+            Err(Mqtt5ReturnCodes::MqttRcMalformedPacket)
+        }
+    }
+}
+
+fn length_prefixed_size(data: &[u8], start: usize) -> Result<usize, Mqtt5ReturnCodes> {
+    if data.len() < start + 2 {
+        return Err(Mqtt5ReturnCodes::MqttRcMalformedPacket);
+    }
+    Ok(2 + ((data[start] as usize) << 8 | data[start + 1] as usize))
+}
+
+fn utf8_string(identifier: u8, value: &[u8]) -> Result<String, Mqtt5ReturnCodes> {
+    String::from_utf8(value[2..].to_vec()).map_err(|_| {
+        error!("[Serializer:Properties] Property {:?} invalida", identifier);
+        Mqtt5ReturnCodes::MqttRcMalformedPacket
+    })
+}
+
+fn push_length_prefixed(data: &mut Vec<u8>, value: &[u8]) {
+    data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    data.extend_from_slice(value);
+}
+
+/// Decodifica un Variable Byte Integer. Devuelve el valor y cuantos bytes ocupa.
+pub(crate) fn decode_variable_length(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().take(4).enumerate() {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+pub(crate) fn encode_variable_length(mut value: usize) -> Vec<u8> {
+    let mut data = vec![];
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        data.push(byte);
+        if value == 0 {
+            return data;
+        }
+    }
+}
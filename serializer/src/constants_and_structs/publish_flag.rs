@@ -133,6 +133,22 @@ impl PublishFlag {
         *self
     }
 
+    pub fn set_retain(&mut self, retain: bool) -> Self {
+        self.retain = retain;
+        if let Ok(p) = Self::to_hex(*self) {
+            return p;
+        }
+        *self
+    }
+
+    pub fn set_dup(&mut self, dup_flag: bool) -> Self {
+        self.dup_flag = dup_flag;
+        if let Ok(p) = Self::to_hex(*self) {
+            return p;
+        }
+        *self
+    }
+
     pub fn hex_value(&self) -> u8 {
         self.byte
     }
@@ -141,6 +157,10 @@ impl PublishFlag {
         self.retain
     }
 
+    pub fn get_dup(&self) -> bool {
+        self.dup_flag
+    }
+
     pub fn get_qos(&self) -> u8 {
         return if !self.qosb1 && self.qosb2 {
             2
@@ -0,0 +1,103 @@
+use crate::constants_and_structs::properties::Properties;
+use crate::mqtt_response::Mqtt5ReturnCodes;
+use crate::packets::publish::Publish;
+use std::collections::HashMap;
+use tracing::error;
+
+/// Topic aliases de una conexion MQTT 5. Cada extremo anuncia en su CONNECT/CONNACK cuantos
+/// aliases acepta (Topic Alias Maximum), y el otro extremo puede usar hasta esa cantidad para
+/// mandarle publish con el topic vacio en lugar del nombre completo.
+#[derive(Clone, Debug, Default)]
+pub struct TopicAliases {
+    /// Aliases que se aceptan en los publish recibidos, y el topic que tiene asignado cada uno.
+    inbound_maximum: u16,
+    inbound: HashMap<u16, String>,
+    /// Aliases que se pueden usar en los publish enviados, y el que se le asigno a cada topic.
+    outbound_maximum: u16,
+    outbound: HashMap<String, u16>,
+}
+
+impl TopicAliases {
+    pub(crate) fn new(inbound_maximum: u16, outbound_maximum: u16) -> Self {
+        TopicAliases {
+            inbound_maximum,
+            inbound: HashMap::new(),
+            outbound_maximum,
+            outbound: HashMap::new(),
+        }
+    }
+
+    pub fn get_inbound_maximum(&self) -> u16 {
+        self.inbound_maximum
+    }
+
+    /// Cantidad de aliases que anuncio el otro extremo. Se vacia la tabla de aliases enviados.
+    pub fn set_outbound_maximum(&mut self, maximum: u16) {
+        self.outbound_maximum = maximum;
+        self.outbound.clear();
+    }
+
+    /// Resuelve el topic de un publish recibido. Si trae topic y alias se guarda la asignacion,
+    /// y si solo trae alias se completa el topic con el que tenia asignado.
+    pub fn resolve_inbound(
+        &mut self,
+        publish: Publish,
+        alias: Option<u16>,
+    ) -> Result<Publish, Mqtt5ReturnCodes> {
+        let topic = publish.get_topic().get_topic();
+        let alias = match alias {
+            Some(alias) => alias,
+            None if topic.is_empty() => {
+                error!("[Serializer:TopicAlias] Publish sin topic ni alias");
+                return Err(Mqtt5ReturnCodes::MqttRcProtocolError);
+            }
+            None => return Ok(publish),
+        };
+        if alias == 0 || alias > self.inbound_maximum {
+            error!("[Serializer:TopicAlias] Alias invalido {:?}", alias);
+            return Err(Mqtt5ReturnCodes::MqttRcTopicAliasInvalid);
+        }
+        if !topic.is_empty() {
+            self.inbound.insert(alias, topic);
+            return Ok(publish);
+        }
+        match self.inbound.get(&alias) {
+            Some(topic) => with_topic(&publish, topic),
+            None => {
+                error!(
+                    "[Serializer:TopicAlias] Alias sin topic asignado {:?}",
+                    alias
+                );
+                Err(Mqtt5ReturnCodes::MqttRcTopicAliasInvalid)
+            }
+        }
+    }
+
+    /// Codifica un publish MQTT 5 a enviar. La primera vez que se envia un topic se le asigna un
+    /// alias, si quedan libres, y las siguientes se envia solo el alias.
+    pub fn encode_outbound(
+        &mut self,
+        publish: &Publish,
+        properties: Properties,
+    ) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        let topic = publish.get_topic().get_topic();
+        if topic.is_empty() {
+            return publish.get_data_v5(&properties);
+        }
+        if let Some(alias) = self.outbound.get(&topic) {
+            let aliased = with_topic(publish, "")?;
+            return aliased.get_data_v5(&properties.set_topic_alias(Some(*alias)));
+        }
+        if self.outbound.len() < self.outbound_maximum as usize {
+            let alias = self.outbound.len() as u16 + 1;
+            self.outbound.insert(topic, alias);
+            return publish.get_data_v5(&properties.set_topic_alias(Some(alias)));
+        }
+        publish.get_data_v5(&properties)
+    }
+}
+
+fn with_topic(publish: &Publish, topic: &str) -> Result<Publish, Mqtt5ReturnCodes> {
+    let topic_filter = crate::new_topic_filter(topic.to_string())?;
+    Publish::new(publish.get_flags(), topic_filter, publish.get_payload())
+}
@@ -3,6 +3,32 @@ use crate::mqtt_response::{Mqtt5ReturnCodes, MqttError};
 use std::error::Error;
 use tracing::error;
 
+const QOS_MASK: u8 = 0b0000_0011;
+const NO_LOCAL: u8 = 0b0000_0100;
+const RETAIN_AS_PUBLISHED: u8 = 0b0000_1000;
+const RETAIN_HANDLING_SHIFT: u8 = 4;
+const RESERVED_MASK: u8 = 0b1100_0000;
+
+/// Cuando se envian los mensajes retenidos al suscribirse (MQTT 5).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RetainHandling {
+    #[default]
+    Always = 0,
+    OnlyNew = 1,
+    Never = 2,
+}
+
+impl RetainHandling {
+    pub fn from_u8(value: u8) -> Option<RetainHandling> {
+        match value {
+            0 => Some(RetainHandling::Always),
+            1 => Some(RetainHandling::OnlyNew),
+            2 => Some(RetainHandling::Never),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TopicFilter {
     topic: String,
@@ -10,6 +36,25 @@ pub struct TopicFilter {
     length_lsb: u8,
     filter: Vec<u8>,
     qos: u8,
+    no_local: bool,
+    retain_as_published: bool,
+    retain_handling: RetainHandling,
+}
+
+/// Separa el byte de opciones de suscripcion en QoS, No Local, Retain As Published y Retain
+/// Handling. Con MQTT 3.1.1 los bits de opciones son 0, asi que queda solo el QoS.
+fn split_options(options: u8) -> Result<(u8, bool, bool, RetainHandling), Mqtt5ReturnCodes> {
+    let qos = options & QOS_MASK;
+    let retain_handling = RetainHandling::from_u8((options >> RETAIN_HANDLING_SHIFT) & 0b11);
+    match retain_handling {
+        Some(retain_handling) if qos <= 2 && options & RESERVED_MASK == 0 => Ok((
+            qos,
+            options & NO_LOCAL != 0,
+            options & RETAIN_AS_PUBLISHED != 0,
+            retain_handling,
+        )),
+        _ => Err(Mqtt5ReturnCodes::MqttRcProtocolError),
+    }
 }
 impl TopicFilter {
     pub(crate) fn new(
@@ -33,16 +78,25 @@ impl TopicFilter {
             return Err(Mqtt5ReturnCodes::MqttRcTopicNameInvalid);
         }
 
-        if qos.is_some() && qos.unwrap() > 2 {
-            error!("[Serializer:TopicFilter] Invalid topic qos");
-            return Err(Mqtt5ReturnCodes::MqttRcProtocolError);
-        }
+        let (qos, no_local, retain_as_published, retain_handling) = match qos {
+            Some(options) => match split_options(options) {
+                Ok(options) => options,
+                Err(e) => {
+                    error!("[Serializer:TopicFilter] Invalid subscription options");
+                    return Err(e);
+                }
+            },
+            None => (3, false, false, RetainHandling::Always),
+        };
 
         Ok(TopicFilter {
             length_msb,
             length_lsb,
             filter,
-            qos: qos.unwrap_or(3),
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
             topic,
         })
     }
@@ -71,18 +125,25 @@ impl TopicFilter {
                 error: Mqtt5ReturnCodes::MqttRcTopicFilterInvalid,
             }));
         }
-        if qos.is_some() && qos.unwrap() > 2 {
-            error!("[Serializer:TopicFilter] Invalid topic qos");
-            return Err(Box::new(MqttError {
-                error: Mqtt5ReturnCodes::MqttRcProtocolError,
-            }));
-        }
+        let (qos, no_local, retain_as_published, retain_handling) = match qos {
+            Some(options) => match split_options(options) {
+                Ok(options) => options,
+                Err(e) => {
+                    error!("[Serializer:TopicFilter] Invalid subscription options");
+                    return Err(Box::new(MqttError { error: e }));
+                }
+            },
+            None => (3, false, false, RetainHandling::Always),
+        };
 
         Ok(TopicFilter {
             length_msb,
             length_lsb,
             filter,
-            qos: qos.unwrap_or(3),
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
             topic: str.unwrap(),
         })
     }
@@ -99,4 +160,37 @@ impl TopicFilter {
     pub fn get_topic(&self) -> String {
         self.topic.clone()
     }
+    pub fn get_no_local(&self) -> bool {
+        self.no_local
+    }
+    pub fn get_retain_as_published(&self) -> bool {
+        self.retain_as_published
+    }
+    pub fn get_retain_handling(&self) -> RetainHandling {
+        self.retain_handling
+    }
+    /// Byte de opciones de suscripcion tal como se envia en el SUBSCRIBE.
+    pub fn get_subscription_options(&self) -> u8 {
+        self.qos
+            | if self.no_local { NO_LOCAL } else { 0 }
+            | if self.retain_as_published {
+                RETAIN_AS_PUBLISHED
+            } else {
+                0
+            }
+            | (self.retain_handling as u8) << RETAIN_HANDLING_SHIFT
+    }
+
+    pub fn set_no_local(mut self, no_local: bool) -> Self {
+        self.no_local = no_local;
+        self
+    }
+    pub fn set_retain_as_published(mut self, retain_as_published: bool) -> Self {
+        self.retain_as_published = retain_as_published;
+        self
+    }
+    pub fn set_retain_handling(mut self, retain_handling: RetainHandling) -> Self {
+        self.retain_handling = retain_handling;
+        self
+    }
 }
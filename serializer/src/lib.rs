@@ -15,7 +15,10 @@ pub use crate::constants_and_structs::mqtt_constants::ConnectAcknowledgeFlags;
 pub use crate::constants_and_structs::mqtt_constants::ConnectReturnCode;
 pub use crate::constants_and_structs::mqtt_constants::PacketType;
 pub use crate::constants_and_structs::mqtt_constants::SubackReturnCode;
+pub use crate::constants_and_structs::properties::Properties;
 pub use crate::constants_and_structs::publish_flag::PublishFlag;
+pub use crate::constants_and_structs::topic_alias::TopicAliases;
+pub use crate::constants_and_structs::topic_filter::RetainHandling;
 pub use crate::constants_and_structs::topic_filter::TopicFilter;
 pub use crate::mqtt_factory::MqttHeader;
 pub use crate::mqtt_response::Mqtt5ReturnCodes;
@@ -87,6 +90,16 @@ pub fn new_connect_return_code(reject_reason: ConnectReturnCode) -> ConnectRetur
     ConnectReturnCodes::new(reject_reason)
 }
 
+/// Connect de MQTT 5 con las properties dadas.
+pub fn new_connect_v5(
+    connect_flag: ConnectFlag,
+    payload_connect: PayloadConnect,
+    properties: Properties,
+    will_properties: Properties,
+) -> Result<Connect, Box<dyn Error>> {
+    Connect::new_v5(connect_flag, payload_connect, properties, will_properties)
+}
+
 pub fn new_connack(
     connect_acknowledge_flags: ConnectAcknowledgeFlags,
     connect_return_code: ConnectReturnCodes,
@@ -98,6 +111,11 @@ pub fn new_connack_by_hex(data: MqttHeader) -> Result<Connack, Mqtt5ReturnCodes>
     mqtt_factory::new_connack(data)
 }
 
+/// Decodifica el connack que recibe un cliente MQTT 5, devolviendo tambien sus properties.
+pub fn new_connack_v5_by_hex(data: MqttHeader) -> Result<(Connack, Properties), Mqtt5ReturnCodes> {
+    mqtt_factory::new_connack_v5(data)
+}
+
 pub fn new_publish_packet_flags(
     retain: Option<bool>,
     qosb1: Option<bool>,
@@ -146,6 +164,20 @@ pub fn new_publish_by_hex(data: MqttHeader) -> Result<Publish, Box<dyn Error>> {
     mqtt_factory::new_publish(data)
 }
 
+/// Decodifica un publish de un cliente MQTT 5, devolviendo tambien sus properties.
+pub fn new_publish_v5_by_hex(data: MqttHeader) -> Result<(Publish, Properties), Box<dyn Error>> {
+    mqtt_factory::new_publish_v5(data)
+}
+
+pub fn new_properties() -> Properties {
+    Properties::new()
+}
+
+/// Tabla de topic aliases de una conexion, con la cantidad de aliases que acepta cada extremo.
+pub fn new_topic_aliases(inbound_maximum: u16, outbound_maximum: u16) -> TopicAliases {
+    TopicAliases::new(inbound_maximum, outbound_maximum)
+}
+
 pub fn new_subscribe(mut topic_filters: Vec<TopicFilter>) -> Result<Subscribe, Box<dyn Error>> {
     Subscribe::new(&mut topic_filters)
 }
@@ -153,6 +185,11 @@ pub fn new_subscribe(mut topic_filters: Vec<TopicFilter>) -> Result<Subscribe, B
 pub fn new_subscribe_by_hex(data: MqttHeader) -> Result<Subscribe, Box<dyn Error>> {
     mqtt_factory::new_subscribe(data)
 }
+pub fn new_subscribe_v5_by_hex(
+    data: MqttHeader,
+) -> Result<(Subscribe, Properties), Box<dyn Error>> {
+    mqtt_factory::new_subscribe_v5(data)
+}
 
 pub fn new_suback(suback_ret_codes: Vec<SubackReturnCode>) -> Suback {
     Suback::new(suback_ret_codes)
@@ -160,6 +197,9 @@ pub fn new_suback(suback_ret_codes: Vec<SubackReturnCode>) -> Suback {
 pub fn new_suback_by_hex(data: MqttHeader) -> Result<Suback, Mqtt5ReturnCodes> {
     mqtt_factory::new_suback(data)
 }
+pub fn new_suback_v5_by_hex(data: MqttHeader) -> Result<(Suback, Properties), Mqtt5ReturnCodes> {
+    mqtt_factory::new_suback_v5(data)
+}
 
 pub fn new_unsubscribe(mut topic_filters: Vec<TopicFilter>) -> Result<Unsubscribe, Box<dyn Error>> {
     Unsubscribe::new(&mut topic_filters)
@@ -195,6 +235,10 @@ pub fn new_disconnect() -> Disconnect {
     Disconnect::new()
 }
 
+pub fn new_disconnect_with_reason(reason_code: Mqtt5ReturnCodes) -> Disconnect {
+    Disconnect::new_with_reason(reason_code)
+}
+
 pub fn new_disconnect_by_hex() -> Disconnect {
     mqtt_factory::new_disconnect()
 }
@@ -474,6 +518,32 @@ mod tests {
         assert_eq!(publish.get_data(), valid_publish.get_data())
     }
 
+    #[test]
+    fn publish_set_qos_flag_reencodes_data() {
+        let flag = PublishFlag::new(None, Option::from(true), None, None)
+            .ok()
+            .unwrap();
+        let filter = TopicFilter::new(
+            0,
+            4,
+            vec![0, 4, b'a', b'/', b'b', b'y'],
+            None,
+            "a/by".to_string(),
+        )
+        .ok()
+        .unwrap();
+        let mut publish = Publish::new(flag, filter.clone(), "payload".to_string())
+            .ok()
+            .unwrap();
+        let downgraded = publish.set_qos_flag(0);
+        let flag_qos0 = PublishFlag::new(None, None, None, None).ok().unwrap();
+        let valid_publish = Publish::new(flag_qos0, filter, "payload".to_string())
+            .ok()
+            .unwrap();
+        assert_eq!(downgraded.get_flags().get_qos(), 0);
+        assert_eq!(downgraded.get_data(), valid_publish.get_data())
+    }
+
     #[test]
     fn create_new_puback() {
         let valid_puback = Puback::new();
@@ -587,6 +657,19 @@ mod tests {
         assert_eq!(suback.get_data(), valid_suback.get_data())
     }
 
+    #[test]
+    fn suback_v5_round_trip() {
+        let suback = Suback::new(vec![
+            SubackReturnCode::MaxQoS1,
+            SubackReturnCode::QuotaExceeded,
+        ]);
+        let data = suback.get_data_v5(&crate::new_properties()).unwrap();
+        assert_eq!(data, vec![0x90, 5, 0, 0, 0, 0x01, 0x97]);
+        let (decoded, _properties) =
+            mqtt_factory::new_suback_v5(MqttHeader::new(data).unwrap()).unwrap();
+        assert_eq!(decoded.get_data(), suback.get_data());
+    }
+
     #[test]
     fn create_new_unsuback() {
         let valid_unsuback = Unsuback::new();
@@ -614,4 +697,233 @@ mod tests {
         let disconnect = mqtt_factory::new_disconnect();
         assert_eq!(disconnect.get_data(), valid_disconnect.get_data())
     }
+
+    #[test]
+    fn create_new_connect_v5_skips_properties() {
+        let connect_flag = ConnectFlag::new(Option::from(true), None, None, None, None, None, None);
+        let connect_flag_hex = connect_flag.ok().unwrap().hex_value();
+        let payload = PayloadConnect::new(
+            "42".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            60,
+        )
+        .ok()
+        .unwrap()
+        .get_data()
+        .clone();
+        // Session Expiry Interval (0x11) de 4 bytes
+        let properties = vec![5, 0x11, 0, 0, 0, 10];
+        let mut data = vec![
+            ((PacketType::CONNECT as u8) << 4) | PACKET_FLAGS_CONNECT,
+            (8 + payload.len() + properties.len()) as u8,
+            LENGTH_LSB_CONNECT,
+            LENGTH_MSB_CONNECT,
+            PROTOCOL_NAME_M,
+            PROTOCOL_NAME_Q,
+            PROTOCOL_NAME_T,
+            PROTOCOL_NAME_T,
+            0x05,
+            connect_flag_hex,
+        ];
+        data.extend_from_slice(&payload[0..2]);
+        data.append(&mut properties.clone());
+        data.extend_from_slice(&payload[2..]);
+        let connect = mqtt_factory::new_connect(MqttHeader::new(data).ok().unwrap())
+            .ok()
+            .unwrap();
+        assert_eq!(connect.get_protocol_version(), 5);
+        assert_eq!(connect.get_payload().get_client_identifier(), "42");
+
+        let disconnect = crate::new_disconnect_with_reason(
+            crate::mqtt_response::Mqtt5ReturnCodes::MqttRcMessageRateTooHigh,
+        );
+        assert_eq!(disconnect.get_data(), vec![0xE0, 1, 0x96]);
+    }
+
+    #[test]
+    fn publish_v5_round_trip_with_message_expiry() {
+        let flags = crate::new_publish_packet_flags(None, Some(true), None, None).unwrap();
+        let topic = crate::new_topic_filter("clima".to_string()).unwrap();
+        let publish = crate::new_publish(flags, topic, "soleado".to_string()).unwrap();
+        let properties = crate::new_properties().set_message_expiry_interval(Some(30));
+        let data = publish.get_data_v5(&properties).unwrap();
+        assert_eq!(data.len(), publish.get_data().len() + 6);
+
+        let (decoded, decoded_properties) =
+            crate::new_publish_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+        assert_eq!(decoded.get_data(), publish.get_data());
+        assert_eq!(decoded_properties.get_message_expiry_interval(), Some(30));
+    }
+
+    #[test]
+    fn publish_v5_response_topic_and_correlation_data() {
+        let flags = crate::new_publish_packet_flags(None, None, None, None).unwrap();
+        let topic = crate::new_topic_filter("servicios/hora".to_string()).unwrap();
+        let publish = crate::new_publish(flags, topic, "?".to_string()).unwrap();
+        let properties = crate::new_properties()
+            .set_response_topic(Some("respuestas/cliente".to_string()))
+            .set_correlation_data(Some(vec![0, 7, 255]));
+        let data = publish.get_data_v5(&properties).unwrap();
+
+        let (_, decoded) =
+            crate::new_publish_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+        assert_eq!(decoded, properties);
+    }
+
+    #[test]
+    fn topic_aliases_replace_repeated_topics() {
+        let flags = crate::new_publish_packet_flags(None, None, None, None).unwrap();
+        let topic = crate::new_topic_filter("flota/camion/42/posicion".to_string()).unwrap();
+        let publish = crate::new_publish(flags, topic, "-31.4,-64.2".to_string()).unwrap();
+        let mut sender = crate::new_topic_aliases(0, 1);
+        let mut receiver = crate::new_topic_aliases(1, 0);
+
+        let first = sender
+            .encode_outbound(&publish, crate::new_properties())
+            .unwrap();
+        let second = sender
+            .encode_outbound(&publish, crate::new_properties())
+            .unwrap();
+        assert!(second.len() < first.len());
+
+        for data in [first, second] {
+            let (decoded, properties) =
+                crate::new_publish_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+            assert_eq!(properties.get_topic_alias(), Some(1));
+            let resolved = receiver
+                .resolve_inbound(decoded, properties.get_topic_alias())
+                .ok()
+                .unwrap();
+            assert_eq!(resolved.get_topic().get_topic(), "flota/camion/42/posicion");
+        }
+
+        let unknown = crate::new_publish(
+            flags,
+            crate::new_topic_filter("".to_string()).unwrap(),
+            "x".to_string(),
+        )
+        .unwrap();
+        assert!(matches!(
+            crate::new_topic_aliases(1, 0).resolve_inbound(unknown, Some(1)),
+            Err(crate::Mqtt5ReturnCodes::MqttRcTopicAliasInvalid)
+        ));
+    }
+
+    #[test]
+    fn connect_v5_will_properties() {
+        let flags =
+            crate::new_connect_flag(Some(true), Some(true), None, Some(true), None, None, None)
+                .unwrap();
+        let payload = crate::new_payload_connect(
+            "sensor".to_string(),
+            "estado/sensor".to_string(),
+            "offline".to_string(),
+            "".to_string(),
+            "".to_string(),
+            0,
+        )
+        .unwrap();
+        let will_properties = crate::new_properties()
+            .set_will_delay_interval(Some(30))
+            .set_message_expiry_interval(Some(600))
+            .set_content_type(Some("text/plain".to_string()));
+        let connect = crate::new_connect_v5(
+            flags,
+            payload,
+            crate::new_properties(),
+            will_properties.clone(),
+        )
+        .unwrap();
+        let decoded =
+            crate::new_connect_by_hex(MqttHeader::new(connect.get_data()).ok().unwrap()).unwrap();
+        assert_eq!(decoded.get_will_properties(), will_properties);
+        assert_eq!(decoded.get_payload().get_will_topic(), "estado/sensor");
+        assert_eq!(decoded.get_payload().get_will_message(), "offline");
+    }
+
+    #[test]
+    fn connect_and_connack_v5_properties() {
+        let flags =
+            crate::new_connect_flag(Some(true), None, None, None, None, None, None).unwrap();
+        let payload = crate::new_payload_connect(
+            "42".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            60,
+        )
+        .unwrap();
+        let properties = crate::new_properties().set_topic_alias_maximum(Some(8));
+        let connect =
+            crate::new_connect_v5(flags, payload, properties, crate::new_properties()).unwrap();
+        let decoded =
+            crate::new_connect_by_hex(MqttHeader::new(connect.get_data()).ok().unwrap()).unwrap();
+        assert_eq!(decoded.get_protocol_version(), 5);
+        assert_eq!(decoded.get_properties().get_topic_alias_maximum(), Some(8));
+        assert_eq!(decoded.get_payload().get_client_identifier(), "42");
+
+        let connack = crate::new_connack(
+            ConnectAcknowledgeFlags::Sp0,
+            crate::new_connect_return_code(ConnectReturnCode::ConnectionAccepted),
+        );
+        let data = connack
+            .get_data_v5(&crate::new_properties().set_topic_alias_maximum(Some(4)))
+            .unwrap();
+        let (decoded, properties) =
+            crate::new_connack_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+        assert!(decoded.get_connect_return_codes().is_accepted());
+        assert_eq!(properties.get_topic_alias_maximum(), Some(4));
+    }
+
+    #[test]
+    fn subscribe_v5_round_trip_with_properties() {
+        let filters = vec![
+            crate::new_topic_filter_with_qos("sensores/temp".to_string(), 1).unwrap(),
+            crate::new_topic_filter_with_qos("alertas".to_string(), 0).unwrap(),
+        ];
+        let subscribe = crate::new_subscribe(filters).unwrap();
+        let data = subscribe.get_data_v5(&crate::new_properties()).unwrap();
+        assert_eq!(data.len(), subscribe.get_data().len() + 1);
+        let (decoded, properties) =
+            crate::new_subscribe_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+        assert_eq!(decoded.get_data(), subscribe.get_data());
+        assert_eq!(properties, crate::new_properties());
+
+        // Subscription Identifier 5: se acepta aunque no se guarde.
+        let mut data = subscribe.get_data();
+        data[1] += 3;
+        data.splice(4..4, [2, 0x0B, 5]);
+        let (decoded, _) =
+            crate::new_subscribe_v5_by_hex(MqttHeader::new(data).ok().unwrap()).unwrap();
+        assert_eq!(decoded.get_topics().len(), 2);
+        assert_eq!(decoded.get_topics()[0].get_topic(), "sensores/temp");
+    }
+
+    #[test]
+    fn subscribe_options_round_trip() {
+        let filter = crate::new_topic_filter_with_qos("sensores/temp".to_string(), 1)
+            .unwrap()
+            .set_no_local(true)
+            .set_retain_as_published(true)
+            .set_retain_handling(crate::RetainHandling::OnlyNew);
+        assert_eq!(filter.get_subscription_options(), 0b0001_1101);
+        let subscribe = crate::new_subscribe(vec![filter]).unwrap();
+
+        let header = MqttHeader::new(subscribe.get_data()).ok().unwrap();
+        let decoded = mqtt_factory::new_subscribe(header).ok().unwrap();
+        let topic = &decoded.get_topics()[0];
+        assert_eq!(topic.get_qos(), 1);
+        assert!(topic.get_no_local());
+        assert!(topic.get_retain_as_published());
+        assert_eq!(topic.get_retain_handling(), crate::RetainHandling::OnlyNew);
+
+        let mut data = subscribe.get_data();
+        *data.last_mut().unwrap() = 0b0011_0001;
+        let header = MqttHeader::new(data).ok().unwrap();
+        assert!(mqtt_factory::new_subscribe(header).is_err());
+    }
 }
@@ -1,9 +1,10 @@
 use crate::constants_and_structs::connect_flag::ConnectFlag;
 use crate::constants_and_structs::connect_return_codes::ConnectReturnCodes;
 use crate::constants_and_structs::mqtt_constants::{
-    ConnectAcknowledgeFlags, PacketType, SubackReturnCode,
+    ConnectAcknowledgeFlags, PacketType, SubackReturnCode, PROTOCOL_VERSION_5,
 };
 use crate::constants_and_structs::payload_connect::PayloadConnect;
+use crate::constants_and_structs::properties::{decode_variable_length, Properties};
 use crate::constants_and_structs::publish_flag::PublishFlag;
 use crate::constants_and_structs::topic_filter::TopicFilter;
 use crate::mqtt_response::{Mqtt5ReturnCodes, MqttError};
@@ -100,9 +101,55 @@ pub(crate) fn new_connect(header: MqttHeader) -> Result<Connect, Box<dyn Error>>
             error: Mqtt5ReturnCodes::MqttRcProtocolError,
         }));
     }
+    // En MQTT 5 despues del keep alive vienen las properties.
+    let protocol_version = header.data[8];
+    let mut properties_size = 0;
+    let mut properties = Properties::new();
+    if protocol_version == PROTOCOL_VERSION_5 && header.data.len() > 12 {
+        properties_size = header.data[12] as usize + 1;
+        if 12 + properties_size > header.data.len() {
+            error!("[Serializer:MqttFactory] Invalid Connect properties");
+            return Err(Box::new(MqttError {
+                error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
+            }));
+        }
+        properties = match Properties::new_by_hex(&header.data[13..12 + properties_size]) {
+            Ok(p) => p,
+            Err(error) => return Err(Box::new(MqttError { error })),
+        };
+    }
     let mut connect_payload: Vec<u8> = vec![];
     for i in 10..(header.data[1] + 2) as usize {
-        connect_payload.push(header.data[i]);
+        if i < 12 || i >= 12 + properties_size {
+            connect_payload.push(header.data[i]);
+        }
+    }
+    // Las properties del will van despues del client identifier.
+    let mut will_properties_size = 0;
+    let mut will_properties = Properties::new();
+    let will_flag = matches!(&connect_flag, Ok(flag) if flag.get_will_flag());
+    if protocol_version == PROTOCOL_VERSION_5 && will_flag && connect_payload.len() > 4 {
+        let start = 4 + connect_payload[3] as usize;
+        let (length, bytes) = match connect_payload
+            .get(start..)
+            .and_then(decode_variable_length)
+        {
+            Some(decoded) if start + decoded.0 + decoded.1 <= connect_payload.len() => decoded,
+            _ => {
+                error!("[Serializer:MqttFactory] Invalid Connect will properties");
+                return Err(Box::new(MqttError {
+                    error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
+                }));
+            }
+        };
+        will_properties_size = length + bytes;
+        will_properties = match Properties::new_by_hex(
+            &connect_payload[start + bytes..start + will_properties_size],
+        ) {
+            Ok(p) => p,
+            Err(error) => return Err(Box::new(MqttError { error })),
+        };
+        connect_payload.drain(start..start + will_properties_size);
     }
     let payload = PayloadConnect::new_by_hex(connect_payload, connect_flag.clone().ok().unwrap());
     if payload.is_err() {
@@ -112,13 +159,23 @@ pub(crate) fn new_connect(header: MqttHeader) -> Result<Connect, Box<dyn Error>>
         }));
     }
     let payload_con = payload.ok().unwrap();
-    if header.data[1] as i32 - payload_con.get_data().len() as i32 - 8 != 0 {
+    if header.data[1] as i32
+        - payload_con.get_data().len() as i32
+        - 8
+        - properties_size as i32
+        - will_properties_size as i32
+        != 0
+    {
         error!("[Serializer:Mqtt Factory] Invalid payload size");
         return Err(Box::new(MqttError {
             error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
         }));
     }
-    Connect::new(connect_flag.ok().unwrap(), payload_con)
+    let connect = Connect::new(connect_flag.ok().unwrap(), payload_con)?;
+    Ok(connect
+        .set_protocol_version(protocol_version)
+        .set_properties(properties)
+        .set_will_properties(will_properties))
 }
 
 pub(crate) fn new_connack(header: MqttHeader) -> Result<Connack, Mqtt5ReturnCodes> {
@@ -138,6 +195,29 @@ pub(crate) fn new_connack(header: MqttHeader) -> Result<Connack, Mqtt5ReturnCode
     Ok(ret)
 }
 
+/// Decodifica un connack de MQTT 5 junto con sus properties.
+pub(crate) fn new_connack_v5(
+    header: MqttHeader,
+) -> Result<(Connack, Properties), Mqtt5ReturnCodes> {
+    if header.data.len() < 5 || header.data[1] as usize + 2 != header.data.len() {
+        error!("[Serializer:Mqtt Factory] Invalid connack size");
+        return Err(Mqtt5ReturnCodes::MqttRcProtocolError);
+    }
+    let properties = match decode_variable_length(&header.data[4..]) {
+        Some((length, bytes)) if 4 + bytes + length == header.data.len() => {
+            Properties::new_by_hex(&header.data[4 + bytes..])?
+        }
+        _ => {
+            error!("[Serializer:Mqtt Factory] Invalid connack properties");
+            return Err(Mqtt5ReturnCodes::MqttRcMalformedPacket);
+        }
+    };
+    let mut data = header.data[..4].to_vec();
+    data[1] = 2;
+    let connack = new_connack(MqttHeader::new(data)?)?;
+    Ok((connack, properties))
+}
+
 pub(crate) fn new_publish(header: MqttHeader) -> Result<Publish, Box<dyn Error>> {
     if header.data.len() > 2 {
         if (header.data[1] + 2) as usize != header.data.len() {
@@ -200,33 +280,135 @@ pub(crate) fn new_publish(header: MqttHeader) -> Result<Publish, Box<dyn Error>>
     Ok(ret.ok().unwrap())
 }
 
+/// Decodifica un publish de MQTT 5: separa las properties que siguen al topic (y al packet
+/// identifier) y arma el publish con el resto del paquete, igual que uno de MQTT 3.1.1.
+pub(crate) fn new_publish_v5(header: MqttHeader) -> Result<(Publish, Properties), Box<dyn Error>> {
+    let invalid_size = || -> Box<dyn Error> {
+        error!("[Serializer:Mqtt Factory] Invalid publish size");
+        MqttError {
+            error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
+        }
+        .into()
+    };
+    if header.data.len() < 4 || (header.data[1] as usize + 2) != header.data.len() {
+        return Err(invalid_size());
+    }
+    let mut position = header.data[3] as usize + 4;
+    if (header.data[0] >> 1) & 3 == 1 {
+        position += 2;
+    }
+    if position > header.data.len() {
+        return Err(invalid_size());
+    }
+    let (length, bytes) = match decode_variable_length(&header.data[position..]) {
+        Some(length) => length,
+        None => return Err(invalid_size()),
+    };
+    let end = position + bytes + length;
+    if end > header.data.len() {
+        return Err(invalid_size());
+    }
+    let properties = match Properties::new_by_hex(&header.data[position + bytes..end]) {
+        Ok(properties) => properties,
+        Err(error) => return Err(MqttError { error }.into()),
+    };
+    let mut data = header.data[..position].to_vec();
+    data.extend_from_slice(&header.data[end..]);
+    data[1] = (data.len() - 2) as u8;
+    let header = match MqttHeader::new(data) {
+        Ok(header) => header,
+        Err(error) => return Err(MqttError { error }.into()),
+    };
+    Ok((new_publish(header)?, properties))
+}
+
 pub(crate) fn new_subscribe(header: MqttHeader) -> Result<Subscribe, Box<dyn Error>> {
     let remaining_size = header.data[1] as usize;
-    let mut filters = topic_filters_with_qos(&header, remaining_size)?;
+    let mut filters = topic_filters_with_qos(&header, 4, remaining_size)?;
     let ret = Subscribe::new(&mut filters)?;
     Ok(ret)
 }
 
+pub(crate) fn new_subscribe_v5(
+    header: MqttHeader,
+) -> Result<(Subscribe, Properties), Box<dyn Error>> {
+    if header.data.len() < 5 || header.data[1] as usize + 2 != header.data.len() {
+        error!("[Serializer:Mqtt Factory] Invalid subscribe size");
+        return Err(Box::new(MqttError {
+            error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
+        }));
+    }
+    let (properties, filters) = match decode_variable_length(&header.data[4..]) {
+        Some((length, bytes)) if 4 + bytes + length <= header.data.len() => {
+            let filters = 4 + bytes + length;
+            (
+                Properties::new_by_hex(&header.data[4 + bytes..filters])
+                    .map_err(|error| MqttError { error })?,
+                filters,
+            )
+        }
+        _ => {
+            error!("[Serializer:Mqtt Factory] Invalid subscribe properties");
+            return Err(Box::new(MqttError {
+                error: Mqtt5ReturnCodes::MqttRcMalformedPacket,
+            }));
+        }
+    };
+    let remaining_size = header.data[1] as usize;
+    let mut filters = topic_filters_with_qos(&header, filters, remaining_size)?;
+    Ok((Subscribe::new(&mut filters)?, properties))
+}
+
 pub(crate) fn new_suback(header: MqttHeader) -> Result<Suback, Mqtt5ReturnCodes> {
     let mut suback_return_codes: Vec<SubackReturnCode> = Vec::new();
     let mut i = 4;
     while i < header.data.len() {
-        match header.data[i] {
-            0x80 => suback_return_codes.push(SubackReturnCode::Failure),
-            0x0 => suback_return_codes.push(SubackReturnCode::MaxQoS0),
-            0x1 => suback_return_codes.push(SubackReturnCode::MaxQoS1),
-            0x2 => suback_return_codes.push(SubackReturnCode::MaxQoS2),
-            _ => {
-                error!("[Serializer:Mqtt Factory] Invalid suback QOS");
-                return Err(Mqtt5ReturnCodes::MqttRcProtocolError);
-            }
-        }
+        suback_return_codes.push(suback_return_code(header.data[i])?);
         i += 3;
     }
     let ret = Suback::new(suback_return_codes);
     Ok(ret)
 }
 
+pub(crate) fn new_suback_v5(header: MqttHeader) -> Result<(Suback, Properties), Mqtt5ReturnCodes> {
+    if header.data.len() < 5 || header.data[1] as usize + 2 != header.data.len() {
+        error!("[Serializer:Mqtt Factory] Invalid suback size");
+        return Err(Mqtt5ReturnCodes::MqttRcProtocolError);
+    }
+    let (properties, codes) = match decode_variable_length(&header.data[4..]) {
+        Some((length, bytes)) if 4 + bytes + length <= header.data.len() => {
+            let codes = 4 + bytes + length;
+            (
+                Properties::new_by_hex(&header.data[4 + bytes..codes])?,
+                codes,
+            )
+        }
+        _ => {
+            error!("[Serializer:Mqtt Factory] Invalid suback properties");
+            return Err(Mqtt5ReturnCodes::MqttRcMalformedPacket);
+        }
+    };
+    let mut suback_return_codes: Vec<SubackReturnCode> = Vec::new();
+    for code in &header.data[codes..] {
+        suback_return_codes.push(suback_return_code(*code)?);
+    }
+    Ok((Suback::new(suback_return_codes), properties))
+}
+
+fn suback_return_code(code: u8) -> Result<SubackReturnCode, Mqtt5ReturnCodes> {
+    match code {
+        0x80 => Ok(SubackReturnCode::Failure),
+        0x0 => Ok(SubackReturnCode::MaxQoS0),
+        0x1 => Ok(SubackReturnCode::MaxQoS1),
+        0x2 => Ok(SubackReturnCode::MaxQoS2),
+        0x97 => Ok(SubackReturnCode::QuotaExceeded),
+        _ => {
+            error!("[Serializer:Mqtt Factory] Invalid suback QOS");
+            Err(Mqtt5ReturnCodes::MqttRcProtocolError)
+        }
+    }
+}
+
 pub(crate) fn new_unsubscribe(header: MqttHeader) -> Result<Unsubscribe, Box<dyn Error>> {
     let remaining_size = header.data[1] as usize;
     let mut filters = topic_filters(&header, remaining_size)?;
@@ -249,11 +431,13 @@ pub(crate) fn new_disconnect() -> Disconnect {
     Disconnect::new()
 }
 
+/// Topic filters con sus opciones desde la posicion `start` hasta el final del paquete.
 fn topic_filters_with_qos(
     header: &MqttHeader,
+    start: usize,
     remaining_size: usize,
 ) -> Result<Vec<TopicFilter>, Box<dyn Error>> {
-    let mut j = 4;
+    let mut j = start;
     let mut filters: Vec<TopicFilter> = Vec::new();
     let mut size = 0;
     while j < remaining_size {
@@ -272,7 +456,7 @@ fn topic_filters_with_qos(
         filters.push(filter);
         j += llsb + 3;
     }
-    if size + 2 != remaining_size {
+    if size + start - 2 != remaining_size {
         error!("[Serializer:Mqtt Factory] Invalid topic filter size");
         return Err(Box::new(MqttError {
             error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
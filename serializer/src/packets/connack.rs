@@ -1,6 +1,8 @@
 use crate::constants_and_structs::mqtt_constants::{
     ConnectAcknowledgeFlags, PacketType, PACKET_FLAGS_CONNACK, REMAINING_LENGTH_CONNACK,
 };
+use crate::constants_and_structs::properties::Properties;
+use crate::mqtt_response::Mqtt5ReturnCodes;
 use crate::ConnectReturnCodes;
 
 pub struct Connack {
@@ -35,6 +37,18 @@ impl Connack {
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
+    /// Codifica el connack para un cliente MQTT 5, con las properties despues del return code.
+    pub fn get_data_v5(&self, properties: &Properties) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        let mut properties = properties.get_data();
+        let remaining_length = REMAINING_LENGTH_CONNACK as usize + properties.len();
+        if remaining_length > u8::MAX as usize {
+            return Err(Mqtt5ReturnCodes::MqttPacketInvalidSize);
+        }
+        let mut data = self.data.clone();
+        data[1] = remaining_length as u8;
+        data.append(&mut properties);
+        Ok(data)
+    }
     pub fn get_packet_type(&self) -> PacketType {
         self.packet_type
     }
@@ -1,9 +1,11 @@
 use crate::constants_and_structs::connect_flag::ConnectFlag;
+use crate::constants_and_structs::mqtt_constants::PROTOCOL_VERSION_5;
 use crate::constants_and_structs::mqtt_constants::{
     PacketType, LENGTH_LSB_CONNECT, LENGTH_MSB_CONNECT, PACKET_FLAGS_CONNECT, PROTOCOL_NAME_M,
     PROTOCOL_NAME_Q, PROTOCOL_NAME_T, PROTOCOL_VERSION,
 };
 use crate::constants_and_structs::payload_connect::PayloadConnect;
+use crate::constants_and_structs::properties::Properties;
 use crate::mqtt_response::Mqtt5ReturnCodes;
 use crate::mqtt_response::MqttError;
 use std::error::Error;
@@ -22,6 +24,8 @@ pub struct Connect {
     protocol_version: u8,
     connect_flag: ConnectFlag,
     payload: PayloadConnect,
+    properties: Properties,
+    will_properties: Properties,
     data: Vec<u8>,
 }
 
@@ -42,6 +46,8 @@ impl Connect {
             protocol_version: PROTOCOL_VERSION,
             connect_flag,
             payload: payload_connect.clone(),
+            properties: Properties::new(),
+            will_properties: Properties::new(),
             data: vec![],
         };
         let payload = &mut payload_connect.get_data().clone();
@@ -93,9 +99,82 @@ impl Connect {
         Ok(connect)
     }
 
+    /// Connect de MQTT 5: las properties van despues del keep alive, que es el comienzo del payload,
+    /// y si hay will sus properties van despues del client identifier.
+    pub(crate) fn new_v5(
+        connect_flag: ConnectFlag,
+        payload_connect: PayloadConnect,
+        properties: Properties,
+        will_properties: Properties,
+    ) -> Result<Self, Box<dyn Error>> {
+        let client_identifier_len = payload_connect.get_client_identifier().len();
+        let mut connect = Connect::new(connect_flag, payload_connect)?;
+        let mut encoded = properties.get_data();
+        let mut encoded_will = match connect_flag.get_will_flag() {
+            true => will_properties.get_data(),
+            false => vec![],
+        };
+        let remaining_length =
+            connect.remaining_length as usize + encoded.len() + encoded_will.len();
+        if remaining_length > u8::MAX as usize {
+            error!("[Serializer:Connect] Invalid Connect size");
+            return Err(Box::new(MqttError {
+                error: Mqtt5ReturnCodes::MqttPacketInvalidSize,
+            }));
+        }
+        // El client identifier vacio no se codifica.
+        let will_start = match client_identifier_len {
+            0 => 12,
+            len => 14 + len,
+        };
+        let mut data = connect.data[..12].to_vec();
+        data[1] = remaining_length as u8;
+        data[8] = PROTOCOL_VERSION_5;
+        data.append(&mut encoded);
+        data.extend_from_slice(&connect.data[12..will_start]);
+        data.append(&mut encoded_will);
+        data.extend_from_slice(&connect.data[will_start..]);
+        connect.remaining_length = remaining_length as u8;
+        connect.data = data;
+        Ok(connect
+            .set_protocol_version(PROTOCOL_VERSION_5)
+            .set_properties(properties)
+            .set_will_properties(will_properties))
+    }
+
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
+    /// Version del protocolo que indico el cliente: 4 para MQTT 3.1.1 y 5 para MQTT 5.
+    pub fn get_protocol_version(&self) -> u8 {
+        self.protocol_version
+    }
+
+    pub(crate) fn set_protocol_version(mut self, protocol_version: u8) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Properties del connect; vacias si el cliente es MQTT 3.1.1.
+    pub fn get_properties(&self) -> Properties {
+        self.properties.clone()
+    }
+
+    pub(crate) fn set_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Properties del will (delay, vencimiento, content type); vacias si el cliente es MQTT 3.1.1.
+    pub fn get_will_properties(&self) -> Properties {
+        self.will_properties.clone()
+    }
+
+    pub(crate) fn set_will_properties(mut self, will_properties: Properties) -> Self {
+        self.will_properties = will_properties;
+        self
+    }
+
     pub fn get_connect_flags(&self) -> ConnectFlag {
         self.connect_flag
     }
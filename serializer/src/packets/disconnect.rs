@@ -1,6 +1,7 @@
 use crate::constants_and_structs::mqtt_constants::{
     PacketType, DISCONNECT_PACKET_FLAGS, DISCONNECT_REMAINING_LENGTH,
 };
+use crate::mqtt_response::Mqtt5ReturnCodes;
 
 pub struct Disconnect {
     //packet_type: PacketType,
@@ -22,6 +23,17 @@ impl Disconnect {
         }
     }
 
+    /// DISCONNECT de MQTT 5 con reason code, sin properties.
+    pub(crate) fn new_with_reason(reason_code: Mqtt5ReturnCodes) -> Self {
+        Disconnect {
+            data: vec![
+                (PacketType::DISCONNECT as u8) << 4 | DISCONNECT_PACKET_FLAGS,
+                1,
+                reason_code as u8,
+            ],
+        }
+    }
+
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
@@ -1,4 +1,5 @@
 use crate::constants_and_structs::mqtt_constants::PacketType;
+use crate::constants_and_structs::properties::Properties;
 
 use crate::constants_and_structs::publish_flag::PublishFlag;
 use crate::constants_and_structs::topic_filter::TopicFilter;
@@ -64,8 +65,46 @@ impl Publish {
     pub fn get_payload(&self) -> String {
         self.payload.clone()
     }
+    /// Cambia el QoS del publish y vuelve a codificar el paquete para que `get_data`
+    /// refleje los nuevos flags (y el packet identifier que solo existe con QoS 1).
     pub fn set_qos_flag(&mut self, qos: u8) -> Self {
-        self.publish_packet_flags = self.publish_packet_flags.set_qos(qos);
+        let flags = self.publish_packet_flags.set_qos(qos);
+        if let Ok(publish) = Publish::new(flags, self.topic_filter.clone(), self.payload.clone()) {
+            *self = publish;
+        }
+        self.clone()
+    }
+    pub fn set_retain_flag(&mut self, retain: bool) -> Self {
+        let flags = self.publish_packet_flags.set_retain(retain);
+        if let Ok(publish) = Publish::new(flags, self.topic_filter.clone(), self.payload.clone()) {
+            *self = publish;
+        }
+        self.clone()
+    }
+    /// Marca el publish como reenvio de uno que no se confirmo.
+    pub fn set_dup_flag(&mut self, dup_flag: bool) -> Self {
+        let flags = self.publish_packet_flags.set_dup(dup_flag);
+        if let Ok(publish) = Publish::new(flags, self.topic_filter.clone(), self.payload.clone()) {
+            *self = publish;
+        }
         self.clone()
     }
+    /// Codifica el publish para un cliente MQTT 5, con las properties despues del topic
+    /// (y del packet identifier). Falla si el paquete no entra en el remaining length.
+    pub fn get_data_v5(&self, properties: &Properties) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        let mut properties = properties.get_data();
+        let remaining_length = self.remaining_length as usize + properties.len();
+        if remaining_length > u8::MAX as usize {
+            return Err(Mqtt5ReturnCodes::MqttPacketInvalidSize);
+        }
+        let mut position = 2 + self.topic_filter.get_filter().len();
+        if self.publish_packet_flags.get_qos() == 1 {
+            position += 2;
+        }
+        let mut data = self.data[..position].to_vec();
+        data[1] = remaining_length as u8;
+        data.append(&mut properties);
+        data.extend_from_slice(&self.data[position..]);
+        Ok(data)
+    }
 }
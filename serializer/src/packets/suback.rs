@@ -1,6 +1,8 @@
 use crate::constants_and_structs::mqtt_constants::{
     PacketType, SubackReturnCode, SUBACK_PACKET_FLAGS,
 };
+use crate::constants_and_structs::properties::Properties;
+use crate::mqtt_response::Mqtt5ReturnCodes;
 
 pub struct Suback {
     //packet_type: PacketType,
@@ -32,6 +34,21 @@ impl Suback {
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
+    /// Datos del suback con el formato de MQTT 5: el packet identifier, las properties y un
+    /// return code por cada topic filter del subscribe.
+    pub fn get_data_v5(&self, properties: &Properties) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        let mut properties = properties.get_data();
+        let remaining_length = 2 + properties.len() + self.suback_return_codes.len();
+        if remaining_length > u8::MAX as usize {
+            return Err(Mqtt5ReturnCodes::MqttPacketInvalidSize);
+        }
+        let mut data = vec![self.data[0], remaining_length as u8, 0, 0];
+        data.append(&mut properties);
+        for code in &self.suback_return_codes {
+            data.push(*code as u8);
+        }
+        Ok(data)
+    }
     pub fn get_return_codes(&self) -> Vec<SubackReturnCode> {
         self.suback_return_codes.clone()
     }
@@ -1,4 +1,5 @@
 use crate::constants_and_structs::mqtt_constants::{PacketType, SUBSCRIBE_PACKET_FLAGS};
+use crate::constants_and_structs::properties::Properties;
 use crate::constants_and_structs::topic_filter::TopicFilter;
 use crate::mqtt_response::Mqtt5ReturnCodes;
 use crate::mqtt_response::MqttError;
@@ -56,7 +57,7 @@ impl Subscribe {
             subscribe
                 .data
                 .append(&mut topic_filter.get_filter().clone());
-            subscribe.data.push(topic_filter.get_qos());
+            subscribe.data.push(topic_filter.get_subscription_options());
         }
         Ok(subscribe)
     }
@@ -64,6 +65,19 @@ impl Subscribe {
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
+    /// Datos del subscribe con el formato de MQTT 5: despues del packet identifier van las
+    /// properties y despues los topic filters con sus opciones.
+    pub fn get_data_v5(&self, properties: &Properties) -> Result<Vec<u8>, Mqtt5ReturnCodes> {
+        let properties = properties.get_data();
+        let remaining_length = self.remaining_length as usize + properties.len();
+        if remaining_length > u8::MAX as usize {
+            return Err(Mqtt5ReturnCodes::MqttPacketInvalidSize);
+        }
+        let mut data = vec![self.data[0], remaining_length as u8, 0, 0];
+        data.extend_from_slice(&properties);
+        data.extend_from_slice(&self.data[4..]);
+        Ok(data)
+    }
     pub fn get_topics(&self) -> Vec<TopicFilter> {
         self.topic_filters.clone()
     }
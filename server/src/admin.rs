@@ -0,0 +1,263 @@
+//! API HTTP/JSON de administracion del broker. Trabaja sobre los mismos archivos y sockets que usa el server.
+//!
+//! - `GET /clients`, `DELETE /clients/<client_id>`, `GET /clients/<client_id>/subscriptions`
+//! - `GET /retained`, `DELETE /retained?topic=<topic>`
+//! - `GET /queues`, `GET /queues/<client_id>`, `DELETE /queues/<client_id>`
+//! - `GET /users`, `POST /users` con `{"username": ..., "password": ...}`, `DELETE /users/<username>`
+use crate::http::{HttpRequest, HttpResponse};
+use crate::json_helper::{
+    read_q_messages, read_retain_messages, read_shared_subs, read_topic_subs, read_user_db,
+    read_users, update_q_messages, update_retain_messages, update_retain_properties,
+    update_user_db,
+};
+use crate::server::{find_socket, online_clients};
+use crate::socket::Socket;
+use serde_json::{json, Value};
+use serializer::{new_mqtt_header, new_publish_by_hex};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+/// Atiende una request de la API. Si se configuro `admin_token` se exige `Authorization: Bearer <token>`.
+pub fn handle(
+    request: HttpRequest,
+    connections: &Arc<Mutex<Vec<Socket>>>,
+    token: Option<&str>,
+) -> HttpResponse {
+    if let Some(token) = token {
+        let expected = "Bearer ".to_string() + token;
+        if request.headers.get("authorization") != Some(&expected) {
+            warn!("[Server:Admin] Request no autorizada a {:?}", request.path);
+            return error_response(401, "no autorizado");
+        }
+    }
+    info!("[Server:Admin] {} {}", request.method, request.path);
+    let segments = request.segments();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"]) => list_clients(connections),
+        ("DELETE", ["clients", client]) => kick_client(connections, client),
+        ("GET", ["clients", client, "subscriptions"]) => client_subscriptions(client),
+        ("GET", ["retained"]) => list_retained(),
+        ("DELETE", ["retained"]) => match request.query.get("topic") {
+            Some(topic) => delete_retained(topic),
+            None => error_response(400, "falta el parametro topic"),
+        },
+        ("GET", ["queues"]) => list_queues(),
+        ("GET", ["queues", client]) => client_queue(client),
+        ("DELETE", ["queues", client]) => purge_queue(client),
+        ("GET", ["users"]) => list_users(),
+        ("POST", ["users"]) => save_user(&request.body),
+        ("DELETE", ["users", username]) => delete_user(username),
+        (_, ["clients"])
+        | (_, ["clients", ..])
+        | (_, ["retained"])
+        | (_, ["queues", ..])
+        | (_, ["users", ..]) => error_response(405, "metodo no permitido"),
+        _ => error_response(404, "no existe el recurso"),
+    }
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    HttpResponse::json(status, &json!({ "error": message }))
+}
+
+fn internal_error(message: &str) -> HttpResponse {
+    error!("[Server:Admin] {}", message);
+    error_response(500, message)
+}
+
+fn list_clients(connections: &Arc<Mutex<Vec<Socket>>>) -> HttpResponse {
+    let users = match read_users() {
+        Ok(u) => u,
+        Err(_) => return internal_error("error al leer users"),
+    };
+    let mut clients: Vec<String> = online_clients(&users, connections)
+        .into_iter()
+        .filter(|client| !client.is_empty())
+        .collect();
+    clients.sort();
+    clients.dedup();
+    HttpResponse::json(200, &json!(clients))
+}
+
+fn kick_client(connections: &Arc<Mutex<Vec<Socket>>>, client: &str) -> HttpResponse {
+    let users = match read_users() {
+        Ok(u) => u,
+        Err(_) => return internal_error("error al leer users"),
+    };
+    match find_socket(connections, &users, client) {
+        Some(socket) => match socket.disconnect() {
+            Ok(_) => {
+                info!("[Server:Admin] Cliente {:?} desconectado", client);
+                HttpResponse::json(200, &json!({ "disconnected": client }))
+            }
+            Err(_) => internal_error("error al cerrar la conexion"),
+        },
+        None => error_response(404, "el cliente no esta conectado"),
+    }
+}
+
+fn client_subscriptions(client: &str) -> HttpResponse {
+    let (subs, shared) = match (read_topic_subs(), read_shared_subs()) {
+        (Ok(subs), Ok(shared)) => (subs, shared),
+        _ => return internal_error("error al leer las suscripciones"),
+    };
+    let mut subscriptions: Vec<Value> = vec![];
+    for (topic, users) in subs.iter() {
+        for user in users.iter().filter(|u| u.get_user() == client) {
+            subscriptions.push(json!({ "topic": topic, "qos": user.get_qos() }));
+        }
+    }
+    for (topic, groups) in shared.iter() {
+        for (group, members) in groups.iter() {
+            for member in members.iter().filter(|u| u.get_user() == client) {
+                subscriptions.push(json!({
+                    "topic": topic,
+                    "group": group,
+                    "qos": member.get_qos()
+                }));
+            }
+        }
+    }
+    subscriptions.sort_by_key(|s| s["topic"].as_str().unwrap_or("").to_string());
+    HttpResponse::json(200, &json!(subscriptions))
+}
+
+fn list_retained() -> HttpResponse {
+    match read_retain_messages() {
+        Ok(retained) => {
+            let retained: serde_json::Map<String, Value> = retained
+                .into_iter()
+                .filter(|(_, messages)| !messages.is_empty())
+                .map(|(topic, messages)| (topic, json!(messages)))
+                .collect();
+            HttpResponse::json(200, &Value::Object(retained))
+        }
+        Err(_) => internal_error("error al leer retain messages"),
+    }
+}
+
+fn delete_retained(topic: &str) -> HttpResponse {
+    let mut removed = None;
+    let result = update_retain_messages(|retained| {
+        removed = retained.remove(topic);
+    })
+    .and_then(|_| {
+        update_retain_properties(|retain_properties| {
+            retain_properties.remove(topic);
+        })
+    });
+    match (result, removed) {
+        (Err(_), _) => internal_error("error al escribir retain messages"),
+        (Ok(_), Some(messages)) => {
+            HttpResponse::json(200, &json!({ "topic": topic, "deleted": messages.len() }))
+        }
+        (Ok(_), None) => error_response(404, "el topic no tiene retain messages"),
+    }
+}
+
+fn list_queues() -> HttpResponse {
+    match read_q_messages() {
+        Ok(queues) => {
+            let depths: serde_json::Map<String, Value> = queues
+                .iter()
+                .map(|(client, messages)| (client.clone(), json!(messages.len())))
+                .collect();
+            HttpResponse::json(200, &Value::Object(depths))
+        }
+        Err(_) => internal_error("error al leer la cola de mensajes"),
+    }
+}
+
+fn client_queue(client: &str) -> HttpResponse {
+    let queues = match read_q_messages() {
+        Ok(q) => q,
+        Err(_) => return internal_error("error al leer la cola de mensajes"),
+    };
+    let messages = match queues.get(client) {
+        Some(m) => m,
+        None => return error_response(404, "el cliente no tiene cola"),
+    };
+    let decoded: Vec<Value> = messages
+        .iter()
+        .map(|message| {
+            let publish = new_mqtt_header(message.get_data())
+                .ok()
+                .and_then(|header| new_publish_by_hex(header).ok());
+            match publish {
+                Some(p) => json!({
+                    "topic": p.get_topic().get_topic(),
+                    "payload": p.get_payload(),
+                    "qos": message.get_qos()
+                }),
+                None => json!({ "data": message.get_data(), "qos": message.get_qos() }),
+            }
+        })
+        .collect();
+    HttpResponse::json(200, &json!(decoded))
+}
+
+fn purge_queue(client: &str) -> HttpResponse {
+    let mut purged = None;
+    let result = update_q_messages(|queues| {
+        if let Some(messages) = queues.get_mut(client) {
+            purged = Some(messages.len());
+            messages.clear();
+        }
+    });
+    match (result, purged) {
+        (Err(_), _) => internal_error("error al escribir la cola de mensajes"),
+        (Ok(_), Some(count)) => {
+            HttpResponse::json(200, &json!({ "client_id": client, "purged": count }))
+        }
+        (Ok(_), None) => error_response(404, "el cliente no tiene cola"),
+    }
+}
+
+/// Lista los usuarios registrados, sin sus passwords.
+fn list_users() -> HttpResponse {
+    match read_user_db() {
+        Ok(users) => {
+            let mut usernames: Vec<String> = users.into_keys().collect();
+            usernames.sort();
+            HttpResponse::json(200, &json!(usernames))
+        }
+        Err(_) => internal_error("error al leer user_db"),
+    }
+}
+
+/// Crea el usuario, o le cambia el password si ya existia.
+fn save_user(body: &str) -> HttpResponse {
+    let user: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(_) => return error_response(400, "body invalido"),
+    };
+    let (username, password) = match (user["username"].as_str(), user["password"].as_str()) {
+        (Some(u), Some(p)) if !u.is_empty() => (u.to_string(), p.to_string()),
+        _ => return error_response(400, "se esperaba {\"username\", \"password\"}"),
+    };
+    let mut existed = false;
+    let result = update_user_db(|users| {
+        existed = users.insert(username.clone(), password).is_some();
+    });
+    match result {
+        Ok(_) => {
+            info!("[Server:Admin] Usuario {:?} guardado", username);
+            let status = if existed { 200 } else { 201 };
+            HttpResponse::json(status, &json!({ "username": username }))
+        }
+        Err(_) => internal_error("error al escribir user_db"),
+    }
+}
+
+fn delete_user(username: &str) -> HttpResponse {
+    let mut removed = false;
+    let result = update_user_db(|users| {
+        removed = users.remove(username).is_some();
+    });
+    match (result, removed) {
+        (Err(_), _) => internal_error("error al escribir user_db"),
+        (Ok(_), true) => HttpResponse::json(200, &json!({ "deleted": username })),
+        (Ok(_), false) => error_response(404, "no existe el usuario"),
+    }
+}
@@ -0,0 +1,101 @@
+//! Control de admision de conexiones nuevas, antes de crear el socket del cliente.
+//!
+//! Se configura en config.txt:
+//! - `max_connections`: maximo de clientes conectados a la vez.
+//! - `max_connections_per_ip`: maximo de clientes conectados desde una misma IP.
+//! - `max_connect_rate`: maximo de conexiones nuevas por segundo desde una misma IP.
+//! - `allow_ips` / `deny_ips`: IPs separadas por `;`, se puede usar `*` (ej: `10.0.0.*`).
+//!   Si hay `allow_ips` solo se aceptan esas IPs; `deny_ips` se verifica primero.
+use crate::packets::subscribe::WildCard;
+use crate::server::config_value;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Que hacer con una conexion nueva.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Accept,
+    /// La IP no esta permitida: se cierra la conexion sin responder.
+    Deny,
+    /// El server esta al limite: se responde CONNACK `ServerUnavailable` y se cierra la conexion.
+    Unavailable,
+}
+
+#[derive(Debug, Default)]
+pub struct AdmissionControl {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_connect_rate: Option<usize>,
+    allow: Vec<WildCard>,
+    deny: Vec<WildCard>,
+    /// Conexiones recientes de cada IP, para el limite de rate.
+    recent: HashMap<IpAddr, Vec<Instant>>,
+}
+
+impl AdmissionControl {
+    pub fn from_config(config: &[Vec<String>]) -> AdmissionControl {
+        let number = |key: &str| {
+            config_value(config, key).and_then(|v| match v.parse::<usize>() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    error!("[Server:Admission] {} invalido: {:?}", key, v);
+                    None
+                }
+            })
+        };
+        let patterns = |key: &str| {
+            config_value(config, key)
+                .map(|v| {
+                    v.split(';')
+                        .map(|ip| ip.trim())
+                        .filter(|ip| !ip.is_empty())
+                        .map(WildCard::new)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        AdmissionControl {
+            max_connections: number("max_connections"),
+            max_per_ip: number("max_connections_per_ip"),
+            max_connect_rate: number("max_connect_rate"),
+            allow: patterns("allow_ips"),
+            deny: patterns("deny_ips"),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Decide si se acepta una conexion de `ip`, dados los clientes conectados en total y desde esa IP.
+    pub fn admit(
+        &mut self,
+        ip: IpAddr,
+        connected: usize,
+        connected_from_ip: usize,
+        now: Instant,
+    ) -> Admission {
+        let address = ip.to_string();
+        if self.deny.iter().any(|pattern| pattern.matches(&address))
+            || (!self.allow.is_empty() && !self.allow.iter().any(|p| p.matches(&address)))
+        {
+            return Admission::Deny;
+        }
+
+        self.recent.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < RATE_WINDOW);
+            !times.is_empty()
+        });
+        let recent = self.recent.entry(ip).or_default();
+        recent.push(now);
+        let rate_exceeded = matches!(self.max_connect_rate, Some(max) if recent.len() > max);
+
+        let full = matches!(self.max_connections, Some(max) if connected >= max);
+        let full_for_ip = matches!(self.max_per_ip, Some(max) if connected_from_ip >= max);
+        if full || full_for_ip || rate_exceeded {
+            return Admission::Unavailable;
+        }
+        Admission::Accept
+    }
+}
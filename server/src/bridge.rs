@@ -0,0 +1,466 @@
+//! Bridge entre brokers: abre una conexion MQTT saliente a otro broker y reenvia los topics configurados.
+//!
+//! Los bridges se configuran en `bridges.json`, por ejemplo:
+//!
+//! ```json
+//! [{"name": "central", "address": "10.0.0.1:1883", "client_id": "sitio1",
+//!   "username": "", "password": "",
+//!   "topics": [{"pattern": "sensores/*", "direction": "out", "qos": 1,
+//!               "local_prefix": "", "remote_prefix": "sitio1/"}]}]
+//! ```
+//!
+//! Un topic local `local_prefix + X` se publica en el broker remoto como `remote_prefix + X`, y al reves
+//! para los mensajes que llegan, siempre que `X` cumpla con `pattern` (que puede tener `*`).
+//!
+//! Con `"direction": "both"` los mensajes que se reenvian al broker remoto vuelven por la suscripcion
+//! del bridge; esos ecos se reconocen por topic y payload y no se vuelven a publicar localmente.
+use crate::packets::publish::{register_publish, IncomingPublish};
+use crate::packets::subscribe::WildCard;
+use crate::socket::Socket;
+use serde_json::Value;
+use serializer::{
+    new_connack_by_hex, new_connect, new_connect_flag, new_payload_connect, new_properties,
+    new_puback, new_publish, new_publish_by_hex, new_suback_by_hex, new_subscribe,
+    new_topic_filter, new_topic_filter_with_qos, PacketType, Publish, SubackReturnCode,
+};
+use std::cmp;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const BRIDGES_PATH: &str = "./bridges.json";
+/// Espera maxima, en segundos, entre intentos de reconexion al broker remoto.
+const MAX_BACKOFF: u64 = 60;
+/// Cantidad maxima de mensajes reenviados que se recuerdan para reconocer sus ecos.
+const MAX_ECHOES: usize = 1024;
+/// Tiempo durante el que se espera el eco de un mensaje reenviado.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+    Both,
+}
+
+/// Topics que se reenvian por el bridge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeTopic {
+    pattern: String,
+    direction: Direction,
+    qos: u8,
+    local_prefix: String,
+    remote_prefix: String,
+}
+
+impl BridgeTopic {
+    /// Topic con el que se publica en el broker remoto un mensaje local, si corresponde reenviarlo.
+    pub fn to_remote(&self, local_topic: &str) -> Option<String> {
+        if self.direction == Direction::In {
+            return None;
+        }
+        let rest = local_topic.strip_prefix(&self.local_prefix)?;
+        if matches_pattern(&self.pattern, rest) {
+            Some(self.remote_prefix.clone() + rest)
+        } else {
+            None
+        }
+    }
+
+    /// Topic local con el que se publica un mensaje que llego del broker remoto, si corresponde.
+    pub fn to_local(&self, remote_topic: &str) -> Option<String> {
+        if self.direction == Direction::Out {
+            return None;
+        }
+        let rest = remote_topic.strip_prefix(&self.remote_prefix)?;
+        if matches_pattern(&self.pattern, rest) {
+            Some(self.local_prefix.clone() + rest)
+        } else {
+            None
+        }
+    }
+
+    /// Filtro al que se suscribe el bridge en el broker remoto.
+    fn remote_filter(&self) -> String {
+        self.remote_prefix.clone() + self.pattern.as_str()
+    }
+}
+
+fn matches_pattern(pattern: &str, topic: &str) -> bool {
+    if pattern.contains('*') {
+        WildCard::new(pattern).matches(topic)
+    } else {
+        pattern == topic
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeConfig {
+    name: String,
+    address: String,
+    client_id: String,
+    username: String,
+    password: String,
+    topics: Vec<BridgeTopic>,
+}
+
+impl BridgeConfig {
+    pub fn get_topics(&self) -> Vec<BridgeTopic> {
+        self.topics.clone()
+    }
+}
+
+/// Lee los bridges de `bridges.json`. Si no existe el archivo no se configura ningun bridge.
+pub fn read_bridges() -> Vec<BridgeConfig> {
+    let data = match fs::read_to_string(BRIDGES_PATH) {
+        Ok(d) => d,
+        Err(_) => return vec![],
+    };
+    match parse_bridges(&data) {
+        Ok(bridges) => bridges,
+        Err(e) => {
+            error!("[Server:Bridge] bridges.json invalido: {:?}", e.to_string());
+            vec![]
+        }
+    }
+}
+
+pub fn parse_bridges(data: &str) -> Result<Vec<BridgeConfig>, Box<dyn Error>> {
+    let value: Value = serde_json::from_str(data)?;
+    let mut bridges = vec![];
+    for bridge in value.as_array().ok_or("se esperaba una lista de bridges")? {
+        let name = string_field(bridge, "name").ok_or("bridge sin name")?;
+        let address = string_field(bridge, "address").ok_or("bridge sin address")?;
+        let mut topics = vec![];
+        for topic in bridge["topics"].as_array().ok_or("bridge sin topics")? {
+            let direction = match string_field(topic, "direction").as_deref() {
+                Some("in") => Direction::In,
+                Some("out") | None => Direction::Out,
+                Some("both") => Direction::Both,
+                Some(other) => return Err(format!("direction invalida: {}", other).into()),
+            };
+            let qos = topic["qos"].as_u64().unwrap_or(0);
+            if qos > 1 {
+                return Err(format!("qos invalido: {}", qos).into());
+            }
+            topics.push(BridgeTopic {
+                pattern: string_field(topic, "pattern").ok_or("topic sin pattern")?,
+                direction,
+                qos: qos as u8,
+                local_prefix: string_field(topic, "local_prefix").unwrap_or_default(),
+                remote_prefix: string_field(topic, "remote_prefix").unwrap_or_default(),
+            });
+        }
+        bridges.push(BridgeConfig {
+            client_id: string_field(bridge, "client_id").unwrap_or_else(|| name.clone()),
+            username: string_field(bridge, "username").unwrap_or_default(),
+            password: string_field(bridge, "password").unwrap_or_default(),
+            name,
+            address,
+            topics,
+        });
+    }
+    Ok(bridges)
+}
+
+fn string_field(value: &Value, field: &str) -> Option<String> {
+    value[field].as_str().map(|s| s.to_string())
+}
+
+/// Mensajes reenviados al broker remoto (topic remoto y payload) que todavia pueden volver por la
+/// suscripcion del bridge.
+#[derive(Debug, Default)]
+pub struct Echoes {
+    pending: VecDeque<(String, String, Instant)>,
+}
+
+impl Echoes {
+    pub fn new() -> Self {
+        Echoes::default()
+    }
+
+    /// Recuerda un mensaje reenviado. Si ya hay `MAX_ECHOES` se olvida el mas viejo.
+    pub fn record(&mut self, topic: &str, payload: &str, now: Instant) {
+        if self.pending.len() == MAX_ECHOES {
+            self.pending.pop_front();
+        }
+        self.pending
+            .push_back((topic.to_string(), payload.to_string(), now));
+    }
+
+    /// Indica si el mensaje que llego del broker remoto es el eco de uno reenviado, y en ese caso
+    /// lo olvida para que una segunda copia si se publique.
+    pub fn take(&mut self, topic: &str, payload: &str, now: Instant) -> bool {
+        self.pending
+            .retain(|(_, _, sent)| now.saturating_duration_since(*sent) < ECHO_TIMEOUT);
+        match self
+            .pending
+            .iter()
+            .position(|(t, p, _)| t == topic && p == payload)
+        {
+            Some(pos) => {
+                self.pending.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Conexion de un bridge al broker remoto. El stream solo existe mientras esta conectado.
+pub struct Bridge {
+    config: BridgeConfig,
+    remote: Mutex<Option<TcpStream>>,
+    echoes: Mutex<Echoes>,
+}
+
+impl Bridge {
+    /// Origen con el que se marcan los mensajes que llegan por el bridge, para no reenviarlos de vuelta.
+    fn origin(&self) -> String {
+        "$bridge/".to_string() + self.config.name.as_str()
+    }
+
+    /// Reenvia al broker remoto un publish local si alguno de los topics del bridge lo incluye.
+    fn forward(&self, incoming: &IncomingPublish) {
+        if incoming.get_origin() == self.origin() {
+            return;
+        }
+        let publish = incoming.get_publish();
+        let topic = publish.get_topic().get_topic();
+        for bridge_topic in self.config.topics.iter() {
+            if let Some(remote_topic) = bridge_topic.to_remote(&topic) {
+                let qos = cmp::min(publish.get_flags().get_qos(), bridge_topic.qos);
+                match with_topic(&publish, remote_topic.clone(), qos) {
+                    Ok(p) => {
+                        if self.send(&p.get_data()).is_err() {
+                            warn!(
+                                "[Server:Bridge] {:?} desconectado, no se reenvio {:?}",
+                                self.config.name, topic
+                            );
+                        } else if bridge_topic.to_local(&remote_topic).is_some() {
+                            self.lock_echoes().record(
+                                &remote_topic,
+                                &publish.get_payload(),
+                                Instant::now(),
+                            );
+                        }
+                    }
+                    Err(e) => error!("[Server:Bridge] error al crear publish {:?}", e),
+                }
+                return;
+            }
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut remote = match self.remote.lock() {
+            Ok(r) => r,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match remote.as_mut() {
+            Some(stream) => Ok(stream.write_all(data)?),
+            None => Err("bridge desconectado".into()),
+        }
+    }
+
+    fn lock_echoes(&self) -> std::sync::MutexGuard<'_, Echoes> {
+        match self.echoes.lock() {
+            Ok(e) => e,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn set_remote(&self, stream: Option<TcpStream>) {
+        match self.remote.lock() {
+            Ok(mut r) => *r = stream,
+            Err(poisoned) => *poisoned.into_inner() = stream,
+        }
+    }
+
+    /// Se conecta al broker remoto y reenvia los mensajes que llegan. Si se pierde la conexion se
+    /// reintenta con una espera que se duplica en cada intento hasta `MAX_BACKOFF` segundos.
+    fn run(&self, sender: Sender<IncomingPublish>) {
+        let mut backoff = 1;
+        loop {
+            match self.connect() {
+                Ok(stream) => {
+                    info!(
+                        "[Server:Bridge] {:?} conectado a {:?}",
+                        self.config.name, self.config.address
+                    );
+                    backoff = 1;
+                    self.receive(stream, &sender);
+                    warn!("[Server:Bridge] {:?} desconectado", self.config.name);
+                }
+                Err(e) => {
+                    error!(
+                        "[Server:Bridge] {:?} no se pudo conectar a {:?}: {:?}",
+                        self.config.name,
+                        self.config.address,
+                        e.to_string()
+                    );
+                }
+            }
+            self.set_remote(None);
+            thread::sleep(Duration::from_secs(backoff));
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(self.config.address.as_str())?;
+        let flags = new_connect_flag(
+            Some(true),
+            None,
+            None,
+            None,
+            None,
+            Some(!self.config.password.is_empty()),
+            Some(!self.config.username.is_empty()),
+        )
+        .map_err(|e| format!("{:?}", e))?;
+        let payload = new_payload_connect(
+            self.config.client_id.clone(),
+            "".to_string(),
+            "".to_string(),
+            self.config.username.clone(),
+            self.config.password.clone(),
+            0,
+        )
+        .map_err(|e| format!("{:?}", e))?;
+        stream.write_all(&new_connect(flags, payload)?.get_data())?;
+        let connack =
+            new_connack_by_hex(Socket::read_all(&mut stream)?).map_err(|e| format!("{:?}", e))?;
+        if !connack.get_connect_return_codes().is_accepted() {
+            return Err(format!(
+                "connect rechazado: {}",
+                connack.get_connect_return_codes().get_reason()
+            )
+            .into());
+        }
+
+        let mut filters = vec![];
+        for topic in self.config.get_topics() {
+            if topic.direction != Direction::Out {
+                filters.push(
+                    new_topic_filter_with_qos(topic.remote_filter(), topic.qos)
+                        .map_err(|e| format!("{:?}", e))?,
+                );
+            }
+        }
+        if !filters.is_empty() {
+            stream.write_all(&new_subscribe(filters.clone())?.get_data())?;
+            let suback = new_suback_by_hex(Socket::read_all(&mut stream)?)
+                .map_err(|e| format!("{:?}", e))?;
+            for (filter, code) in filters.iter().zip(suback.get_return_codes()) {
+                if let SubackReturnCode::Failure = code {
+                    warn!(
+                        "[Server:Bridge] {:?} no se pudo suscribir a {:?}",
+                        self.config.name,
+                        filter.get_topic()
+                    );
+                }
+            }
+        }
+        self.set_remote(Some(stream.try_clone()?));
+        Ok(stream)
+    }
+
+    fn receive(&self, mut stream: TcpStream, sender: &Sender<IncomingPublish>) {
+        loop {
+            let header = match Socket::read_all(&mut stream) {
+                Ok(h) => h,
+                Err(_) => return,
+            };
+            if let PacketType::PUBLISH = header.get_control_packet_type() {
+                let publish = match new_publish_by_hex(header) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("[Server:Bridge] publish invalido {:?}", e.to_string());
+                        continue;
+                    }
+                };
+                if publish.get_flags().get_qos() == 1
+                    && self.send(&new_puback().get_data()).is_err()
+                {
+                    return;
+                }
+                self.inject(publish, sender);
+            }
+        }
+    }
+
+    /// Publica localmente un mensaje que llego del broker remoto.
+    fn inject(&self, publish: Publish, sender: &Sender<IncomingPublish>) {
+        let topic = publish.get_topic().get_topic();
+        if self
+            .lock_echoes()
+            .take(&topic, &publish.get_payload(), Instant::now())
+        {
+            return;
+        }
+        let (local_topic, qos) = match self
+            .config
+            .topics
+            .iter()
+            .find_map(|t| Some((t.to_local(&topic)?, t.qos)))
+        {
+            Some((local_topic, qos)) => (local_topic, cmp::min(publish.get_flags().get_qos(), qos)),
+            None => return,
+        };
+        let local = match with_topic(&publish, local_topic, qos) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("[Server:Bridge] error al crear publish {:?}", e);
+                return;
+            }
+        };
+        if register_publish(&local, None, &new_properties()).is_err() {
+            error!("[Server:Bridge] error al registrar el topic {:?}", topic);
+        }
+        let _result = sender.send(IncomingPublish::new(local, self.origin()));
+    }
+}
+
+/// Copia del publish con otro topic y QoS.
+fn with_topic(publish: &Publish, topic: String, qos: u8) -> Result<Publish, String> {
+    let flags = publish.get_flags().set_qos(qos);
+    let topic_filter = new_topic_filter(topic).map_err(|e| format!("{:?}", e))?;
+    new_publish(flags, topic_filter, publish.get_payload()).map_err(|e| format!("{:?}", e))
+}
+
+/// Bridges configurados, cada uno atendido por su propio thread.
+pub struct Bridges {
+    bridges: Vec<Arc<Bridge>>,
+}
+
+impl Bridges {
+    pub fn start(configs: Vec<BridgeConfig>, sender: Sender<IncomingPublish>) -> Self {
+        let mut bridges = vec![];
+        for config in configs {
+            let bridge = Arc::new(Bridge {
+                config,
+                remote: Mutex::new(None),
+                echoes: Mutex::new(Echoes::new()),
+            });
+            let bridge_ref = Arc::clone(&bridge);
+            let sender = sender.clone();
+            thread::spawn(move || bridge_ref.run(sender));
+            bridges.push(bridge);
+        }
+        Bridges { bridges }
+    }
+
+    pub fn forward(&self, incoming: &IncomingPublish) {
+        for bridge in self.bridges.iter() {
+            bridge.forward(incoming);
+        }
+    }
+}
@@ -0,0 +1,618 @@
+//! Cluster de brokers: varios servers que comparten topics, retain messages y sesiones.
+//!
+//! Se configura en `cluster.json`, con todos los nodos listando a los demas como peers y con el
+//! mismo `secret`:
+//!
+//! ```json
+//! {"node_id": "a", "listen": "127.0.0.1:19001", "peers": ["127.0.0.1:19002"], "secret": "..."}
+//! ```
+//!
+//! Los nodos se comunican con mensajes JSON, uno por linea:
+//! - `hello`: identifica al nodo al abrir la conexion y lleva el `secret` del cluster. Una
+//!   conexion que no empieza con un `hello` con el secret correcto se cierra sin procesar nada.
+//! - `topics`: cada segundo, los topics que conoce el nodo y los que tienen suscriptores en el.
+//! - `publish`: un publish reenviado a un nodo con suscriptores del topic.
+//! - `retain`: un retain message, que se replica en todos los nodos.
+//! - `claim` / `session`: al conectarse un cliente, el nodo reclama su sesion a los demas, que le
+//!   devuelven sus suscripciones (tambien las compartidas) y mensajes encolados y cierran la
+//!   conexion que tuvieran con el.
+//!
+//! Las suscripciones compartidas se reparten entre los miembros de cada grupo dentro de cada nodo.
+use crate::json_helper::{
+    read_shared_subs, read_topic_subs, read_users, remove_user, take_q_messages, update_q_messages,
+    update_shared_subs, write_topic_subs, write_topic_unsubs,
+};
+use crate::packets::publish::{register_publish, IncomingPublish};
+use crate::packets::queue_message::QueueMessage;
+use crate::packets::shared_subscription::remove_from_all;
+use crate::packets::user_qos::UserQos;
+use crate::protocol::{properties_from_json, properties_to_json};
+use crate::server::find_socket;
+use crate::socket::Socket;
+use crate::will;
+use serde_json::{json, Value};
+use serializer::{new_mqtt_header, new_publish_by_hex, Publish};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const CLUSTER_PATH: &str = "./cluster.json";
+/// Prefijo del origen de los publish que llegan de otro nodo, para no reenviarlos de vuelta.
+const CLUSTER_ORIGIN: &str = "$cluster/";
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Tiempo que se espera a que los demas nodos devuelvan la sesion de un cliente que se conecta.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(1);
+/// Tiempo que se espera, al devolver una sesion, a que se cierre la conexion del cliente y se
+/// repartan los publish pendientes. Tiene que ser menor que `CLAIM_TIMEOUT`.
+const RELEASE_TIMEOUT: Duration = Duration::from_millis(500);
+const RELEASE_POLL: Duration = Duration::from_millis(20);
+const MAX_BACKOFF: u64 = 30;
+
+static CLUSTER: OnceLock<Arc<Cluster>> = OnceLock::new();
+
+/// Cluster del server, si se configuro `cluster.json`.
+pub fn cluster() -> Option<Arc<Cluster>> {
+    CLUSTER.get().cloned()
+}
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    node_id: String,
+    listen: String,
+    peers: Vec<String>,
+    secret: String,
+}
+
+impl ClusterConfig {
+    pub fn get_node_id(&self) -> String {
+        self.node_id.clone()
+    }
+
+    pub fn get_peers(&self) -> Vec<String> {
+        self.peers.clone()
+    }
+}
+
+/// Lee `cluster.json`. Si no existe el server funciona como un nodo aislado.
+pub fn read_cluster_config() -> Option<ClusterConfig> {
+    let data = fs::read_to_string(CLUSTER_PATH).ok()?;
+    match parse_cluster_config(&data) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            error!(
+                "[Server:Cluster] cluster.json invalido: {:?}",
+                e.to_string()
+            );
+            None
+        }
+    }
+}
+
+pub fn parse_cluster_config(data: &str) -> Result<ClusterConfig, Box<dyn Error>> {
+    let value: Value = serde_json::from_str(data)?;
+    let node_id = value["node_id"].as_str().ok_or("falta node_id")?;
+    let listen = value["listen"].as_str().ok_or("falta listen")?;
+    let secret = match value["secret"].as_str() {
+        Some(secret) if !secret.is_empty() => secret,
+        _ => return Err("falta secret".into()),
+    };
+    let mut peers = vec![];
+    for peer in value["peers"].as_array().ok_or("falta peers")? {
+        peers.push(peer.as_str().ok_or("peer invalido")?.to_string());
+    }
+    Ok(ClusterConfig {
+        node_id: node_id.to_string(),
+        listen: listen.to_string(),
+        peers,
+        secret: secret.to_string(),
+    })
+}
+
+/// Conexion saliente a otro nodo. El nombre del nodo se conoce cuando responde el `hello`.
+struct Peer {
+    address: String,
+    node: Mutex<Option<String>>,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl Peer {
+    fn send(&self, message: &Value) -> Result<(), Box<dyn Error>> {
+        let mut stream = lock(&self.stream);
+        match stream.as_mut() {
+            Some(s) => write_message(s, message),
+            None => Err("peer desconectado".into()),
+        }
+    }
+
+    fn get_node(&self) -> Option<String> {
+        lock(&self.node).clone()
+    }
+}
+
+pub struct Cluster {
+    node_id: String,
+    secret: String,
+    peers: Vec<Peer>,
+    /// Topics con suscriptores en cada nodo, segun el ultimo `topics` recibido.
+    routes: Mutex<HashMap<String, HashSet<String>>>,
+    /// Clientes con un `claim` en curso y el channel donde se esperan las respuestas.
+    claims: Mutex<HashMap<String, Sender<Value>>>,
+    connections: Arc<Mutex<Vec<Socket>>>,
+    sender: Sender<IncomingPublish>,
+    /// Pedidos al thread que reparte los publish para que reparta los que tiene pendientes.
+    flush: Sender<Sender<()>>,
+}
+
+impl Cluster {
+    /// Inicia el cluster: escucha a los demas nodos, se conecta a los peers y sincroniza los topics.
+    pub fn start(
+        config: ClusterConfig,
+        connections: Arc<Mutex<Vec<Socket>>>,
+        sender: Sender<IncomingPublish>,
+        flush: Sender<Sender<()>>,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(config.listen.as_str())?;
+        let cluster = Arc::new(Cluster {
+            node_id: config.get_node_id(),
+            secret: config.secret.clone(),
+            peers: config
+                .get_peers()
+                .iter()
+                .map(|address| Peer {
+                    address: address.clone(),
+                    node: Mutex::new(None),
+                    stream: Mutex::new(None),
+                })
+                .collect(),
+            routes: Mutex::new(HashMap::new()),
+            claims: Mutex::new(HashMap::new()),
+            connections,
+            sender,
+            flush,
+        });
+        if CLUSTER.set(Arc::clone(&cluster)).is_err() {
+            error!("[Server:Cluster] El cluster ya estaba iniciado");
+            return Ok(());
+        }
+        info!(
+            "[Server:Cluster] Nodo {:?} escuchando en {:?}",
+            config.node_id, config.listen
+        );
+
+        let cluster_ref = Arc::clone(&cluster);
+        thread::spawn(move || cluster_ref.accept(listener));
+        for i in 0..cluster.peers.len() {
+            let cluster_ref = Arc::clone(&cluster);
+            thread::spawn(move || cluster_ref.connect_peer(i));
+        }
+        let cluster_ref = Arc::clone(&cluster);
+        thread::spawn(move || loop {
+            cluster_ref.sync_topics();
+            thread::sleep(SYNC_INTERVAL);
+        });
+        Ok(())
+    }
+
+    fn accept(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let cluster = Arc::clone(&self);
+            thread::spawn(move || {
+                let writer = match stream.try_clone() {
+                    Ok(w) => Mutex::new(w),
+                    Err(_) => return,
+                };
+                cluster.read_messages(stream, Some(&writer), None);
+            });
+        }
+    }
+
+    /// Mantiene la conexion saliente al peer, reconectando con backoff si se corta.
+    fn connect_peer(self: Arc<Self>, index: usize) {
+        let peer = &self.peers[index];
+        let mut backoff = 1;
+        loop {
+            match TcpStream::connect(peer.address.as_str()) {
+                Ok(mut stream) => {
+                    backoff = 1;
+                    let hello = self.hello();
+                    let reader = stream.try_clone();
+                    if let (Ok(_), Ok(reader)) = (write_message(&mut stream, &hello), reader) {
+                        *lock(&peer.stream) = Some(stream);
+                        self.read_messages(reader, None, Some(index));
+                    }
+                    *lock(&peer.stream) = None;
+                    if let Some(node) = peer.get_node() {
+                        warn!("[Server:Cluster] Se perdio la conexion con {:?}", node);
+                        lock(&self.routes).remove(&node);
+                    }
+                }
+                Err(_) => {
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+            thread::sleep(Duration::from_secs(backoff));
+        }
+    }
+
+    fn hello(&self) -> Value {
+        json!({ "type": "hello", "node": self.node_id, "secret": self.secret })
+    }
+
+    /// Lee los mensajes de una conexion con otro nodo. `peer` es el indice del peer si la conexion
+    /// es saliente; en las entrantes las respuestas se escriben en `writer`. El primer mensaje
+    /// tiene que ser un `hello` con el secret del cluster, si no se cierra la conexion.
+    fn read_messages(
+        &self,
+        stream: TcpStream,
+        writer: Option<&Mutex<TcpStream>>,
+        peer: Option<usize>,
+    ) {
+        let address = stream.peer_addr().ok();
+        let reader = BufReader::new(stream);
+        let mut authenticated = false;
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
+            };
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(m) => m,
+                Err(_) => {
+                    warn!("[Server:Cluster] Mensaje invalido {:?}", line);
+                    continue;
+                }
+            };
+            if !authenticated {
+                if message["type"].as_str() != Some("hello")
+                    || message["secret"].as_str() != Some(self.secret.as_str())
+                {
+                    warn!(
+                        "[Server:Cluster] Se cierra la conexion con {:?}: no envio el secret del cluster",
+                        address
+                    );
+                    return;
+                }
+                authenticated = true;
+            }
+            let reply = |response: &Value| match (peer, writer) {
+                (Some(i), _) => self.peers[i].send(response),
+                (None, Some(w)) => write_message(&mut lock(w), response),
+                (None, None) => Err("conexion sin writer".into()),
+            };
+            if self.handle(&message, peer, reply).is_err() {
+                error!("[Server:Cluster] Error al procesar {:?}", message["type"]);
+            }
+        }
+    }
+
+    fn handle<F>(
+        &self,
+        message: &Value,
+        peer: Option<usize>,
+        reply: F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(&Value) -> Result<(), Box<dyn Error>>,
+    {
+        let node = message["node"].as_str().unwrap_or("").to_string();
+        match message["type"].as_str() {
+            Some("hello") => match peer {
+                Some(i) => {
+                    info!("[Server:Cluster] Conectado al nodo {:?}", node);
+                    *lock(&self.peers[i].node) = Some(node);
+                }
+                None => reply(&self.hello())?,
+            },
+            Some("topics") => {
+                let subscribed: HashSet<String> = strings(&message["subscribed"]).collect();
+                lock(&self.routes).insert(node, subscribed);
+                let mut subs = read_topic_subs()?;
+                let before = subs.len();
+                for topic in strings(&message["known"]) {
+                    subs.entry(topic).or_insert_with(Vec::new);
+                }
+                if subs.len() != before {
+                    write_topic_subs(subs)?;
+                }
+            }
+            Some("publish") => {
+                let publish = decode_publish(&message["data"])?;
+                let origin = CLUSTER_ORIGIN.to_string() + node.as_str();
+                let incoming = IncomingPublish::new(publish, origin)
+                    .with_expiry(message["expires_at"].as_u64())
+                    .with_properties(properties_from_json(&message["properties"]));
+                let _result = self.sender.send(incoming);
+            }
+            Some("retain") => {
+                let publish = decode_publish(&message["data"])?;
+                let properties = properties_from_json(&message["properties"]);
+                register_publish(&publish, message["expires_at"].as_u64(), &properties)?;
+            }
+            Some("claim") => {
+                let client = message["client"].as_str().ok_or("claim sin client")?;
+                reply(&self.release_session(client))?;
+            }
+            Some("session") => {
+                let client = message["client"].as_str().ok_or("session sin client")?;
+                if let Some(waiting) = lock(&self.claims).get(client) {
+                    let _result = waiting.send(message.clone());
+                }
+            }
+            _ => warn!("[Server:Cluster] Tipo de mensaje desconocido"),
+        }
+        Ok(())
+    }
+
+    /// Envia a cada peer los topics conocidos y los que tienen suscriptores en este nodo.
+    fn sync_topics(&self) {
+        let subs = match read_topic_subs() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let mut subscribed: Vec<&String> = subs
+            .iter()
+            .filter(|(_, users)| !users.is_empty())
+            .map(|(topic, _)| topic)
+            .collect();
+        let shared = read_shared_subs().unwrap_or_default();
+        subscribed.extend(shared.keys());
+        let message = json!({
+            "type": "topics",
+            "node": self.node_id,
+            "known": subs.keys().collect::<Vec<&String>>(),
+            "subscribed": subscribed,
+        });
+        for peer in self.peers.iter() {
+            let _result = peer.send(&message);
+        }
+    }
+
+    /// Reenvia el publish a los nodos con suscriptores del topic, y si es retain lo replica en todos.
+    /// Los publish que llegaron de otro nodo no se vuelven a reenviar.
+    pub fn forward(&self, incoming: &IncomingPublish) {
+        if incoming.get_origin().starts_with(CLUSTER_ORIGIN) {
+            return;
+        }
+        let publish = incoming.get_publish();
+        let topic = publish.get_topic().get_topic();
+        let data = publish.get_data();
+        let expires_at = incoming.get_expires_at();
+        let properties = properties_to_json(&incoming.get_properties());
+        let routes = lock(&self.routes).clone();
+        for peer in self.peers.iter() {
+            let node = match peer.get_node() {
+                Some(n) => n,
+                None => continue,
+            };
+            if publish.get_flags().get_retain() {
+                let retain = json!({
+                    "type": "retain",
+                    "node": self.node_id,
+                    "data": data,
+                    "expires_at": expires_at,
+                    "properties": properties,
+                });
+                let _result = peer.send(&retain);
+            }
+            if routes.get(&node).map(|t| t.contains(&topic)) == Some(true) {
+                let message = json!({
+                    "type": "publish",
+                    "node": self.node_id,
+                    "data": data,
+                    "expires_at": expires_at,
+                    "properties": properties,
+                });
+                if peer.send(&message).is_err() {
+                    warn!(
+                        "[Server:Cluster] No se pudo reenviar {:?} a {:?}",
+                        topic, node
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reclama la sesion del cliente a los demas nodos antes de aceptar su connect. Si la sesion no
+    /// es limpia se instalan en este nodo las suscripciones y la cola que devuelvan. Devuelve true si
+    /// algun nodo tenia una sesion del cliente.
+    pub fn claim_session(&self, client: &str, clean_session: bool) -> bool {
+        let (tx, rx) = mpsc::channel();
+        lock(&self.claims).insert(client.to_string(), tx);
+        let claim = json!({ "type": "claim", "node": self.node_id, "client": client });
+        let sent = self
+            .peers
+            .iter()
+            .filter(|peer| peer.send(&claim).is_ok())
+            .count();
+        let deadline = Instant::now() + CLAIM_TIMEOUT;
+        let mut resumed = false;
+        for _ in 0..sent {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let session = match rx.recv_timeout(remaining) {
+                Ok(s) => s,
+                Err(_) => {
+                    warn!("[Server:Cluster] Sin respuesta al reclamar {:?}", client);
+                    break;
+                }
+            };
+            if session["found"].as_bool() == Some(true) {
+                resumed = true;
+                if !clean_session {
+                    if let Err(e) = install_session(client, &session) {
+                        error!(
+                            "[Server:Cluster] error al instalar sesion {:?}",
+                            e.to_string()
+                        );
+                    }
+                }
+            }
+        }
+        lock(&self.claims).remove(client);
+        resumed && !clean_session
+    }
+
+    /// Quita de este nodo la sesion del cliente que se conecto en otro nodo y la devuelve. Antes
+    /// se reparten los publish que ya se recibieron, para que los que van a la cola del cliente
+    /// viajen con la sesion.
+    fn release_session(&self, client: &str) -> Value {
+        let deadline = Instant::now() + RELEASE_TIMEOUT;
+        if let Ok(users) = read_users() {
+            if let Some(socket) = find_socket(&self.connections, &users, client) {
+                info!("[Server:Cluster] {:?} se conecto en otro nodo", client);
+                let _result = socket.disconnect();
+                while socket.is_connected() && Instant::now() < deadline {
+                    thread::sleep(RELEASE_POLL);
+                }
+            }
+        }
+        // El cliente se reconecto en otro nodo: su will pendiente ya no se publica.
+        will::cancel(client);
+        let (done, wait) = mpsc::channel();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if self.flush.send(done).is_err() || wait.recv_timeout(remaining).is_err() {
+            warn!(
+                "[Server:Cluster] No se repartieron los publish pendientes de {:?}",
+                client
+            );
+        }
+        // El cliente es conocido si se conecto alguna vez a este nodo, aunque no tenga suscripciones
+        // ni mensajes encolados.
+        let known = read_users()
+            .map(|users| users.values().any(|user| user == client))
+            .unwrap_or(false);
+        if known && remove_user(client).is_err() {
+            error!("[Server:Cluster] error al quitar a {:?} de users", client);
+        }
+        let mut subscriptions = vec![];
+        if let Ok(subs) = read_topic_subs() {
+            let mut topics = vec![];
+            for (topic, users) in subs.iter() {
+                if let Some(user) = users.iter().find(|u| u.get_user() == client) {
+                    subscriptions.push(json!({ "topic": topic, "subscription": user }));
+                    topics.push(topic.clone());
+                }
+            }
+            if !topics.is_empty() && write_topic_unsubs(subs, client.to_string(), topics).is_err() {
+                error!(
+                    "[Server:Cluster] error al quitar suscripciones de {:?}",
+                    client
+                );
+            }
+        }
+        let mut shared = vec![];
+        let released = update_shared_subs(|shared_subs| {
+            for (topic, groups) in shared_subs.iter() {
+                for (group, members) in groups.iter() {
+                    if let Some(member) = members.iter().find(|m| m.get_user() == client) {
+                        shared.push(
+                            json!({ "topic": topic, "group": group, "subscription": member }),
+                        );
+                    }
+                }
+            }
+            remove_from_all(shared_subs, client);
+        });
+        if released.is_err() {
+            error!(
+                "[Server:Cluster] error al quitar suscripciones compartidas de {:?}",
+                client
+            );
+        }
+        let queue = take_q_messages(client.to_string()).unwrap_or_default();
+        json!({
+            "type": "session",
+            "node": self.node_id,
+            "client": client,
+            "found": known || !subscriptions.is_empty() || !shared.is_empty() || !queue.is_empty(),
+            "subscriptions": subscriptions,
+            "shared": shared,
+            "queue": serde_json::to_value(queue).unwrap_or_default(),
+        })
+    }
+}
+
+/// Agrega a este nodo las suscripciones, los grupos compartidos y los mensajes encolados que
+/// devolvio otro nodo.
+fn install_session(client: &str, session: &Value) -> Result<(), Box<dyn Error>> {
+    let mut subs = read_topic_subs()?;
+    for subscription in session["subscriptions"].as_array().unwrap_or(&vec![]) {
+        let topic = subscription["topic"]
+            .as_str()
+            .ok_or("suscripcion sin topic")?;
+        // Se conservan las opciones de MQTT 5 (no local, retain as published) de la suscripcion.
+        let user: UserQos = serde_json::from_value(subscription["subscription"].clone())?;
+        let users = subs.entry(topic.to_string()).or_insert_with(Vec::new);
+        users.retain(|u| u.get_user() != client);
+        users.push(user);
+    }
+    write_topic_subs(subs)?;
+    let mut shared = vec![];
+    for membership in session["shared"].as_array().unwrap_or(&vec![]) {
+        let topic = membership["topic"].as_str().ok_or("grupo sin topic")?;
+        let group = membership["group"].as_str().ok_or("grupo sin nombre")?;
+        let member: UserQos = serde_json::from_value(membership["subscription"].clone())?;
+        shared.push((topic.to_string(), group.to_string(), member));
+    }
+    if !shared.is_empty() {
+        update_shared_subs(|shared_subs| {
+            for (topic, group, member) in shared {
+                let members = shared_subs
+                    .entry(topic)
+                    .or_default()
+                    .entry(group)
+                    .or_default();
+                members.retain(|m| m.get_user() != client);
+                members.push(member);
+            }
+        })?;
+    }
+    let mut queue: Vec<QueueMessage> = serde_json::from_value(session["queue"].clone())?;
+    if !queue.is_empty() {
+        update_q_messages(|q_messages| {
+            let current = q_messages
+                .entry(client.to_string())
+                .or_insert_with(Vec::new);
+            queue.append(current);
+            *current = queue;
+        })?;
+    }
+    Ok(())
+}
+
+fn decode_publish(data: &Value) -> Result<Publish, Box<dyn Error>> {
+    let data: Vec<u8> = serde_json::from_value(data.clone())?;
+    let header = new_mqtt_header(data).map_err(|e| format!("{:?}", e))?;
+    new_publish_by_hex(header)
+}
+
+fn strings(value: &Value) -> impl Iterator<Item = String> + '_ {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+}
+
+fn write_message(stream: &mut TcpStream, message: &Value) -> Result<(), Box<dyn Error>> {
+    stream.write_all((message.to_string() + "\n").as_bytes())?;
+    Ok(())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
@@ -0,0 +1,50 @@
+//! Vencimiento de mensajes encolados y retenidos.
+//!
+//! Un publish MQTT 5 puede traer un message expiry interval; a los publish de clientes MQTT 3.1.1
+//! se les aplica el `message_ttl` de config.txt (en segundos), si esta configurado. El vencimiento
+//! se guarda como instante absoluto en segundos unix, para que siga valiendo despues de reiniciar
+//! el server y al pasar de un nodo a otro del cluster.
+use crate::server::config_value;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+static DEFAULT_TTL: OnceLock<Option<u32>> = OnceLock::new();
+
+/// Lee `message_ttl` de la configuracion. Se llama una sola vez, al iniciar el server.
+pub fn init_default_ttl(config: &[Vec<String>]) {
+    let ttl = config_value(config, "message_ttl").and_then(|v| match v.parse::<u32>() {
+        Ok(ttl) => Some(ttl),
+        Err(_) => {
+            error!("[Server:Expiry] message_ttl invalido: {:?}", v);
+            None
+        }
+    });
+    let _result = DEFAULT_TTL.set(ttl);
+}
+
+/// Vencimiento que se aplica a los publish de clientes MQTT 3.1.1.
+pub fn default_ttl() -> Option<u32> {
+    DEFAULT_TTL.get().copied().flatten()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Instante en que vence un mensaje recibido en `now` con el intervalo dado.
+pub fn expires_at(interval: Option<u32>, now: u64) -> Option<u64> {
+    interval.map(|interval| now + interval as u64)
+}
+
+pub fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    matches!(expires_at, Some(at) if at <= now)
+}
+
+/// Segundos de vida que le quedan al mensaje, o None si no vence.
+pub fn remaining(expires_at: Option<u64>, now: u64) -> Option<u32> {
+    expires_at.map(|at| at.saturating_sub(now).min(u32::MAX as u64) as u32)
+}
@@ -0,0 +1,236 @@
+//! Servidor HTTP minimo (HTTP/1.1, una request por conexion) para los endpoints auxiliares del broker.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Tamaño maximo de body que se acepta, para no reservar memoria de mas con un Content-Length invalido.
+const MAX_BODY: usize = 1024 * 1024;
+/// Tiempo que tiene el cliente para enviar la request completa y para recibir la respuesta. Si no,
+/// una conexion que no envia nada (o envia de a un byte) tendria ocupado su thread para siempre.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request ya parseada. Los nombres de los headers se guardan en minuscula y el path y el query
+/// string ya estan decodificados.
+#[derive(Debug, Clone, Default)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    /// Segmentos del path sin los `/`, por ejemplo `/clients/c1` -> `["clients", "c1"]`.
+    pub fn segments(&self) -> Vec<String> {
+        self.path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(decode)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &str, body: String) -> Self {
+        HttpResponse {
+            status,
+            content_type: content_type.to_string(),
+            body,
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        HttpResponse::new(status, "text/plain; charset=utf-8", body.to_string() + "\n")
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        HttpResponse::new(status, "application/json", body.to_string())
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "not found")
+    }
+}
+
+/// Escucha en `address` y atiende cada conexion en un thread, respondiendo con lo que devuelva `handler`.
+pub fn serve<F>(address: String, handler: F) -> std::io::Result<()>
+where
+    F: Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(address.clone())?;
+    info!("[Server:Http] Escuchando en {:?}", address);
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    error!(
+                        "[Server:Http] error al aceptar conexion {:?}",
+                        e.to_string()
+                    );
+                    continue;
+                }
+            };
+            if stream.set_write_timeout(Some(TIMEOUT)).is_err() {
+                error!("[Server:Http] error al configurar el timeout de la conexion");
+                continue;
+            }
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || {
+                let response = match read_request(&mut stream) {
+                    Ok(request) => handler(request),
+                    Err(_) => HttpResponse::text(400, "bad request"),
+                };
+                if write_response(&mut stream, response).is_err() {
+                    error!("[Server:Http] error al enviar respuesta");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Lee y parsea una request, que tiene que llegar completa antes de `TIMEOUT`.
+fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, Box<dyn Error>> {
+    let mut reader = BufReader::new(Deadline {
+        stream,
+        deadline: Instant::now() + TIMEOUT,
+    });
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or("request vacia")?.to_string();
+    let target = parts.next().ok_or("request sin path")?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(l) => l.parse()?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err("body demasiado grande".into());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+    Ok(HttpRequest {
+        method,
+        path,
+        query,
+        headers,
+        body: String::from_utf8(body)?,
+    })
+}
+
+/// Lectura del socket con un vencimiento para toda la request y no para cada `read`.
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Parsea `clave=valor&otra=valor`.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (decode(key), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// Decodifica `%XX` y `+` de la url.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn write_response(stream: &mut TcpStream, response: HttpResponse) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
@@ -1,8 +1,11 @@
 //! Modulo de procesamiento de archivos
+use crate::packets::queue_message::QueueMessage;
 use crate::packets::user_qos::UserQos;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
+use std::sync::{Mutex, MutexGuard};
 /// `json_helper` es una coleccion de funciones que se encargan de la escritura en archivos
 /// json que se utilizan para guardar la infomación del servidor como retain messages, queue messages,
 /// subscribers, users, etc.
@@ -15,33 +18,144 @@ pub fn read_retain_messages() -> Result<HashMap<String, Vec<String>>, Box<dyn Er
     Ok(retain_message)
 }
 
-pub fn read_q_messages() -> Result<HashMap<String, Vec<Vec<u8>>>, Box<dyn Error>> {
+/// Cola de mensajes pendientes de cada cliente, en orden de llegada.
+pub type QueueMessages = HashMap<String, Vec<QueueMessage>>;
+
+/// Evita que el thread del server y los threads de cada socket pisen sus cambios sobre la cola.
+static Q_MESSAGES_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn read_q_messages() -> Result<QueueMessages, Box<dyn Error>> {
     let data = read_from_path("./queue_messages.json".to_string());
-    let q_messages: HashMap<String, Vec<Vec<u8>>> = serde_json::from_str(&data)?;
+    let q_messages: QueueMessages = serde_json::from_str(&data)?;
     Ok(q_messages)
 }
 
-pub fn write_q_messages(data: HashMap<String, Vec<Vec<u8>>>) -> Result<bool, Box<dyn Error>> {
-    let current_data = read_q_messages()?;
-    let new_data = append_maps_q_mess(current_data, data);
-    write_to_path("./queue_messages.json".to_string(), new_data)
+/// Sobreescribe la cola de mensajes completa. Los mensajes repetidos se conservan,
+/// para modificar la cola a partir de su estado actual usar `update_q_messages`.
+pub fn write_q_messages(data: QueueMessages) -> Result<bool, Box<dyn Error>> {
+    write_to_path("./queue_messages.json".to_string(), data)
 }
 
-pub fn write_remove_q_messages(
-    data: HashMap<String, Vec<Vec<u8>>>,
-    user: String,
-) -> Result<bool, Box<dyn Error>> {
-    let current_data = read_q_messages()?;
-    let new_data = append_maps_remove_q_mess(current_data, data, user);
-    write_to_path("./queue_messages.json".to_string(), new_data)
+/// Lee la cola, le aplica `update` y la vuelve a escribir sin que otro thread la modifique en el medio.
+pub fn update_q_messages<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut QueueMessages),
+{
+    let _guard = match Q_MESSAGES_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut q_messages = read_q_messages()?;
+    update(&mut q_messages);
+    write_q_messages(q_messages)
+}
+
+/// Quita y devuelve los mensajes encolados del cliente, dejando su cola vacia.
+pub fn take_q_messages(user: String) -> Result<Vec<QueueMessage>, Box<dyn Error>> {
+    let mut messages = vec![];
+    update_q_messages(|q_messages| {
+        if let Some(m) = q_messages.insert(user, vec![]) {
+            messages = m;
+        }
+    })?;
+    Ok(messages)
 }
 
 pub fn write_retain_messages(data: HashMap<String, Vec<String>>) -> Result<bool, Box<dyn Error>> {
+    let _guard = match RETAIN_MESSAGES_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
     let current_data = read_retain_messages()?;
     let new_data = append_maps_ret(current_data, data);
     write_to_path("./retain_messages.json".to_string(), new_data)
 }
 
+static RETAIN_MESSAGES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Lee los retain messages, les aplica `update` y los vuelve a escribir tal cual quedaron, sin mezclarlos
+/// con los del archivo como hace `write_retain_messages`, por lo que permite borrar o reemplazar mensajes.
+pub fn update_retain_messages<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut HashMap<String, Vec<String>>),
+{
+    let _guard = match RETAIN_MESSAGES_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut retain_message = read_retain_messages()?;
+    update(&mut retain_message);
+    write_to_path("./retain_messages.json".to_string(), retain_message)
+}
+
+/// Reemplaza los retain messages del topic por un unico payload. Se usa para los `$SYS` topics,
+/// donde solo interesa el ultimo valor publicado.
+pub fn replace_retain_message(topic: String, payload: String) -> Result<bool, Box<dyn Error>> {
+    update_retain_messages(|retain_message| {
+        retain_message.insert(topic, vec![payload]);
+    })
+}
+
+/// Vencimiento de los retain messages: por cada topic, el instante (segundos unix) en que vence
+/// cada payload. Los payloads que no vencen no aparecen.
+pub type RetainExpiry = HashMap<String, HashMap<String, u64>>;
+
+static RETAIN_EXPIRY_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn read_retain_expiry() -> Result<RetainExpiry, Box<dyn Error>> {
+    let data = read_from_path("./retain_expiry.json".to_string());
+    if data.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let retain_expiry: RetainExpiry = serde_json::from_str(&data)?;
+    Ok(retain_expiry)
+}
+
+/// Lee los vencimientos de los retain messages, les aplica `update` y los vuelve a escribir.
+pub fn update_retain_expiry<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut RetainExpiry),
+{
+    let _guard = match RETAIN_EXPIRY_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut retain_expiry = read_retain_expiry()?;
+    update(&mut retain_expiry);
+    write_to_path("./retain_expiry.json".to_string(), retain_expiry)
+}
+
+/// Properties reenviadas de los retain messages de clientes MQTT 5: por cada topic, las de cada
+/// payload, como las guarda `protocol::properties_to_json`. Los payloads sin properties no aparecen.
+pub type RetainProperties = HashMap<String, HashMap<String, Value>>;
+
+static RETAIN_PROPERTIES_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn read_retain_properties() -> Result<RetainProperties, Box<dyn Error>> {
+    let data = read_from_path("./retain_properties.json".to_string());
+    if data.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let retain_properties: RetainProperties = serde_json::from_str(&data)?;
+    Ok(retain_properties)
+}
+
+/// Lee las properties de los retain messages, les aplica `update` y las vuelve a escribir.
+pub fn update_retain_properties<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut RetainProperties),
+{
+    let _guard = match RETAIN_PROPERTIES_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut retain_properties = read_retain_properties()?;
+    update(&mut retain_properties);
+    write_to_path("./retain_properties.json".to_string(), retain_properties)
+}
+
+static TOPIC_SUBS_LOCK: Mutex<()> = Mutex::new(());
+
 pub fn read_topic_subs() -> Result<HashMap<String, Vec<UserQos>>, Box<dyn Error>> {
     let data = read_from_path("./topic_subscribers.json".to_string());
     let topic_subs: HashMap<String, Vec<UserQos>> = serde_json::from_str(&data)?;
@@ -49,6 +163,10 @@ pub fn read_topic_subs() -> Result<HashMap<String, Vec<UserQos>>, Box<dyn Error>
 }
 
 pub fn write_topic_subs(data: HashMap<String, Vec<UserQos>>) -> Result<bool, Box<dyn Error>> {
+    let _guard = match TOPIC_SUBS_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
     let current_data = read_topic_subs()?;
     let new_data = append_maps_sub(current_data, data);
     write_to_path("./topic_subscribers.json".to_string(), new_data)
@@ -59,11 +177,58 @@ pub fn write_topic_unsubs(
     user: String,
     topics: Vec<String>,
 ) -> Result<bool, Box<dyn Error>> {
+    let _guard = match TOPIC_SUBS_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
     let current_data = read_topic_subs()?;
     let new_data = append_maps_unsub(current_data, data, user, topics);
     write_to_path("./topic_subscribers.json".to_string(), new_data)
 }
 
+/// Lee las suscripciones, les aplica `update` y las vuelve a escribir tal cual quedaron, sin que otro
+/// thread las modifique en el medio.
+pub fn update_topic_subs<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut HashMap<String, Vec<UserQos>>),
+{
+    let _guard = match TOPIC_SUBS_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut topic_subs = read_topic_subs()?;
+    update(&mut topic_subs);
+    write_to_path("./topic_subscribers.json".to_string(), topic_subs)
+}
+
+/// Suscripciones compartidas (`$share/<grupo>/<filtro>`): por cada topic, los miembros de cada grupo.
+pub type SharedSubs = HashMap<String, HashMap<String, Vec<UserQos>>>;
+
+static SHARED_SUBS_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn read_shared_subs() -> Result<SharedSubs, Box<dyn Error>> {
+    let data = read_from_path("./shared_subscribers.json".to_string());
+    if data.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let shared_subs: SharedSubs = serde_json::from_str(&data)?;
+    Ok(shared_subs)
+}
+
+/// Lee las suscripciones compartidas, les aplica `update` y las vuelve a escribir de forma atomica.
+pub fn update_shared_subs<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut SharedSubs),
+{
+    let _guard = match SHARED_SUBS_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut shared_subs = read_shared_subs()?;
+    update(&mut shared_subs);
+    write_to_path("./shared_subscribers.json".to_string(), shared_subs)
+}
+
 pub fn read_users() -> Result<HashMap<u32, String>, Box<dyn Error>> {
     let data = read_from_path("./users.json".to_string());
     let users: HashMap<u32, String> = serde_json::from_str(&data)?;
@@ -76,12 +241,55 @@ pub fn write_users(data: HashMap<u32, String>) -> Result<bool, Box<dyn Error>> {
     write_to_path("./users.json".to_string(), new_data)
 }
 
+/// Quita al cliente de `users.json`, por ejemplo cuando su sesion pasa a otro nodo del cluster.
+pub fn remove_user(client: &str) -> Result<bool, Box<dyn Error>> {
+    let mut users = read_users()?;
+    users.retain(|_, user| user != client);
+    write_to_path("./users.json".to_string(), users)
+}
+
 pub fn read_user_db() -> Result<HashMap<String, String>, Box<dyn Error>> {
     let data = read_from_path("./user_db.json".to_string());
     let users: HashMap<String, String> = serde_json::from_str(&data)?;
     Ok(users)
 }
 
+static USER_DB_LOCK: Mutex<()> = Mutex::new(());
+
+/// Lee los usuarios registrados, les aplica `update` y los vuelve a escribir.
+pub fn update_user_db<F>(update: F) -> Result<bool, Box<dyn Error>>
+where
+    F: FnOnce(&mut HashMap<String, String>),
+{
+    let _guard = match USER_DB_LOCK.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut users = read_user_db()?;
+    update(&mut users);
+    write_to_path("./user_db.json".to_string(), users)
+}
+
+/// Toma los locks de los archivos de estado que se modifican con los `update_*`, para que no quede
+/// ninguna escritura a medias mientras se tengan. Se usa al apagar el server.
+pub fn lock_state() -> Vec<MutexGuard<'static, ()>> {
+    [
+        &Q_MESSAGES_LOCK,
+        &RETAIN_MESSAGES_LOCK,
+        &RETAIN_EXPIRY_LOCK,
+        &RETAIN_PROPERTIES_LOCK,
+        &TOPIC_SUBS_LOCK,
+        &SHARED_SUBS_LOCK,
+        &USER_DB_LOCK,
+    ]
+    .iter()
+    .map(|lock| match lock.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    })
+    .collect()
+}
+
 fn write_to_path<T: serde::ser::Serialize + std::cmp::Eq + std::hash::Hash, U: serde::Serialize>(
     path: String,
     data: HashMap<T, U>,
@@ -130,55 +338,6 @@ fn read_from_path(path: String) -> String {
     data
 }
 
-fn append_maps_remove_q_mess(
-    mut old_map: HashMap<String, Vec<Vec<u8>>>,
-    new_map: HashMap<String, Vec<Vec<u8>>>,
-    user: String,
-) -> HashMap<String, Vec<Vec<u8>>> {
-    for k in old_map.clone().keys() {
-        if k.clone() == user.clone() {
-            old_map.remove(&user.clone());
-            old_map.insert(user.clone(), vec![]);
-        } else {
-            let mut values = new_map[k].clone();
-            values.append(&mut old_map[k].clone());
-            let mut unique: Vec<Vec<u8>> = vec![];
-            for value in values {
-                if !unique.contains(&value) {
-                    unique.push(value);
-                }
-            }
-            old_map.insert(k.clone(), unique);
-        }
-    }
-    old_map
-}
-
-fn append_maps_q_mess(
-    mut old_map: HashMap<String, Vec<Vec<u8>>>,
-    new_map: HashMap<String, Vec<Vec<u8>>>,
-) -> HashMap<String, Vec<Vec<u8>>> {
-    for k in old_map.clone().keys() {
-        let mut values = new_map[k].clone();
-        values.append(&mut old_map[k].clone());
-        let mut unique: Vec<Vec<u8>> = vec![];
-        for value in values {
-            if !unique.contains(&value) {
-                unique.push(value);
-            }
-        }
-        old_map.insert(k.clone(), unique);
-    }
-
-    for k in new_map.keys() {
-        old_map
-            .entry(k.clone())
-            .or_insert_with(|| new_map[k].clone());
-    }
-
-    old_map
-}
-
 fn append_maps_users(
     mut old_map: HashMap<u32, String>,
     new_map: HashMap<u32, String>,
@@ -0,0 +1,216 @@
+//! Limites de uso por cliente y por usuario: publishes y bytes por segundo, cantidad de
+//! suscripciones y tamaño maximo de paquete.
+//!
+//! Los limites por cliente se configuran en config.txt con `max_publish_rate`, `max_bytes_rate`,
+//! `max_subscriptions` y `max_packet_size`. En `user_limits.json` se pueden definir limites por
+//! usuario; los de rate se cuentan entre todas las conexiones del usuario, y los de suscripciones
+//! y tamaño de paquete se aplican a cada una de ellas:
+//!
+//! ```json
+//! {"sensor": {"publish_rate": 10, "bytes_rate": 2048, "subscriptions": 5, "packet_size": 128}}
+//! ```
+use serde_json::Value;
+use serializer::Mqtt5ReturnCodes;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::error;
+
+const USER_LIMITS_PATH: &str = "./user_limits.json";
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Ventanas de rate compartidas por las conexiones de cada usuario con limites.
+static USER_WINDOWS: OnceLock<Mutex<HashMap<String, Arc<Mutex<RateWindow>>>>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    publish_rate: Option<u64>,
+    bytes_rate: Option<u64>,
+    subscriptions: Option<usize>,
+    packet_size: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_config(config: &[Vec<String>]) -> Limits {
+        let value = |key: &str| {
+            crate::server::config_value(config, key).and_then(|v| match v.parse::<u64>() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    error!("[Server:Limits] {} invalido: {:?}", key, v);
+                    None
+                }
+            })
+        };
+        Limits {
+            publish_rate: value("max_publish_rate"),
+            bytes_rate: value("max_bytes_rate"),
+            subscriptions: value("max_subscriptions").map(|n| n as usize),
+            packet_size: value("max_packet_size").map(|n| n as usize),
+        }
+    }
+
+    fn from_json(value: &Value) -> Limits {
+        Limits {
+            publish_rate: value["publish_rate"].as_u64(),
+            bytes_rate: value["bytes_rate"].as_u64(),
+            subscriptions: value["subscriptions"].as_u64().map(|n| n as usize),
+            packet_size: value["packet_size"].as_u64().map(|n| n as usize),
+        }
+    }
+
+    /// Verifica el tamaño del paquete.
+    fn check_size(&self, size: usize) -> Result<(), Mqtt5ReturnCodes> {
+        match self.packet_size {
+            Some(max) if size > max => Err(Mqtt5ReturnCodes::MqttPacketInvalidSize),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verifica que las suscripciones actuales mas las pedidas no superen el maximo.
+    fn check_subscriptions(
+        &self,
+        current: usize,
+        requested: usize,
+    ) -> Result<(), Mqtt5ReturnCodes> {
+        match self.subscriptions {
+            Some(max) if current + requested > max => Err(Mqtt5ReturnCodes::MqttRcQuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Lee los limites por usuario de `user_limits.json`. Si no existe no hay limites por usuario.
+pub fn read_user_limits() -> HashMap<String, Limits> {
+    let data = match fs::read_to_string(USER_LIMITS_PATH) {
+        Ok(d) => d,
+        Err(_) => return HashMap::new(),
+    };
+    match parse_user_limits(&data) {
+        Ok(limits) => limits,
+        Err(e) => {
+            error!(
+                "[Server:Limits] user_limits.json invalido: {:?}",
+                e.to_string()
+            );
+            HashMap::new()
+        }
+    }
+}
+
+pub fn parse_user_limits(data: &str) -> Result<HashMap<String, Limits>, Box<dyn Error>> {
+    let value: Value = serde_json::from_str(data)?;
+    let users = value.as_object().ok_or("se esperaba un objeto")?;
+    Ok(users
+        .iter()
+        .map(|(user, limits)| (user.clone(), Limits::from_json(limits)))
+        .collect())
+}
+
+/// Publishes y bytes recibidos en la ventana de un segundo actual.
+#[derive(Clone, Debug)]
+pub struct RateWindow {
+    started: Instant,
+    publishes: u64,
+    bytes: u64,
+}
+
+impl RateWindow {
+    pub fn new(now: Instant) -> RateWindow {
+        RateWindow {
+            started: now,
+            publishes: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Suma el paquete a la ventana y verifica los limites de rate.
+    pub fn record(
+        &mut self,
+        now: Instant,
+        bytes: usize,
+        is_publish: bool,
+        limits: &Limits,
+    ) -> Result<(), Mqtt5ReturnCodes> {
+        if now.duration_since(self.started) >= RATE_WINDOW {
+            *self = RateWindow::new(now);
+        }
+        self.bytes += bytes as u64;
+        if is_publish {
+            self.publishes += 1;
+        }
+        let too_many_publishes = matches!(limits.publish_rate, Some(max) if self.publishes > max);
+        let too_many_bytes = matches!(limits.bytes_rate, Some(max) if self.bytes > max);
+        if too_many_publishes || too_many_bytes {
+            return Err(Mqtt5ReturnCodes::MqttRcMessageRateTooHigh);
+        }
+        Ok(())
+    }
+}
+
+/// Limites que se aplican a una conexion: los del cliente, y los del usuario con el que se
+/// autentico si tiene.
+#[derive(Clone)]
+pub struct Quota {
+    limits: Limits,
+    window: RateWindow,
+    user: Option<(Limits, Arc<Mutex<RateWindow>>)>,
+}
+
+impl Quota {
+    pub fn new(limits: Limits) -> Quota {
+        Quota {
+            limits,
+            window: RateWindow::new(Instant::now()),
+            user: None,
+        }
+    }
+
+    /// Aplica los limites de `user_limits.json` del usuario, si tiene.
+    pub fn set_user(&mut self, username: &str) {
+        let limits = match read_user_limits().get(username) {
+            Some(l) => *l,
+            None => {
+                self.user = None;
+                return;
+            }
+        };
+        let windows = USER_WINDOWS.get_or_init(|| Mutex::new(HashMap::new()));
+        let window = match windows.lock() {
+            Ok(mut w) => Arc::clone(
+                w.entry(username.to_string())
+                    .or_insert_with(|| Arc::new(Mutex::new(RateWindow::new(Instant::now())))),
+            ),
+            Err(_) => return,
+        };
+        self.user = Some((limits, window));
+    }
+
+    /// Verifica el tamaño y el rate de un paquete recibido.
+    pub fn check_packet(&mut self, size: usize, is_publish: bool) -> Result<(), Mqtt5ReturnCodes> {
+        self.limits.check_size(size)?;
+        let now = Instant::now();
+        self.window.record(now, size, is_publish, &self.limits)?;
+        if let Some((limits, window)) = &self.user {
+            limits.check_size(size)?;
+            if let Ok(mut window) = window.lock() {
+                window.record(now, size, is_publish, limits)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifica que el cliente pueda agregar `requested` suscripciones a las `current` que tiene.
+    pub fn check_subscriptions(
+        &self,
+        current: usize,
+        requested: usize,
+    ) -> Result<(), Mqtt5ReturnCodes> {
+        self.limits.check_subscriptions(current, requested)?;
+        match &self.user {
+            Some((limits, _)) => limits.check_subscriptions(current, requested),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,11 +1,22 @@
 use crate::server::Server;
-use tracing::info;
+use tracing::{error, info};
 extern crate serializer;
 
+mod admin;
+mod admission;
+mod bridge;
+mod cluster;
+mod expiry;
+mod http;
 mod json_helper;
+mod limits;
+mod metrics;
 mod packets;
+mod protocol;
 mod server;
 mod socket;
+mod stats;
+mod will;
 
 fn start_listening(server: &mut Server) {
     info!("[Server] Servidor comienza a escuchar.");
@@ -19,6 +30,10 @@ fn main() {
     tracing_subscriber::fmt().with_writer(non_blocking).init();
     info!("[Server] Inicio de servidor.");
     let mut server = Server::new();
+    let server_ref = server.clone();
+    if ctrlc::set_handler(move || server_ref.shutdown()).is_err() {
+        error!("[Server] No se pudo instalar el handler de SIGTERM/SIGINT");
+    }
     start_listening(&mut server);
 }
 
@@ -47,4 +62,780 @@ mod tests {
         data.append(&mut topic.get_filter().clone());
         data.append(&mut "sample message".to_string().into_bytes());
     }
+
+    #[test]
+    fn queue_messages_keep_order_and_duplicates() {
+        use crate::json_helper::QueueMessages;
+        use crate::packets::queue_message::QueueMessage;
+
+        let mut queue: QueueMessages = std::collections::HashMap::new();
+        let messages = vec![
+            QueueMessage::new(vec![0x32, 1, 2], 1),
+            QueueMessage::new(vec![0x32, 1, 2], 1),
+            QueueMessage::new(vec![0x30, 3], 0),
+        ];
+        queue.insert("client".to_string(), messages.clone());
+        let json = serde_json::to_string(&queue).unwrap();
+        let decoded: QueueMessages = serde_json::from_str(&json).unwrap();
+        assert!(decoded["client"] == messages);
+    }
+
+    #[test]
+    fn subscription_qos_of_user() {
+        use crate::packets::publish::subscription_qos;
+        use crate::packets::user_qos::UserQos;
+
+        let mut subs = std::collections::HashMap::new();
+        subs.insert(
+            "topic".to_string(),
+            vec![
+                UserQos::new("a".to_string(), 0),
+                UserQos::new("b".to_string(), 1),
+            ],
+        );
+        assert_eq!(subscription_qos(&subs, "topic", "b"), Some(1));
+        assert_eq!(subscription_qos(&subs, "topic", "c"), None);
+        assert_eq!(subscription_qos(&subs, "other", "a"), None);
+    }
+
+    #[test]
+    fn shared_subscription_round_robin() {
+        use crate::packets::shared_subscription::{parse_shared_filter, RoundRobin};
+        use crate::packets::user_qos::UserQos;
+
+        assert_eq!(
+            parse_shared_filter("$share/grupo/topic"),
+            Some(("grupo".to_string(), "topic".to_string()))
+        );
+        assert_eq!(parse_shared_filter("$share//topic"), None);
+        assert_eq!(parse_shared_filter("topic"), None);
+
+        let members = vec![
+            UserQos::new("a".to_string(), 1),
+            UserQos::new("b".to_string(), 0),
+        ];
+        let mut round_robin = RoundRobin::new();
+        let first = round_robin.candidates("topic", "grupo", &members);
+        assert_eq!(first[0].get_user(), "a");
+        round_robin.delivered("topic", "grupo", &members, "a");
+        let second = round_robin.candidates("topic", "grupo", &members);
+        assert_eq!(second[0].get_user(), "b");
+        assert_eq!(second[1].get_user(), "a");
+    }
+
+    #[test]
+    fn sys_topics_values() {
+        use crate::stats::{is_sys_topic, SysInfo, STATS};
+
+        let info = SysInfo {
+            uptime: 5,
+            connected_clients: 2,
+            ..SysInfo::default()
+        };
+        let topics = info.topics(&STATS);
+        assert!(topics.iter().all(|(topic, _)| is_sys_topic(topic)));
+        assert!(topics.contains(&("$SYS/broker/uptime".to_string(), "5".to_string())));
+        assert!(topics.contains(&("$SYS/broker/clients/connected".to_string(), "2".to_string())));
+        assert!(!is_sys_topic("topic"));
+        assert!(!is_sys_topic("$share/grupo/topic"));
+        assert!(!is_sys_topic("$SYSTEM"));
+    }
+
+    #[test]
+    fn metrics_render_prometheus_text() {
+        use crate::metrics::METRICS;
+        use crate::packets::queue_message::QueueMessage;
+        use crate::stats::STATS;
+        use std::time::Duration;
+
+        METRICS.fanout(Duration::from_millis(2));
+        let mut queues = std::collections::HashMap::new();
+        queues.insert(
+            "cliente".to_string(),
+            vec![QueueMessage::new(vec![0x30], 0)],
+        );
+        let text = METRICS.render(&STATS, 3, &queues);
+        assert!(text.contains("# TYPE mqtt_packets_received_total counter"));
+        assert!(text.contains("mqtt_connected_clients 3"));
+        assert!(text.contains("mqtt_queue_depth{client_id=\"cliente\"} 1"));
+        assert!(text.contains("mqtt_publish_fanout_seconds_bucket{le=\"0.005\"}"));
+        assert!(text.contains("mqtt_publish_fanout_seconds_bucket{le=\"+Inf\"}"));
+    }
+
+    #[test]
+    fn bridge_topic_remapping() {
+        use crate::bridge::parse_bridges;
+
+        let bridges = parse_bridges(
+            r#"[{"name": "central", "address": "127.0.0.1:1884",
+                 "topics": [{"pattern": "sensores/*", "direction": "out", "qos": 1,
+                             "remote_prefix": "sitio1/"},
+                            {"pattern": "cmd", "direction": "in",
+                             "local_prefix": "remoto/", "remote_prefix": "sitio1/"}]}]"#,
+        )
+        .unwrap();
+        let topics = bridges[0].get_topics();
+        assert_eq!(
+            topics[0].to_remote("sensores/temp"),
+            Some("sitio1/sensores/temp".to_string())
+        );
+        assert_eq!(topics[0].to_remote("otro/temp"), None);
+        assert_eq!(topics[0].to_local("sitio1/sensores/temp"), None);
+        assert_eq!(
+            topics[1].to_local("sitio1/cmd"),
+            Some("remoto/cmd".to_string())
+        );
+        assert_eq!(topics[1].to_remote("remoto/cmd"), None);
+        assert!(parse_bridges(r#"[{"name": "x"}]"#).is_err());
+    }
+
+    #[test]
+    fn bridge_skips_echoes_of_forwarded_messages() {
+        use crate::bridge::Echoes;
+        use std::time::{Duration, Instant};
+
+        let now = Instant::now();
+        let mut echoes = Echoes::new();
+        echoes.record("sitio1/cmd", "on", now);
+        echoes.record("sitio1/cmd", "on", now);
+        assert!(!echoes.take("sitio1/cmd", "off", now));
+        assert!(echoes.take("sitio1/cmd", "on", now));
+        assert!(echoes.take("sitio1/cmd", "on", now));
+        assert!(!echoes.take("sitio1/cmd", "on", now));
+
+        echoes.record("sitio1/cmd", "on", now);
+        assert!(!echoes.take("sitio1/cmd", "on", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn cluster_config() {
+        use crate::cluster::parse_cluster_config;
+
+        let config = parse_cluster_config(
+            r#"{"node_id": "a", "listen": "127.0.0.1:19001", "secret": "s",
+                "peers": ["127.0.0.1:19002", "127.0.0.1:19003"]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.get_node_id(), "a");
+        assert_eq!(
+            config.get_peers(),
+            vec!["127.0.0.1:19002".to_string(), "127.0.0.1:19003".to_string()]
+        );
+        assert!(parse_cluster_config(r#"{"node_id": "a", "peers": []}"#).is_err());
+        assert!(parse_cluster_config(r#"{"node_id": "a", "listen": "x", "peers": [1]}"#).is_err());
+        assert!(parse_cluster_config(r#"{"node_id": "a", "listen": "x", "peers": []}"#).is_err());
+    }
+
+    #[test]
+    fn connect_credentials() {
+        use crate::packets::connect::check_credentials;
+        use serializer::ConnectReturnCode;
+
+        let connect = |username: Option<&str>, password: Option<&str>| {
+            let flags = serializer::new_connect_flag(
+                Some(true),
+                None,
+                None,
+                None,
+                None,
+                Some(password.is_some()),
+                Some(username.is_some()),
+            )
+            .unwrap();
+            let payload = serializer::new_payload_connect(
+                "cliente".to_string(),
+                "".to_string(),
+                "".to_string(),
+                username.unwrap_or("").to_string(),
+                password.unwrap_or("").to_string(),
+                0,
+            )
+            .unwrap();
+            serializer::new_connect(flags, payload).unwrap()
+        };
+        let mut user_db = std::collections::HashMap::new();
+        user_db.insert("admin".to_string(), "secreto".to_string());
+        let accepted = |code| matches!(code, ConnectReturnCode::ConnectionAccepted);
+        let bad_credentials = |code| matches!(code, ConnectReturnCode::BadUserNameOrPassword);
+        assert!(accepted(check_credentials(&connect(None, None), &user_db)));
+        assert!(accepted(check_credentials(
+            &connect(Some("admin"), Some("secreto")),
+            &user_db
+        )));
+        assert!(bad_credentials(check_credentials(
+            &connect(Some("admin"), Some("otro")),
+            &user_db
+        )));
+        assert!(bad_credentials(check_credentials(
+            &connect(Some("nadie"), None),
+            &user_db
+        )));
+    }
+
+    #[test]
+    fn rate_window_limits() {
+        use crate::limits::{parse_user_limits, RateWindow};
+        use std::time::{Duration, Instant};
+
+        let limits = parse_user_limits(r#"{"sensor": {"publish_rate": 2, "bytes_rate": 100}}"#)
+            .unwrap()["sensor"];
+        let start = Instant::now();
+        let mut window = RateWindow::new(start);
+        assert!(window.record(start, 10, true, &limits).is_ok());
+        assert!(window.record(start, 10, false, &limits).is_ok());
+        assert!(window.record(start, 10, true, &limits).is_ok());
+        assert!(window.record(start, 10, true, &limits).is_err());
+        let next = start + Duration::from_secs(1);
+        assert!(window.record(next, 10, true, &limits).is_ok());
+        assert!(window.record(next, 200, false, &limits).is_err());
+        assert!(parse_user_limits("[]").is_err());
+    }
+
+    #[test]
+    fn admission_control() {
+        use crate::admission::{Admission, AdmissionControl};
+        use std::time::Instant;
+
+        let config = vec![
+            vec!["server".to_string(), "127.0.0.1".to_string()],
+            vec!["port".to_string(), "1883".to_string()],
+            vec!["deny_ips".to_string(), "10.0.0.*".to_string()],
+            vec!["max_connections".to_string(), "10".to_string()],
+            vec!["max_connections_per_ip".to_string(), "2".to_string()],
+            vec!["max_connect_rate".to_string(), "3".to_string()],
+        ];
+        let mut control = AdmissionControl::from_config(&config);
+        let now = Instant::now();
+        let ip = "192.168.0.5".parse().unwrap();
+        assert_eq!(
+            control.admit("10.0.0.7".parse().unwrap(), 0, 0, now),
+            Admission::Deny
+        );
+        assert_eq!(control.admit(ip, 0, 0, now), Admission::Accept);
+        assert_eq!(control.admit(ip, 1, 2, now), Admission::Unavailable);
+        assert_eq!(control.admit(ip, 10, 0, now), Admission::Unavailable);
+        assert_eq!(control.admit(ip, 1, 0, now), Admission::Unavailable);
+        let later = now + std::time::Duration::from_secs(1);
+        assert_eq!(control.admit(ip, 1, 1, later), Admission::Accept);
+    }
+
+    #[test]
+    fn message_expiry() {
+        use crate::expiry::{expires_at, is_expired, remaining};
+        use crate::packets::queue_message::QueueMessage;
+
+        let at = expires_at(Some(30), 1_000);
+        assert_eq!(at, Some(1_030));
+        assert!(!is_expired(at, 1_029));
+        assert!(is_expired(at, 1_030));
+        assert!(!is_expired(None, u64::MAX));
+        assert_eq!(remaining(at, 1_010), Some(20));
+        assert_eq!(remaining(None, 1_010), None);
+
+        let message = QueueMessage::new(vec![48, 0], 0).with_expiry(at);
+        let json = serde_json::to_string(&message).unwrap();
+        let decoded: QueueMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, message);
+        let old: QueueMessage = serde_json::from_str(r#"{"data":[48,0],"qos":0}"#).unwrap();
+        assert_eq!(old.get_expires_at(), None);
+    }
+
+    #[test]
+    fn stored_messages_keep_forwarded_properties() {
+        use crate::packets::publish::{register_publish, send_retain_messages_to_sub};
+        use crate::packets::queue_message::QueueMessage;
+        use crate::packets::subscribe::Subscribed;
+        use crate::protocol::ClientProtocol;
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+
+        let properties = serializer::new_properties()
+            .set_response_topic(Some("respuestas/cliente".to_string()))
+            .set_correlation_data(Some(vec![1, 2, 3]));
+        let message = QueueMessage::new(vec![48, 0], 0).with_properties(properties.clone());
+        let json = serde_json::to_string(&message).unwrap();
+        let decoded: QueueMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.get_properties(), properties);
+        let old: QueueMessage = serde_json::from_str(r#"{"data":[48,0],"qos":0}"#).unwrap();
+        assert_eq!(old.get_properties(), serializer::new_properties());
+
+        let _state = state_dir(
+            "retain_properties",
+            &[(
+                "topic_subscribers.json",
+                r#"{"pedidos":[{"user":"cliente","qos":0}]}"#,
+            )],
+        );
+        let flags = serializer::new_publish_packet_flags(Some(true), None, None, None).unwrap();
+        let topic = serializer::new_topic_filter("pedidos".to_string()).unwrap();
+        let publish = serializer::new_publish(flags, topic, "hola".to_string()).unwrap();
+        register_publish(&publish, None, &properties).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        let aliases = Arc::new(Mutex::new(serializer::new_topic_aliases(0, 0)));
+        let subscribed = Subscribed {
+            topic: "pedidos".to_string(),
+            retain_handling: serializer::RetainHandling::Always,
+            is_new: true,
+        };
+        send_retain_messages_to_sub(
+            &subscribed,
+            &mut accepted,
+            "cliente".to_string(),
+            &ClientProtocol::new(crate::socket::PROTOCOL_VERSION_5, aliases),
+        )
+        .unwrap();
+        let data = read_packet(&mut client);
+        let header = serializer::new_mqtt_header(data).unwrap();
+        let (retained, received) = serializer::new_publish_v5_by_hex(header).unwrap();
+        assert_eq!(retained.get_payload(), "hola");
+        assert_eq!(
+            received.get_response_topic(),
+            properties.get_response_topic()
+        );
+        assert_eq!(
+            received.get_correlation_data(),
+            properties.get_correlation_data()
+        );
+    }
+
+    #[test]
+    fn delayed_will_cancelled_on_reconnect() {
+        use crate::will::{cancel, schedule};
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let (tx, rx) = channel();
+        let (cancelled, late, replaced) = (tx.clone(), tx.clone(), tx.clone());
+        schedule("will_late", Duration::from_secs(60), move || {
+            let _result = late.send("will_late");
+        });
+        schedule("will_a", Duration::from_millis(50), move || {
+            let _result = cancelled.send("will_a");
+        });
+        schedule("will_b", Duration::from_secs(60), move || {
+            let _result = replaced.send("will_b viejo");
+        });
+        // El will nuevo del cliente reemplaza al pendiente y vence antes que el de `will_late`.
+        schedule("will_b", Duration::from_millis(50), move || {
+            let _result = tx.send("will_b");
+        });
+        assert!(cancel("will_a"));
+        assert!(!cancel("will_c"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok("will_b"));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(cancel("will_late"));
+        assert!(!cancel("will_b"));
+    }
+
+    #[test]
+    fn subscription_options_of_user() {
+        use crate::packets::user_qos::UserQos;
+
+        let filter =
+            serializer::new_topic_filter_with_qos("topic".to_string(), 0b0000_1101).unwrap();
+        let subscription = UserQos::from_filter("a".to_string(), &filter);
+        assert_eq!(subscription.get_qos(), 1);
+        assert!(subscription.get_no_local());
+        assert!(subscription.get_retain_as_published());
+
+        let json = serde_json::to_string(&subscription).unwrap();
+        let decoded: UserQos = serde_json::from_str(&json).unwrap();
+        assert!(decoded == subscription);
+        let old: UserQos = serde_json::from_str(r#"{"user":"a","qos":1}"#).unwrap();
+        assert!(!old.get_no_local() && !old.get_retain_as_published());
+        let seq: UserQos = serde_json::from_str(r#"["a",1,true,true]"#).unwrap();
+        assert!(seq == subscription);
+    }
+
+    /// Los tests que usan los archivos de estado del server corren de a uno, cada uno con su
+    /// directorio temporal como directorio actual.
+    static STATE_DIR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn state_dir(name: &str, files: &[(&str, &str)]) -> std::sync::MutexGuard<'static, ()> {
+        let guard = match STATE_DIR.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let dir = std::env::temp_dir().join(format!("mqtt_server_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let defaults = [
+            ("topic_subscribers.json", "{}"),
+            ("retain_messages.json", "{}"),
+            ("queue_messages.json", "{}"),
+            ("user_db.json", "{}"),
+            ("users.json", r#"{"0":""}"#),
+        ];
+        for (file, content) in defaults.iter().chain(files.iter()) {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        std::env::set_current_dir(&dir).unwrap();
+        guard
+    }
+
+    type Connections = std::sync::Arc<std::sync::Mutex<Vec<crate::socket::Socket>>>;
+
+    /// Request a la API de administracion con el token `secreto`.
+    fn admin_request(
+        connections: &Connections,
+        method: &str,
+        target: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (target, ""),
+        };
+        let mut request = crate::http::HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.to_string(),
+            ..Default::default()
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap();
+            request.query.insert(key.to_string(), value.to_string());
+        }
+        request
+            .headers
+            .insert("authorization".to_string(), "Bearer secreto".to_string());
+        let response = crate::admin::handle(request, connections, Some("secreto"));
+        (
+            response.status,
+            serde_json::from_str(&response.body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn admin_api_requires_token() {
+        use crate::admin::handle;
+        use crate::http::HttpRequest;
+
+        let _state = state_dir("admin_token", &[]);
+        let connections = Connections::default();
+        let request = |authorization: Option<&str>| {
+            let mut request = HttpRequest {
+                method: "GET".to_string(),
+                path: "/users".to_string(),
+                ..Default::default()
+            };
+            if let Some(value) = authorization {
+                request
+                    .headers
+                    .insert("authorization".to_string(), value.to_string());
+            }
+            request
+        };
+        assert_eq!(
+            handle(request(None), &connections, Some("secreto")).status,
+            401
+        );
+        assert_eq!(
+            handle(request(Some("Bearer otro")), &connections, Some("secreto")).status,
+            401
+        );
+        assert_eq!(
+            handle(
+                request(Some("Bearer secreto")),
+                &connections,
+                Some("secreto")
+            )
+            .status,
+            200
+        );
+        assert_eq!(handle(request(None), &connections, None).status, 200);
+        assert_eq!(admin_request(&connections, "PUT", "/users", "").0, 405);
+        assert_eq!(admin_request(&connections, "GET", "/otro", "").0, 404);
+    }
+
+    #[test]
+    fn admin_api_lists_and_kicks_clients() {
+        use crate::limits::Limits;
+        use crate::socket::Socket;
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        let _state = state_dir(
+            "admin_clients",
+            &[
+                ("users.json", r#"{"0":"","1":"c1","2":"c2"}"#),
+                (
+                    "topic_subscribers.json",
+                    r#"{"sensores":[{"user":"c1","qos":1}],"otro":[{"user":"c2","qos":0}]}"#,
+                ),
+                (
+                    "shared_subscribers.json",
+                    r#"{"alertas":{"grupo":[{"user":"c1","qos":0}]}}"#,
+                ),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let (sender, _receiver) = std::sync::mpsc::channel();
+        let connections = Connections::default();
+        connections
+            .lock()
+            .unwrap()
+            .push(Socket::new(accepted, sender, 1, Limits::default()));
+
+        let (status, clients) = admin_request(&connections, "GET", "/clients", "");
+        assert_eq!((status, clients), (200, serde_json::json!(["c1"])));
+
+        let (status, subscriptions) =
+            admin_request(&connections, "GET", "/clients/c1/subscriptions", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            subscriptions,
+            serde_json::json!([
+                {"topic": "alertas", "group": "grupo", "qos": 0},
+                {"topic": "sensores", "qos": 1}
+            ])
+        );
+
+        assert_eq!(
+            admin_request(&connections, "DELETE", "/clients/c2", "").0,
+            404
+        );
+        let (status, kicked) = admin_request(&connections, "DELETE", "/clients/c1", "");
+        assert_eq!((status, kicked["disconnected"].as_str()), (200, Some("c1")));
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn admin_api_retained_messages() {
+        let _state = state_dir(
+            "admin_retained",
+            &[(
+                "retain_messages.json",
+                r#"{"sensores":["20","21"],"vacio":[]}"#,
+            )],
+        );
+        let connections = Connections::default();
+        let (status, retained) = admin_request(&connections, "GET", "/retained", "");
+        assert_eq!(
+            (status, retained),
+            (200, serde_json::json!({"sensores": ["20", "21"]}))
+        );
+        assert_eq!(
+            admin_request(&connections, "DELETE", "/retained", "").0,
+            400
+        );
+        let (status, deleted) =
+            admin_request(&connections, "DELETE", "/retained?topic=sensores", "");
+        assert_eq!((status, deleted["deleted"].as_u64()), (200, Some(2)));
+        assert_eq!(
+            admin_request(&connections, "DELETE", "/retained?topic=sensores", "").0,
+            404
+        );
+        assert_eq!(
+            admin_request(&connections, "GET", "/retained", ""),
+            (200, serde_json::json!({}))
+        );
+    }
+
+    #[test]
+    fn admin_api_queues() {
+        use crate::json_helper::update_q_messages;
+        use crate::packets::queue_message::QueueMessage;
+
+        let _state = state_dir("admin_queues", &[]);
+        let flags = serializer::new_publish_packet_flags(None, None, None, None).unwrap();
+        let topic = serializer::new_topic_filter("sensores".to_string()).unwrap();
+        let publish = serializer::new_publish(flags, topic, "20".to_string()).unwrap();
+        update_q_messages(|queues| {
+            queues.insert(
+                "c1".to_string(),
+                vec![QueueMessage::new(publish.get_data(), 0)],
+            );
+            queues.insert("c2".to_string(), vec![]);
+        })
+        .unwrap();
+        let connections = Connections::default();
+
+        let (status, depths) = admin_request(&connections, "GET", "/queues", "");
+        assert_eq!(
+            (status, depths),
+            (200, serde_json::json!({"c1": 1, "c2": 0}))
+        );
+        let (status, queue) = admin_request(&connections, "GET", "/queues/c1", "");
+        assert_eq!(
+            (status, queue),
+            (
+                200,
+                serde_json::json!([{"topic": "sensores", "payload": "20", "qos": 0}])
+            )
+        );
+        assert_eq!(admin_request(&connections, "GET", "/queues/c3", "").0, 404);
+        let (status, purged) = admin_request(&connections, "DELETE", "/queues/c1", "");
+        assert_eq!((status, purged["purged"].as_u64()), (200, Some(1)));
+        assert_eq!(
+            admin_request(&connections, "GET", "/queues/c1", ""),
+            (200, serde_json::json!([]))
+        );
+    }
+
+    #[test]
+    fn admin_api_users() {
+        use crate::json_helper::read_user_db;
+
+        let _state = state_dir("admin_users", &[("user_db.json", r#"{"admin":"1234"}"#)]);
+        let connections = Connections::default();
+        let user = r#"{"username": "sensor", "password": "abc"}"#;
+        assert_eq!(admin_request(&connections, "POST", "/users", user).0, 201);
+        assert_eq!(admin_request(&connections, "POST", "/users", user).0, 200);
+        assert_eq!(admin_request(&connections, "POST", "/users", "{}").0, 400);
+        assert_eq!(read_user_db().unwrap()["sensor"], "abc");
+        assert_eq!(
+            admin_request(&connections, "GET", "/users", ""),
+            (200, serde_json::json!(["admin", "sensor"]))
+        );
+        assert_eq!(
+            admin_request(&connections, "DELETE", "/users/sensor", "").0,
+            200
+        );
+        assert_eq!(
+            admin_request(&connections, "DELETE", "/users/sensor", "").0,
+            404
+        );
+        assert!(!read_user_db().unwrap().contains_key("sensor"));
+    }
+
+    /// Conecta un cliente MQTT 5 a un socket del server con `limits`. El server lo atiende en
+    /// su thread como a cualquier cliente.
+    fn connect_v5(limits: crate::limits::Limits) -> std::net::TcpStream {
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let (sender, _receiver) = std::sync::mpsc::channel();
+        crate::socket::Socket::new(accepted, sender, 1, limits).handle_client();
+        let flags =
+            serializer::new_connect_flag(Some(true), None, None, None, None, None, None).unwrap();
+        let payload = serializer::new_payload_connect(
+            "limitado".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+            0,
+        )
+        .unwrap();
+        let properties = serializer::new_properties();
+        let connect =
+            serializer::new_connect_v5(flags, payload, properties.clone(), properties).unwrap();
+        client.write_all(&connect.get_data()).unwrap();
+        assert_eq!(read_packet(&mut client)[0] >> 4, 2);
+        client
+    }
+
+    fn read_packet(stream: &mut std::net::TcpStream) -> Vec<u8> {
+        use std::io::Read;
+
+        let mut data = vec![0_u8; 2];
+        stream.read_exact(&mut data).unwrap();
+        let mut rest = vec![0_u8; data[1] as usize];
+        stream.read_exact(&mut rest).unwrap();
+        data.append(&mut rest);
+        data
+    }
+
+    fn subscribe(stream: &mut std::net::TcpStream, topics: &[&str]) -> Vec<u8> {
+        use std::io::Write;
+
+        let filters = topics
+            .iter()
+            .map(|t| serializer::new_topic_filter_with_qos(t.to_string(), 0).unwrap())
+            .collect();
+        let subscribe = serializer::new_subscribe(filters).unwrap();
+        let data = subscribe
+            .get_data_v5(&serializer::new_properties())
+            .unwrap();
+        stream.write_all(&data).unwrap();
+        read_packet(stream)
+    }
+
+    #[test]
+    fn clients_subscribe_to_their_own_response_topic() {
+        use std::io::Write;
+
+        let _state = state_dir("response_topic", &[("topic_subscribers.json", "{}")]);
+        let mut client = connect_v5(crate::limits::Limits::default());
+
+        assert_eq!(
+            subscribe(&mut client, &["respuestas/otro", "respuestas/limitado"]),
+            vec![0x90, 5, 0, 0, 0, 0x80, 0x00]
+        );
+        // Las suscripciones se guardan despues del SUBACK: el PINGRESP llega cuando ya se guardaron.
+        client
+            .write_all(&serializer::new_pingreq_by_hex().get_data())
+            .unwrap();
+        assert_eq!(read_packet(&mut client), vec![0xd0, 0]);
+        let subs = crate::json_helper::read_topic_subs().unwrap();
+        assert!(!subs.contains_key("respuestas/otro"));
+        assert_eq!(subs["respuestas/limitado"][0].get_user(), "limitado");
+    }
+
+    #[test]
+    fn subscription_quota_counts_new_topics() {
+        use crate::limits::parse_user_limits;
+
+        let _state = state_dir(
+            "subscription_quota",
+            &[(
+                "topic_subscribers.json",
+                r#"{"casa/luz":[],"casa/puerta":[],"jardin":[]}"#,
+            )],
+        );
+        let limits = parse_user_limits(r#"{"cliente": {"subscriptions": 2}}"#).unwrap();
+        let mut client = connect_v5(limits["cliente"]);
+
+        // El wildcard alcanza tres topics, mas que el maximo: SUBACK de MQTT 5 con QuotaExceeded.
+        assert_eq!(subscribe(&mut client, &["*"]), vec![0x90, 4, 0, 0, 0, 0x97]);
+        assert_eq!(
+            subscribe(&mut client, &["casa/*"]),
+            vec![0x90, 4, 0, 0, 0, 0x00]
+        );
+        // Volver a suscribirse a los mismos topics no suma suscripciones.
+        assert_eq!(
+            subscribe(&mut client, &["casa/luz", "casa/puerta"]),
+            vec![0x90, 5, 0, 0, 0, 0x00, 0x00]
+        );
+        assert_eq!(
+            subscribe(&mut client, &["jardin"]),
+            vec![0x90, 4, 0, 0, 0, 0x97]
+        );
+    }
+
+    #[test]
+    fn packet_size_is_checked_before_reading_the_packet() {
+        use crate::limits::parse_user_limits;
+        use std::io::{Read, Write};
+
+        let _state = state_dir("packet_size", &[]);
+        let limits = parse_user_limits(r#"{"cliente": {"packet_size": 64}}"#).unwrap();
+        let mut client = connect_v5(limits["cliente"]);
+
+        // Solo el fixed header de un PUBLISH de 202 bytes: se desconecta sin esperar el resto.
+        client.write_all(&[0x30, 200]).unwrap();
+        let disconnect = read_packet(&mut client);
+        assert_eq!(disconnect[0], 0xE0);
+        assert_eq!(
+            disconnect[2],
+            serializer::Mqtt5ReturnCodes::MqttPacketInvalidSize as u8
+        );
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
 }
@@ -0,0 +1,240 @@
+//! Metricas del broker en formato de texto de Prometheus, servidas en `/metrics`.
+use crate::json_helper::QueueMessages;
+use crate::stats::Stats;
+use serializer::PacketType;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Limites superiores (en segundos) de los buckets del histograma de latencia de fan-out.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+const PACKET_TYPES: [(PacketType, &str); 14] = [
+    (PacketType::CONNECT, "CONNECT"),
+    (PacketType::CONNACK, "CONNACK"),
+    (PacketType::PUBLISH, "PUBLISH"),
+    (PacketType::PUBACK, "PUBACK"),
+    (PacketType::PUBREC, "PUBREC"),
+    (PacketType::PUBREL, "PUBREL"),
+    (PacketType::PUBCOMP, "PUBCOMP"),
+    (PacketType::SUBSCRIBE, "SUBSCRIBE"),
+    (PacketType::SUBACK, "SUBACK"),
+    (PacketType::UNSUSCRIBE, "UNSUBSCRIBE"),
+    (PacketType::UNSUBACK, "UNSUBACK"),
+    (PacketType::PINGREQ, "PINGREQ"),
+    (PacketType::PINGRESP, "PINGRESP"),
+    (PacketType::DISCONNECT, "DISCONNECT"),
+];
+
+/// Histograma acumulativo con buckets fijos.
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (i, limit) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *limit {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (i, limit) in LATENCY_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                limit,
+                self.buckets[i].load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Contadores globales que se actualizan desde los sockets, el server y los packets.
+pub struct Metrics {
+    packets_received: [AtomicU64; PACKET_TYPES.len()],
+    auth_failures: AtomicU64,
+    connections_opened: AtomicU64,
+    connections_closed: AtomicU64,
+    fanout_latency: Histogram,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            packets_received: [const { AtomicU64::new(0) }; PACKET_TYPES.len()],
+            auth_failures: AtomicU64::new(0),
+            connections_opened: AtomicU64::new(0),
+            connections_closed: AtomicU64::new(0),
+            fanout_latency: Histogram::new(),
+        }
+    }
+
+    pub fn packet_received(&self, packet_type: PacketType) {
+        // Los valores de PacketType empiezan en 1 (CONNECT).
+        let index = packet_type as usize - 1;
+        if let Some(counter) = self.packets_received.get(index) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Tiempo desde que llega un publish al server hasta que se termina de repartir a los suscriptores.
+    pub fn fanout(&self, elapsed: Duration) {
+        self.fanout_latency.observe(elapsed);
+    }
+
+    /// Genera el texto de `/metrics`. Los gauges (clientes conectados y colas) se calculan
+    /// en el momento a partir del estado del server.
+    pub fn render(
+        &self,
+        stats: &Stats,
+        connected_clients: usize,
+        queues: &QueueMessages,
+    ) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "mqtt_packets_received_total",
+            "Paquetes MQTT recibidos de los clientes, por tipo.",
+            "counter",
+        );
+        for (i, (_, name)) in PACKET_TYPES.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "mqtt_packets_received_total{{type=\"{}\"}} {}",
+                name,
+                self.packets_received[i].load(Ordering::Relaxed)
+            );
+        }
+
+        counter(
+            &mut out,
+            "mqtt_messages_received_total",
+            "Publish recibidos de los clientes.",
+            stats.get_messages_received(),
+        );
+        counter(
+            &mut out,
+            "mqtt_messages_sent_total",
+            "Publish enviados a los suscriptores.",
+            stats.get_messages_sent(),
+        );
+        counter(
+            &mut out,
+            "mqtt_bytes_received_total",
+            "Bytes recibidos de los clientes.",
+            stats.get_bytes_received(),
+        );
+        counter(
+            &mut out,
+            "mqtt_bytes_sent_total",
+            "Bytes de publish enviados a los suscriptores.",
+            stats.get_bytes_sent(),
+        );
+        counter(
+            &mut out,
+            "mqtt_auth_failures_total",
+            "Connect rechazados por usuario o password invalidos.",
+            self.auth_failures.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "mqtt_connections_opened_total",
+            "Conexiones TCP aceptadas.",
+            self.connections_opened.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "mqtt_connections_closed_total",
+            "Conexiones cerradas.",
+            self.connections_closed.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "mqtt_connected_clients",
+            "Clientes conectados.",
+            "gauge",
+        );
+        let _ = writeln!(out, "mqtt_connected_clients {}", connected_clients);
+
+        header(
+            &mut out,
+            "mqtt_queue_depth",
+            "Mensajes encolados para cada cliente desconectado.",
+            "gauge",
+        );
+        let mut clients: Vec<&String> = queues.keys().collect();
+        clients.sort();
+        for client in clients {
+            let _ = writeln!(
+                out,
+                "mqtt_queue_depth{{client_id=\"{}\"}} {}",
+                escape_label(client),
+                queues[client].len()
+            );
+        }
+
+        self.fanout_latency.render(
+            &mut out,
+            "mqtt_publish_fanout_seconds",
+            "Latencia de reparto de cada publish a sus suscriptores.",
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
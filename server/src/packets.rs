@@ -4,6 +4,10 @@
 pub mod connect;
 /// Procesamiento de publish packets
 pub mod publish;
+/// Mensajes encolados para clientes desconectados
+pub mod queue_message;
+/// Suscripciones compartidas entre varios clientes
+pub mod shared_subscription;
 /// Procesamiento de subscribe packets
 pub mod subscribe;
 /// Procesamiento de unsubscribe packets
@@ -1,33 +1,43 @@
+use crate::cluster::cluster;
 use crate::json_helper::{
-    read_q_messages, read_topic_subs, read_user_db, read_users, write_q_messages,
+    read_topic_subs, read_user_db, read_users, update_q_messages, update_shared_subs,
     write_topic_unsubs, write_users,
 };
+use crate::metrics::METRICS;
 use crate::packets::publish::send_queue_messages;
+use crate::packets::shared_subscription::remove_from_all;
+use crate::protocol::ClientProtocol;
 use serializer::mqtt_response::MqttError;
 use serializer::{
     new_connack, new_connect_return_code, Connect, ConnectAcknowledgeFlags, ConnectReturnCode,
     Mqtt5ReturnCodes,
 };
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::Write;
 use std::net::TcpStream;
 use std::time::Duration;
 use tracing::{error, info};
 
-/// Control de logica de paquete Connect
+/// CONNECT aceptado: el usuario con el que queda la conexion y si se retomo su sesion.
+pub struct AcceptedConnect {
+    pub user: (u32, String),
+    pub session_present: bool,
+}
+
+/// Control de logica de paquete Connect. Si se rechaza el CONNECT se responde el CONNACK con el
+/// error y se devuelve `None`. Si se acepta se prepara la sesion, y el CONNACK se envia con
+/// `accept_connect` cuando el socket termina de configurar la conexion.
 pub fn resolve_connect(
     connect: Connect,
     stream: &mut TcpStream,
     mut user: (u32, String),
-    read: &mut TcpStream,
-) -> Result<(u32, String), Box<dyn Error>> {
+    protocol: &ClientProtocol,
+) -> Result<Option<AcceptedConnect>, Box<dyn Error>> {
     let flag = connect.get_connect_flags();
     let payload = connect.get_payload();
     let client = payload.get_client_identifier();
-    let mut users = read_users()?;
-    let user_db = read_user_db()?;
     let mut connect_ack_flags = ConnectAcknowledgeFlags::Sp0;
-    let mut return_code: ConnectReturnCode = ConnectReturnCode::ConnectionAccepted;
     let username = payload.get_username();
     let password = payload.get_password();
     let mut keep_alive = payload.get_keep_alive();
@@ -59,13 +69,38 @@ pub fn resolve_connect(
         payload.get_will_topic(),
         payload.get_will_message()
     );
+
+    // Las credenciales se verifican antes de tocar la sesion: un CONNECT rechazado no puede
+    // desconectar al cliente que ya usa ese client id ni borrar o migrar su sesion.
+    let return_code = check_credentials(&connect, &read_user_db()?);
+    if let ConnectReturnCode::BadUserNameOrPassword = return_code {
+        METRICS.auth_failure();
+    }
+    if !matches!(return_code, ConnectReturnCode::ConnectionAccepted) {
+        info!(
+            "Enviando CONNACK: \n\
+        Connect Acknowledge Flags:  {:?}, \n\
+        Connect Return Code: {:?}",
+            connect_ack_flags, return_code
+        );
+        send_connack(stream, connect_ack_flags, return_code, protocol)?;
+        return Ok(None);
+    }
+
+    let resumed = match cluster() {
+        Some(cluster) => cluster.claim_session(client, flag.get_clean_session()),
+        None => false,
+    };
+    let mut users = read_users()?;
+    if resumed {
+        // La sesion persistente venia de otro nodo del cluster.
+        users.insert(user.0, client.clone());
+    }
     user = (user.0, client.clone());
     if flag.get_clean_session() {
         users.insert(user.0, client.clone());
         let mut subs = read_topic_subs()?;
         let mut topics = vec![];
-        let mut mess = read_q_messages()?;
-        mess.insert(client.clone(), vec![]);
         let keys = subs.keys().clone().collect::<Vec<&String>>();
         let mut mutable_keys = vec![];
         for key in keys {
@@ -87,20 +122,31 @@ pub fn resolve_connect(
                 subs.insert(sub.clone(), userqos);
             }
         }
-        if write_q_messages(mess).is_ok() {}
+        if update_q_messages(|mess| {
+            mess.insert(client.clone(), vec![]);
+        })
+        .is_err()
+        {
+            error!("error al limpiar la cola de mensajes de {:?}", client);
+        }
         if write_topic_unsubs(subs, client.clone(), topics).is_ok() {}
+        if update_shared_subs(|shared| remove_from_all(shared, client)).is_err() {
+            error!(
+                "error al limpiar las suscripciones compartidas de {:?}",
+                client
+            );
+        }
     } else {
         if !users.clone().values().any(|v| v.clone() == client.clone()) {
-            connect_ack_flags = ConnectAcknowledgeFlags::Sp0;
-            return_code = ConnectReturnCode::IdentifierRejected;
+            let return_code = ConnectReturnCode::IdentifierRejected;
             info!(
                 "Enviando CONNACK: \n\
             Connect Acknowledge Flags:  {:?}, \n\
             Connect Return Code: {:?}",
                 connect_ack_flags, return_code
             );
-            return match send_connack(stream, connect_ack_flags, return_code)? {
-                true => Ok((0, "".to_string())),
+            return match send_connack(stream, connect_ack_flags, return_code, protocol)? {
+                true => Ok(None),
                 false => Err(Box::new(MqttError {
                     error: Mqtt5ReturnCodes::MqttRcClientidNotValid,
                 })),
@@ -121,48 +167,71 @@ pub fn resolve_connect(
         }
     }
 
-    if flag.get_password_flag() && flag.get_username_flag() {
-        if user_db.contains_key(&username.clone()) {
-            if user_db[&username.clone()] == password.clone() {
-                return_code = ConnectReturnCode::ConnectionAccepted;
-            } else {
-                return_code = ConnectReturnCode::BadUserNameOrPassword;
-            }
-        } else {
-            return_code = ConnectReturnCode::BadUserNameOrPassword;
-        }
-    } else if flag.get_username_flag() && !flag.get_password_flag() {
-        if user_db.contains_key(&username.clone()) {
-            return_code = ConnectReturnCode::ConnectionAccepted;
-        } else {
-            return_code = ConnectReturnCode::BadUserNameOrPassword;
+    match write_users(users.clone()) {
+        Ok(_) => {}
+        Err(_) => {
+            error!("error al escribir users")
         }
-    } else if flag.get_password_flag() && !flag.get_username_flag() {
-        error!("[CONNECT]flag de password, y no de username");
-        return_code = ConnectReturnCode::InvalidProtocol;
-    } else if !flag.get_username_flag() && !flag.get_password_flag() {
-        return_code = ConnectReturnCode::ConnectionAccepted;
     }
+    Ok(Some(AcceptedConnect {
+        user,
+        session_present: matches!(connect_ack_flags, ConnectAcknowledgeFlags::Sp1),
+    }))
+}
 
+/// Envia el CONNACK de un CONNECT aceptado y despues los mensajes que se le encolaron al cliente
+/// mientras estaba desconectado.
+pub fn accept_connect(
+    stream: &mut TcpStream,
+    read: &mut TcpStream,
+    accepted: &AcceptedConnect,
+    protocol: &ClientProtocol,
+) -> Result<(), Box<dyn Error>> {
+    let connect_ack_flags = match accepted.session_present {
+        true => ConnectAcknowledgeFlags::Sp1,
+        false => ConnectAcknowledgeFlags::Sp0,
+    };
+    let return_code = ConnectReturnCode::ConnectionAccepted;
     info!(
         "Enviando CONNACK: \n\
     Connect Acknowledge Flags:  {:?}, \n\
     Connect Return Code: {:?}",
         connect_ack_flags, return_code
     );
-    let ret = send_connack(stream, connect_ack_flags, return_code)?;
-    if send_queue_messages(stream, user.1.clone(), read).is_ok() {}
-    match write_users(users.clone()) {
-        Ok(_) => {}
-        Err(_) => {
-            error!("error al escribir users")
-        }
-    }
-    match ret {
-        true => Ok(user),
-        false => Err(Box::new(MqttError {
+    if !send_connack(stream, connect_ack_flags, return_code, protocol)? {
+        return Err(Box::new(MqttError {
             error: Mqtt5ReturnCodes::MqttRcClientidNotValid,
-        })),
+        }));
+    }
+    if send_queue_messages(stream, accepted.user.1.clone(), read, protocol).is_ok() {}
+    Ok(())
+}
+
+/// Verifica el usuario y el password del CONNECT contra `user_db`.
+pub fn check_credentials(
+    connect: &Connect,
+    user_db: &HashMap<String, String>,
+) -> ConnectReturnCode {
+    let flag = connect.get_connect_flags();
+    let payload = connect.get_payload();
+    let username = payload.get_username();
+    let password = payload.get_password();
+    if flag.get_password_flag() && flag.get_username_flag() {
+        match user_db.get(username) {
+            Some(p) if p == password => ConnectReturnCode::ConnectionAccepted,
+            _ => ConnectReturnCode::BadUserNameOrPassword,
+        }
+    } else if flag.get_username_flag() && !flag.get_password_flag() {
+        if user_db.contains_key(username) {
+            ConnectReturnCode::ConnectionAccepted
+        } else {
+            ConnectReturnCode::BadUserNameOrPassword
+        }
+    } else if flag.get_password_flag() && !flag.get_username_flag() {
+        error!("[CONNECT]flag de password, y no de username");
+        ConnectReturnCode::InvalidProtocol
+    } else {
+        ConnectReturnCode::ConnectionAccepted
     }
 }
 
@@ -170,10 +239,11 @@ fn send_connack(
     stream: &mut TcpStream,
     connect_ack_flags: ConnectAcknowledgeFlags,
     return_code: ConnectReturnCode,
+    protocol: &ClientProtocol,
 ) -> Result<bool, Box<dyn Error>> {
     let connect_return_codes = new_connect_return_code(return_code);
     let connack = new_connack(connect_ack_flags, connect_return_codes);
-    match stream.write(&connack.get_data()) {
+    match stream.write(&protocol.encode_connack(&connack)) {
         Ok(_) => Ok(true),
         Err(e) => {
             error!("{}", e.to_string());
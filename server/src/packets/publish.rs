@@ -1,39 +1,103 @@
+use crate::expiry;
 use crate::json_helper;
 use crate::json_helper::{
-    read_q_messages, read_topic_subs, write_remove_q_messages, write_retain_messages,
-    write_topic_subs,
+    read_retain_expiry, read_retain_properties, read_topic_subs, take_q_messages,
+    update_q_messages, update_retain_expiry, update_retain_messages, update_retain_properties,
+    update_topic_subs, write_retain_messages,
 };
+use crate::packets::queue_message::QueueMessage;
+use crate::packets::subscribe::Subscribed;
+use crate::packets::user_qos::UserQos;
+use crate::protocol::{properties_from_json, properties_to_json, ClientProtocol};
 use crate::socket::Socket;
+use crate::stats::{is_sys_topic, STATS};
 use serializer::mqtt_response::MqttError;
 use serializer::{
-    new_mqtt_header, new_publish, new_publish_by_hex, new_publish_packet_flags, new_topic_filter,
-    Mqtt5ReturnCodes, PacketType, Publish, PublishFlag, TopicFilter,
+    new_mqtt_header, new_properties, new_publish, new_publish_by_hex, new_publish_packet_flags,
+    new_topic_filter, Mqtt5ReturnCodes, PacketType, Properties, Publish, PublishFlag,
+    RetainHandling, TopicFilter,
 };
+use std::cmp;
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::Write;
 use std::net::TcpStream;
 use std::sync::mpsc::Sender;
 use tracing::{error, info, warn};
 
+/// Publish que se envia al thread del server para que lo reparta, junto con quien lo origino:
+/// el client id del que lo publico, o el nombre del bridge por el que llego. Si el mensaje vence,
+/// lleva tambien el instante de vencimiento, y si llego de un cliente MQTT 5 las properties que se
+/// le reenvian a los suscriptores.
+#[derive(Clone)]
+pub struct IncomingPublish {
+    publish: Publish,
+    origin: String,
+    expires_at: Option<u64>,
+    properties: Properties,
+}
+
+impl IncomingPublish {
+    pub fn new(publish: Publish, origin: String) -> Self {
+        IncomingPublish {
+            publish,
+            origin,
+            expires_at: None,
+            properties: new_properties(),
+        }
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn get_expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    pub fn get_properties(&self) -> Properties {
+        self.properties.clone()
+    }
+
+    pub fn get_publish(&self) -> Publish {
+        self.publish.clone()
+    }
+
+    pub fn get_origin(&self) -> String {
+        self.origin.clone()
+    }
+}
+
 /// Logica de paquete Publish
 pub fn resolve_publish(
     publish: serializer::Publish,
     stream: &mut TcpStream,
-    sender: &Sender<Publish>,
+    sender: &Sender<IncomingPublish>,
+    origin: String,
+    expires_at: Option<u64>,
+    properties: Properties,
 ) -> Result<bool, Box<dyn Error>> {
     let flags = publish.get_flags();
     let topic = publish.get_topic();
 
-    write_retain(flags, topic.clone(), publish.clone());
-
-    let mut subs = read_topic_subs()?;
-    subs.entry(topic.get_topic()).or_insert_with(|| Vec::new());
-    match write_topic_subs(subs) {
-        Ok(_) => {}
-        Err(_) => {
-            error!("error al escribir topic subs")
+    if is_sys_topic(&topic.get_topic()) {
+        warn!(
+            "[Server:Publish] Los clientes no pueden publicar en {:?}",
+            topic.get_topic()
+        );
+        if flags.get_qos() == 1 {
+            send_puback(stream);
         }
+        return Ok(false);
     }
+
+    register_publish(&publish, expires_at, &properties)?;
     match flags.get_qos() {
         3 => {
             error!("QOS no valido para Publish")
@@ -41,11 +105,38 @@ pub fn resolve_publish(
         1 => send_puback(stream),
         _ => {}
     }
-    let ret = send_message_to_subs(sender, publish)?;
+    let incoming = IncomingPublish::new(publish, origin)
+        .with_expiry(expires_at)
+        .with_properties(properties);
+    let ret = send_message_to_subs(sender, incoming)?;
 
     Ok(ret)
 }
 
+/// Guarda el retain message del publish y crea el topic si no existia, para que se lo pueda suscribir.
+pub fn register_publish(
+    publish: &Publish,
+    expires_at: Option<u64>,
+    properties: &Properties,
+) -> Result<(), Box<dyn Error>> {
+    let topic = publish.get_topic();
+    write_retain(
+        publish.get_flags(),
+        topic.clone(),
+        publish.clone(),
+        expires_at,
+        properties,
+    );
+
+    let result = update_topic_subs(|subs| {
+        subs.entry(topic.get_topic()).or_insert_with(Vec::new);
+    });
+    if result.is_err() {
+        error!("error al escribir topic subs")
+    }
+    Ok(())
+}
+
 fn send_puback(stream: &mut TcpStream) {
     let puback = serializer::new_puback();
     info!("Enviando PUBACK: {:?}", puback.get_data());
@@ -57,30 +148,117 @@ fn send_puback(stream: &mut TcpStream) {
     }
 }
 
-pub fn write_retain(flags: PublishFlag, topic: TopicFilter, publish: Publish) {
+/// Guarda el payload como retain message del topic, junto con su vencimiento y las properties
+/// reenviadas si las tiene.
+pub fn write_retain(
+    flags: PublishFlag,
+    topic: TopicFilter,
+    publish: Publish,
+    expires_at: Option<u64>,
+    properties: &Properties,
+) {
     if flags.get_retain() {
-        let mut retain_message = json_helper::read_retain_messages().ok().unwrap();
-        if retain_message.contains_key(&topic.get_topic()) {
-            let mut value = retain_message[(&topic.get_topic())].clone();
-            value.push(publish.get_payload());
-            retain_message.insert(topic.get_topic(), value);
-        } else {
-            retain_message.insert(topic.get_topic(), vec![publish.get_payload()]);
-        }
-        match json_helper::write_retain_messages(retain_message) {
-            Ok(_) => {}
-            Err(_) => {
-                error!("error al escribir retain messages")
+        let result = update_retain_messages(|retain_message| {
+            let payloads = retain_message.entry(topic.get_topic()).or_default();
+            if !payloads.contains(&publish.get_payload()) {
+                payloads.push(publish.get_payload());
+            }
+        });
+        if result.is_err() {
+            error!("error al escribir retain messages")
+        }
+        let result = update_retain_expiry(|retain_expiry| {
+            let payloads = retain_expiry.entry(topic.get_topic()).or_default();
+            match expires_at {
+                Some(at) => {
+                    payloads.insert(publish.get_payload(), at);
+                }
+                None => {
+                    payloads.remove(&publish.get_payload());
+                }
+            }
+            if payloads.is_empty() {
+                retain_expiry.remove(&topic.get_topic());
             }
+        });
+        if result.is_err() {
+            error!("[Server:Publish] error al escribir el vencimiento de retain messages");
+        }
+        let stored = properties_to_json(properties);
+        let result = update_retain_properties(|retain_properties| {
+            let payloads = retain_properties.entry(topic.get_topic()).or_default();
+            if stored.as_object().is_some_and(|p| !p.is_empty()) {
+                payloads.insert(publish.get_payload(), stored);
+            } else {
+                payloads.remove(&publish.get_payload());
+            }
+            if payloads.is_empty() {
+                retain_properties.remove(&topic.get_topic());
+            }
+        });
+        if result.is_err() {
+            error!("[Server:Publish] error al escribir las properties de retain messages");
         }
     }
 }
 
+/// Borra los retain messages vencidos del topic y devuelve el vencimiento de los que quedan.
+fn remove_expired_retain(topic: &str, now: u64) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+    let mut payloads = read_retain_expiry()?.remove(topic).unwrap_or_default();
+    let expired: Vec<String> = payloads
+        .iter()
+        .filter(|(_, at)| expiry::is_expired(Some(**at), now))
+        .map(|(payload, _)| payload.clone())
+        .collect();
+    if expired.is_empty() {
+        return Ok(payloads);
+    }
+    info!(
+        "[Server:Publish] {:?} retain messages vencidos en {:?}",
+        expired.len(),
+        topic
+    );
+    update_retain_messages(|retain_message| {
+        if let Some(messages) = retain_message.get_mut(topic) {
+            messages.retain(|message| !expired.contains(message));
+        }
+    })?;
+    update_retain_expiry(|retain_expiry| {
+        if let Some(stored) = retain_expiry.get_mut(topic) {
+            stored.retain(|payload, _| !expired.contains(payload));
+            if stored.is_empty() {
+                retain_expiry.remove(topic);
+            }
+        }
+    })?;
+    update_retain_properties(|retain_properties| {
+        if let Some(stored) = retain_properties.get_mut(topic) {
+            stored.retain(|payload, _| !expired.contains(payload));
+            if stored.is_empty() {
+                retain_properties.remove(topic);
+            }
+        }
+    })?;
+    payloads.retain(|payload, _| !expired.contains(payload));
+    Ok(payloads)
+}
+
+/// Envia los retain messages del topic al cliente que se suscribio, segun el Retain Handling de la
+/// suscripcion: siempre, solo si la suscripcion es nueva, o nunca.
 pub fn send_retain_messages_to_sub(
-    topic: String,
+    subscribed: &Subscribed,
     stream: &mut TcpStream,
     user: String,
+    protocol: &ClientProtocol,
 ) -> Result<bool, Box<dyn Error>> {
+    match subscribed.retain_handling {
+        RetainHandling::Always => {}
+        RetainHandling::OnlyNew if subscribed.is_new => {}
+        _ => return Ok(false),
+    }
+    let topic = subscribed.topic.clone();
+    let retain_expiry = remove_expired_retain(&topic, expiry::now())?;
+    let retain_properties = read_retain_properties()?.remove(&topic).unwrap_or_default();
     let mut retain_message = json_helper::read_retain_messages()?;
     let subs = json_helper::read_topic_subs()?;
     if !retain_message.contains_key(&topic) {
@@ -101,7 +279,7 @@ pub fn send_retain_messages_to_sub(
             let p_flags = set_flag(userqos.get_qos())?;
             for message in messages.clone() {
                 match new_publish(p_flags, tf.clone(), message) {
-                    Ok(p) => publish_vec.push(p),
+                    Ok(mut p) => publish_vec.push(p.set_retain_flag(true)),
                     Err(e) => return Err(Box::new(MqttError { error: e })),
                 }
             }
@@ -110,8 +288,14 @@ pub fn send_retain_messages_to_sub(
     }
 
     for publ in publish_vec {
-        match stream.write(&publ.get_data()) {
-            Ok(_) => {
+        let expires_at = retain_expiry.get(&publ.get_payload()).copied();
+        let properties = match retain_properties.get(&publ.get_payload()) {
+            Some(stored) => properties_from_json(stored),
+            None => new_properties(),
+        };
+        match protocol.write_publish(stream, &publ, expires_at, &properties) {
+            Ok(size) => {
+                STATS.publish_sent(size);
                 if publ.get_flags().get_qos() == 1 {
                     //TODO puback
                 }
@@ -139,8 +323,8 @@ pub fn send_retain_messages_to_sub(
 }
 
 fn send_message_to_subs(
-    sender: &Sender<Publish>,
-    publish: Publish,
+    sender: &Sender<IncomingPublish>,
+    publish: IncomingPublish,
 ) -> Result<bool, Box<dyn Error>> {
     match sender.send(publish) {
         Ok(_) => {}
@@ -154,51 +338,111 @@ fn send_message_to_subs(
     Ok(true)
 }
 
+/// Entrega al cliente que se reconecta los mensajes que se le encolaron mientras estaba desconectado,
+/// en el orden en que llegaron. Cada mensaje se envia con el menor QoS entre el encolado y el de la
+/// suscripcion actual del cliente; si el cliente ya no esta suscripto al topic o el mensaje vencio,
+/// se descarta.
 pub fn send_queue_messages(
     stream: &mut TcpStream,
     user: String,
     read: &mut TcpStream,
+    protocol: &ClientProtocol,
 ) -> Result<bool, Box<dyn Error>> {
-    let mut mess_hash = read_q_messages()?;
-    if mess_hash.contains_key(&user) {
-        let messages = mess_hash[&user].clone();
-        for message in messages {
-            let header;
-            match new_mqtt_header(message) {
-                Ok(h) => header = h,
-                Err(e) => return Err(Box::new(MqttError { error: e })),
+    let messages = take_q_messages(user.clone())?;
+    let subs = read_topic_subs()?;
+    let now = expiry::now();
+    let mut pending = messages.into_iter();
+    while let Some(message) = pending.next() {
+        if expiry::is_expired(message.get_expires_at(), now) {
+            info!(
+                "[Server:Publish] mensaje encolado para {:?} vencido, descartado",
+                user
+            );
+            continue;
+        }
+        let header = match new_mqtt_header(message.get_data()) {
+            Ok(h) => h,
+            Err(e) => {
+                error!("[Server:Publish] mensaje encolado invalido {:?}", e);
+                continue;
             }
-            let publish = new_publish_by_hex(header)?;
-            match stream.write(&publish.get_data()) {
-                Ok(_) => {
-                    if publish.get_flags().get_qos() == 1 {
-                        match Socket::read_all(read)?.get_control_packet_type() {
-                            PacketType::PUBACK => {}
-                            _ => {
-                                warn!(
-                                        "[Server:Publish] packet invalido cuando enviando publish a subscriptor");
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "[Server:Publish] cuando enviando publish a subscriptor {:?}",
-                        e.to_string()
-                    )
+        };
+        let mut publish = match new_publish_by_hex(header) {
+            Ok(p) => p,
+            Err(e) => {
+                error!(
+                    "[Server:Publish] mensaje encolado invalido {:?}",
+                    e.to_string()
+                );
+                continue;
+            }
+        };
+        let granted_qos = match subscription_qos(&subs, &publish.get_topic().get_topic(), &user) {
+            Some(qos) => qos,
+            None => {
+                info!(
+                    "[Server:Publish] {:?} ya no esta suscripto a {:?}, mensaje descartado",
+                    user,
+                    publish.get_topic().get_topic()
+                );
+                continue;
+            }
+        };
+        let qos = cmp::min(message.get_qos(), granted_qos);
+        publish = publish.set_qos_flag(qos);
+        match protocol.write_publish(
+            stream,
+            &publish,
+            message.get_expires_at(),
+            &message.get_properties(),
+        ) {
+            Ok(size) => STATS.publish_sent(size),
+            Err(e) => {
+                error!(
+                    "[Server:Publish] cuando enviando publish a subscriptor {:?}",
+                    e.to_string()
+                );
+                let mut undelivered = vec![message];
+                undelivered.extend(pending);
+                requeue_messages(user, undelivered);
+                return Err(e.into());
+            }
+        }
+        if qos == 1 {
+            match Socket::read_all(read)?.get_control_packet_type() {
+                PacketType::PUBACK => {}
+                _ => {
+                    warn!("[Server:Publish] packet invalido cuando enviando publish a subscriptor");
                 }
             }
         }
-        mess_hash.remove(&user);
-        mess_hash.insert(user.clone(), vec![]);
-        if write_remove_q_messages(mess_hash, user).is_ok() {}
-        Ok(true)
-    } else {
-        error!("User not found in hashmap");
-        Err(Box::new(MqttError {
-            error: Mqtt5ReturnCodes::MqttRcNoMatchingSubscribers,
-        }))
     }
+    Ok(true)
+}
+
+/// Devuelve los mensajes que no se llegaron a entregar al principio de la cola del cliente,
+/// delante de los que se hayan encolado mientras tanto.
+fn requeue_messages(user: String, mut undelivered: Vec<QueueMessage>) {
+    let result = update_q_messages(|q_messages| {
+        let queue = q_messages.entry(user).or_insert_with(Vec::new);
+        undelivered.append(queue);
+        *queue = undelivered;
+    });
+    if result.is_err() {
+        error!("[Server:Publish] error al reencolar mensajes");
+    }
+}
+
+/// QoS con el que el cliente esta suscripto al topic, si es que lo esta.
+pub fn subscription_qos(
+    subs: &HashMap<String, Vec<UserQos>>,
+    topic: &str,
+    user: &str,
+) -> Option<u8> {
+    subs.get(topic)?
+        .iter()
+        .find(|userqos| userqos.get_user() == user)
+        .map(|userqos| userqos.get_qos())
 }
 
 fn set_flag(qos: u8) -> Result<PublishFlag, Box<dyn Error>> {
@@ -0,0 +1,200 @@
+use crate::protocol::{properties_from_json, properties_to_json};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use serializer::{new_properties, Properties};
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Mensaje encolado para un cliente desconectado. Guarda el publish ya codificado
+/// y el QoS efectivo con el que debe entregarse (el menor entre el del publish y el de la suscripcion).
+/// Si el mensaje vence, guarda el instante de vencimiento en segundos unix, y si lo publico un
+/// cliente MQTT 5, las properties que se le reenvian al suscriptor.
+#[derive(Clone, Debug)]
+pub struct QueueMessage {
+    data: Vec<u8>,
+    qos: u8,
+    expires_at: Option<u64>,
+    properties: Properties,
+}
+
+impl Serialize for QueueMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("QueueMessage", 4)?;
+        state.serialize_field("data", &self.data)?;
+        state.serialize_field("qos", &self.qos)?;
+        state.serialize_field("expires_at", &self.expires_at)?;
+        state.serialize_field("properties", &properties_to_json(&self.properties))?;
+        state.end()
+    }
+}
+
+impl PartialEq for QueueMessage {
+    fn eq(&self, other: &Self) -> bool {
+        other.qos == self.qos
+            && self.data == other.data
+            && self.expires_at == other.expires_at
+            && self.properties == other.properties
+    }
+}
+
+const FIELDS: &[&str] = &["data", "qos", "expires_at", "properties"];
+impl<'de> Deserialize<'de> for QueueMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        enum Field {
+            Data,
+            Qos,
+            ExpiresAt,
+            Properties,
+        }
+        impl<'de> Deserialize<'de> for Field {
+            fn deserialize<D>(deserializer: D) -> Result<Field, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                struct FieldVisitor;
+
+                impl<'de> Visitor<'de> for FieldVisitor {
+                    type Value = Field;
+
+                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                        formatter.write_str("`data`, `qos`, `expires_at` or `properties`")
+                    }
+
+                    fn visit_str<E>(self, value: &str) -> Result<Field, E>
+                    where
+                        E: de::Error,
+                    {
+                        match value {
+                            "data" => Ok(Field::Data),
+                            "qos" => Ok(Field::Qos),
+                            "expires_at" => Ok(Field::ExpiresAt),
+                            "properties" => Ok(Field::Properties),
+                            _ => Err(de::Error::unknown_field(value, FIELDS)),
+                        }
+                    }
+                }
+
+                deserializer.deserialize_identifier(FieldVisitor)
+            }
+        }
+
+        struct QueueMessageVisitor;
+
+        impl<'de> Visitor<'de> for QueueMessageVisitor {
+            type Value = QueueMessage;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("struct QueueMessage")
+            }
+
+            fn visit_seq<V>(self, mut seq: V) -> Result<QueueMessage, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                let data = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let qos = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let expires_at = seq.next_element()?.flatten();
+                let properties: Option<Value> = seq.next_element()?;
+                Ok(QueueMessage::new(data, qos)
+                    .with_expiry(expires_at)
+                    .with_properties(properties_from_json(&properties.unwrap_or_default())))
+            }
+
+            fn visit_map<V>(self, mut map: V) -> Result<QueueMessage, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut data = None;
+                let mut qos = None;
+                let mut expires_at = None;
+                let mut properties: Option<Value> = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Data => {
+                            if data.is_some() {
+                                return Err(de::Error::duplicate_field("data"));
+                            }
+                            data = Some(map.next_value()?);
+                        }
+                        Field::Qos => {
+                            if qos.is_some() {
+                                return Err(de::Error::duplicate_field("qos"));
+                            }
+                            qos = Some(map.next_value()?);
+                        }
+                        Field::ExpiresAt => {
+                            if expires_at.is_some() {
+                                return Err(de::Error::duplicate_field("expires_at"));
+                            }
+                            expires_at = Some(map.next_value()?);
+                        }
+                        Field::Properties => {
+                            if properties.is_some() {
+                                return Err(de::Error::duplicate_field("properties"));
+                            }
+                            properties = Some(map.next_value()?);
+                        }
+                    }
+                }
+                let data = data.ok_or_else(|| de::Error::missing_field("data"))?;
+                let qos = qos.ok_or_else(|| de::Error::missing_field("qos"))?;
+                // Las colas guardadas antes de que existieran el vencimiento y las properties no
+                // los tienen.
+                Ok(QueueMessage::new(data, qos)
+                    .with_expiry(expires_at.flatten())
+                    .with_properties(properties_from_json(&properties.unwrap_or_default())))
+            }
+        }
+
+        deserializer.deserialize_struct("QueueMessage", FIELDS, QueueMessageVisitor)
+    }
+}
+
+impl QueueMessage {
+    pub fn new(data: Vec<u8>, qos: u8) -> Self {
+        QueueMessage {
+            data,
+            qos,
+            expires_at: None,
+            properties: new_properties(),
+        }
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn get_data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    pub fn get_qos(&self) -> u8 {
+        self.qos
+    }
+
+    pub fn get_expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    pub fn get_properties(&self) -> Properties {
+        self.properties.clone()
+    }
+}
@@ -0,0 +1,108 @@
+//! Suscripciones compartidas: `$share/<grupo>/<filtro>`.
+//! Cada mensaje publicado en un topic se entrega a un solo miembro de cada grupo suscripto,
+//! repartiendo los mensajes entre los miembros conectados con round-robin.
+use crate::json_helper::SharedSubs;
+use crate::packets::subscribe::WildCard;
+use crate::packets::user_qos::UserQos;
+use std::collections::HashMap;
+
+pub const SHARE_PREFIX: &str = "$share/";
+
+/// Indica si el filtro de un subscribe/unsubscribe corresponde a una suscripcion compartida.
+pub fn is_shared(filter: &str) -> bool {
+    filter.starts_with(SHARE_PREFIX)
+}
+
+/// Separa `$share/<grupo>/<filtro>` en grupo y filtro. El grupo no puede ser vacio ni contener
+/// wildcards, y el filtro no puede ser vacio.
+pub fn parse_shared_filter(filter: &str) -> Option<(String, String)> {
+    let rest = filter.strip_prefix(SHARE_PREFIX)?;
+    let (group, topic) = rest.split_once('/')?;
+    if group.is_empty() || topic.is_empty() || group.contains('*') || group.contains('+') {
+        return None;
+    }
+    Some((group.to_string(), topic.to_string()))
+}
+
+/// Quita al cliente del grupo en los topics que coinciden con el filtro (que puede tener `*`).
+/// Devuelve los topics de los que se lo quito.
+pub fn remove_from_group(
+    shared: &mut SharedSubs,
+    group: &str,
+    filter: &str,
+    user: &str,
+) -> Vec<String> {
+    let wild_card = WildCard::new(filter);
+    let mut removed = vec![];
+    for (topic, groups) in shared.iter_mut() {
+        let matches = if filter.contains('*') {
+            wild_card.matches(topic)
+        } else {
+            topic == filter
+        };
+        if !matches {
+            continue;
+        }
+        if let Some(members) = groups.get_mut(group) {
+            let before = members.len();
+            members.retain(|m| m.get_user() != user);
+            if members.len() != before {
+                removed.push(topic.clone());
+            }
+        }
+    }
+    remove_empty_groups(shared);
+    removed
+}
+
+/// Quita al cliente de todos los grupos, por ejemplo al conectarse con clean session.
+pub fn remove_from_all(shared: &mut SharedSubs, user: &str) {
+    for groups in shared.values_mut() {
+        for members in groups.values_mut() {
+            members.retain(|m| m.get_user() != user);
+        }
+    }
+    remove_empty_groups(shared);
+}
+
+fn remove_empty_groups(shared: &mut SharedSubs) {
+    for groups in shared.values_mut() {
+        groups.retain(|_, members| !members.is_empty());
+    }
+    shared.retain(|_, groups| !groups.is_empty());
+}
+
+/// Lleva la cuenta de a que miembro de cada grupo le toca el proximo mensaje.
+#[derive(Default)]
+pub struct RoundRobin {
+    next: HashMap<(String, String), usize>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            next: HashMap::new(),
+        }
+    }
+
+    /// Devuelve los miembros del grupo en el orden en que hay que intentar entregarles el mensaje:
+    /// primero al que le toca por round-robin, y si no esta conectado o falla el envio, a los siguientes.
+    pub fn candidates(&self, topic: &str, group: &str, members: &[UserQos]) -> Vec<UserQos> {
+        if members.is_empty() {
+            return vec![];
+        }
+        let key = (topic.to_string(), group.to_string());
+        let start = self.next.get(&key).copied().unwrap_or(0) % members.len();
+        let mut ordered = members[start..].to_vec();
+        ordered.extend_from_slice(&members[..start]);
+        ordered
+    }
+
+    /// Registra que el mensaje se entrego a `user`, para que el proximo le toque al siguiente miembro.
+    pub fn delivered(&mut self, topic: &str, group: &str, members: &[UserQos], user: &str) {
+        if let Some(pos) = members.iter().position(|m| m.get_user() == user) {
+            let key = (topic.to_string(), group.to_string());
+            self.next.insert(key, (pos + 1) % members.len());
+        }
+    }
+}
@@ -1,71 +1,253 @@
 use crate::json_helper;
 use crate::json_helper::write_topic_subs;
+use crate::packets::shared_subscription::{is_shared, parse_shared_filter};
 use crate::packets::user_qos::UserQos;
-use serializer::{new_suback, new_topic_filter_with_qos, SubackReturnCode, Subscribe, TopicFilter};
-use std::collections::HashMap;
+use crate::protocol::ClientProtocol;
+use crate::stats::is_sys_topic;
+use serializer::{
+    new_suback, new_topic_filter_with_qos, RetainHandling, SubackReturnCode, Subscribe, TopicFilter,
+};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io;
 use std::io::Write;
 use std::net::TcpStream;
 use tracing::{error, warn};
 
-/// Logica de paquete Subscribe
+/// Prefijo de los topics de respuestas de los pedidos: cada cliente puede suscribirse a
+/// `respuestas/<client id>` aunque el topic todavia no exista.
+pub const RESPONSE_TOPIC_PREFIX: &str = "respuestas/";
+
+/// Topic al que se suscribio el cliente, con lo que hace falta para decidir si se le envian los
+/// retain messages.
+pub struct Subscribed {
+    pub topic: String,
+    pub retain_handling: RetainHandling,
+    pub is_new: bool,
+}
+
+/// Logica de paquete Subscribe. El SUBACK tiene un return code por filtro, en el mismo orden que
+/// el SUBSCRIBE; un filtro con `*` se acepta si coincide con al menos un topic existente. El topic
+/// de respuestas del cliente se crea al suscribirse.
 pub fn resolve_subscribe(
     stream: &mut TcpStream,
     subscribe: Subscribe,
     user: (u32, String),
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut topics = subscribe.get_topics();
+    protocol: &ClientProtocol,
+) -> Result<Vec<Subscribed>, Box<dyn Error>> {
     let mut topic_subs = json_helper::read_topic_subs()?;
+    let mut suback_payload: Vec<serializer::SubackReturnCode> = vec![];
+    let mut subscribed: Vec<Subscribed> = vec![];
+    let mut new_members: Vec<(String, String, u8)> = vec![];
 
-    for t in topics.clone() {
-        if t.get_topic().contains('*') {
-            topics.append(&mut wild_card_topics(
-                t.get_topic(),
-                topic_subs.clone(),
-                t.get_qos(),
-            ));
+    for filter in subscribe.get_topics() {
+        if is_shared(&filter.get_topic()) {
+            suback_payload.push(shared_subscribe(&filter, &topic_subs, &mut new_members));
+            continue;
         }
-    }
-    topics = remove_duplicates_and_wild_cards(topics);
-
-    let mut suback_payload: Vec<serializer::SubackReturnCode> = vec![];
-    let mut new_sub = vec![];
-    for topic in topics {
-        let topic_str = topic.get_topic();
-        if topic_subs.contains_key(&topic_str.clone()) {
-            let mut userqos = topic_subs[&topic_str].clone();
-            if !contains_user(userqos.clone(), (*user.1).to_string()) {
-                new_sub.push(topic_str.clone());
-                userqos.push(UserQos::new((*user.1).to_string(), topic.get_qos()));
+        let topics = matching_topics(&filter, &topic_subs, &user.1);
+        if topics.is_empty() {
+            warn!(
+                "[Server:Subscribe] No existe el topic {:?}",
+                filter.get_topic()
+            );
+            suback_payload.push(SubackReturnCode::Failure);
+            continue;
+        }
+        for topic in topics {
+            let topic_str = topic.get_topic();
+            let mut userqos = topic_subs.get(&topic_str).cloned().unwrap_or_default();
+            let subscription = UserQos::from_filter((*user.1).to_string(), &topic);
+            let is_new = !contains_user(userqos.clone(), (*user.1).to_string());
+            if is_new {
+                userqos.push(subscription);
             } else {
-                userqos = replace_qos(userqos, (*user.1).to_string(), topic.get_qos());
+                userqos = replace_subscription(userqos, subscription);
+            }
+            // Si dos filtros del mismo SUBSCRIBE alcanzan el topic se registra una sola vez.
+            if !subscribed.iter().any(|s| s.topic == topic_str) {
+                subscribed.push(Subscribed {
+                    topic: topic_str.clone(),
+                    retain_handling: topic.get_retain_handling(),
+                    is_new,
+                });
             }
             topic_subs.insert(topic_str, userqos);
-            suback_payload.push(suback_ret_code(topic.get_qos()));
-        } else {
-            warn!("[Server:Subscribe] No existe el topic {:?}", topic_str);
-            suback_payload.push(SubackReturnCode::Failure);
-            //si no existe tirar suback failure
         }
+        suback_payload.push(suback_ret_code(filter.get_qos()));
     }
-    if send_suback(stream, suback_payload).is_err() {
+    add_shared_members(new_members, &user.1);
+    if send_suback(stream, protocol, suback_payload).is_err() {
         error!("[Server:Subscribe] Error al mandar suback")
     }
     let result = write_topic_subs(topic_subs)?;
     if result {
-        Ok(new_sub.clone())
+        Ok(subscribed)
     } else {
         Ok(vec![])
     }
 }
 
+/// Topics existentes que alcanza el filtro, con las opciones de suscripcion del filtro. El topic de
+/// respuestas de `user` se devuelve aunque no exista.
+fn matching_topics(
+    filter: &TopicFilter,
+    topic_subs: &HashMap<String, Vec<UserQos>>,
+    user: &str,
+) -> Vec<TopicFilter> {
+    let topic = filter.get_topic();
+    if topic.contains('*') {
+        wild_card_topics(topic, topic_subs.clone(), filter.get_subscription_options())
+    } else if topic_subs.contains_key(&topic) || is_response_topic(&topic, user) {
+        vec![filter.clone()]
+    } else {
+        vec![]
+    }
+}
+
+/// Return code de una suscripcion compartida. Igual que en las suscripciones comunes el topic ya
+/// tiene que existir, y los filtros con `*` se expanden a los topics existentes. Los grupos a los
+/// que hay que agregar al cliente se acumulan en `new_members` como (topic, grupo, QoS).
+fn shared_subscribe(
+    filter: &TopicFilter,
+    topic_subs: &HashMap<String, Vec<UserQos>>,
+    new_members: &mut Vec<(String, String, u8)>,
+) -> SubackReturnCode {
+    let (group, topic) = match parse_shared_filter(&filter.get_topic()) {
+        Some(parsed) if filter.get_qos() <= 1 => parsed,
+        _ => {
+            warn!(
+                "[Server:Subscribe] Suscripcion compartida invalida {:?}",
+                filter.get_topic()
+            );
+            return SubackReturnCode::Failure;
+        }
+    };
+    let topics: Vec<String> = if topic.contains('*') {
+        wild_card_topics(
+            topic.clone(),
+            topic_subs.clone(),
+            filter.get_subscription_options(),
+        )
+        .iter()
+        .map(|t| t.get_topic())
+        .collect()
+    } else if topic_subs.contains_key(&topic) {
+        vec![topic.clone()]
+    } else {
+        vec![]
+    };
+    if topics.is_empty() {
+        warn!("[Server:Subscribe] No existe el topic {:?}", topic);
+        return SubackReturnCode::Failure;
+    }
+    for t in topics {
+        new_members.push((t, group.clone(), filter.get_qos()));
+    }
+    suback_ret_code(filter.get_qos())
+}
+
+/// Agrega al cliente a los grupos de las suscripciones compartidas. A las suscripciones
+/// compartidas no se les envian los retain messages.
+fn add_shared_members(new_members: Vec<(String, String, u8)>, user: &str) {
+    if new_members.is_empty() {
+        return;
+    }
+    let result = json_helper::update_shared_subs(|shared| {
+        for (topic, group, qos) in new_members {
+            let members = shared
+                .entry(topic)
+                .or_insert_with(HashMap::new)
+                .entry(group)
+                .or_insert_with(Vec::new);
+            match members.iter().position(|m| m.get_user() == user) {
+                Some(pos) => members[pos] = UserQos::new(user.to_string(), qos),
+                None => members.push(UserQos::new(user.to_string(), qos)),
+            }
+        }
+    });
+    if result.is_err() {
+        error!("[Server:Subscribe] Error al escribir suscripciones compartidas");
+    }
+}
+
+/// Cantidad de suscripciones del cliente, comunes y compartidas.
+pub fn count_subscriptions(user: &str) -> Result<usize, Box<dyn Error>> {
+    let subs = json_helper::read_topic_subs()?;
+    let shared = json_helper::read_shared_subs()?;
+    let common = subs
+        .values()
+        .filter(|users| users.iter().any(|u| u.get_user() == user))
+        .count();
+    let in_groups = shared
+        .values()
+        .flat_map(|groups| groups.values())
+        .filter(|members| members.iter().any(|u| u.get_user() == user))
+        .count();
+    Ok(common + in_groups)
+}
+
+/// Cantidad de suscripciones que agregaria el SUBSCRIBE: cada topic que alcanza un filtro con `*`
+/// cuenta como una, y no se cuentan las que el cliente ya tiene.
+pub fn count_new_subscriptions(subscribe: &Subscribe, user: &str) -> Result<usize, Box<dyn Error>> {
+    let subs = json_helper::read_topic_subs()?;
+    let shared = json_helper::read_shared_subs()?;
+    let mut new: HashSet<(String, Option<String>)> = HashSet::new();
+    for filter in subscribe.get_topics() {
+        if is_shared(&filter.get_topic()) {
+            let (group, topic) = match parse_shared_filter(&filter.get_topic()) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let topic_filter = match new_topic_filter_with_qos(topic, filter.get_qos()) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            for t in matching_topics(&topic_filter, &subs, user) {
+                let topic = t.get_topic();
+                let is_member = shared
+                    .get(&topic)
+                    .and_then(|groups| groups.get(&group))
+                    .is_some_and(|members| members.iter().any(|u| u.get_user() == user));
+                if !is_member {
+                    new.insert((topic, Some(group.clone())));
+                }
+            }
+            continue;
+        }
+        for t in matching_topics(&filter, &subs, user) {
+            let topic = t.get_topic();
+            let users = subs.get(&topic).cloned().unwrap_or_default();
+            if !contains_user(users, user.to_string()) {
+                new.insert((topic, None));
+            }
+        }
+    }
+    Ok(new.len())
+}
+
+fn is_response_topic(topic: &str, user: &str) -> bool {
+    !user.is_empty() && topic.strip_prefix(RESPONSE_TOPIC_PREFIX) == Some(user)
+}
+
+/// Responde el SUBSCRIBE con el mismo return code para todos los filtros, sin suscribir al cliente.
+pub fn reject_subscribe(
+    stream: &mut TcpStream,
+    protocol: &ClientProtocol,
+    filters: usize,
+    code: SubackReturnCode,
+) -> io::Result<usize> {
+    send_suback(stream, protocol, vec![code; filters])
+}
+
 fn send_suback(
     stream: &mut TcpStream,
+    protocol: &ClientProtocol,
     suback_payload: Vec<serializer::SubackReturnCode>,
 ) -> io::Result<usize> {
-    let suback = new_suback(suback_payload);
-    stream.write(&suback.get_data())
+    let data = protocol.encode_suback(&new_suback(suback_payload));
+    stream.write_all(&data)?;
+    Ok(data.len())
 }
 
 fn suback_ret_code(qos: u8) -> SubackReturnCode {
@@ -88,26 +270,31 @@ fn contains_user(userqos: Vec<UserQos>, user: String) -> bool {
     false
 }
 
-fn replace_qos(mut userqos: Vec<UserQos>, user: String, qos: u8) -> Vec<UserQos> {
+fn replace_subscription(mut userqos: Vec<UserQos>, subscription: UserQos) -> Vec<UserQos> {
     for i in 0..userqos.len() {
-        if userqos[i].get_user() == user.clone() {
-            userqos[i] = UserQos::new(user, qos);
+        if userqos[i].get_user() == subscription.get_user() {
+            userqos[i] = subscription;
             break;
         }
     }
     userqos
 }
-///Uso de WildCards para multiples subscribe de topics
+///Uso de WildCards para multiples subscribe de topics. Los topics que coinciden se suscriben con
+/// las mismas opciones de suscripcion que el filtro.
 pub fn wild_card_topics(
     topic: String,
     topic_subs: HashMap<String, Vec<UserQos>>,
-    qos: u8,
+    options: u8,
 ) -> Vec<TopicFilter> {
     let mut topics: Vec<TopicFilter> = vec![];
     for key in topic_subs.keys() {
+        // Los `$SYS` topics solo los alcanza un wildcard que tambien empiece con `$`.
+        if is_sys_topic(key) && !topic.starts_with('$') {
+            continue;
+        }
         let wild_card = WildCard::new(&topic);
         if wild_card.matches(key) {
-            match new_topic_filter_with_qos(key.clone(), qos) {
+            match new_topic_filter_with_qos(key.clone(), options) {
                 Ok(t) => topics.push(t),
                 Err(_) => {
                     error!("error al crear topic wild card")
@@ -1,5 +1,6 @@
 use crate::json_helper;
 use crate::json_helper::write_topic_unsubs;
+use crate::packets::shared_subscription::{is_shared, parse_shared_filter, remove_from_group};
 use crate::packets::subscribe::{remove_duplicates_and_wild_cards, WildCard};
 use crate::packets::user_qos::UserQos;
 use serializer::{new_topic_filter, new_unsuback, TopicFilter, Unsubscribe};
@@ -16,7 +17,10 @@ pub fn resolve_unsubscribe(
     unsubscribe: Unsubscribe,
     user: (u32, String),
 ) -> Result<(), Box<dyn Error>> {
-    let mut topics = unsubscribe.get_topic_filters();
+    let (shared, mut topics): (Vec<TopicFilter>, Vec<TopicFilter>) = unsubscribe
+        .get_topic_filters()
+        .into_iter()
+        .partition(|t| is_shared(&t.get_topic()));
     let mut unsub_topic = vec![];
     let mut topic_subs = json_helper::read_topic_subs()?;
 
@@ -54,6 +58,7 @@ pub fn resolve_unsubscribe(
             //si no existe tirar suback failure
         }
     }
+    resolve_shared_unsubscribe(shared, (*user.1).to_string());
     if send_unsuback(stream).is_err() {
         error!("[Server:Subscribe] Error al mandar suback")
     }
@@ -61,6 +66,35 @@ pub fn resolve_unsubscribe(
     Ok(())
 }
 
+/// Quita al cliente de los grupos de las suscripciones compartidas `$share/<grupo>/<filtro>`.
+fn resolve_shared_unsubscribe(filters: Vec<TopicFilter>, user: String) {
+    if filters.is_empty() {
+        return;
+    }
+    let result = json_helper::update_shared_subs(|shared| {
+        for filter in filters {
+            match parse_shared_filter(&filter.get_topic()) {
+                Some((group, topic)) => {
+                    if remove_from_group(shared, &group, &topic, &user).is_empty() {
+                        warn!(
+                            "[Server:Unsubscribe] {:?} no pertenece a {:?}",
+                            user,
+                            filter.get_topic()
+                        );
+                    }
+                }
+                None => warn!(
+                    "[Server:Unsubscribe] Suscripcion compartida invalida {:?}",
+                    filter.get_topic()
+                ),
+            }
+        }
+    });
+    if result.is_err() {
+        error!("[Server:Unsubscribe] Error al escribir suscripciones compartidas");
+    }
+}
+
 fn send_unsuback(stream: &mut TcpStream) -> io::Result<usize> {
     let unsuback = new_unsuback();
     stream.write(&unsuback.get_data())
@@ -1,6 +1,7 @@
 use serde::de::{MapAccess, SeqAccess, Visitor};
 use serde::ser::SerializeStruct;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serializer::TopicFilter;
 use std::fmt;
 use std::fmt::Formatter;
 
@@ -8,6 +9,8 @@ use std::fmt::Formatter;
 pub struct UserQos {
     user: String,
     qos: u8,
+    no_local: bool,
+    retain_as_published: bool,
 }
 
 impl Serialize for UserQos {
@@ -15,20 +18,25 @@ impl Serialize for UserQos {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("UserQos", 2)?;
+        let mut state = serializer.serialize_struct("UserQos", 4)?;
         state.serialize_field("user", &self.user)?;
         state.serialize_field("qos", &self.qos)?;
+        state.serialize_field("no_local", &self.no_local)?;
+        state.serialize_field("retain_as_published", &self.retain_as_published)?;
         state.end()
     }
 }
 
 impl PartialEq for UserQos {
     fn eq(&self, other: &Self) -> bool {
-        other.qos == self.qos && self.user == other.user
+        other.qos == self.qos
+            && self.user == other.user
+            && self.no_local == other.no_local
+            && self.retain_as_published == other.retain_as_published
     }
 }
 
-const FIELDS: &'static [&'static str] = &["user", "qos"];
+const FIELDS: &'static [&'static str] = &["user", "qos", "no_local", "retain_as_published"];
 impl<'de> Deserialize<'de> for UserQos {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -37,6 +45,8 @@ impl<'de> Deserialize<'de> for UserQos {
         enum Field {
             User,
             Qos,
+            NoLocal,
+            RetainAsPublished,
         }
         impl<'de> Deserialize<'de> for Field {
             fn deserialize<D>(deserializer: D) -> Result<Field, D::Error>
@@ -49,7 +59,7 @@ impl<'de> Deserialize<'de> for UserQos {
                     type Value = Field;
 
                     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                        formatter.write_str("`user` or `qos`")
+                        formatter.write_str("`user`, `qos`, `no_local` or `retain_as_published`")
                     }
 
                     fn visit_str<E>(self, value: &str) -> Result<Field, E>
@@ -59,6 +69,8 @@ impl<'de> Deserialize<'de> for UserQos {
                         match value {
                             "user" => Ok(Field::User),
                             "qos" => Ok(Field::Qos),
+                            "no_local" => Ok(Field::NoLocal),
+                            "retain_as_published" => Ok(Field::RetainAsPublished),
                             _ => Err(de::Error::unknown_field(value, FIELDS)),
                         }
                     }
@@ -87,7 +99,11 @@ impl<'de> Deserialize<'de> for UserQos {
                 let qos = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                Ok(UserQos::new(user, qos))
+                let no_local: Option<bool> = seq.next_element()?;
+                let retain_as_published: Option<bool> = seq.next_element()?;
+                Ok(UserQos::new(user, qos)
+                    .set_no_local(no_local.unwrap_or_default())
+                    .set_retain_as_published(retain_as_published.unwrap_or_default()))
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<UserQos, V::Error>
@@ -96,6 +112,8 @@ impl<'de> Deserialize<'de> for UserQos {
             {
                 let mut user = None;
                 let mut qos = None;
+                let mut no_local = None;
+                let mut retain_as_published = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::User => {
@@ -110,11 +128,26 @@ impl<'de> Deserialize<'de> for UserQos {
                             }
                             qos = Some(map.next_value()?);
                         }
+                        Field::NoLocal => {
+                            if no_local.is_some() {
+                                return Err(de::Error::duplicate_field("no_local"));
+                            }
+                            no_local = Some(map.next_value()?);
+                        }
+                        Field::RetainAsPublished => {
+                            if retain_as_published.is_some() {
+                                return Err(de::Error::duplicate_field("retain_as_published"));
+                            }
+                            retain_as_published = Some(map.next_value()?);
+                        }
                     }
                 }
                 let user = user.ok_or_else(|| de::Error::missing_field("user"))?;
                 let qos = qos.ok_or_else(|| de::Error::missing_field("qos"))?;
-                Ok(UserQos::new(user, qos))
+                // Las suscripciones guardadas antes de MQTT 5 no tienen opciones.
+                Ok(UserQos::new(user, qos)
+                    .set_no_local(no_local.unwrap_or_default())
+                    .set_retain_as_published(retain_as_published.unwrap_or_default()))
             }
         }
 
@@ -124,7 +157,29 @@ impl<'de> Deserialize<'de> for UserQos {
 
 impl UserQos {
     pub fn new(user: String, qos: u8) -> Self {
-        UserQos { user, qos }
+        UserQos {
+            user,
+            qos,
+            no_local: false,
+            retain_as_published: false,
+        }
+    }
+
+    /// Crea la suscripcion con las opciones de MQTT 5 del filtro.
+    pub fn from_filter(user: String, filter: &TopicFilter) -> Self {
+        UserQos::new(user, filter.get_qos())
+            .set_no_local(filter.get_no_local())
+            .set_retain_as_published(filter.get_retain_as_published())
+    }
+
+    pub fn set_no_local(mut self, no_local: bool) -> Self {
+        self.no_local = no_local;
+        self
+    }
+
+    pub fn set_retain_as_published(mut self, retain_as_published: bool) -> Self {
+        self.retain_as_published = retain_as_published;
+        self
     }
 
     pub fn get_user(&self) -> String {
@@ -134,4 +189,14 @@ impl UserQos {
     pub fn get_qos(&self) -> u8 {
         self.qos
     }
+
+    /// No enviarle al cliente sus propios publishes.
+    pub fn get_no_local(&self) -> bool {
+        self.no_local
+    }
+
+    /// Reenviar el publish con el flag de retain con el que llego.
+    pub fn get_retain_as_published(&self) -> bool {
+        self.retain_as_published
+    }
 }
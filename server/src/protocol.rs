@@ -0,0 +1,152 @@
+//! Codificacion de los paquetes que se envian a cada cliente segun lo que negocio en su CONNECT.
+//!
+//! A los clientes MQTT 5 se les envian las properties: el tiempo de vida restante de cada mensaje,
+//! los topic aliases, y el content type, el response topic y la correlation data que puso el que
+//! publico. Estas tres ultimas se guardan tambien con los mensajes encolados y con los retenidos,
+//! para enviarlas cuando se entreguen. Con `topic_alias_maximum` en config.txt se indica cuantos
+//! aliases acepta el server de cada cliente (por defecto 10, con 0 no se aceptan).
+use crate::expiry;
+use crate::server::config_value;
+use crate::socket::PROTOCOL_VERSION_5;
+use serde_json::{json, Map, Value};
+use serializer::{new_properties, Connack, Properties, Publish, Suback, TopicAliases};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use tracing::error;
+
+const TOPIC_ALIAS_MAXIMUM: u16 = 10;
+
+static ALIAS_MAXIMUM: OnceLock<u16> = OnceLock::new();
+
+/// Lee `topic_alias_maximum` de la configuracion. Se llama una sola vez, al iniciar el server.
+pub fn init(config: &[Vec<String>]) {
+    let maximum = match config_value(config, "topic_alias_maximum") {
+        Some(v) => v.parse::<u16>().unwrap_or_else(|_| {
+            error!("[Server:Protocol] topic_alias_maximum invalido: {:?}", v);
+            TOPIC_ALIAS_MAXIMUM
+        }),
+        None => TOPIC_ALIAS_MAXIMUM,
+    };
+    let _result = ALIAS_MAXIMUM.set(maximum);
+}
+
+/// Cantidad de topic aliases que el server acepta de cada cliente MQTT 5.
+pub fn topic_alias_maximum() -> u16 {
+    ALIAS_MAXIMUM.get().copied().unwrap_or(TOPIC_ALIAS_MAXIMUM)
+}
+
+/// Properties de un publish recibido que se les reenvian a los suscriptores.
+pub fn forwarded_properties(properties: &Properties) -> Properties {
+    new_properties()
+        .set_content_type(properties.get_content_type())
+        .set_response_topic(properties.get_response_topic())
+        .set_correlation_data(properties.get_correlation_data())
+}
+
+/// Properties reenviadas tal como se guardan en los archivos de estado. Las que no estan se omiten.
+pub fn properties_to_json(properties: &Properties) -> Value {
+    let mut stored = Map::new();
+    if let Some(content_type) = properties.get_content_type() {
+        stored.insert("content_type".to_string(), json!(content_type));
+    }
+    if let Some(topic) = properties.get_response_topic() {
+        stored.insert("response_topic".to_string(), json!(topic));
+    }
+    if let Some(correlation) = properties.get_correlation_data() {
+        stored.insert("correlation_data".to_string(), json!(correlation));
+    }
+    Value::Object(stored)
+}
+
+/// Properties reenviadas guardadas con `properties_to_json`. Si no hay nada guardado no tiene
+/// ninguna.
+pub fn properties_from_json(value: &Value) -> Properties {
+    let string = |key: &str| value[key].as_str().map(|s| s.to_string());
+    let correlation = value["correlation_data"].as_array().map(|bytes| {
+        bytes
+            .iter()
+            .filter_map(|b| b.as_u64().map(|b| b as u8))
+            .collect()
+    });
+    new_properties()
+        .set_content_type(string("content_type"))
+        .set_response_topic(string("response_topic"))
+        .set_correlation_data(correlation)
+}
+
+pub fn lock_aliases(aliases: &Mutex<TopicAliases>) -> MutexGuard<'_, TopicAliases> {
+    match aliases.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Version de MQTT del cliente y sus topic aliases, compartidos entre los clones de su socket.
+#[derive(Clone)]
+pub struct ClientProtocol {
+    protocol_version: u8,
+    aliases: Arc<Mutex<TopicAliases>>,
+}
+
+impl ClientProtocol {
+    pub fn new(protocol_version: u8, aliases: Arc<Mutex<TopicAliases>>) -> Self {
+        ClientProtocol {
+            protocol_version,
+            aliases,
+        }
+    }
+
+    /// Envia el publish al cliente, con las properties reenviadas del que lo publico, y devuelve
+    /// cuantos bytes se escribieron. Los aliases se asignan
+    /// y se escriben sin soltar la tabla, para que el cliente reciba cada alias despues del publish
+    /// que lo define aunque otro thread le este enviando publish al mismo tiempo.
+    pub fn write_publish(
+        &self,
+        stream: &mut TcpStream,
+        publish: &Publish,
+        expires_at: Option<u64>,
+        forwarded: &Properties,
+    ) -> std::io::Result<usize> {
+        if self.protocol_version != PROTOCOL_VERSION_5 {
+            stream.write_all(&publish.get_data())?;
+            return Ok(publish.get_data().len());
+        }
+        let now = expiry::now();
+        let properties = forwarded
+            .clone()
+            .set_message_expiry_interval(expiry::remaining(expires_at, now));
+        let mut aliases = lock_aliases(&self.aliases);
+        let data = match aliases.encode_outbound(publish, properties) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("[Server:Protocol] no se pudo codificar el publish: {:?}", e);
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+        };
+        stream.write_all(&data)?;
+        Ok(data.len())
+    }
+
+    /// Codifica el connack. A los clientes MQTT 5 se les anuncia cuantos topic aliases acepta el server.
+    pub fn encode_connack(&self, connack: &Connack) -> Vec<u8> {
+        if self.protocol_version != PROTOCOL_VERSION_5 {
+            return connack.get_data();
+        }
+        let maximum = lock_aliases(&self.aliases).get_inbound_maximum();
+        let properties = new_properties().set_topic_alias_maximum(Some(maximum));
+        connack
+            .get_data_v5(&properties)
+            .unwrap_or_else(|_| connack.get_data())
+    }
+
+    /// Codifica el suback con el formato de la version de MQTT del cliente.
+    pub fn encode_suback(&self, suback: &Suback) -> Vec<u8> {
+        if self.protocol_version != PROTOCOL_VERSION_5 {
+            return suback.get_data();
+        }
+        suback
+            .get_data_v5(&new_properties())
+            .unwrap_or_else(|_| suback.get_data())
+    }
+}
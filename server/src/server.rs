@@ -1,16 +1,56 @@
 //! Estructura del Server
-use crate::json_helper::{read_q_messages, read_topic_subs, read_users, write_q_messages};
+use crate::admin;
+use crate::admission::{Admission, AdmissionControl};
+use crate::bridge::{read_bridges, Bridges};
+use crate::cluster::{cluster, read_cluster_config, Cluster};
+use crate::expiry;
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::json_helper::{
+    lock_state, read_q_messages, read_retain_messages, read_shared_subs, read_topic_subs,
+    read_users, replace_retain_message, update_q_messages, update_topic_subs,
+};
+use crate::limits::Limits;
+use crate::metrics::METRICS;
+use crate::packets::publish::IncomingPublish;
+use crate::packets::queue_message::QueueMessage;
+use crate::packets::shared_subscription::RoundRobin;
 use crate::packets::user_qos::UserQos;
+use crate::protocol::{self, ClientProtocol};
 use crate::socket::Socket;
-use serializer::Publish;
+use crate::stats::{is_sys_topic, SysInfo, STATS, SYS_PREFIX};
+use crate::will;
+use serializer::{
+    new_connack, new_connect_by_hex, new_connect_return_code, new_publish,
+    new_publish_packet_flags, new_topic_aliases, new_topic_filter, ConnectAcknowledgeFlags,
+    ConnectReturnCode, PacketType, Properties, Publish,
+};
+use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
-use std::net::TcpListener;
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use std::{process, thread};
+use tracing::{error, info, warn};
+
+/// Cada cuantos segundos se publican los `$SYS` topics si no se configura `sys_interval`.
+const SYS_INTERVAL: u64 = 10;
+/// Cuanto se espera antes de volver a aceptar conexiones despues de un error.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+/// Cada cuanto el thread que reparte los publish revisa si le pidieron un flush.
+const FLUSH_POLL: Duration = Duration::from_millis(100);
+/// Cuanto se espera a que se repartan los publish pendientes al apagar el server.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Cuanto se espera el CONNECT de una conexion que se rechaza por estar el server al limite.
+const UNAVAILABLE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Conexiones rechazadas por estar el server al limite que pueden esperar su CONNACK. Si hay mas
+/// se cierran sin responder.
+const UNAVAILABLE_QUEUE: usize = 64;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 /// Estructura del servidor, contiene el socket donde escucha incoming connections,
 /// un vector con cada cliente conectado y el sender del MPSC channel que se le envía
@@ -20,7 +60,11 @@ pub(crate) struct Server {
     port: u16,
     socket: Arc<TcpListener>,
     connections: Arc<Mutex<Vec<Socket>>>,
-    sender: Sender<Publish>,
+    sender: Sender<IncomingPublish>,
+    limits: Limits,
+    admission: Arc<Mutex<AdmissionControl>>,
+    flush: Sender<Sender<()>>,
+    unavailable: SyncSender<TcpStream>,
 }
 
 impl Server {
@@ -35,7 +79,15 @@ impl Server {
         if binding.is_err() {
             error!("Error al realizar conexion.");
         }
-        let (sender, receiver) = mpsc::channel::<Publish>();
+        let (sender, receiver) = mpsc::channel::<IncomingPublish>();
+        let (flush, flush_receiver) = mpsc::channel::<Sender<()>>();
+        let (unavailable, unavailable_receiver) = mpsc::sync_channel(UNAVAILABLE_QUEUE);
+        // Un solo thread responde a las conexiones rechazadas por estar el server al limite.
+        thread::spawn(move || {
+            for stream in unavailable_receiver {
+                reject_unavailable(stream);
+            }
+        });
         let connections: Vec<Socket> = Vec::new();
 
         let server = Server {
@@ -44,12 +96,75 @@ impl Server {
             socket: Arc::new(binding.unwrap()),
             connections: Arc::new(Mutex::new(connections)),
             sender,
+            limits: Limits::from_config(&config),
+            admission: Arc::new(Mutex::new(AdmissionControl::from_config(&config))),
+            flush,
+            unavailable,
         };
 
+        expiry::init_default_ttl(&config);
+        protocol::init(&config);
+        will::init(&config);
+        let queue_qos0 = config_value(&config, "queue_qos0") == Some("true".to_string());
         let connection_ref = Arc::clone(&server.connections);
+        let bridges = Bridges::start(read_bridges(), server.sender.clone());
         thread::spawn(move || {
-            Self::receive_packets(connection_ref, receiver);
+            Self::receive_packets(
+                connection_ref,
+                receiver,
+                flush_receiver,
+                bridges,
+                queue_qos0,
+            );
         });
+        if let Some(cluster_config) = read_cluster_config() {
+            let connection_ref = Arc::clone(&server.connections);
+            let started = Cluster::start(
+                cluster_config,
+                connection_ref,
+                server.sender.clone(),
+                server.flush.clone(),
+            );
+            if started.is_err() {
+                error!("[Server] No se pudo iniciar el cluster");
+            }
+        }
+
+        let sys_interval = match config_value(&config, "sys_interval") {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                error!("[Server] sys_interval invalido: {:?}", value);
+                SYS_INTERVAL
+            }),
+            None => SYS_INTERVAL,
+        };
+        if let Some(port) = config_value(&config, "metrics_port") {
+            let connection_ref = Arc::clone(&server.connections);
+            let address = server.address.clone() + ":" + port.as_str();
+            let result = http::serve(address, move |request| {
+                serve_metrics(request, &connection_ref)
+            });
+            if result.is_err() {
+                error!("[Server] No se pudo iniciar el endpoint de metricas");
+            }
+        }
+        if let Some(port) = config_value(&config, "admin_port") {
+            // La API de administracion solo se expone localmente.
+            let connection_ref = Arc::clone(&server.connections);
+            let token = config_value(&config, "admin_token");
+            let result = http::serve("127.0.0.1:".to_string() + port.as_str(), move |request| {
+                admin::handle(request, &connection_ref, token.as_deref())
+            });
+            if result.is_err() {
+                error!("[Server] No se pudo iniciar la API de administracion");
+            }
+        }
+        if sys_interval > 0 {
+            let connection_ref = Arc::clone(&server.connections);
+            let sender = server.sender.clone();
+            thread::spawn(move || {
+                publish_sys_topics(connection_ref, sender, Duration::from_secs(sys_interval));
+            });
+        }
         server
     }
 
@@ -66,11 +181,29 @@ impl Server {
         let mut i = *users.keys().max().unwrap() + 1;
         loop {
             for stream in self.socket.incoming() {
-                let new_client = Socket::new(stream.unwrap(), self.sender.clone(), i);
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        // Errores como quedarse sin file descriptors son transitorios.
+                        error!("[Server] Error al aceptar conexion: {:?}", e.to_string());
+                        thread::sleep(ACCEPT_RETRY);
+                        continue;
+                    }
+                };
+                if is_shutting_down() {
+                    continue;
+                }
+                let stream = match self.admit(stream) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let new_client = Socket::new(stream, self.sender.clone(), i, self.limits);
+                METRICS.connection_opened();
                 let cloned_client = new_client.clone();
                 info!("Nueva conexion!");
                 match self.connections.lock() {
                     Ok(mut conn_vec) => {
+                        conn_vec.retain(|socket| socket.is_connected());
                         conn_vec.push(cloned_client);
                     }
                     Err(_) => {
@@ -84,113 +217,477 @@ impl Server {
         }
     }
 
+    /// Aplica el control de admision a una conexion nueva. Si no se acepta la conexion se cierra,
+    /// respondiendo antes CONNACK `ServerUnavailable` si el server esta al limite.
+    fn admit(&self, stream: TcpStream) -> Option<TcpStream> {
+        let ip = match stream.peer_addr() {
+            Ok(address) => address.ip(),
+            Err(_) => return None,
+        };
+        let (connected, connected_from_ip) = match self.connections.lock() {
+            Ok(mut conn_vec) => {
+                conn_vec.retain(|socket| socket.is_connected());
+                let from_ip = conn_vec
+                    .iter()
+                    .filter(|socket| socket.get_ip() == Some(ip))
+                    .count();
+                (conn_vec.len(), from_ip)
+            }
+            Err(_) => return None,
+        };
+        let admission = match self.admission.lock() {
+            Ok(mut control) => control.admit(ip, connected, connected_from_ip, Instant::now()),
+            Err(_) => return None,
+        };
+        let stream = match admission {
+            Admission::Accept => return Some(stream),
+            Admission::Deny => {
+                warn!("[Server] Conexion rechazada de {:?}", ip);
+                stream
+            }
+            Admission::Unavailable => {
+                warn!("[Server] Server al limite, conexion rechazada de {:?}", ip);
+                // Se espera el CONNECT en otro thread para no demorar las demas conexiones. Si ya
+                // hay demasiadas esperando, la conexion se cierra directamente.
+                match self.unavailable.try_send(stream) {
+                    Ok(()) => return None,
+                    Err(TrySendError::Full(s)) | Err(TrySendError::Disconnected(s)) => s,
+                }
+            }
+        };
+        let _result = stream.shutdown(Shutdown::Both);
+        None
+    }
+
     /// Lee el receiver del MPSC channel esperando incoming publish packets y los procesa.
-    fn receive_packets(connections: Arc<Mutex<Vec<Socket>>>, receiver: Receiver<Publish>) {
+    /// Cuando llega un pedido por `flush` reparte los publish pendientes y avisa al que lo pidio.
+    fn receive_packets(
+        connections: Arc<Mutex<Vec<Socket>>>,
+        receiver: Receiver<IncomingPublish>,
+        flush: Receiver<Sender<()>>,
+        bridges: Bridges,
+        queue_qos0: bool,
+    ) {
+        let mut round_robin = RoundRobin::new();
         loop {
-            match receiver.recv() {
-                Ok(mut packet) => {
-                    let topic = packet.clone().get_topic().get_topic();
-                    let subs;
-                    match read_topic_subs() {
-                        Ok(h) => {
-                            subs = h;
-                        }
-                        Err(_) => continue,
-                    }
-                    let userhash;
-                    match read_users() {
-                        Ok(h) => {
-                            userhash = h;
-                        }
-                        Err(_) => continue,
-                    }
-                    save_messages(
-                        subs.clone(),
-                        userhash.clone(),
-                        connections.clone(),
-                        packet.clone(),
+            match receiver.recv_timeout(FLUSH_POLL) {
+                Ok(incoming) => {
+                    dispatch(
+                        &connections,
+                        &incoming,
+                        &bridges,
+                        &mut round_robin,
+                        queue_qos0,
                     );
-                    let users = subs[&topic].clone();
-                    for user in users {
-                        let sockets = connections.lock().unwrap();
-                        for socket in sockets.to_vec() {
-                            if !userhash.contains_key(&socket.get_user()) {
-                                continue;
-                            }
-                            let u = userhash[&socket.get_user()].clone();
-                            if u == user.get_user() {
-                                if packet.get_flags().get_qos() == 0 {
-                                    match socket.get_write_stream().write(&packet.get_data()) {
-                                        Ok(_) => {}
-                                        Err(_) => {
-                                            error!("error al enviar a subs")
-                                        }
-                                    }
-                                } else if user.get_qos() == 1 {
-                                    let ret = socket.get_write_stream().write(&packet.get_data());
-                                    if ret.is_err() {
-                                        error!("error al enviar a subs")
-                                    } else {
-                                        match Socket::read_all(&mut socket.get_read_stream()) {
-                                            Ok(h) => {
-                                                if let serializer::PacketType::PUBACK =
-                                                    h.get_control_packet_type()
-                                                {
-                                                }
-                                            }
-                                            Err(_) => {
-                                                error!("error al leer")
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    packet = packet.set_qos_flag(0);
-                                    match socket.get_write_stream().write(&packet.get_data()) {
-                                        Ok(_) => {}
-                                        Err(_) => {
-                                            error!("error al enviar a subs")
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    info!("[Server] Received packet publish");
                 }
-                Err(_) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
             }
+            if let Ok(done) = flush.try_recv() {
+                while let Ok(incoming) = receiver.try_recv() {
+                    dispatch(
+                        &connections,
+                        &incoming,
+                        &bridges,
+                        &mut round_robin,
+                        queue_qos0,
+                    );
+                }
+                let _result = done.send(());
+            }
+        }
+    }
+
+    /// Apagado ordenado del server: deja de aceptar conexiones, desconecta a los clientes,
+    /// reparte los publish que quedaban en el channel y espera a que terminen las escrituras
+    /// de estado. Las sesiones persistentes quedan guardadas para cuando el server vuelva a iniciar.
+    /// Los last will de los clientes desconectados por el apagado no se publican.
+    pub fn shutdown(&self) {
+        info!("[Server] Apagando server.");
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        let sockets: Vec<Socket> = match self.connections.lock() {
+            Ok(conn_vec) => conn_vec
+                .iter()
+                .filter(|s| s.is_connected())
+                .cloned()
+                .collect(),
+            Err(_) => vec![],
+        };
+        for socket in sockets.iter() {
+            socket.shutdown_disconnect();
+        }
+        // Se espera a que terminen los threads de los clientes, para que los publish que lleguen
+        // despues se encolen en vez de enviarse a conexiones cerradas.
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while sockets.iter().any(|s| s.is_connected()) && Instant::now() < deadline {
+            thread::sleep(FLUSH_POLL);
+        }
+        let (done, wait) = mpsc::channel();
+        if self.flush.send(done).is_err() || wait.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+            error!("[Server] No se pudieron repartir los publish pendientes");
+        }
+        let _state = lock_state();
+        info!("[Server] Server apagado.");
+        process::exit(0);
+    }
+}
+
+/// Indica si el server se esta apagando.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Reparte un publish: lo encola para los clientes desconectados, lo envia a las suscripciones
+/// compartidas y a los suscriptores conectados, y lo reenvia a los bridges y al cluster.
+fn dispatch(
+    connections: &Arc<Mutex<Vec<Socket>>>,
+    incoming: &IncomingPublish,
+    bridges: &Bridges,
+    round_robin: &mut RoundRobin,
+    queue_qos0: bool,
+) {
+    let received = Instant::now();
+    let packet = incoming.get_publish();
+    let topic = packet.clone().get_topic().get_topic();
+    let expires_at = incoming.get_expires_at();
+    let properties = incoming.get_properties();
+    if expiry::is_expired(expires_at, expiry::now()) {
+        info!("[Server] Publish en {:?} vencido, descartado", topic);
+        return;
+    }
+    let subs;
+    match read_topic_subs() {
+        Ok(h) => {
+            subs = h;
+        }
+        Err(_) => return,
+    }
+    let userhash;
+    match read_users() {
+        Ok(h) => {
+            userhash = h;
+        }
+        Err(_) => return,
+    }
+    let origin = incoming.get_origin();
+    save_messages(
+        subs.clone(),
+        userhash.clone(),
+        connections.clone(),
+        incoming,
+        queue_qos0,
+    );
+    deliver_shared(connections, &userhash, incoming, round_robin, queue_qos0);
+    if let Some(users) = subs.get(&topic) {
+        for user in users {
+            if user.get_no_local() && user.get_user() == origin {
+                continue;
+            }
+            if let Some(socket) = find_socket(connections, &userhash, &user.get_user()) {
+                deliver(&socket, &packet, user, expires_at, &properties);
+            }
+        }
+    }
+    bridges.forward(incoming);
+    if let Some(cluster) = cluster() {
+        cluster.forward(incoming);
+    }
+    METRICS.fanout(received.elapsed());
+    info!("[Server] Received packet publish");
+}
+
+/// Envia el publish al socket con el menor QoS entre el del publish y el de la suscripcion. El
+/// PUBACK de los QoS 1 lo lee el thread del cliente, que es el unico que lee de su socket. A los
+/// clientes MQTT 5 se les envia el tiempo de vida que le queda al mensaje y las properties
+/// reenviadas. Devuelve false si no se pudo escribir en el socket.
+fn deliver(
+    socket: &Socket,
+    packet: &Publish,
+    subscription: &UserQos,
+    expires_at: Option<u64>,
+    properties: &Properties,
+) -> bool {
+    let publish = forwarded(packet, subscription);
+    let mut stream = socket.get_write_stream();
+    match socket
+        .get_protocol()
+        .write_publish(&mut stream, &publish, expires_at, properties)
+    {
+        Ok(size) => STATS.publish_sent(size),
+        Err(_) => {
+            error!("error al enviar a subs");
+            return false;
         }
     }
+    true
 }
 
+/// Publish tal como se le reenvia a una suscripcion: con el menor QoS entre el del publish y el de
+/// la suscripcion, y sin el flag de retain salvo que la suscripcion tenga Retain As Published.
+fn forwarded(packet: &Publish, subscription: &UserQos) -> Publish {
+    let mut publish = packet.clone();
+    let qos = cmp::min(packet.get_flags().get_qos(), subscription.get_qos());
+    if qos != packet.get_flags().get_qos() {
+        publish = publish.set_qos_flag(qos);
+    }
+    if packet.get_flags().get_retain() && !subscription.get_retain_as_published() {
+        publish = publish.set_retain_flag(false);
+    }
+    publish
+}
+
+/// Busca el socket conectado del cliente.
+pub(crate) fn find_socket(
+    connections: &Arc<Mutex<Vec<Socket>>>,
+    users: &HashMap<u32, String>,
+    client: &str,
+) -> Option<Socket> {
+    let sockets = connections.lock().ok()?;
+    sockets
+        .iter()
+        .filter(|socket| socket.is_connected())
+        .find(|socket| users.get(&socket.get_user()).map(|u| u.as_str()) == Some(client))
+        .cloned()
+}
+
+/// Entrega el publish a un solo miembro conectado de cada grupo compartido del topic, repartiendo con
+/// round-robin. Si el miembro elegido no esta conectado o falla el envio se prueba con el siguiente, y si
+/// no hay ninguno conectado el mensaje se encola para el proximo miembro con sesion.
+fn deliver_shared(
+    connections: &Arc<Mutex<Vec<Socket>>>,
+    users: &HashMap<u32, String>,
+    incoming: &IncomingPublish,
+    round_robin: &mut RoundRobin,
+    queue_qos0: bool,
+) {
+    let packet = incoming.get_publish();
+    let expires_at = incoming.get_expires_at();
+    let properties = incoming.get_properties();
+    let topic = packet.get_topic().get_topic();
+    let groups = match read_shared_subs() {
+        Ok(shared) => match shared.get(&topic) {
+            Some(groups) => groups.clone(),
+            None => return,
+        },
+        Err(_) => {
+            error!("[Server] error al leer suscripciones compartidas");
+            return;
+        }
+    };
+    for (group, members) in groups {
+        let candidates = round_robin.candidates(&topic, &group, &members);
+        let mut delivered = false;
+        for member in candidates.iter() {
+            if let Some(socket) = find_socket(connections, users, &member.get_user()) {
+                if deliver(&socket, &packet, member, expires_at, &properties) {
+                    round_robin.delivered(&topic, &group, &members, &member.get_user());
+                    delivered = true;
+                    break;
+                }
+            }
+        }
+        if delivered {
+            continue;
+        }
+        // Nadie del grupo esta conectado: se encola para el siguiente que tenga sesion persistente.
+        // Si ninguno la tiene el mensaje se descarta, porque no lo va a reclamar nadie.
+        let member = candidates
+            .iter()
+            .find(|member| users.values().any(|u| *u == member.get_user()));
+        if let Some(member) = member {
+            round_robin.delivered(&topic, &group, &members, &member.get_user());
+            let publish = forwarded(&packet, member);
+            let qos = publish.get_flags().get_qos();
+            if qos == 0 && !queue_qos0 {
+                continue;
+            }
+            let message = QueueMessage::new(publish.get_data(), qos)
+                .with_expiry(expires_at)
+                .with_properties(properties.clone());
+            let result = update_q_messages(|q_messages| {
+                q_messages
+                    .entry(member.get_user())
+                    .or_insert_with(Vec::new)
+                    .push(message);
+            });
+            if result.is_err() {
+                error!(
+                    "[Server] error al encolar mensaje para el grupo {:?}",
+                    group
+                );
+            }
+        }
+    }
+}
+
+/// Encola el publish para los suscriptores del topic que tienen sesion pero no estan conectados.
+/// Se guarda con el QoS efectivo (el menor entre el del publish y el de la suscripcion) y los mensajes
+/// QoS 0 solo se encolan si `queue_qos0` esta habilitado en la configuracion. Los mensajes se
+/// guardan con las properties reenviadas, para enviarlas cuando se entreguen.
 fn save_messages(
     subs: HashMap<String, Vec<UserQos>>,
-    mut users: HashMap<u32, String>,
+    users: HashMap<u32, String>,
     connections: Arc<Mutex<Vec<Socket>>>,
-    packet: Publish,
+    incoming: &IncomingPublish,
+    queue_qos0: bool,
 ) {
-    if let Ok(mut h) = read_q_messages() {
-        for socket in connections.lock().unwrap().to_vec() {
-            users.remove(&socket.get_user());
-        }
-        let topic = packet.get_topic().get_topic();
-        let userqos = subs[&topic].clone();
-        for user in userqos {
-            let mut contains = false;
-            for u in users.values() {
-                if u.clone() == user.get_user() {
-                    contains = true;
+    let packet = incoming.get_publish();
+    let origin = incoming.get_origin();
+    let expires_at = incoming.get_expires_at();
+    let properties = incoming.get_properties();
+    let userqos = match subs.get(&packet.get_topic().get_topic()) {
+        Some(u) => u.clone(),
+        None => return,
+    };
+    let online = online_clients(&users, &connections);
+    let mut queued: Vec<(String, QueueMessage)> = vec![];
+    for user in userqos {
+        if online.contains(&user.get_user()) || !users.values().any(|u| *u == user.get_user()) {
+            continue;
+        }
+        if user.get_no_local() && user.get_user() == origin {
+            continue;
+        }
+        let publish = forwarded(&packet, &user);
+        let qos = publish.get_flags().get_qos();
+        if qos == 0 && !queue_qos0 {
+            continue;
+        }
+        let message = QueueMessage::new(publish.get_data(), qos)
+            .with_expiry(expires_at)
+            .with_properties(properties.clone());
+        queued.push((user.get_user(), message));
+    }
+    if queued.is_empty() {
+        return;
+    }
+    let result = update_q_messages(|q_messages| {
+        for (user, message) in queued {
+            q_messages
+                .entry(user)
+                .or_insert_with(Vec::new)
+                .push(message);
+        }
+    });
+    if result.is_err() {
+        error!("[Server] error al encolar mensajes para clientes desconectados");
+    }
+}
+
+/// Publica cada `interval` los `$SYS/broker/...` topics como retain messages, para que los clientes
+/// puedan suscribirse y recibir el ultimo valor apenas se suscriben.
+fn publish_sys_topics(
+    connections: Arc<Mutex<Vec<Socket>>>,
+    sender: Sender<IncomingPublish>,
+    interval: Duration,
+) {
+    let started = Instant::now();
+    loop {
+        let topics = sys_info(&connections, started).topics(&STATS);
+        // Los topics tienen que existir para que se los pueda suscribir.
+        let result = update_topic_subs(|subs| {
+            for (topic, _) in topics.iter() {
+                subs.entry(topic.clone()).or_insert_with(Vec::new);
+            }
+        });
+        if result.is_err() {
+            error!("[Server] error al escribir los $SYS topics");
+        }
+        for (topic, value) in topics {
+            if replace_retain_message(topic.clone(), value.clone()).is_err() {
+                error!("[Server] error al guardar el retain message de {:?}", topic);
+            }
+            let publish = new_publish_packet_flags(Some(true), None, None, None)
+                .and_then(|flags| Ok((flags, new_topic_filter(topic)?)))
+                .and_then(|(flags, topic_filter)| new_publish(flags, topic_filter, value));
+            match publish {
+                Ok(p) => {
+                    if sender
+                        .send(IncomingPublish::new(p, SYS_PREFIX.to_string()))
+                        .is_err()
+                    {
+                        return;
+                    }
                 }
+                Err(e) => error!("[Server] error al crear publish $SYS {:?}", e),
             }
-            if contains {
-                let mut mess = h[&user.get_user().clone()].clone();
-                mess.push(packet.get_data());
-                h.remove(&user.get_user().clone());
-                h.insert(user.get_user().clone(), mess);
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Responde `GET /metrics` con las metricas en formato Prometheus.
+fn serve_metrics(request: HttpRequest, connections: &Arc<Mutex<Vec<Socket>>>) -> HttpResponse {
+    if request.path != "/metrics" {
+        return HttpResponse::not_found();
+    }
+    if request.method != "GET" {
+        return HttpResponse::text(405, "method not allowed");
+    }
+    let users = read_users().unwrap_or_default();
+    let connected = online_clients(&users, connections)
+        .iter()
+        .filter(|client| !client.is_empty())
+        .count();
+    let queues = read_q_messages().unwrap_or_default();
+    HttpResponse::new(
+        200,
+        "text/plain; version=0.0.4",
+        METRICS.render(&STATS, connected, &queues),
+    )
+}
+
+/// Calcula el estado actual del broker a partir de los sockets conectados y los archivos del server.
+fn sys_info(connections: &Arc<Mutex<Vec<Socket>>>, started: Instant) -> SysInfo {
+    let users = read_users().unwrap_or_default();
+    let online: Vec<String> = online_clients(&users, connections)
+        .into_iter()
+        .filter(|client| !client.is_empty())
+        .collect();
+    let mut subscribers: Vec<String> = vec![];
+    for user_qos in read_topic_subs().unwrap_or_default().values() {
+        subscribers.extend(user_qos.iter().map(|u| u.get_user()));
+    }
+    for groups in read_shared_subs().unwrap_or_default().values() {
+        for members in groups.values() {
+            subscribers.extend(members.iter().map(|u| u.get_user()));
+        }
+    }
+    let retained_messages = read_retain_messages()
+        .unwrap_or_default()
+        .iter()
+        .filter(|(topic, _)| !is_sys_topic(topic))
+        .map(|(_, messages)| messages.len())
+        .sum();
+    let queued_messages = read_q_messages()
+        .unwrap_or_default()
+        .values()
+        .map(|messages| messages.len())
+        .sum();
+    SysInfo {
+        uptime: started.elapsed().as_secs(),
+        connected_clients: online.len(),
+        subscriptions: subscribers.len(),
+        active_subscriptions: subscribers.iter().filter(|u| online.contains(u)).count(),
+        retained_messages,
+        queued_messages,
+    }
+}
+
+/// Client ids de los clientes que tienen un socket conectado.
+pub(crate) fn online_clients(
+    users: &HashMap<u32, String>,
+    connections: &Arc<Mutex<Vec<Socket>>>,
+) -> Vec<String> {
+    let mut online = vec![];
+    if let Ok(sockets) = connections.lock() {
+        for socket in sockets.iter().filter(|s| s.is_connected()) {
+            if let Some(client) = users.get(&socket.get_user()) {
+                online.push(client.clone());
             }
         }
-        if write_q_messages(h).is_ok() {}
     }
+    online
 }
 
 fn decode_config() -> Vec<Vec<String>> {
@@ -230,5 +727,44 @@ fn decode_config() -> Vec<Vec<String>> {
         "[Server] Obteniendo configuraciones: Servidor:{:?}; Puerto:{:?}",
         server[1], port[1]
     );
-    return vec![server, port];
+    let mut config = vec![server, port];
+    // El resto de las claves son opcionales, por ejemplo `queue_qos0:true`.
+    for entry in data.iter().skip(2) {
+        let option: Vec<String> = entry.split(':').map(|s| s.trim().to_string()).collect();
+        if option.len() == 2 && !option[0].is_empty() {
+            config.push(option);
+        } else if !entry.trim().is_empty() {
+            error!("[Server] Opcion de configuracion invalida: {:?}", entry);
+        }
+    }
+    config
+}
+
+/// Busca el valor de una opcion de configuracion opcional.
+pub(crate) fn config_value(config: &[Vec<String>], key: &str) -> Option<String> {
+    config
+        .iter()
+        .skip(2)
+        .find(|option| option[0] == key)
+        .map(|option| option[1].clone())
+}
+
+/// Responde CONNACK `ServerUnavailable` con la version de MQTT del CONNECT de la conexion y la
+/// cierra. Si el cliente no envia el CONNECT a tiempo se cierra sin responder.
+fn reject_unavailable(mut stream: TcpStream) {
+    let _result = stream.set_read_timeout(Some(UNAVAILABLE_TIMEOUT));
+    let connect = Socket::read_all(&mut stream)
+        .ok()
+        .filter(|header| matches!(header.get_control_packet_type(), PacketType::CONNECT))
+        .and_then(|header| new_connect_by_hex(header).ok());
+    if let Some(connect) = connect {
+        let aliases = Arc::new(Mutex::new(new_topic_aliases(0, 0)));
+        let protocol = ClientProtocol::new(connect.get_protocol_version(), aliases);
+        let connack = new_connack(
+            ConnectAcknowledgeFlags::Sp0,
+            new_connect_return_code(ConnectReturnCode::ServerUnavailable),
+        );
+        let _result = stream.write_all(&protocol.encode_connack(&connack));
+    }
+    let _result = stream.shutdown(Shutdown::Both);
 }
@@ -1,17 +1,35 @@
 //! Estructura que almacena la información de cada client
-use crate::json_helper::{read_topic_subs, write_topic_subs};
+use crate::expiry;
+use crate::json_helper::{read_topic_subs, update_shared_subs, write_topic_subs};
+use crate::limits::{Limits, Quota};
+use crate::metrics::METRICS;
 use crate::packets;
+use crate::packets::publish::IncomingPublish;
+use crate::packets::shared_subscription::remove_from_all;
+use crate::protocol::{self, lock_aliases, ClientProtocol};
+use crate::server::is_shutting_down;
+use crate::stats::STATS;
+use crate::will;
 use serializer::mqtt_response::Mqtt5ReturnCodes::MqttRcProtocolError;
 use serializer::mqtt_response::MqttError;
-use serializer::{new_mqtt_header, Connect, Mqtt5ReturnCodes, MqttHeader, PacketType, Publish};
+use serializer::{
+    new_disconnect_with_reason, new_mqtt_header, new_properties, new_topic_aliases, Connect,
+    Mqtt5ReturnCodes, MqttHeader, PacketType, Properties, Publish, SubackReturnCode, TopicAliases,
+};
 use std::error::Error;
 use std::io::ErrorKind::WouldBlock;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{cmp, thread};
 use tracing::{error, info, warn};
 
+pub(crate) const PROTOCOL_VERSION_3: u8 = 4;
+pub(crate) const PROTOCOL_VERSION_5: u8 = 5;
+
 /// Estructura que guarda la información de cada conexion. Un socket es un cliente conectado.
 /// Posee dos TcpStream, uno para read y otro para write. Sender es el sender de un MPSC channel
 /// donde se envían los Publish packets al servidor para que este reparta a los correspondientes clientes.
@@ -19,20 +37,40 @@ pub struct Socket {
     user: (u32, String),
     read: TcpStream,
     write: TcpStream,
-    sender: Sender<Publish>,
+    sender: Sender<IncomingPublish>,
     last_will: Vec<u8>,
+    will_properties: Properties,
+    connected: Arc<AtomicBool>,
+    quota: Quota,
+    protocol_version: Arc<AtomicU8>,
+    aliases: Arc<Mutex<TopicAliases>>,
+    ip: Option<IpAddr>,
+    clean_session: bool,
 }
 
 impl Socket {
-    pub fn new(connection: TcpStream, sender: Sender<Publish>, i: u32) -> Self {
+    pub fn new(
+        connection: TcpStream,
+        sender: Sender<IncomingPublish>,
+        i: u32,
+        limits: Limits,
+    ) -> Self {
         let read = connection;
         let write = read.try_clone().unwrap();
+        let ip = read.peer_addr().ok().map(|address| address.ip());
         Socket {
             read,
             write,
             user: (i, "".to_string()),
             sender,
             last_will: vec![],
+            will_properties: new_properties(),
+            connected: Arc::new(AtomicBool::new(true)),
+            quota: Quota::new(limits),
+            protocol_version: Arc::new(AtomicU8::new(PROTOCOL_VERSION_3)),
+            aliases: Arc::new(Mutex::new(new_topic_aliases(0, 0))),
+            ip,
+            clean_session: true,
         }
     }
 
@@ -40,12 +78,46 @@ impl Socket {
         self.user.0
     }
 
+    /// Version de MQTT con la que se conecto el cliente. Se comparte entre los clones del socket.
+    pub fn get_protocol_version(&self) -> u8 {
+        self.protocol_version.load(Ordering::SeqCst)
+    }
+
+    /// Como se le envian los paquetes al cliente segun lo que negocio en su CONNECT.
+    pub fn get_protocol(&self) -> ClientProtocol {
+        ClientProtocol::new(self.get_protocol_version(), Arc::clone(&self.aliases))
+    }
+
+    /// IP desde la que se conecto el cliente.
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// Indica si el thread que atiende al cliente sigue corriendo. Se comparte entre los clones
+    /// del socket, por lo que el server puede saber cuando un cliente se desconecto.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Cierra la conexion desde el server. El thread del cliente termina como en un ungraceful
+    /// disconnect, por lo que se publica su last will.
+    pub fn disconnect(&self) -> std::io::Result<()> {
+        self.read.shutdown(Shutdown::Both)
+    }
+
+    /// Desconecta al cliente porque el server se esta apagando. A los clientes MQTT 5 se les
+    /// envia un DISCONNECT con reason code `ServerShuttingDown`.
+    pub fn shutdown_disconnect(&self) {
+        if self.get_protocol_version() == PROTOCOL_VERSION_5 {
+            let disconnect = new_disconnect_with_reason(Mqtt5ReturnCodes::MqttRcServerShuttingDown);
+            let _result = (&self.write).write_all(&disconnect.get_data());
+        }
+        let _result = self.disconnect();
+    }
+
     pub fn get_write_stream(&self) -> TcpStream {
         self.write.try_clone().unwrap()
     }
-    pub fn get_read_stream(&self) -> TcpStream {
-        self.read.try_clone().unwrap()
-    }
 
     pub fn handle_client(mut self) {
         let mut read = self.read.try_clone().unwrap();
@@ -59,7 +131,9 @@ impl Socket {
                     Err(_) => {
                         if self.write.shutdown(Shutdown::Write).is_ok() {}
                         if self.read.shutdown(Shutdown::Read).is_ok() {}
-                        self.resolve_last_will(self.last_will.clone());
+                        if !is_shutting_down() {
+                            self.resolve_last_will(self.last_will.clone());
+                        }
                         break;
                     }
                 }
@@ -67,6 +141,11 @@ impl Socket {
                     break;
                 }
             }
+            self.connected.store(false, Ordering::SeqCst);
+            if self.clean_session && !self.user.1.is_empty() {
+                self.leave_shared_groups();
+            }
+            METRICS.connection_closed();
         });
     }
 
@@ -74,10 +153,17 @@ impl Socket {
         &mut self,
         read: &mut TcpStream,
         write: &mut TcpStream,
-        sender: &Sender<Publish>,
+        sender: &Sender<IncomingPublish>,
     ) -> Result<bool, Box<dyn Error>> {
-        let data = Self::read_all(read)?;
-        info!("Paquete recibido: {:?}", data.get_control_packet_type());
+        // El tamaño y el rate se verifican con el fixed header, antes de leer el resto del paquete.
+        let (fixed, header) = Self::read_fixed_header(read)?;
+        info!("Paquete recibido: {:?}", header.get_control_packet_type());
+        let size = header.get_remaining_length() as usize + 2;
+        let is_publish = matches!(header.get_control_packet_type(), PacketType::PUBLISH);
+        if let Err(reason) = self.quota.check_packet(size, is_publish) {
+            return Err(self.reject(write, reason));
+        }
+        let data = Self::read_remaining(read, fixed)?;
         return match self.read_array(data, write, sender, read) {
             Ok(b) => Ok(b),
             Err(_) => Ok(false),
@@ -87,10 +173,13 @@ impl Socket {
     /// Leo una cantidad n de bytes del incoming stream, la cantidad n esta definida por el primer incoming byte.
     /// A partir de esos n bytes puedo decodificar el tipo de packet recibido.
     pub fn read_all(stream: &mut TcpStream) -> Result<MqttHeader, Box<dyn Error>> {
+        let (fixed, _header) = Self::read_fixed_header(stream)?;
+        Self::read_remaining(stream, fixed)
+    }
+
+    /// Lee los dos bytes del fixed header, con el tipo de paquete y el remaining length.
+    fn read_fixed_header(stream: &mut TcpStream) -> Result<([u8; 2], MqttHeader), Box<dyn Error>> {
         let mut size_buf = [0_u8; 2];
-        let msg_size: u32;
-        let header: MqttHeader;
-        let mut result: Vec<u8> = Vec::new();
         match stream.read_exact(&mut size_buf) {
             Ok(_) => {
                 if size_buf[0] == 0 && size_buf[1] == 0 {
@@ -99,25 +188,28 @@ impl Socket {
                     }));
                 }
                 match new_mqtt_header(size_buf.to_vec()) {
-                    Ok(h) => {
-                        header = h;
-                        msg_size = header.get_remaining_length() as u32;
-                        result.append(&mut size_buf.to_vec())
-                    }
-                    Err(e) => {
-                        return Err(Box::new(MqttError { error: e }));
-                    }
+                    Ok(h) => Ok((size_buf, h)),
+                    Err(e) => Err(Box::new(MqttError { error: e })),
                 }
             }
             Err(ref e) => {
                 if e.kind() == WouldBlock {
                     println!("Timeout");
                 }
-                return Err(Box::new(MqttError {
+                Err(Box::new(MqttError {
                     error: MqttRcProtocolError,
-                }));
+                }))
             }
         }
+    }
+
+    /// Lee el resto del paquete cuyo fixed header es `fixed`.
+    fn read_remaining(
+        stream: &mut TcpStream,
+        fixed: [u8; 2],
+    ) -> Result<MqttHeader, Box<dyn Error>> {
+        let msg_size = fixed[1] as u32;
+        let mut result: Vec<u8> = fixed.to_vec();
 
         // Leer del socket la cantidad de bytes que indica el header
         let mut bytes_read: u32 = 0;
@@ -139,7 +231,14 @@ impl Socket {
         }
 
         match new_mqtt_header(result) {
-            Ok(h) => Ok(h),
+            Ok(h) => {
+                METRICS.packet_received(h.get_control_packet_type());
+                STATS.packet_received(
+                    msg_size as usize + 2,
+                    matches!(h.get_control_packet_type(), PacketType::PUBLISH),
+                );
+                Ok(h)
+            }
             Err(_) => Err(Box::new(MqttError {
                 error: MqttRcProtocolError,
             })),
@@ -150,7 +249,7 @@ impl Socket {
         &mut self,
         header: MqttHeader,
         stream: &mut TcpStream,
-        sender: &Sender<Publish>,
+        sender: &Sender<IncomingPublish>,
         read: &mut TcpStream,
     ) -> Result<bool, Box<dyn Error>> {
         //leo
@@ -166,13 +265,32 @@ impl Socket {
         match header.get_control_packet_type() {
             PacketType::CONNECT => {
                 let connect = serializer::new_connect_by_hex(header)?;
-                let ret = packets::connect::resolve_connect(connect.clone(), stream, user, read);
+                self.protocol_version
+                    .store(connect.get_protocol_version(), Ordering::SeqCst);
+                self.quota.set_user(connect.get_payload().get_username());
+                *lock_aliases(&self.aliases) =
+                    if connect.get_protocol_version() == PROTOCOL_VERSION_5 {
+                        let maximum = connect.get_properties().get_topic_alias_maximum();
+                        new_topic_aliases(protocol::topic_alias_maximum(), maximum.unwrap_or(0))
+                    } else {
+                        new_topic_aliases(0, 0)
+                    };
+                let protocol = self.get_protocol();
+                let ret =
+                    packets::connect::resolve_connect(connect.clone(), stream, user, &protocol);
                 match ret {
-                    Ok(ret) => {
-                        self.user = ret.clone();
-                        if ret.1 != *"" {
-                            self.handle_last_will(connect);
-                        }
+                    Ok(Some(accepted)) => {
+                        self.user = accepted.user.clone();
+                        self.clean_session = connect.get_connect_flags().get_clean_session();
+                        will::cancel(&accepted.user.1);
+                        self.handle_last_will(connect);
+                        // El CONNACK se envia con la conexion ya configurada.
+                        packets::connect::accept_connect(stream, read, &accepted, &protocol)?;
+                    }
+                    Ok(None) => {
+                        // CONNACK con error: se cierra la conexion.
+                        let _result = stream.shutdown(Shutdown::Both);
+                        return Ok(false);
                     }
                     Err(_e) => {
                         error!("CONNECT resolve error");
@@ -180,21 +298,74 @@ impl Socket {
                 }
             }
             PacketType::PUBLISH => {
-                let publish = serializer::new_publish_by_hex(header)?;
-                let ret = packets::publish::resolve_publish(publish, stream, sender)?;
+                let (publish, interval, properties) =
+                    if self.get_protocol_version() == PROTOCOL_VERSION_5 {
+                        let (publish, properties) = serializer::new_publish_v5_by_hex(header)?;
+                        let alias = properties.get_topic_alias();
+                        match lock_aliases(&self.aliases).resolve_inbound(publish, alias) {
+                            Ok(publish) => (
+                                publish,
+                                properties.get_message_expiry_interval(),
+                                protocol::forwarded_properties(&properties),
+                            ),
+                            Err(reason) => return Err(self.reject(stream, reason)),
+                        }
+                    } else {
+                        (
+                            serializer::new_publish_by_hex(header)?,
+                            expiry::default_ttl(),
+                            new_properties(),
+                        )
+                    };
+                let expires_at = expiry::expires_at(interval, expiry::now());
+                let ret = packets::publish::resolve_publish(
+                    publish,
+                    stream,
+                    sender,
+                    user.1.clone(),
+                    expires_at,
+                    properties,
+                )?;
                 if ret {
                     info!("PUBACK enviado correctamente.");
                 }
             }
             PacketType::SUBSCRIBE => {
-                let subscribe = serializer::new_subscribe_by_hex(header)?;
-                let new_subs =
-                    packets::subscribe::resolve_subscribe(stream, subscribe, user.clone())?;
-                for topic in new_subs {
+                let subscribe = match self.get_protocol_version() {
+                    PROTOCOL_VERSION_5 => serializer::new_subscribe_v5_by_hex(header)?.0,
+                    _ => serializer::new_subscribe_by_hex(header)?,
+                };
+                let current = packets::subscribe::count_subscriptions(&user.1)?;
+                let requested = packets::subscribe::count_new_subscriptions(&subscribe, &user.1)?;
+                if let Err(reason) = self.quota.check_subscriptions(current, requested) {
+                    warn!(
+                        "[Server:Socket] {:?} supero el limite de suscripciones: {:?}",
+                        user.1, reason
+                    );
+                    let code = match self.get_protocol_version() {
+                        PROTOCOL_VERSION_5 => SubackReturnCode::QuotaExceeded,
+                        _ => SubackReturnCode::Failure,
+                    };
+                    packets::subscribe::reject_subscribe(
+                        stream,
+                        &self.get_protocol(),
+                        subscribe.get_topics().len(),
+                        code,
+                    )?;
+                    return Ok(true);
+                }
+                let subscribed = packets::subscribe::resolve_subscribe(
+                    stream,
+                    subscribe,
+                    user.clone(),
+                    &self.get_protocol(),
+                )?;
+                for topic in subscribed.iter() {
                     match packets::publish::send_retain_messages_to_sub(
                         topic,
                         stream,
                         (user.1).to_string(),
+                        &self.get_protocol(),
                     ) {
                         Ok(_) => {}
                         Err(_) => {
@@ -211,6 +382,11 @@ impl Socket {
                 let pingresp = serializer::new_pingresp_by_hex();
                 stream.write_all(&pingresp.get_data())?;
             }
+            PacketType::PUBACK => {
+                // Confirma un publish QoS 1 que se le envio al cliente. No se reenvian los publish
+                // sin confirmar, asi que no hay nada que hacer.
+                info!("PUBACK recibido de {:?}", user.1);
+            }
             PacketType::DISCONNECT => {
                 return Ok(false);
             }
@@ -222,11 +398,43 @@ impl Socket {
         Ok(true)
     }
 
+    /// La sesion de un cliente con clean session termina al desconectarse, asi que deja de ser
+    /// miembro de los grupos compartidos: si no, se le seguirian encolando mensajes que nunca va
+    /// a reclamar.
+    fn leave_shared_groups(&self) {
+        let client = self.user.1.clone();
+        if update_shared_subs(|shared| remove_from_all(shared, &client)).is_err() {
+            error!(
+                "[Server:Socket] error al quitar a {:?} de los grupos compartidos",
+                client
+            );
+        }
+    }
+
+    /// Corta la conexion de un cliente que supero sus limites o envio un paquete invalido. A los
+    /// clientes MQTT 5 se les envia un DISCONNECT con el reason code; a los de 3.1.1 solo se les
+    /// cierra la conexion.
+    fn reject(&self, write: &mut TcpStream, reason: Mqtt5ReturnCodes) -> Box<dyn Error> {
+        warn!(
+            "[Server:Socket] Se desconecta a {:?}: {:?}",
+            self.user.1, reason
+        );
+        if self.get_protocol_version() == PROTOCOL_VERSION_5 {
+            let disconnect = new_disconnect_with_reason(reason.clone());
+            if write.write_all(&disconnect.get_data()).is_err() {
+                error!("[Server:Socket] error al enviar disconnect");
+            }
+        }
+        Box::new(MqttError { error: reason })
+    }
+
     /// En caso de que una conexion nueva posea last will/topic, esta funcion se encarga de generar un
-    /// Publish packet data para luego enviar en caso de ungraceful disconnect.
+    /// Publish packet data para luego enviar en caso de ungraceful disconnect. Tambien se guardan las
+    /// properties del will de los clientes MQTT 5.
     fn handle_last_will(&mut self, connect: Connect) {
         let flags = connect.get_connect_flags();
         if flags.get_will_flag() {
+            self.will_properties = connect.get_will_properties();
             let payload = connect.get_payload();
             let topic = payload.get_will_topic();
             let msg = payload.get_will_message();
@@ -268,7 +476,8 @@ impl Socket {
     }
 
     /// En caso de ungraceful disconnect acá se procesa el last will, se genera el correspondiente publish y se
-    /// envía al server para ser procesado y repartido
+    /// envía al server para ser procesado y repartido. Si el will tiene Will Delay Interval se publica
+    /// recien despues de ese tiempo, salvo que el cliente se reconecte antes.
     fn resolve_last_will(&self, data: Vec<u8>) {
         if !data.is_empty() {
             let header = serializer::new_mqtt_header(data);
@@ -277,16 +486,22 @@ impl Socket {
                     let publish = serializer::new_publish_by_hex(h);
                     match publish {
                         Ok(p) => {
-                            if p.get_flags().get_retain() {
-                                let payload_flags = p.get_flags();
-                                let topic_filter = p.get_topic();
-                                packets::publish::write_retain(
-                                    payload_flags,
-                                    topic_filter,
-                                    p.clone(),
-                                );
+                            let properties = self.will_properties.clone();
+                            let interval = match self.get_protocol_version() {
+                                PROTOCOL_VERSION_5 => properties.get_message_expiry_interval(),
+                                _ => expiry::default_ttl(),
+                            };
+                            let sender = self.sender.clone();
+                            let client = self.user.1.clone();
+                            let publish =
+                                move || publish_will(&sender, p, client, interval, &properties);
+                            match self.will_properties.get_will_delay_interval() {
+                                Some(delay) if delay > 0 => {
+                                    let delay = Duration::from_secs(delay as u64);
+                                    will::schedule(&self.user.1, delay, publish);
+                                }
+                                _ => publish(),
                             }
-                            let _result = self.sender.send(p);
                         }
                         Err(_e) => {
                             error!("Error creating last_will packet");
@@ -301,6 +516,32 @@ impl Socket {
     }
 }
 
+/// Publica el will: guarda el retain message si corresponde y se lo envia al server para que lo
+/// reparta. El vencimiento del will se cuenta desde que se publica.
+fn publish_will(
+    sender: &Sender<IncomingPublish>,
+    will: Publish,
+    client: String,
+    interval: Option<u32>,
+    properties: &Properties,
+) {
+    let expires_at = expiry::expires_at(interval, expiry::now());
+    let properties = protocol::forwarded_properties(properties);
+    if will.get_flags().get_retain() {
+        packets::publish::write_retain(
+            will.get_flags(),
+            will.get_topic(),
+            will.clone(),
+            expires_at,
+            &properties,
+        );
+    }
+    let incoming = IncomingPublish::new(will, client)
+        .with_expiry(expires_at)
+        .with_properties(properties);
+    let _result = sender.send(incoming);
+}
+
 impl Clone for Socket {
     fn clone(&self) -> Socket {
         Socket {
@@ -309,6 +550,13 @@ impl Clone for Socket {
             user: self.user.clone(),
             sender: self.sender.clone(),
             last_will: self.last_will.clone(),
+            will_properties: self.will_properties.clone(),
+            connected: Arc::clone(&self.connected),
+            quota: self.quota.clone(),
+            protocol_version: Arc::clone(&self.protocol_version),
+            aliases: Arc::clone(&self.aliases),
+            ip: self.ip,
+            clean_session: self.clean_session,
         }
     }
 }
@@ -0,0 +1,98 @@
+//! Estadisticas del broker que se publican periodicamente como retain messages en `$SYS/broker/...`.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Prefijo de los topics reservados del broker. Los clientes pueden suscribirse pero no publicar en ellos.
+pub const SYS_PREFIX: &str = "$SYS/";
+
+/// Contadores globales que actualizan los threads de cada socket y el thread que reparte los publish.
+pub struct Stats {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+pub static STATS: Stats = Stats::new();
+
+impl Stats {
+    const fn new() -> Self {
+        Stats {
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Registra un paquete leido de un cliente. Solo los publish cuentan como mensajes.
+    pub fn packet_received(&self, bytes: usize, is_publish: bool) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if is_publish {
+            self.messages_received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Registra un publish enviado a un suscriptor.
+    pub fn publish_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_messages_received(&self) -> u64 {
+        self.messages_received.load(Ordering::Relaxed)
+    }
+    pub fn get_messages_sent(&self) -> u64 {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+    pub fn get_bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+    pub fn get_bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Indica si el topic es de los que publica el broker (`$SYS/...`).
+pub fn is_sys_topic(topic: &str) -> bool {
+    topic.starts_with(SYS_PREFIX)
+}
+
+/// Estado del broker calculado a partir del server en el momento de publicar los `$SYS` topics.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SysInfo {
+    pub uptime: u64,
+    pub connected_clients: usize,
+    pub subscriptions: usize,
+    pub active_subscriptions: usize,
+    pub retained_messages: usize,
+    pub queued_messages: usize,
+}
+
+impl SysInfo {
+    /// Topics `$SYS/broker/...` con su valor, junto con los contadores globales de `STATS`.
+    pub fn topics(&self, stats: &Stats) -> Vec<(String, String)> {
+        let values = vec![
+            ("uptime", self.uptime.to_string()),
+            ("clients/connected", self.connected_clients.to_string()),
+            ("subscriptions/count", self.subscriptions.to_string()),
+            (
+                "subscriptions/active",
+                self.active_subscriptions.to_string(),
+            ),
+            (
+                "messages/received",
+                stats.get_messages_received().to_string(),
+            ),
+            ("messages/sent", stats.get_messages_sent().to_string()),
+            ("messages/retained", self.retained_messages.to_string()),
+            ("messages/queued", self.queued_messages.to_string()),
+            ("bytes/received", stats.get_bytes_received().to_string()),
+            ("bytes/sent", stats.get_bytes_sent().to_string()),
+        ];
+        values
+            .into_iter()
+            .map(|(name, value)| (format!("{}broker/{}", SYS_PREFIX, name), value))
+            .collect()
+    }
+}
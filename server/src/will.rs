@@ -0,0 +1,141 @@
+//! Last will con Will Delay Interval (MQTT 5).
+//!
+//! Si el will de un cliente trae un delay, cuando se corta la conexion se espera ese tiempo antes
+//! de publicarlo. Si el cliente se vuelve a conectar con el mismo client id antes de que pase,
+//! el will se cancela. Los wills pendientes no se publican si el server se apaga.
+//!
+//! Un solo thread publica los wills pendientes en orden de vencimiento. El delay que pide el
+//! cliente se limita a `max_will_delay` segundos de la configuracion.
+use crate::server::{config_value, is_shutting_down};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Delay maximo de un will si la configuracion no trae `max_will_delay`: un dia.
+const MAX_WILL_DELAY: u64 = 86400;
+
+static MAX_DELAY: OnceLock<u64> = OnceLock::new();
+static TIMER: OnceLock<(Mutex<Timer>, Condvar)> = OnceLock::new();
+
+type Publish = Box<dyn FnOnce() + Send>;
+
+/// Wills pendientes. Cada will se identifica por un numero para no confundirlo con el de una
+/// conexion posterior del mismo cliente.
+#[derive(Default)]
+struct Timer {
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    wills: HashMap<u64, Publish>,
+    clients: HashMap<String, u64>,
+    next_id: u64,
+    running: bool,
+}
+
+impl Timer {
+    fn remove(&mut self, client: &str) -> bool {
+        match self.clients.remove(client) {
+            Some(id) => {
+                self.wills.remove(&id);
+                self.deadlines
+                    .retain(|Reverse((_, pending))| *pending != id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Lee `max_will_delay` de la configuracion. Se llama una sola vez, al iniciar el server.
+pub fn init(config: &[Vec<String>]) {
+    let maximum = match config_value(config, "max_will_delay") {
+        Some(v) => v.parse::<u64>().unwrap_or_else(|_| {
+            error!("[Server:Will] max_will_delay invalido: {:?}", v);
+            MAX_WILL_DELAY
+        }),
+        None => MAX_WILL_DELAY,
+    };
+    let _result = MAX_DELAY.set(maximum);
+}
+
+fn max_delay() -> Duration {
+    Duration::from_secs(MAX_DELAY.get().copied().unwrap_or(MAX_WILL_DELAY))
+}
+
+fn timer() -> (MutexGuard<'static, Timer>, &'static Condvar) {
+    let (timer, wakeup) = TIMER.get_or_init(|| (Mutex::new(Timer::default()), Condvar::new()));
+    let guard = match timer.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    (guard, wakeup)
+}
+
+/// Ejecuta `publish` despues de `delay`, salvo que antes se cancele el will del cliente. Un will
+/// nuevo del mismo cliente reemplaza al pendiente.
+pub fn schedule<F>(client: &str, delay: Duration, publish: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let delay = delay.min(max_delay());
+    info!(
+        "[Server:Will] El will de {:?} se publica en {:?} segundos",
+        client,
+        delay.as_secs()
+    );
+    let (mut timer, wakeup) = timer();
+    timer.remove(client);
+    let id = timer.next_id;
+    timer.next_id += 1;
+    timer.deadlines.push(Reverse((Instant::now() + delay, id)));
+    timer.wills.insert(id, Box::new(publish));
+    timer.clients.insert(client.to_string(), id);
+    if !timer.running {
+        timer.running = true;
+        thread::spawn(publish_due_wills);
+    }
+    wakeup.notify_one();
+}
+
+/// Cancela el will pendiente del cliente, si tenia uno. Devuelve si se cancelo.
+pub fn cancel(client: &str) -> bool {
+    let (mut timer, wakeup) = timer();
+    let cancelled = timer.remove(client);
+    if cancelled {
+        wakeup.notify_one();
+        info!(
+            "[Server:Will] {:?} se reconecto, se cancela su last will",
+            client
+        );
+    }
+    cancelled
+}
+
+/// Espera al will que vence primero y lo publica sin tener tomado el lock.
+fn publish_due_wills() {
+    let (mut timer, wakeup) = timer();
+    loop {
+        let now = Instant::now();
+        timer = match timer.deadlines.peek().copied() {
+            Some(Reverse((deadline, id))) if deadline <= now => {
+                timer.deadlines.pop();
+                timer.clients.retain(|_, pending| *pending != id);
+                let publish = timer.wills.remove(&id);
+                drop(timer);
+                if let Some(publish) = publish.filter(|_| !is_shutting_down()) {
+                    publish();
+                }
+                self::timer().0
+            }
+            Some(Reverse((deadline, _))) => match wakeup.wait_timeout(timer, deadline - now) {
+                Ok((g, _)) => g,
+                Err(poisoned) => poisoned.into_inner().0,
+            },
+            None => match wakeup.wait(timer) {
+                Ok(g) => g,
+                Err(poisoned) => poisoned.into_inner(),
+            },
+        };
+    }
+}
@@ -0,0 +1,220 @@
+//! Levanta dos nodos de un cluster y verifica el traspaso de sesiones entre ellos: ni un CONNECT
+//! con credenciales invalidas ni una conexion al puerto del cluster sin el secret pueden reclamar
+//! la sesion de otro cliente, y un CONNECT valido se lleva las suscripciones y los mensajes
+//! encolados del nodo donde estaba.
+use serializer::{
+    new_connack_by_hex, new_connect, new_connect_flag, new_disconnect, new_mqtt_header,
+    new_payload_connect, new_puback, new_publish, new_publish_by_hex, new_publish_packet_flags,
+    new_subscribe, new_topic_filter, new_topic_filter_with_qos, ConnectAcknowledgeFlags,
+    MqttHeader, PacketType,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+/// Tiempo para que los nodos se conecten entre si y sincronicen los topics.
+const CLUSTER_SYNC: Duration = Duration::from_secs(3);
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Proceso de un nodo, que se termina aunque falle el test.
+struct Node(Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server(dir: &Path, port: u16) -> Node {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .current_dir(dir)
+        .spawn()
+        .expect("no se pudo iniciar el server");
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return Node(child);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("el server no empezo a escuchar");
+}
+
+fn node_dir(node: &str, port: u16, listen: u16, peer: u16) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mqtt_server_cluster_{}_{}", node, port));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let files = [
+        (
+            "config.txt",
+            format!("server:127.0.0.1,port:{},sys_interval:0", port),
+        ),
+        (
+            "cluster.json",
+            format!(
+                r#"{{"node_id": "{}", "listen": "127.0.0.1:{}", "peers": ["127.0.0.1:{}"], "secret": "cluster"}}"#,
+                node, listen, peer
+            ),
+        ),
+        ("topic_subscribers.json", r#"{"noticias":[]}"#.to_string()),
+        ("retain_messages.json", "{}".to_string()),
+        ("queue_messages.json", "{}".to_string()),
+        ("user_db.json", r#"{"admin":"secreto"}"#.to_string()),
+        ("users.json", r#"{"0":""}"#.to_string()),
+    ];
+    for (name, content) in files.iter() {
+        fs::write(dir.join(name), content).unwrap();
+    }
+    dir
+}
+
+fn read_packet(stream: &mut TcpStream) -> MqttHeader {
+    let mut data = vec![0_u8; 2];
+    stream.read_exact(&mut data).unwrap();
+    let mut rest = vec![0_u8; data[1] as usize];
+    stream.read_exact(&mut rest).unwrap();
+    data.append(&mut rest);
+    new_mqtt_header(data).unwrap()
+}
+
+/// Conecta al cliente como `admin` con `password`. Devuelve el stream, si se acepto la conexion y
+/// el session present del CONNACK.
+fn connect(
+    port: u16,
+    client: &str,
+    clean_session: bool,
+    password: &str,
+) -> (TcpStream, bool, bool) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let flags = new_connect_flag(
+        Some(clean_session),
+        None,
+        None,
+        None,
+        None,
+        Some(true),
+        Some(true),
+    )
+    .unwrap();
+    let payload = new_payload_connect(
+        client.to_string(),
+        "".to_string(),
+        "".to_string(),
+        "admin".to_string(),
+        password.to_string(),
+        0,
+    )
+    .unwrap();
+    stream
+        .write_all(&new_connect(flags, payload).unwrap().get_data())
+        .unwrap();
+    let connack = new_connack_by_hex(read_packet(&mut stream)).unwrap();
+    let session_present = matches!(
+        connack.get_connect_acknowledge_flags(),
+        ConnectAcknowledgeFlags::Sp1
+    );
+    let accepted = connack.get_connect_return_codes().is_accepted();
+    (stream, accepted, session_present)
+}
+
+fn publish(stream: &mut TcpStream, payload: &str) {
+    let flags = new_publish_packet_flags(None, Some(true), None, None).unwrap();
+    let topic = new_topic_filter("noticias".to_string()).unwrap();
+    let publish = new_publish(flags, topic, payload.to_string()).unwrap();
+    stream.write_all(&publish.get_data()).unwrap();
+    assert!(matches!(
+        read_packet(stream).get_control_packet_type(),
+        PacketType::PUBACK
+    ));
+}
+
+fn receive(stream: &mut TcpStream) -> String {
+    let publish = new_publish_by_hex(read_packet(stream)).unwrap();
+    assert_eq!(publish.get_topic().get_topic(), "noticias");
+    stream.write_all(&new_puback().get_data()).unwrap();
+    publish.get_payload()
+}
+
+#[test]
+fn session_moves_between_nodes_only_with_valid_credentials() {
+    let (port_a, port_b) = (free_port(), free_port());
+    let (listen_a, listen_b) = (free_port(), free_port());
+    let dir_a = node_dir("a", port_a, listen_a, listen_b);
+    let dir_b = node_dir("b", port_b, listen_b, listen_a);
+    let node_b = start_server(&dir_b, port_b);
+    let node_a = start_server(&dir_a, port_a);
+    thread::sleep(CLUSTER_SYNC);
+
+    // El cliente se suscribe en el nodo b.
+    let (mut subscriber, accepted, _) = connect(port_b, "persistente", true, "secreto");
+    assert!(accepted);
+    let filter = new_topic_filter_with_qos("noticias".to_string(), 1).unwrap();
+    subscriber
+        .write_all(&new_subscribe(vec![filter]).unwrap().get_data())
+        .unwrap();
+    assert!(matches!(
+        read_packet(&mut subscriber).get_control_packet_type(),
+        PacketType::SUBACK
+    ));
+
+    // Un CONNECT al nodo a con su client id y un password invalido se rechaza sin desconectarlo.
+    let (mut intruder, accepted, _) = connect(port_a, "persistente", false, "invalido");
+    assert!(!accepted);
+    assert_eq!(intruder.read(&mut [0; 1]).unwrap(), 0);
+    // Tampoco puede reclamarla una conexion al puerto del cluster que no manda el secret.
+    let mut node = TcpStream::connect(("127.0.0.1", listen_b)).unwrap();
+    node.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    node.write_all(
+        b"{\"type\":\"hello\",\"node\":\"x\"}\n{\"type\":\"claim\",\"client\":\"persistente\"}\n",
+    )
+    .unwrap();
+    assert_eq!(node.read(&mut [0; 1]).unwrap(), 0);
+    let (mut publisher, _, _) = connect(port_b, "publicador", true, "secreto");
+    publish(&mut publisher, "sigue conectado");
+    assert_eq!(receive(&mut subscriber), "sigue conectado");
+
+    // Mientras esta desconectado se le encola un mensaje en el nodo b.
+    subscriber.write_all(&new_disconnect().get_data()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    publish(&mut publisher, "encolado");
+
+    // Al reconectarse al nodo a recupera la sesion y el mensaje encolado.
+    let (mut subscriber, accepted, session_present) =
+        connect(port_a, "persistente", false, "secreto");
+    assert!(accepted && session_present);
+    assert_eq!(receive(&mut subscriber), "encolado");
+
+    // La suscripcion ahora esta en el nodo a: lo que se publica en b le llega por el cluster.
+    thread::sleep(CLUSTER_SYNC);
+    publish(&mut publisher, "migrado");
+    assert_eq!(receive(&mut subscriber), "migrado");
+
+    // Un cliente sin suscripciones ni mensajes encolados tambien tiene sesion en el otro nodo.
+    let (mut quiet, accepted, _) = connect(port_b, "sin_suscripciones", true, "secreto");
+    assert!(accepted);
+    quiet.write_all(&new_disconnect().get_data()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let (_quiet, accepted, session_present) =
+        connect(port_a, "sin_suscripciones", false, "secreto");
+    assert!(accepted && session_present);
+
+    drop((node_a, node_b));
+    let _ = fs::remove_dir_all(&dir_a);
+    let _ = fs::remove_dir_all(&dir_b);
+}
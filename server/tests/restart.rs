@@ -0,0 +1,142 @@
+//! Reinicia el server con SIGTERM y verifica que la sesion persistente de un cliente sobrevive:
+//! al reconectarse recibe session present y el mensaje que se le encolo mientras estaba desconectado.
+use serializer::{
+    new_connack_by_hex, new_connect, new_connect_flag, new_disconnect, new_mqtt_header,
+    new_payload_connect, new_puback, new_publish, new_publish_by_hex, new_publish_packet_flags,
+    new_subscribe, new_topic_filter, new_topic_filter_with_qos, ConnectAcknowledgeFlags,
+    MqttHeader, PacketType,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+fn start_server(dir: &Path, port: u16) -> Child {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .current_dir(dir)
+        .spawn()
+        .expect("no se pudo iniciar el server");
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return child;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("el server no empezo a escuchar");
+}
+
+fn state_dir(port: u16) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mqtt_server_restart_{}", port));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let files = [
+        (
+            "config.txt",
+            format!("server:127.0.0.1,port:{},sys_interval:0", port),
+        ),
+        ("topic_subscribers.json", r#"{"noticias":[]}"#.to_string()),
+        ("retain_messages.json", "{}".to_string()),
+        ("queue_messages.json", "{}".to_string()),
+        ("user_db.json", "{}".to_string()),
+        ("users.json", r#"{"0":""}"#.to_string()),
+    ];
+    for (name, content) in files.iter() {
+        fs::write(dir.join(name), content).unwrap();
+    }
+    dir
+}
+
+fn read_packet(stream: &mut TcpStream) -> MqttHeader {
+    let mut data = vec![0_u8; 2];
+    stream.read_exact(&mut data).unwrap();
+    let mut rest = vec![0_u8; data[1] as usize];
+    stream.read_exact(&mut rest).unwrap();
+    data.append(&mut rest);
+    new_mqtt_header(data).unwrap()
+}
+
+/// Conecta al cliente y devuelve el stream junto con el session present del CONNACK.
+fn connect(port: u16, client: &str, clean_session: bool) -> (TcpStream, bool) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let flags = new_connect_flag(Some(clean_session), None, None, None, None, None, None).unwrap();
+    let payload = new_payload_connect(
+        client.to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        0,
+    )
+    .unwrap();
+    stream
+        .write_all(&new_connect(flags, payload).unwrap().get_data())
+        .unwrap();
+    let connack = new_connack_by_hex(read_packet(&mut stream)).unwrap();
+    let session_present = matches!(
+        connack.get_connect_acknowledge_flags(),
+        ConnectAcknowledgeFlags::Sp1
+    );
+    (stream, session_present)
+}
+
+#[test]
+fn persistent_session_survives_restart() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let dir = state_dir(port);
+    let mut server = start_server(&dir, port);
+
+    // El cliente se suscribe con QoS 1 y se desconecta.
+    let (mut subscriber, _) = connect(port, "persistente", true);
+    let filter = new_topic_filter_with_qos("noticias".to_string(), 1).unwrap();
+    subscriber
+        .write_all(&new_subscribe(vec![filter]).unwrap().get_data())
+        .unwrap();
+    assert!(matches!(
+        read_packet(&mut subscriber).get_control_packet_type(),
+        PacketType::SUBACK
+    ));
+    subscriber.write_all(&new_disconnect().get_data()).unwrap();
+
+    // Mientras esta desconectado se le encola un mensaje.
+    thread::sleep(Duration::from_millis(300));
+    let (mut publisher, _) = connect(port, "publicador", true);
+    let flags = new_publish_packet_flags(None, Some(true), None, None).unwrap();
+    let topic = new_topic_filter("noticias".to_string()).unwrap();
+    let publish = new_publish(flags, topic, "encolado".to_string()).unwrap();
+    publisher.write_all(&publish.get_data()).unwrap();
+    assert!(matches!(
+        read_packet(&mut publisher).get_control_packet_type(),
+        PacketType::PUBACK
+    ));
+
+    let status = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(server.wait().unwrap().success());
+
+    let mut server = start_server(&dir, port);
+    let (mut subscriber, session_present) = connect(port, "persistente", false);
+    assert!(session_present);
+    let publish = new_publish_by_hex(read_packet(&mut subscriber)).unwrap();
+    assert_eq!(publish.get_topic().get_topic(), "noticias");
+    assert_eq!(publish.get_payload(), "encolado");
+    subscriber.write_all(&new_puback().get_data()).unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&dir);
+}